@@ -1,9 +1,100 @@
 use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
 use anchor_lang::solana_program::clock::Clock;
-use anchor_spl::token::{self, TokenAccount, Token, Transfer};
+use anchor_lang::solana_program::ed25519_program;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::program::{get_return_data, invoke};
+use anchor_lang::solana_program::program_option::COption;
+use anchor_lang::solana_program::sysvar::instructions::{load_current_index_checked, load_instruction_at_checked};
+use anchor_lang::system_program;
+use anchor_spl::token::{self, Burn, Mint, TokenAccount, Token, Transfer};
 
 declare_id!("5Qyc9MhKk2Dfh3TrGnruFaUPCoYbBcWRjkWc2pqQFkbs");
 
+/// Fixed-point scale applied to `acc_reward_per_share`.
+pub const REWARD_PRECISION: u128 = 1_000_000_000_000;
+
+/// Denominator for all basis-point rates.
+pub const BPS_DENOMINATOR: u64 = 10000;
+
+/// Fixed-point scale applied to `fee_mint_price`.
+pub const PRICE_PRECISION: u64 = 1_000_000_000;
+
+/// LP shares permanently locked by the first deposit, so the share price can't be
+/// cheaply inflated by an attacker holding the only outstanding share.
+pub const MINIMUM_LIQUIDITY: u64 = 1000;
+
+/// Most a cranker may be paid out of a fee distribution (bps of the amount distributed),
+/// whatever `crank_reward` is set to.
+pub const MAX_CRANK_REWARD_BPS: u64 = 100;
+
+/// Seconds a borrower has to repay a flash loan.
+pub const FLASH_LOAN_DURATION: i64 = 30;
+
+/// Maximum number of pool vaults a single flash loan may draw from.
+pub const MAX_LOAN_SOURCES: usize = 4;
+
+/// Maximum number of entries in the flash loan whitelist.
+pub const MAX_WHITELIST_LEN: usize = 10;
+
+/// Whitelist entry types, stored per entry in `whitelist_entry_types`. A key entry admits
+/// that exact borrower; a program entry admits any borrower account the program owns,
+/// such as its PDAs.
+pub const WHITELIST_ENTRY_KEY: u8 = 0;
+pub const WHITELIST_ENTRY_PROGRAM: u8 = 1;
+
+/// Current layout versions. Mutating instructions reject accounts at any other
+/// version until they are brought up to date by the matching `migrate_*` instruction.
+/// Each is bumped whenever the matching account's `LEN` changes.
+pub const GLOBAL_STATE_VERSION: u8 = 2;
+pub const POOL_VERSION: u8 = 2;
+pub const USER_STAKE_VERSION: u8 = 2;
+
+/// Maximum number of co-signing admins.
+pub const MAX_ADMINS: usize = 5;
+
+/// Maximum number of amount-bracketed fee tiers.
+pub const MAX_FEE_TIERS: usize = 8;
+
+/// Maximum number of borrowers the reputation index will record.
+/// The index grows by one entry per new borrower, so this also bounds its rent.
+pub const MAX_INDEXED_BORROWERS: usize = 1000;
+
+/// Number of recent loans kept in the loan history; older entries are overwritten.
+pub const LOAN_HISTORY_LEN: usize = 16;
+
+/// Most loan records returned by one `get_loan_history` call, keeping the page within
+/// the return data limit.
+pub const MAX_LOAN_HISTORY_PAGE: usize = 10;
+
+/// Loan record statuses. An open loan is reported as defaulted once it has outlived
+/// `FLASH_LOAN_DURATION` without being repaid.
+pub const LOAN_OPEN: u8 = 0;
+pub const LOAN_REPAID: u8 = 1;
+pub const LOAN_DEFAULTED: u8 = 2;
+
+/// Seconds without a repayment after which a borrower's reputation may be closed.
+pub const REPUTATION_INACTIVITY_WINDOW: i64 = 365 * 24 * 60 * 60;
+
+/// Seconds in a (365-day) year, used to annualize reward rates.
+pub const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+
+/// Longest lock a stake position may take on.
+pub const MAX_STAKE_LOCK_DURATION: i64 = 365 * 24 * 60 * 60;
+
+/// Extra reward weight (bps) earned by a position locked for `MAX_STAKE_LOCK_DURATION`.
+/// Shorter locks earn a proportional boost; unlocked positions earn none.
+pub const MAX_LOCK_BOOST_BPS: u64 = 10000;
+
+/// Largest share (bps) of seized collateral `liquidate_collateral` may pay its caller.
+pub const MAX_LIQUIDATION_INCENTIVE_BPS: u64 = 2000;
+
+/// Longest vesting schedule (seconds) `claim_to_vesting` may set up.
+pub const MAX_VESTING_DURATION: i64 = 4 * 365 * 24 * 60 * 60;
+
+/// Longest `unpause_delay` (seconds) that may be configured, so a pause cannot outlast a week.
+pub const MAX_UNPAUSE_DELAY: i64 = 7 * 24 * 60 * 60;
+
 #[program]
 pub mod ryft {
     use super::*;
@@ -11,480 +102,7224 @@ pub mod ryft {
     /// Initializes the global state for RYFT.
     /// `fee_rate` is provided in basis points.
     pub fn initialize(ctx: Context<Initialize>, fee_rate: u64) -> Result<()> {
+        ctx.accounts.init_state(fee_rate);
+        Ok(())
+    }
+
+    /// Initializes the protocol and applies every setting in `config` in one step.
+    /// The whole config is validated first, so an invalid field leaves nothing created.
+    pub fn initialize_with_config(ctx: Context<Initialize>, config: InitConfig) -> Result<()> {
+        config.validate()?;
+        ctx.accounts.init_state(config.fee_rate);
+        config.apply(&mut ctx.accounts.global_state, Clock::get()?.unix_timestamp);
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to register a liquidity pool for a token mint.
+    pub fn create_pool(ctx: Context<CreatePool>) -> Result<()> {
+        require!(ctx.accounts.global_state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+        require!(ctx.accounts.global_state.admin_threshold == 0, CustomError::MultisigRequired);
+        {
+            let pool = &mut ctx.accounts.pool;
+            pool.mint = ctx.accounts.pool_account.mint;
+            pool.pool_account = ctx.accounts.pool_account.key();
+            pool.accumulated_fees = 0;
+            pool.total_loans = 0;
+            pool.fee_rate = ctx.accounts.global_state.fee_rate;
+            pool.version = POOL_VERSION;
+        }
+        audit(ctx.accounts.admin.key(), AuditAction::CreatePool, AuditValue::None, ctx.accounts.pool.key())?;
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to bring `global_state` up to the current layout version,
+    /// growing the account to the current size if needed. Takes the state unchecked, since an
+    /// outdated one may not deserialize until grown. Settings read from the zeroed tail, or
+    /// otherwise out of range, are reset to their safe defaults.
+    pub fn migrate_global_state(ctx: Context<MigrateGlobalState>) -> Result<()> {
+        grow_account(
+            &ctx.accounts.global_state,
+            &GlobalState::DISCRIMINATOR,
+            8 + GlobalState::LEN,
+            &ctx.accounts.admin,
+            &ctx.accounts.system_program,
+        )?;
+        let mut data = ctx.accounts.global_state.try_borrow_mut_data()?;
+        let mut state = GlobalState::try_deserialize(&mut &data[..])?;
+        require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+        apply_safe_defaults(&mut state);
+        state.version = GLOBAL_STATE_VERSION;
+        state.try_serialize(&mut &mut data[..])?;
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to bring a pool up to the current layout version.
+    /// Takes the pool unchecked, since an outdated pool may not deserialize. A pool grown
+    /// to hold its own `fee_rate` starts out on the global one. Audited as `version, fee_rate`,
+    /// with a `fee_rate` of 0 for a layout that had none.
+    pub fn migrate_pool(ctx: Context<MigratePool>) -> Result<()> {
+        require!(ctx.accounts.global_state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+        require!(ctx.accounts.global_state.admin_threshold == 0, CustomError::MultisigRequired);
+        let grown = ctx.accounts.pool.data_len() < 8 + Pool::LEN;
+        let (old_version, old_fee_rate) = {
+            let data = ctx.accounts.pool.try_borrow_data()?;
+            let fee_rate = if grown { 0 } else { Pool::try_deserialize(&mut &data[..])?.fee_rate };
+            (data[data.len() - 1], fee_rate)
+        };
+        migrate_account(
+            &ctx.accounts.pool,
+            &Pool::DISCRIMINATOR,
+            8 + Pool::LEN,
+            POOL_VERSION,
+            &ctx.accounts.admin,
+            &ctx.accounts.system_program,
+        )?;
+        let new_fee_rate = if grown { ctx.accounts.global_state.fee_rate } else { old_fee_rate };
+        if grown {
+            let mut data = ctx.accounts.pool.try_borrow_mut_data()?;
+            let mut pool = Pool::try_deserialize(&mut &data[..])?;
+            pool.fee_rate = new_fee_rate;
+            pool.try_serialize(&mut &mut data[..])?;
+        }
+        audit(
+            ctx.accounts.admin.key(),
+            AuditAction::MigratePool,
+            AuditValue::Numbers(vec![old_version as u64, old_fee_rate]),
+            AuditValue::Numbers(vec![POOL_VERSION as u64, new_fee_rate]),
+        )
+    }
+
+    /// Brings the caller's stake account up to the current layout version.
+    /// Takes the account unchecked, since an outdated one may not deserialize.
+    pub fn migrate_user_stake(ctx: Context<MigrateUserStake>) -> Result<()> {
+        migrate_account(
+            &ctx.accounts.user_stake,
+            &UserStake::DISCRIMINATOR,
+            8 + UserStake::LEN,
+            USER_STAKE_VERSION,
+            &ctx.accounts.user,
+            &ctx.accounts.system_program,
+        )
+    }
+
+    /// Admin-controlled instruction to create the (initially empty) reputation index.
+    pub fn create_reputation_index(ctx: Context<CreateReputationIndex>) -> Result<()> {
+        require!(ctx.accounts.global_state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+        require!(ctx.accounts.global_state.admin_threshold == 0, CustomError::MultisigRequired);
+        ctx.accounts.reputation_index.borrowers = Vec::new();
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to create the loan history, which keeps the last
+    /// `LOAN_HISTORY_LEN` loans issued with it.
+    pub fn create_loan_history(ctx: Context<CreateLoanHistory>) -> Result<()> {
+        require!(ctx.accounts.global_state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+        require!(ctx.accounts.global_state.admin_threshold == 0, CustomError::MultisigRequired);
+        let history = &mut ctx.accounts.loan_history;
+        history.total_loans = 0;
+        history.records = Vec::new();
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to create the program-owned collateral escrow for a mint.
+    /// Escrows live at a PDA derived from the mint and are owned by the `escrow_authority` PDA,
+    /// so only this program can move collateral out of them.
+    pub fn create_collateral_escrow(ctx: Context<CreateCollateralEscrow>) -> Result<()> {
+        require!(ctx.accounts.global_state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+        require!(ctx.accounts.global_state.admin_threshold == 0, CustomError::MultisigRequired);
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to create the canonical vaults, right after `initialize`:
+    /// program-owned token accounts for the pool, fee and stake mints at PDAs derived from
+    /// the global state and mint, all owned by the `vault_authority` PDA. From then on only
+    /// these vaults are accepted, pools must be created over the canonical pool vault, and
+    /// the program signs every transfer out of them.
+    pub fn initialize_vaults(ctx: Context<InitializeVaults>) -> Result<()> {
         {
             let state = &mut ctx.accounts.global_state;
-            state.admin = *ctx.accounts.admin.key;
-            state.fee_rate = fee_rate;
-            state.total_liquidity = 0;
-            state.total_staked = 0;
-            state.accumulated_fees = 0;
-            state.is_flash_loan_active = false;
-            state.treasury_account = ctx.accounts.treasury.key();
-            // Initialize whitelist with an empty vector.
-            state.flash_loan_whitelist = Vec::new();
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            // Replacing live vaults would strand what they hold.
+            require!(
+                !state.canonical_vaults && state.total_liquidity == 0 && state.total_staked == 0,
+                CustomError::VaultsAlreadyInitialized
+            );
+            state.pool_vault = ctx.accounts.pool_vault.key();
+            state.fee_vault = ctx.accounts.fee_vault.key();
+            state.stake_vault = ctx.accounts.stake_vault.key();
+            state.vault_authority_bump = ctx.bumps.vault_authority;
+            state.pool_vault_bump = ctx.bumps.pool_vault;
+            state.fee_vault_bump = ctx.bumps.fee_vault;
+            state.stake_vault_bump = ctx.bumps.stake_vault;
+            state.canonical_vaults = true;
         }
         Ok(())
     }
 
+    /// Returns what `repay_flash_loan` would draw from the repayment account right now to
+    /// close the borrower's open loan: outstanding principal plus fee, less any staking
+    /// rebate (pass `borrower_stake` to have it applied). Fees are fixed at borrow time,
+    /// so the figure only changes through partial repayments. A fee owed in a separate
+    /// fee mint is not included.
+    pub fn get_repayment_amount(ctx: Context<GetRepaymentAmount>) -> Result<u64> {
+        let state = &ctx.accounts.global_state;
+        let flash_loan_state = &ctx.accounts.flash_loan_state;
+        require!(
+            state.is_flash_loan_active && state.active_borrower == flash_loan_state.borrower,
+            CustomError::NoOpenLoan
+        );
+        let elapsed = elapsed_since(flash_loan_state.start_time, Clock::get()?.unix_timestamp)?;
+        require!(elapsed <= FLASH_LOAN_DURATION, CustomError::FlashLoanExpired);
+        let stake = ctx.accounts.borrower_stake.as_ref().map_or(0, |s| s.amount);
+        let (outstanding, _, _) = repayment_due(state, flash_loan_state, stake);
+        Ok(outstanding)
+    }
+
+    /// Returns up to `limit` (at most `MAX_LOAN_HISTORY_PAGE`) recorded loans, newest first,
+    /// after skipping the `offset` most recent.
+    pub fn get_loan_history(ctx: Context<GetLoanHistory>, offset: u64, limit: u8) -> Result<Vec<LoanRecord>> {
+        let now = Clock::get()?.unix_timestamp;
+        Ok(ctx.accounts.loan_history.recent(offset, (limit as usize).min(MAX_LOAN_HISTORY_PAGE), now))
+    }
+
+    /// Returns the fees accumulated by a single pool.
+    pub fn get_pool_fees(ctx: Context<GetPoolFees>) -> Result<u64> {
+        Ok(ctx.accounts.pool.accumulated_fees)
+    }
+
+    /// Returns the current staking APR in basis points for an unboosted stake, annualizing the
+    /// running emission rate. There is no price oracle, so the APR is denominated in reward
+    /// tokens per staked token. Zero when nothing is staked or no schedule is running.
+    pub fn get_staking_apr(ctx: Context<GetStakingApr>) -> Result<u64> {
+        let state = &ctx.accounts.global_state;
+        Ok(staking_apr_bps(state, Clock::get()?.unix_timestamp))
+    }
+
+    /// Returns how the fee on a loan of `amount` would be built up right now, through the same
+    /// pipeline `flash_loan` charges with. `voucher` and `subsidized` say whether the loan
+    /// would use a voucher or draw on the subsidy vault. With `elapsed` set, the breakdown
+    /// also covers repaying after that many seconds: time-based pro-rating and, when
+    /// `borrower_stake` is passed, the staking rebate.
+    pub fn quote_fee(ctx: Context<QuoteFee>, amount: u64, elapsed: Option<i64>, voucher: bool, subsidized: bool) -> Result<FeeBreakdown> {
+        let borrower_ctx = BorrowerFeeContext {
+            voucher,
+            subsidized,
+            stake: ctx.accounts.borrower_stake.as_ref().map_or(0, |s| s.amount),
+        };
+        effective_fee(&ctx.accounts.global_state, ctx.accounts.pool.as_deref(), &borrower_ctx, amount, elapsed)
+    }
+
+    /// Returns a solvency summary comparing what the pool and stake vaults physically hold
+    /// with what the books say they owe. Pass the open loan, if any, so its unrepaid
+    /// principal counts as lent out rather than missing.
+    pub fn get_health(ctx: Context<GetHealth>) -> Result<HealthReport> {
+        let state = &ctx.accounts.global_state;
+        let outstanding_loans = match &ctx.accounts.active_loan {
+            Some(loan) if state.is_flash_loan_active && loan.borrower == state.active_borrower => {
+                loan.amount.saturating_sub(loan.repaid)
+            }
+            _ => 0,
+        };
+        let pool_balance = ctx.accounts.pool_account.amount;
+        let stake_vault_balance = ctx.accounts.stake_vault.amount;
+        // LP claims must be backed by the vault plus whatever is lent out.
+        let pool_solvent = pool_balance as u128 + outstanding_loans as u128 >= state.total_liquidity as u128;
+        let stake_solvent = stake_vault_balance >= state.total_staked;
+        Ok(HealthReport {
+            pool_balance,
+            total_liquidity: state.total_liquidity,
+            accumulated_fees: state.accumulated_fees,
+            outstanding_loans,
+            utilization_bps: spot_utilization(state, outstanding_loans),
+            stake_vault_balance,
+            total_staked: state.total_staked,
+            pool_solvent,
+            stake_solvent,
+            is_solvent: pool_solvent && stake_solvent,
+        })
+    }
+
     /// Governance-controlled instruction to update the fee rate.
     pub fn update_fee_rate(ctx: Context<UpdateFeeRate>, new_fee_rate: u64) -> Result<()> {
+        require!(new_fee_rate <= BPS_DENOMINATOR, CustomError::InvalidBps);
         {
             let state = &mut ctx.accounts.global_state;
             require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.fee_rate;
             state.fee_rate = new_fee_rate;
+            audit(ctx.accounts.admin.key(), AuditAction::UpdateFeeRate, old_value, new_fee_rate)?;
         }
         Ok(())
     }
 
-    /// Deposits tokens from a liquidity provider into the pool.
-    pub fn deposit_liquidity(ctx: Context<DepositLiquidity>, amount: u64) -> Result<()> {
-        // Perform token transfer (immutable borrow inside helper)
+    /// Admin-controlled instruction to set one pool's fee rate (bps), which proportional
+    /// loans from that pool pay in place of the global `fee_rate`. Pools start out on
+    /// the global rate at creation.
+    pub fn update_pool_fee_rate(ctx: Context<UpdatePoolFeeRate>, new_fee_rate: u64) -> Result<()> {
+        require!(new_fee_rate <= BPS_DENOMINATOR, CustomError::InvalidBps);
         {
-            let transfer_ctx = ctx.accounts.into_transfer_to_pool_context();
-            token::transfer(transfer_ctx, amount)?;
+            let state = &ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
         }
-        // Update liquidity in state in its own block
+        let old_value = ctx.accounts.pool.fee_rate;
+        ctx.accounts.pool.fee_rate = new_fee_rate;
+        audit(ctx.accounts.admin.key(), AuditAction::UpdatePoolFeeRate, old_value, new_fee_rate)?;
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to register `vault` as an extra vault of `pool`, which
+    /// flash loans the pool account alone can't cover may also draw from. The protocol's
+    /// stake, fee, reward and subsidy vaults can never be registered.
+    pub fn add_pool_vault(ctx: Context<AddPoolVault>) -> Result<()> {
         {
-            let state = &mut ctx.accounts.global_state;
-            state.total_liquidity = state.total_liquidity.checked_add(amount).unwrap();
+            let state = &ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!is_protocol_vault(state, &ctx.accounts.vault.key()), CustomError::InvalidPoolVault);
         }
+        let registration = &mut ctx.accounts.registration;
+        registration.pool = ctx.accounts.pool.key();
+        registration.vault = ctx.accounts.vault.key();
+        audit(ctx.accounts.admin.key(), AuditAction::AddPoolVault, AuditValue::None, registration.vault)?;
         Ok(())
     }
 
-    /// Withdraws liquidity from the pool back to the provider.
-    pub fn withdraw_liquidity(ctx: Context<WithdrawLiquidity>, amount: u64) -> Result<()> {
-        // First, check that enough liquidity exists.
+    /// Admin-controlled instruction to deregister an extra vault of a pool, refunding the
+    /// registration's rent to the admin.
+    pub fn remove_pool_vault(ctx: Context<RemovePoolVault>) -> Result<()> {
+        let state = &ctx.accounts.global_state;
+        require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+        require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+        audit(ctx.accounts.admin.key(), AuditAction::RemovePoolVault, ctx.accounts.registration.vault, AuditValue::None)?;
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to set the amount-bracketed fee schedule. A loan pays the
+    /// `fee_bps` of the highest tier whose `threshold` it meets; loans below every threshold,
+    /// or any loan when `tiers` is empty, pay the flat `fee_rate`.
+    pub fn set_fee_tiers(ctx: Context<UpdateConfig>, tiers: Vec<FeeTier>) -> Result<()> {
+        validate_fee_tiers(&tiers)?;
         {
-            let available = ctx.accounts.global_state.total_liquidity;
-            require!(available >= amount, CustomError::InsufficientLiquidity);
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = AuditValue::from(state.fee_tiers.as_slice());
+            state.fee_tiers = tiers;
+            audit(ctx.accounts.admin.key(), AuditAction::SetFeeTiers, old_value, state.fee_tiers.as_slice())?;
         }
-        // Then perform the token transfer.
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to choose the fee model. Under `FixedAbsolute` every loan
+    /// pays the same flat fee, under `Utilization` the rate follows `util_ema`, and under
+    /// `TimeBased` the quoted rate is pro-rated by how long the loan stays open; in each case
+    /// `fee_rate` and the fee tiers are ignored and the fee floor still applies.
+    pub fn set_fee_model(ctx: Context<UpdateConfig>, fee_model: FeeModel) -> Result<()> {
+        validate_fee_model(fee_model)?;
         {
-            let transfer_ctx = ctx.accounts.into_transfer_from_pool_context();
-            token::transfer(transfer_ctx, amount)?;
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.fee_model;
+            state.fee_model = fee_model;
+            audit(ctx.accounts.admin.key(), AuditAction::SetFeeModel, old_value, fee_model)?;
         }
-        // Finally, update the global state.
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to set how much weight each loan and repayment gives the
+    /// current utilization in `util_ema`. Zero freezes the average.
+    pub fn set_util_smoothing(ctx: Context<UpdateConfig>, util_smoothing_bps: u64) -> Result<()> {
+        require!(util_smoothing_bps <= BPS_DENOMINATOR, CustomError::InvalidBps);
         {
             let state = &mut ctx.accounts.global_state;
-            state.total_liquidity = state.total_liquidity.checked_sub(amount).unwrap();
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.util_smoothing_bps;
+            state.util_smoothing_bps = util_smoothing_bps;
+            audit(ctx.accounts.admin.key(), AuditAction::SetUtilSmoothing, old_value, util_smoothing_bps)?;
         }
         Ok(())
     }
 
-    /// Stake RYFT tokens for flash loan priority and yield.
-    pub fn stake(ctx: Context<Stake>, amount: u64) -> Result<()> {
-        // First, transfer tokens from the user to the stake vault.
+    /// Admin-controlled instruction to choose how much instructions log through events:
+    /// `Full` (the default), `Minimal` (balances and fees reported as zero) or `Off`.
+    pub fn set_event_level(ctx: Context<UpdateConfig>, event_level: EventLevel) -> Result<()> {
         {
-            let transfer_ctx = ctx.accounts.into_transfer_to_stake_context();
-            token::transfer(transfer_ctx, amount)?;
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.event_level;
+            state.event_level = event_level;
+            audit(ctx.accounts.admin.key(), AuditAction::SetEventLevel, old_value, event_level)?;
         }
-        // Then update the user's stake.
+        Ok(())
+    }
+
+    /// Pauser-controlled emergency stop. While paused, new loans, deposits, staking and
+    /// reward claims are rejected; `emergency_unstake` still works. Pausing takes effect
+    /// at once, but unpausing is only allowed `unpause_delay` seconds after the pause.
+    pub fn set_paused(ctx: Context<SetPaused>, paused: bool) -> Result<()> {
+        let current_time = Clock::get()?.unix_timestamp;
         {
-            let user_stake = &mut ctx.accounts.user_stake;
-            if user_stake.amount == 0 {
-                user_stake.last_stake_timestamp = Clock::get()?.unix_timestamp;
+            let state = &mut ctx.accounts.global_state;
+            require!(role_holder(state, state.pauser) == *ctx.accounts.pauser.key, CustomError::Unauthorized);
+            if paused && !state.paused {
+                state.paused_at = current_time;
             }
-            user_stake.amount = user_stake.amount.checked_add(amount).unwrap();
+            if !paused && state.paused {
+                require!(
+                    current_time >= state.paused_at.checked_add(state.unpause_delay).unwrap(),
+                    CustomError::UnpauseTooSoon
+                );
+            }
+            let old_value = state.paused;
+            state.paused = paused;
+            audit(ctx.accounts.pauser.key(), AuditAction::SetPaused, old_value, paused)?;
         }
-        // And update the global staked total.
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to penalize unstaking within `early_unstake_period`
+    /// seconds of opening a position. The penalty is `penalty_bps` of the amount, rounded up
+    /// and never below `min_penalty`, and is paid to the treasury. A zero period turns it off.
+    pub fn set_unstake_penalty(
+        ctx: Context<UpdateConfig>,
+        early_unstake_period: i64,
+        penalty_bps: u64,
+        min_penalty: u64,
+    ) -> Result<()> {
+        require!(early_unstake_period >= 0, CustomError::InvalidDuration);
+        require!(penalty_bps <= BPS_DENOMINATOR, CustomError::InvalidBps);
         {
             let state = &mut ctx.accounts.global_state;
-            state.total_staked = state.total_staked.checked_add(amount).unwrap();
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = AuditValue::Numbers(vec![
+                state.early_unstake_period as u64,
+                state.unstake_penalty_bps,
+                state.min_unstake_penalty,
+            ]);
+            state.early_unstake_period = early_unstake_period;
+            state.unstake_penalty_bps = penalty_bps;
+            state.min_unstake_penalty = min_penalty;
+            let new_value = AuditValue::Numbers(vec![early_unstake_period as u64, penalty_bps, min_penalty]);
+            audit(ctx.accounts.admin.key(), AuditAction::SetUnstakePenalty, old_value, new_value)?;
         }
         Ok(())
     }
 
-    /// Unstake previously staked RYFT tokens.
-    pub fn unstake(ctx: Context<Unstake>, amount: u64) -> Result<()> {
-        // Ensure the user has enough staked tokens.
+    /// Admin-controlled instruction to set how long (seconds) the program must stay paused
+    /// before it can be unpaused, up to `MAX_UNPAUSE_DELAY`. Zero allows unpausing at once.
+    /// Rejected while paused, so the delay of a pause in progress cannot be cut short.
+    pub fn set_unpause_delay(ctx: Context<UpdateConfig>, unpause_delay: i64) -> Result<()> {
+        require!((0..=MAX_UNPAUSE_DELAY).contains(&unpause_delay), CustomError::InvalidDuration);
         {
-            let current_stake = ctx.accounts.user_stake.amount;
-            require!(current_stake >= amount, CustomError::InsufficientStake);
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            require!(!state.paused, CustomError::ProgramPaused);
+            let old_value = state.unpause_delay;
+            state.unpause_delay = unpause_delay;
+            audit(ctx.accounts.admin.key(), AuditAction::SetUnpauseDelay, old_value, unpause_delay)?;
         }
-        // Transfer tokens from the stake vault back to the user.
+        Ok(())
+    }
+
+    /// Pauser-controlled switch for new stake alone. While set, `stake`, `open_position` and
+    /// `compound_rewards` are rejected; unstaking, emergency exits and claims keep working.
+    pub fn set_staking_paused(ctx: Context<SetPaused>, staking_paused: bool) -> Result<()> {
         {
-            let transfer_ctx = ctx.accounts.into_transfer_from_stake_context();
-            token::transfer(transfer_ctx, amount)?;
+            let state = &mut ctx.accounts.global_state;
+            require!(role_holder(state, state.pauser) == *ctx.accounts.pauser.key, CustomError::Unauthorized);
+            let old_value = state.staking_paused;
+            state.staking_paused = staking_paused;
+            audit(ctx.accounts.pauser.key(), AuditAction::SetStakingPaused, old_value, staking_paused)?;
         }
-        // Update the user's stake.
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to hand the pause switch to a separate `pauser` key.
+    pub fn set_pauser(ctx: Context<UpdateConfig>, pauser: Pubkey) -> Result<()> {
         {
-            let user_stake = &mut ctx.accounts.user_stake;
-            user_stake.amount = user_stake.amount.checked_sub(amount).unwrap();
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            let old_value = state.pauser;
+            state.pauser = pauser;
+            audit(ctx.accounts.admin.key(), AuditAction::SetPauser, old_value, pauser)?;
         }
-        // Update the global staked total.
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to hand fee withdrawals to a separate `treasurer` key.
+    pub fn set_treasurer(ctx: Context<UpdateConfig>, treasurer: Pubkey) -> Result<()> {
         {
             let state = &mut ctx.accounts.global_state;
-            state.total_staked = state.total_staked.checked_sub(amount).unwrap();
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            let old_value = state.treasurer;
+            state.treasurer = treasurer;
+            audit(ctx.accounts.admin.key(), AuditAction::SetTreasurer, old_value, treasurer)?;
         }
         Ok(())
     }
 
-    /// Executes an atomic flash loan. The borrowed funds must be repaid in the same transaction.
-    /// Features include reentrancy protection, whitelist check, time-limited execution, and collateral backing.
-    pub fn flash_loan(ctx: Context<FlashLoan>, amount: u64, collateral_amount: u64) -> Result<()> {
-        // Set reentrancy flag and perform whitelist check.
+    /// Admin-controlled instruction to hand the sensitive instructions (fee rate, model, tiers
+    /// and split, pauser, treasurer, voucher signer, compliance hook, swap program, wind-down,
+    /// config lock) to `threshold` of `admins`. From then on they only run through
+    /// `propose_admin_action`, `approve_admin_action` and `execute_admin_action`, and the
+    /// admin set itself can only be changed the same way. Instructions that move tokens
+    /// (stake vault migration, protocol liquidity withdrawal, wind-down finalization) keep
+    /// their own accounts and instead consume an approved action for the same change.
+    pub fn set_admins(ctx: Context<UpdateConfig>, admins: Vec<Pubkey>, threshold: u8) -> Result<()> {
+        validate_admins(&admins, threshold)?;
         {
             let state = &mut ctx.accounts.global_state;
-            require!(!state.is_flash_loan_active, CustomError::FlashLoanInProgress);
-            state.is_flash_loan_active = true;
-            if !state.flash_loan_whitelist.is_empty() {
-                require!(state.flash_loan_whitelist.contains(ctx.accounts.borrower.key), CustomError::NotWhitelisted);
-            }
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            let old_value = AuditValue::Admins(state.admins.clone(), state.admin_threshold);
+            state.admins = admins;
+            state.admin_threshold = threshold;
+            let new_value = AuditValue::Admins(state.admins.clone(), threshold);
+            audit(ctx.accounts.admin.key(), AuditAction::SetAdmins, old_value, new_value)?;
         }
-        // Check pool liquidity.
-        if ctx.accounts.pool_account.amount < amount {
-            {
-                let state = &mut ctx.accounts.global_state;
-                state.is_flash_loan_active = false;
-            }
-            return Err(CustomError::InsufficientLiquidity.into());
+        Ok(())
+    }
+
+    /// Proposes a sensitive change for the co-signing admins. The proposer, who must be one
+    /// of them, counts as its first approval.
+    pub fn propose_admin_action(ctx: Context<ProposeAdminAction>, action: AdminActionKind) -> Result<()> {
+        {
+            let state = &ctx.accounts.global_state;
+            require!(state.admin_threshold > 0, CustomError::MultisigNotEnabled);
+            require!(state.admins.contains(ctx.accounts.proposer.key), CustomError::Unauthorized);
         }
-        // Transfer collateral (if provided).
-        if collateral_amount > 0 {
-            {
-                let collateral_ctx = ctx.accounts.into_transfer_collateral_context();
-                token::transfer(collateral_ctx, collateral_amount)?;
+        validate_admin_action(&action)?;
+        let admin_action = &mut ctx.accounts.admin_action;
+        admin_action.global_state = ctx.accounts.global_state.key();
+        admin_action.proposer = *ctx.accounts.proposer.key;
+        admin_action.action = action;
+        admin_action.approvals = vec![*ctx.accounts.proposer.key];
+        admin_action.executed = false;
+        Ok(())
+    }
+
+    /// Adds the signing admin's approval to a pending action.
+    pub fn approve_admin_action(ctx: Context<ApproveAdminAction>) -> Result<()> {
+        require!(
+            ctx.accounts.global_state.admins.contains(ctx.accounts.admin.key),
+            CustomError::Unauthorized
+        );
+        let admin_action = &mut ctx.accounts.admin_action;
+        require!(!admin_action.executed, CustomError::ActionAlreadyExecuted);
+        require!(!admin_action.approvals.contains(ctx.accounts.admin.key), CustomError::AlreadyApproved);
+        admin_action.approvals.push(*ctx.accounts.admin.key);
+        Ok(())
+    }
+
+    /// Carries out a pending action once `admin_threshold` current admins have approved it.
+    /// Approvals from keys since removed from `admins` no longer count.
+    pub fn execute_admin_action(ctx: Context<ExecuteAdminAction>) -> Result<()> {
+        check_admin_approvals(&ctx.accounts.global_state, &ctx.accounts.admin_action)?;
+        let action = ctx.accounts.admin_action.action.clone();
+        // Audited on behalf of the proposer; anyone may trigger the execution itself.
+        let proposer = ctx.accounts.admin_action.proposer;
+        {
+            let state = &mut ctx.accounts.global_state;
+            match action {
+                AdminActionKind::UpdateFeeRate(fee_rate) => {
+                    require!(fee_rate <= BPS_DENOMINATOR, CustomError::InvalidBps);
+                    require!(!state.config_locked, CustomError::ConfigLocked);
+                    audit(proposer, AuditAction::UpdateFeeRate, state.fee_rate, fee_rate)?;
+                    state.fee_rate = fee_rate;
+                }
+                AdminActionKind::UpdatePoolFeeRate { pool, fee_rate } => {
+                    require!(fee_rate <= BPS_DENOMINATOR, CustomError::InvalidBps);
+                    require!(!state.config_locked, CustomError::ConfigLocked);
+                    let pool_state = ctx.accounts.pool.as_mut().ok_or(CustomError::ActionNeedsAccounts)?;
+                    require!(pool_state.key() == pool, CustomError::ActionMismatch);
+                    audit(proposer, AuditAction::UpdatePoolFeeRate, pool_state.fee_rate, fee_rate)?;
+                    pool_state.fee_rate = fee_rate;
+                }
+                AdminActionKind::SetPauser(pauser) => {
+                    audit(proposer, AuditAction::SetPauser, state.pauser, pauser)?;
+                    state.pauser = pauser;
+                }
+                AdminActionKind::SetTreasurer(treasurer) => {
+                    audit(proposer, AuditAction::SetTreasurer, state.treasurer, treasurer)?;
+                    state.treasurer = treasurer;
+                }
+                AdminActionKind::SetAdmins { admins, threshold } => {
+                    let old_value = AuditValue::Admins(state.admins.clone(), state.admin_threshold);
+                    audit(proposer, AuditAction::SetAdmins, old_value, AuditValue::Admins(admins.clone(), threshold))?;
+                    state.admins = admins;
+                    state.admin_threshold = threshold;
+                }
+                AdminActionKind::LockConfig => {
+                    audit(proposer, AuditAction::LockConfig, state.config_locked, true)?;
+                    state.config_locked = true;
+                }
+                AdminActionKind::SetFeeTiers(tiers) => {
+                    require!(!state.config_locked, CustomError::ConfigLocked);
+                    let old_value = AuditValue::from(state.fee_tiers.as_slice());
+                    audit(proposer, AuditAction::SetFeeTiers, old_value, tiers.as_slice())?;
+                    state.fee_tiers = tiers;
+                }
+                AdminActionKind::SetFeeModel(fee_model) => {
+                    require!(!state.config_locked, CustomError::ConfigLocked);
+                    audit(proposer, AuditAction::SetFeeModel, state.fee_model, fee_model)?;
+                    state.fee_model = fee_model;
+                }
+                AdminActionKind::SetFeeSplit { lp_share_bps, staker_share_bps } => {
+                    require!(!state.config_locked, CustomError::ConfigLocked);
+                    let old_value = AuditValue::Numbers(vec![state.lp_share_bps, state.staker_share_bps]);
+                    let new_value = AuditValue::Numbers(vec![lp_share_bps, staker_share_bps]);
+                    audit(proposer, AuditAction::SetFeeSplit, old_value, new_value)?;
+                    state.lp_share_bps = lp_share_bps;
+                    state.staker_share_bps = staker_share_bps;
+                }
+                AdminActionKind::SetComplianceHook(compliance_hook) => {
+                    require!(!state.config_locked, CustomError::ConfigLocked);
+                    audit(proposer, AuditAction::SetComplianceHook, state.compliance_hook, compliance_hook)?;
+                    state.compliance_hook = compliance_hook;
+                }
+                AdminActionKind::SetVoucherSigner(voucher_signer) => {
+                    require!(!state.config_locked, CustomError::ConfigLocked);
+                    audit(proposer, AuditAction::SetVoucherSigner, state.voucher_signer, voucher_signer)?;
+                    state.voucher_signer = voucher_signer;
+                }
+                AdminActionKind::SetSwapProgram(swap_program) => {
+                    require!(!state.config_locked, CustomError::ConfigLocked);
+                    audit(proposer, AuditAction::SetSwapProgram, state.swap_program, swap_program)?;
+                    state.swap_program = swap_program;
+                }
+                AdminActionKind::InitiateWindDown => wind_down(state, proposer)?,
+                AdminActionKind::MigrateStakeVault { .. }
+                | AdminActionKind::WithdrawProtocolLiquidity { .. }
+                | AdminActionKind::FinalizeWindDown { .. } => {
+                    return err!(CustomError::ActionNeedsAccounts);
+                }
             }
         }
-        // Read the fee rate from global state (immutable borrow) and compute fee.
-        let fee_rate = ctx.accounts.global_state.fee_rate;
-        let fee = amount.checked_mul(fee_rate).unwrap() / 10000;
-        // Record flash loan details.
+        ctx.accounts.admin_action.executed = true;
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to move every staked token from the current stake vault
+    /// into `new_vault` and record it as the stake vault. The new vault must hold the same
+    /// mint and be owned by `new_vault_authority`, which signs to prove control of it.
+    /// Stake positions and `total_staked` are untouched. Once co-signing is set up, needs
+    /// an approved `MigrateStakeVault` action for `new_vault`.
+    pub fn migrate_stake_vault(ctx: Context<MigrateStakeVault>) -> Result<()> {
+        require!(ctx.accounts.global_state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+        consume_admin_action(
+            &ctx.accounts.global_state,
+            ctx.accounts.admin_action.as_mut(),
+            &AdminActionKind::MigrateStakeVault { new_vault: ctx.accounts.new_vault.key() },
+        )?;
+        // The canonical stake vault is fixed once created.
+        require!(!ctx.accounts.global_state.canonical_vaults, CustomError::InvalidStakeVault);
+        {
+            let old_vault = &ctx.accounts.stake_vault;
+            let new_vault = &ctx.accounts.new_vault;
+            require!(new_vault.key() != old_vault.key(), CustomError::SelfTransfer);
+            require!(new_vault.mint == old_vault.mint, CustomError::MintMismatch);
+            require!(
+                new_vault.owner == ctx.accounts.new_vault_authority.key(),
+                CustomError::InvalidStakeVault
+            );
+        }
+        let amount = ctx.accounts.stake_vault.amount;
+        if amount > 0 {
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_transfer_to_new_vault_context(signer_seeds);
+            token::transfer(transfer_ctx, amount)?;
+        }
+        ctx.accounts.new_vault.reload()?;
+        assert_stake_solvency(&ctx.accounts.new_vault, &ctx.accounts.global_state)?;
         {
-            let flash_loan_state = &mut ctx.accounts.flash_loan_state;
-            flash_loan_state.amount = amount;
-            flash_loan_state.fee = fee;
-            flash_loan_state.start_time = Clock::get()?.unix_timestamp;
-            flash_loan_state.collateral = collateral_amount;
+            let state = &mut ctx.accounts.global_state;
+            audit(ctx.accounts.admin.key(), AuditAction::MigrateStakeVault, state.stake_vault, ctx.accounts.new_vault.key())?;
+            state.stake_vault = ctx.accounts.new_vault.key();
+        }
+        if events_enabled(&ctx.accounts.global_state) {
+            emit!(StakeVaultMigrated {
+                old_vault: ctx.accounts.stake_vault.key(),
+                new_vault: ctx.accounts.new_vault.key(),
+                amount,
+            });
         }
-        // Transfer the flash loan amount to the borrower.
+        Ok(())
+    }
+
+    /// Treasurer-controlled instruction to move `amount` of collected fees out of the fee vault.
+    pub fn withdraw_fees(ctx: Context<WithdrawFees>, amount: u64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        {
+            let state = &ctx.accounts.global_state;
+            require!(role_holder(state, state.treasurer) == *ctx.accounts.treasurer.key, CustomError::Unauthorized);
+            // The LPs' share of prepaid fees is theirs to compound, not the treasury's.
+            let available = ctx.accounts.fee_vault.amount.saturating_sub(state.lp_vault_fees);
+            require!(available >= amount, CustomError::InsufficientFees);
+        }
+        let old_value = ctx.accounts.fee_vault.amount;
         {
-            let transfer_ctx = ctx.accounts.into_transfer_to_borrower_context();
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_transfer_from_fee_vault_context(signer_seeds);
             token::transfer(transfer_ctx, amount)?;
         }
+        ctx.accounts.fee_vault.reload()?;
+        audit(ctx.accounts.treasurer.key(), AuditAction::WithdrawFees, old_value, ctx.accounts.fee_vault.amount)?;
         Ok(())
     }
 
-    /// Repays a flash loan.
-    /// Enforces repayment within a time limit and updates the borrower's reputation.
-    pub fn repay_flash_loan(ctx: Context<RepayFlashLoan>) -> Result<()> {
-        let flash_loan_state = &ctx.accounts.flash_loan_state;
-        let current_time = Clock::get()?.unix_timestamp;
-        require!(current_time - flash_loan_state.start_time <= 30, CustomError::FlashLoanExpired);
+    /// Admin-controlled instruction to route the protocol-owned share of each flash loan fee
+    /// to the treasury instead of leaving it in the pool.
+    pub fn set_route_pol_fees(ctx: Context<UpdateConfig>, route_pol_fees_to_treasury: bool) -> Result<()> {
         {
             let state = &mut ctx.accounts.global_state;
-            state.accumulated_fees = state.accumulated_fees.checked_add(flash_loan_state.fee).unwrap();
-            state.is_flash_loan_active = false;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.route_pol_fees_to_treasury;
+            state.route_pol_fees_to_treasury = route_pol_fees_to_treasury;
+            audit(ctx.accounts.admin.key(), AuditAction::SetRoutePolFees, old_value, route_pol_fees_to_treasury)?;
         }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to set (or clear) the compliance hook program.
+    /// `flash_loan` invokes the hook with the borrower as a read-only account and
+    /// `borrower: Pubkey, amount: u64` (little-endian) as data; the hook approves the loan
+    /// by setting return data whose first byte is nonzero.
+    pub fn set_compliance_hook(ctx: Context<UpdateConfig>, compliance_hook: Option<Pubkey>) -> Result<()> {
         {
-            let reputation = &mut ctx.accounts.borrower_reputation;
-            reputation.borrower = *ctx.accounts.borrower.key;
-            reputation.reputation = reputation.reputation.checked_add(1).unwrap();
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.compliance_hook;
+            state.compliance_hook = compliance_hook;
+            audit(ctx.accounts.admin.key(), AuditAction::SetComplianceHook, old_value, compliance_hook)?;
         }
         Ok(())
     }
 
-    /// Distributes rewards to stakers.
-    /// This function is a placeholder for multi-token yield distribution and smart treasury mechanisms.
-    pub fn distribute_rewards(ctx: Context<DistributeRewards>) -> Result<()> {
-        // Reward distribution logic goes here.
+    /// Admin-controlled instruction to set (or clear) the key that signs fee-waiver vouchers.
+    pub fn set_voucher_signer(ctx: Context<UpdateConfig>, voucher_signer: Option<Pubkey>) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.voucher_signer;
+            state.voucher_signer = voucher_signer;
+            audit(ctx.accounts.admin.key(), AuditAction::SetVoucherSigner, old_value, voucher_signer)?;
+        }
         Ok(())
     }
 
-    /// Compound staking rewards by auto-reinvesting them.
-    pub fn compound_rewards(ctx: Context<CompoundRewards>) -> Result<()> {
-        // Auto-compounding logic goes here.
+    /// Admin-controlled instruction to cap the rewards injected (donated or funded) per epoch
+    /// of `epoch_length` seconds. A cap of zero disables the limit. A new cap counts what the
+    /// current epoch has already emitted; only a new epoch length, or enabling a disabled cap,
+    /// starts a fresh epoch.
+    pub fn set_emission_cap(ctx: Context<UpdateConfig>, epoch_emission_cap: u64, epoch_length: i64) -> Result<()> {
+        require!(epoch_length > 0, CustomError::InvalidDuration);
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = AuditValue::Numbers(vec![state.epoch_emission_cap, state.emission_epoch_length as u64]);
+            let new_value = AuditValue::Numbers(vec![epoch_emission_cap, epoch_length as u64]);
+            audit(ctx.accounts.admin.key(), AuditAction::SetEmissionCap, old_value, new_value)?;
+            // Nothing is counted while the cap is off, so re-enabling it cannot resume an epoch.
+            if epoch_length != state.emission_epoch_length || state.epoch_emission_cap == 0 {
+                state.emission_epoch_start = Clock::get()?.unix_timestamp;
+                state.epoch_emitted = 0;
+            }
+            state.epoch_emission_cap = epoch_emission_cap;
+            state.emission_epoch_length = epoch_length;
+        }
         Ok(())
     }
 
-    /// Executes a multi-hop flash loan across multiple liquidity pools.
-    /// This is a placeholder for composable flash loans.
-    pub fn multi_hop_flash_loan(ctx: Context<MultiHopFlashLoan>, amounts: Vec<u64>) -> Result<()> {
-        // Multi-hop flash loan logic goes here.
+    /// Admin-controlled instruction to set the minimum size of a new stake position.
+    pub fn set_min_stake(ctx: Context<UpdateConfig>, min_stake: u64) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.min_stake;
+            state.min_stake = min_stake;
+            audit(ctx.accounts.admin.key(), AuditAction::SetMinStake, old_value, min_stake)?;
+        }
         Ok(())
     }
-}
 
-//
-// Account Contexts & Helpers
-//
+    /// Admin-controlled instruction to set the DEX adapter program used to swap claimed rewards.
+    pub fn set_swap_program(ctx: Context<UpdateConfig>, swap_program: Pubkey) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.swap_program;
+            state.swap_program = swap_program;
+            audit(ctx.accounts.admin.key(), AuditAction::SetSwapProgram, old_value, swap_program)?;
+        }
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct Initialize<'info> {
-    #[account(init, payer = admin, space = 8 + GlobalState::LEN)]
-    pub global_state: Account<'info, GlobalState>,
-    #[account(mut)]
-    pub admin: Signer<'info>,
-    /// Treasury account for fee redistribution.
-    pub treasury: AccountInfo<'info>,
-    pub system_program: Program<'info, System>,
-}
+    /// Admin-controlled instruction to cap the principal a single transaction may borrow,
+    /// regardless of available liquidity. Zero disables the cap.
+    pub fn set_per_tx_loan_cap(ctx: Context<UpdateConfig>, per_tx_loan_cap: u64) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.per_tx_loan_cap;
+            state.per_tx_loan_cap = per_tx_loan_cap;
+            audit(ctx.accounts.admin.key(), AuditAction::SetPerTxLoanCap, old_value, per_tx_loan_cap)?;
+        }
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct UpdateFeeRate<'info> {
-    #[account(mut)]
-    pub global_state: Account<'info, GlobalState>,
-    #[account(mut)]
-    pub admin: Signer<'info>,
-}
+    /// Admin-controlled instruction to cap each borrower's loans by reputation at
+    /// `base_cap + reputation * cap_per_rep`, never above `ceiling` (zero = no ceiling).
+    /// A zero `base_cap` turns the reputation caps off.
+    pub fn set_reputation_caps(ctx: Context<UpdateConfig>, base_cap: u64, cap_per_rep: u64, ceiling: u64) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = AuditValue::Numbers(vec![state.rep_base_cap, state.rep_cap_per_point, state.rep_cap_ceiling]);
+            let new_value = AuditValue::Numbers(vec![base_cap, cap_per_rep, ceiling]);
+            audit(ctx.accounts.admin.key(), AuditAction::SetReputationCaps, old_value, new_value)?;
+            state.rep_base_cap = base_cap;
+            state.rep_cap_per_point = cap_per_rep;
+            state.rep_cap_ceiling = ceiling;
+        }
+        Ok(())
+    }
 
-#[derive(Accounts)]
-pub struct DepositLiquidity<'info> {
+    /// Admin-controlled instruction to cap the collateral a loan may escrow at
+    /// `max_collateral_bps` of its principal. Zero disables the cap.
+    pub fn set_max_collateral_bps(ctx: Context<UpdateConfig>, max_collateral_bps: u64) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.max_collateral_bps;
+            state.max_collateral_bps = max_collateral_bps;
+            audit(ctx.accounts.admin.key(), AuditAction::SetMaxCollateralBps, old_value, max_collateral_bps)?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to require collateral in `accepted_collateral_mint` rather
+    /// than the pool's own mint. The default key restores the pool's mint.
+    pub fn set_accepted_collateral_mint(ctx: Context<UpdateConfig>, accepted_collateral_mint: Pubkey) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.accepted_collateral_mint;
+            state.accepted_collateral_mint = accepted_collateral_mint;
+            audit(ctx.accounts.admin.key(), AuditAction::SetAcceptedCollateralMint, old_value, accepted_collateral_mint)?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to pay whoever liquidates an expired loan
+    /// `liquidation_incentive_bps` of the seized collateral, at most `MAX_LIQUIDATION_INCENTIVE_BPS`.
+    pub fn set_liquidation_incentive(ctx: Context<UpdateConfig>, liquidation_incentive_bps: u64) -> Result<()> {
+        require!(liquidation_incentive_bps <= MAX_LIQUIDATION_INCENTIVE_BPS, CustomError::InvalidBps);
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.liquidation_incentive_bps;
+            state.liquidation_incentive_bps = liquidation_incentive_bps;
+            audit(ctx.accounts.admin.key(), AuditAction::SetLiquidationIncentive, old_value, liquidation_incentive_bps)?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to cap the flash loan fee rebate paid to staking borrowers
+    /// at `rebate_cap_bps` of the fee.
+    pub fn set_rebate_cap_bps(ctx: Context<UpdateConfig>, rebate_cap_bps: u64) -> Result<()> {
+        require!(rebate_cap_bps <= BPS_DENOMINATOR, CustomError::InvalidBps);
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.rebate_cap_bps;
+            state.rebate_cap_bps = rebate_cap_bps;
+            audit(ctx.accounts.admin.key(), AuditAction::SetRebateCapBps, old_value, rebate_cap_bps)?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to set the balance below which an unstake or liquidity
+    /// withdrawal sweeps out the remainder and closes the position. Zero disables it.
+    pub fn set_dust_threshold(ctx: Context<UpdateConfig>, dust_threshold: u64) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.dust_threshold;
+            state.dust_threshold = dust_threshold;
+            audit(ctx.accounts.admin.key(), AuditAction::SetDustThreshold, old_value, dust_threshold)?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to set the vault fee subsidies are paid from and the
+    /// most of any one loan's fee a subsidy covers. Zero `subsidy_per_loan` stops subsidies.
+    pub fn set_subsidy(ctx: Context<UpdateConfig>, subsidy_vault: Pubkey, subsidy_per_loan: u64) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = AuditValue::Keyed(state.subsidy_vault, state.subsidy_per_loan);
+            let new_value = AuditValue::Keyed(subsidy_vault, subsidy_per_loan);
+            audit(ctx.accounts.admin.key(), AuditAction::SetSubsidy, old_value, new_value)?;
+            // The balance belongs to the old vault and does not carry over.
+            if state.subsidy_vault != subsidy_vault {
+                state.subsidy_balance = 0;
+            }
+            state.subsidy_vault = subsidy_vault;
+            state.subsidy_per_loan = subsidy_per_loan;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to configure fee distribution to stakers: `staker_fee_bps`
+    /// of each LP fee is set aside for stakers, `crank_distribution` may credit it once every
+    /// `distribution_interval` seconds, and pays its caller up to `crank_reward`, but never
+    /// more than `MAX_CRANK_REWARD_BPS` of the amount distributed.
+    pub fn set_distribution_params(
+        ctx: Context<UpdateConfig>,
+        staker_fee_bps: u64,
+        distribution_interval: i64,
+        crank_reward: u64,
+    ) -> Result<()> {
+        require!(staker_fee_bps <= BPS_DENOMINATOR, CustomError::InvalidBps);
+        require!(distribution_interval >= 0, CustomError::InvalidDuration);
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = AuditValue::Numbers(vec![
+                state.staker_fee_bps,
+                state.distribution_interval as u64,
+                state.crank_reward,
+            ]);
+            let new_value = AuditValue::Numbers(vec![staker_fee_bps, distribution_interval as u64, crank_reward]);
+            audit(ctx.accounts.admin.key(), AuditAction::SetDistributionParams, old_value, new_value)?;
+            state.staker_fee_bps = staker_fee_bps;
+            state.distribution_interval = distribution_interval;
+            state.crank_reward = crank_reward;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to split every pool-paid fee three ways: `lp_share_bps`
+    /// to LPs, `staker_share_bps` to stakers and the remainder to the treasury. Setting
+    /// both to zero turns the split off and restores the POL and staker fee routing.
+    pub fn set_fee_split(ctx: Context<UpdateConfig>, lp_share_bps: u64, staker_share_bps: u64) -> Result<()> {
+        validate_fee_split(lp_share_bps, staker_share_bps)?;
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = AuditValue::Numbers(vec![state.lp_share_bps, state.staker_share_bps]);
+            let new_value = AuditValue::Numbers(vec![lp_share_bps, staker_share_bps]);
+            audit(ctx.accounts.admin.key(), AuditAction::SetFeeSplit, old_value, new_value)?;
+            state.lp_share_bps = lp_share_bps;
+            state.staker_share_bps = staker_share_bps;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to set the lowest fee, in basis points of the principal,
+    /// any loan pays once every discount, override and rebate has been applied. Zero disables it.
+    pub fn set_min_effective_fee_bps(ctx: Context<UpdateConfig>, min_effective_fee_bps: u64) -> Result<()> {
+        require!(min_effective_fee_bps <= BPS_DENOMINATOR, CustomError::InvalidBps);
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.min_effective_fee_bps;
+            state.min_effective_fee_bps = min_effective_fee_bps;
+            audit(ctx.accounts.admin.key(), AuditAction::SetMinEffectiveFeeBps, old_value, min_effective_fee_bps)?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to set the minimum number of seconds a staker must wait
+    /// between reward claims, and separately between compounds. Zero disables a cooldown.
+    pub fn set_claim_cooldowns(ctx: Context<UpdateConfig>, claim_cooldown: i64, compound_cooldown: i64) -> Result<()> {
+        require!(claim_cooldown >= 0 && compound_cooldown >= 0, CustomError::InvalidDuration);
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = AuditValue::Numbers(vec![state.claim_cooldown as u64, state.compound_cooldown as u64]);
+            let new_value = AuditValue::Numbers(vec![claim_cooldown as u64, compound_cooldown as u64]);
+            audit(ctx.accounts.admin.key(), AuditAction::SetClaimCooldowns, old_value, new_value)?;
+            state.claim_cooldown = claim_cooldown;
+            state.compound_cooldown = compound_cooldown;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to keep `reserve_bps` of total liquidity in the pool,
+    /// on top of what is lent out, whenever LPs withdraw. Zero disables the reserve.
+    pub fn set_reserve_bps(ctx: Context<UpdateConfig>, reserve_bps: u64) -> Result<()> {
+        require!(reserve_bps <= BPS_DENOMINATOR, CustomError::InvalidBps);
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.reserve_bps;
+            state.reserve_bps = reserve_bps;
+            audit(ctx.accounts.admin.key(), AuditAction::SetReserveBps, old_value, reserve_bps)?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to choose how fees with a remainder are rounded: down
+    /// (the default, favoring borrowers) or up by one unit (favoring LPs).
+    pub fn set_round_fees_up(ctx: Context<UpdateConfig>, round_fees_up: bool) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.round_fees_up;
+            state.round_fees_up = round_fees_up;
+            audit(ctx.accounts.admin.key(), AuditAction::SetRoundFeesUp, old_value, round_fees_up)?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to release each LP fee into the pool's redeemable value
+    /// linearly over `fee_unlock_period` seconds. Zero credits fees immediately.
+    pub fn set_fee_unlock_period(ctx: Context<UpdateConfig>, fee_unlock_period: i64) -> Result<()> {
+        require!(fee_unlock_period >= 0, CustomError::InvalidDuration);
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let current_time = Clock::get()?.unix_timestamp;
+            // Settle what has unlocked so far under the old period before switching.
+            state.locked_fees = locked_lp_fees(state, current_time)?;
+            state.fees_locked_at = current_time;
+            let old_value = state.fee_unlock_period;
+            state.fee_unlock_period = fee_unlock_period;
+            audit(ctx.accounts.admin.key(), AuditAction::SetFeeUnlockPeriod, old_value, fee_unlock_period)?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to collect flash loan fees in `fee_mint` instead of the
+    /// loan mint, converted at `fee_mint_price`. Passing `None` reverts to the loan mint.
+    pub fn set_fee_mint(ctx: Context<UpdateConfig>, fee_mint: Option<Pubkey>, fee_mint_price: u64) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            require!(fee_mint.is_none() || fee_mint_price > 0, CustomError::InvalidFeeMintPrice);
+            let old_value = AuditValue::Keyed(state.fee_mint.unwrap_or_default(), state.fee_mint_price);
+            let new_value = AuditValue::Keyed(fee_mint.unwrap_or_default(), fee_mint_price);
+            audit(ctx.accounts.admin.key(), AuditAction::SetFeeMint, old_value, new_value)?;
+            state.fee_mint = fee_mint;
+            state.fee_mint_price = fee_mint_price;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to burn `burn_share_bps` of every fee collected in the
+    /// fee mint instead of sending it to the fee vault. Repayments must then pass the fee
+    /// mint so the share can be burned. Zero disables burning.
+    pub fn set_burn_share_bps(ctx: Context<UpdateConfig>, burn_share_bps: u64) -> Result<()> {
+        require!(burn_share_bps <= BPS_DENOMINATOR, CustomError::InvalidBps);
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.burn_share_bps;
+            state.burn_share_bps = burn_share_bps;
+            audit(ctx.accounts.admin.key(), AuditAction::SetBurnShareBps, old_value, burn_share_bps)?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to configure how repayments earn reputation.
+    /// Each repayment earns `reputation_per_loan`, plus that much again for every
+    /// full `size_bucket` of principal when `size_bucket` is nonzero. Scores are
+    /// capped at `max_reputation` (0 = uncapped).
+    pub fn set_reputation_params(
+        ctx: Context<UpdateConfig>,
+        reputation_per_loan: u64,
+        size_bucket: u64,
+        max_reputation: u64,
+    ) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = AuditValue::Numbers(vec![
+                state.reputation_per_loan,
+                state.reputation_size_bucket,
+                state.max_reputation,
+            ]);
+            let new_value = AuditValue::Numbers(vec![reputation_per_loan, size_bucket, max_reputation]);
+            audit(ctx.accounts.admin.key(), AuditAction::SetReputationParams, old_value, new_value)?;
+            state.reputation_per_loan = reputation_per_loan;
+            state.reputation_size_bucket = size_bucket;
+            state.max_reputation = max_reputation;
+        }
+        Ok(())
+    }
+
+    /// Permanently locks economic parameters (fee rate, auto-whitelist threshold,
+    /// minimum stake, reputation parameters).
+    /// This is one-way: there is no instruction to unlock. Operational controls such
+    /// as whitelist management remain available to the admin.
+    pub fn lock_config(ctx: Context<UpdateConfig>) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            let old_value = state.config_locked;
+            state.config_locked = true;
+            audit(ctx.accounts.admin.key(), AuditAction::LockConfig, old_value, true)?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to begin sunsetting the protocol. New loans, deposits
+    /// and stakes are rejected from here on, while withdrawals, unstaking and reward claims
+    /// keep working so everyone can exit. Like `lock_config`, this is one-way.
+    pub fn initiate_wind_down(ctx: Context<UpdateConfig>) -> Result<()> {
+        let state = &mut ctx.accounts.global_state;
+        require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+        require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+        wind_down(state, ctx.accounts.admin.key())
+    }
+
+    /// Admin-controlled instruction to finish a wind-down once no loan is open and every LP
+    /// and staker has exited. Sweeps what is left in the pool (the locked minimum liquidity,
+    /// protocol-owned liquidity and rounding dust) and the reward vault to the admin's
+    /// accounts, then closes `GlobalState`. Once co-signing is set up, needs an approved
+    /// `FinalizeWindDown` action for both destinations.
+    pub fn finalize_wind_down(ctx: Context<FinalizeWindDown>) -> Result<()> {
+        require!(ctx.accounts.global_state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+        consume_admin_action(
+            &ctx.accounts.global_state,
+            ctx.accounts.admin_action.as_mut(),
+            &AdminActionKind::FinalizeWindDown {
+                destination: ctx.accounts.destination.key(),
+                reward_destination: ctx.accounts.reward_destination.key(),
+            },
+        )?;
+        require!(
+            is_pool_authority(&ctx.accounts.pool_account, ctx.accounts.pool_authority.key),
+            CustomError::InvalidPoolAuthority
+        );
+        {
+            let state = &ctx.accounts.global_state;
+            require!(state.winding_down, CustomError::NotWindingDown);
+            require!(!state.is_flash_loan_active, CustomError::FlashLoanInProgress);
+            require!(
+                state.total_shares <= MINIMUM_LIQUIDITY && state.total_staked == 0,
+                CustomError::WindDownIncomplete
+            );
+        }
+        let pool_residual = ctx.accounts.pool_account.amount;
+        if pool_residual > 0 {
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_transfer_from_pool_context(signer_seeds);
+            token::transfer(transfer_ctx, pool_residual)?;
+        }
+        let reward_residual = ctx.accounts.reward_vault.amount;
+        if reward_residual > 0 {
+            let transfer_ctx = ctx.accounts.into_transfer_from_reward_vault_context();
+            token::transfer(transfer_ctx, reward_residual)?;
+        }
+        audit(
+            ctx.accounts.admin.key(),
+            AuditAction::FinalizeWindDown,
+            AuditValue::Numbers(vec![pool_residual, reward_residual]),
+            AuditValue::Numbers(vec![0, 0]),
+        )?;
+        if events_enabled(&ctx.accounts.global_state) {
+            emit!(WindDownFinalized {
+                admin: ctx.accounts.admin.key(),
+                pool_residual,
+                reward_residual,
+            });
+        }
+        // `close = admin` returns the state account's rent once the instruction succeeds.
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to add a borrower to the flash loan whitelist.
+    pub fn add_to_whitelist(ctx: Context<UpdateConfig>, borrower: Pubkey) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            let old_value = state.flash_loan_whitelist.clone();
+            set_whitelist_entry(state, borrower, WHITELIST_ENTRY_KEY, 0)?;
+            audit(ctx.accounts.admin.key(), AuditAction::AddToWhitelist, old_value, state.flash_loan_whitelist.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to whitelist a borrower until `expiry`, after which the
+    /// entry no longer admits them. An `expiry` of 0 never lapses.
+    pub fn add_to_whitelist_with_expiry(ctx: Context<UpdateConfig>, borrower: Pubkey, expiry: i64) -> Result<()> {
+        require!(expiry >= 0, CustomError::InvalidExpiry);
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            let old_value = state.flash_loan_whitelist.clone();
+            set_whitelist_entry(state, borrower, WHITELIST_ENTRY_KEY, expiry)?;
+            audit(ctx.accounts.admin.key(), AuditAction::AddToWhitelistWithExpiry, old_value, state.flash_loan_whitelist.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to whitelist every borrower account owned by `program`,
+    /// so composing protocols can borrow through their PDAs.
+    pub fn add_program_to_whitelist(ctx: Context<UpdateConfig>, program: Pubkey) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            let old_value = state.flash_loan_whitelist.clone();
+            set_whitelist_entry(state, program, WHITELIST_ENTRY_PROGRAM, 0)?;
+            audit(ctx.accounts.admin.key(), AuditAction::AddProgramToWhitelist, old_value, state.flash_loan_whitelist.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to replace the whole whitelist with the borrower keys in
+    /// `entries`, duplicates dropped. Program entries are cleared too. Fails with
+    /// `WhitelistFull`, changing nothing, if the distinct entries don't fit.
+    pub fn set_whitelist(ctx: Context<UpdateConfig>, entries: Vec<Pubkey>) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            let old_value = state.flash_loan_whitelist.clone();
+            state.flash_loan_whitelist = Vec::new();
+            state.whitelist_entry_types = Vec::new();
+            state.whitelist_expiries = Vec::new();
+            for entry in entries {
+                set_whitelist_entry(state, entry, WHITELIST_ENTRY_KEY, 0)?;
+            }
+            audit(ctx.accounts.admin.key(), AuditAction::SetWhitelist, old_value, state.flash_loan_whitelist.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to add every borrower in `entries` to the whitelist in
+    /// one call. Keys already listed are kept; all or none are added.
+    pub fn add_many_to_whitelist(ctx: Context<UpdateConfig>, entries: Vec<Pubkey>) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            let old_value = state.flash_loan_whitelist.clone();
+            for entry in entries {
+                set_whitelist_entry(state, entry, WHITELIST_ENTRY_KEY, 0)?;
+            }
+            audit(ctx.accounts.admin.key(), AuditAction::AddManyToWhitelist, old_value, state.flash_loan_whitelist.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to remove a borrower from the flash loan whitelist.
+    pub fn remove_from_whitelist(ctx: Context<UpdateConfig>, borrower: Pubkey) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            let old_value = state.flash_loan_whitelist.clone();
+            let entries: Vec<(Pubkey, u8, i64)> = state
+                .flash_loan_whitelist
+                .iter()
+                .enumerate()
+                .filter(|(_, entry)| **entry != borrower)
+                .map(|(i, entry)| (*entry, whitelist_entry_type(state, i), whitelist_entry_expiry(state, i)))
+                .collect();
+            state.flash_loan_whitelist = entries.iter().map(|(entry, _, _)| *entry).collect();
+            state.whitelist_entry_types = entries.iter().map(|(_, entry_type, _)| *entry_type).collect();
+            state.whitelist_expiries = entries.iter().map(|(_, _, expiry)| *expiry).collect();
+            audit(ctx.accounts.admin.key(), AuditAction::RemoveFromWhitelist, old_value, state.flash_loan_whitelist.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to set the reputation at which borrowers bypass
+    /// the whitelist. A threshold of 0 disables the bypass.
+    pub fn set_auto_whitelist_threshold(ctx: Context<UpdateConfig>, threshold: u64) -> Result<()> {
+        {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(!state.config_locked, CustomError::ConfigLocked);
+            let old_value = state.auto_whitelist_threshold;
+            state.auto_whitelist_threshold = threshold;
+            audit(ctx.accounts.admin.key(), AuditAction::SetAutoWhitelistThreshold, old_value, threshold)?;
+        }
+        Ok(())
+    }
+
+    /// Deposits tokens from a liquidity provider into the pool.
+    pub fn deposit_liquidity(ctx: Context<DepositLiquidity>, amount: u64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        require!(!ctx.accounts.global_state.paused, CustomError::ProgramPaused);
+        require!(!ctx.accounts.global_state.winding_down, CustomError::WindingDown);
+        let pre_balance = ctx.accounts.pool_account.amount;
+        let current_time = Clock::get()?.unix_timestamp;
+        // Price the deposit against the LP-owned part of the pool, rounding shares down.
+        let (shares, first_deposit) = {
+            let state = &ctx.accounts.global_state;
+            let lp_value = lp_value(state, pre_balance, current_time)?;
+            (shares_for_deposit(amount, state.total_shares, lp_value)?, state.total_shares == 0)
+        };
+        // Perform token transfer (immutable borrow inside helper)
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_to_pool_context();
+            token::transfer(transfer_ctx, amount)?;
+        }
+        ctx.accounts.pool_account.reload()?;
+        let post_balance = ctx.accounts.pool_account.amount;
+        // Credit the provider's position.
+        {
+            let position = &mut ctx.accounts.provider_position;
+            position.owner = *ctx.accounts.provider.key;
+            accrue_position_share_seconds(position, current_time)?;
+            position.amount = position.amount.checked_add(amount).unwrap();
+            position.shares = position.shares.checked_add(shares).unwrap();
+        }
+        // Update liquidity in state in its own block
+        {
+            let state = &mut ctx.accounts.global_state;
+            accrue_total_share_seconds(state, current_time)?;
+            state.total_liquidity = state.total_liquidity.checked_add(amount).unwrap();
+            // The first deposit also mints MINIMUM_LIQUIDITY shares to no one.
+            let minted = if first_deposit { shares.checked_add(MINIMUM_LIQUIDITY).unwrap() } else { shares };
+            state.total_shares = state.total_shares.checked_add(minted).unwrap();
+        }
+        if events_enabled(&ctx.accounts.global_state) {
+            emit!(LiquidityDeposited {
+                provider: ctx.accounts.provider.key(),
+                amount,
+                pre_balance: event_detail(&ctx.accounts.global_state, pre_balance),
+                post_balance: event_detail(&ctx.accounts.global_state, post_balance),
+            });
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to add protocol-owned liquidity (POL) to the pool.
+    /// POL counts toward `total_liquidity` but credits no provider position.
+    pub fn seed_liquidity(ctx: Context<SeedLiquidity>, amount: u64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        require!(ctx.accounts.global_state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+        require!(ctx.accounts.global_state.admin_threshold == 0, CustomError::MultisigRequired);
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_to_pool_context();
+            token::transfer(transfer_ctx, amount)?;
+        }
+        {
+            let state = &mut ctx.accounts.global_state;
+            state.total_liquidity = state.total_liquidity.checked_add(amount).unwrap();
+            let old_value = state.protocol_owned_liquidity;
+            state.protocol_owned_liquidity = state.protocol_owned_liquidity.checked_add(amount).unwrap();
+            audit(ctx.accounts.admin.key(), AuditAction::SeedLiquidity, old_value, state.protocol_owned_liquidity)?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to withdraw protocol-owned liquidity from the pool.
+    /// Once co-signing is set up, needs an approved `WithdrawProtocolLiquidity` action for
+    /// `amount` and `destination`.
+    pub fn withdraw_protocol_liquidity(ctx: Context<WithdrawProtocolLiquidity>, amount: u64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        require!(ctx.accounts.global_state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+        consume_admin_action(
+            &ctx.accounts.global_state,
+            ctx.accounts.admin_action.as_mut(),
+            &AdminActionKind::WithdrawProtocolLiquidity { amount, destination: ctx.accounts.destination.key() },
+        )?;
+        require!(
+            is_pool_authority(&ctx.accounts.pool_account, ctx.accounts.pool_authority.key),
+            CustomError::InvalidPoolAuthority
+        );
+        require!(
+            ctx.accounts.global_state.protocol_owned_liquidity >= amount,
+            CustomError::InsufficientPosition
+        );
+        {
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_transfer_from_pool_context(signer_seeds);
+            token::transfer(transfer_ctx, amount)?;
+        }
+        {
+            let state = &mut ctx.accounts.global_state;
+            state.total_liquidity = state.total_liquidity.checked_sub(amount).unwrap();
+            let old_value = state.protocol_owned_liquidity;
+            state.protocol_owned_liquidity = state.protocol_owned_liquidity.checked_sub(amount).unwrap();
+            audit(ctx.accounts.admin.key(), AuditAction::WithdrawProtocolLiquidity, old_value, state.protocol_owned_liquidity)?;
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled recovery tool that resets `total_liquidity` to the pool account's
+    /// balance minus `accumulated_fees`, e.g. after an incident or a foreign-token recovery
+    /// left the books out of step with the vault. Only allowed while paused with no loan
+    /// open, and never as a lone admin once co-signing is set up.
+    pub fn reconcile_liquidity(ctx: Context<ReconcileLiquidity>) -> Result<()> {
+        let (old_total, new_total) = {
+            let state = &mut ctx.accounts.global_state;
+            require!(state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+            require!(state.admin_threshold == 0, CustomError::MultisigRequired);
+            require!(state.paused, CustomError::NotPaused);
+            require!(!state.is_flash_loan_active, CustomError::FlashLoanInProgress);
+            let old_total = state.total_liquidity;
+            state.total_liquidity = ctx.accounts.pool_account.amount.saturating_sub(state.accumulated_fees);
+            audit(ctx.accounts.admin.key(), AuditAction::ReconcileLiquidity, old_total, state.total_liquidity)?;
+            (old_total, state.total_liquidity)
+        };
+        if events_enabled(&ctx.accounts.global_state) {
+            emit!(LiquidityReconciled {
+                admin: ctx.accounts.admin.key(),
+                old_total,
+                new_total,
+                delta: new_total as i128 - old_total as i128,
+            });
+        }
+        Ok(())
+    }
+
+    /// Withdraws liquidity from the provider's position.
+    /// The signing `authority` is either the provider or an operator holding a
+    /// `WithdrawalAllowance`, which is drawn down by the withdrawn amount.
+    /// Funds go to the optional `destination` token account if given, otherwise
+    /// back to the provider's own token account.
+    pub fn withdraw_liquidity(ctx: Context<WithdrawLiquidity>, amount: u64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        require!(
+            is_pool_authority(&ctx.accounts.pool_account, ctx.accounts.pool_authority.key),
+            CustomError::InvalidPoolAuthority
+        );
+        // First, check that enough liquidity exists.
+        {
+            let available = ctx.accounts.global_state.total_liquidity;
+            require!(available >= amount, CustomError::InsufficientLiquidity);
+        }
+        require!(
+            within_reserve(&ctx.accounts.global_state, ctx.accounts.pool_account.amount, amount),
+            CustomError::ReserveBreached
+        );
+        // Check the provider's shares cover the withdrawal, rounding the shares burned up.
+        let current_time = Clock::get()?.unix_timestamp;
+        let shares = {
+            let state = &ctx.accounts.global_state;
+            let lp_value = lp_value(state, ctx.accounts.pool_account.amount, current_time)?;
+            let shares = shares_for_withdrawal(amount, state.total_shares, lp_value)?;
+            require!(ctx.accounts.provider_position.shares >= shares, CustomError::InsufficientPosition);
+            shares
+        };
+        // A third-party destination must hold the pool's mint.
+        if let Some(destination) = &ctx.accounts.destination {
+            require!(destination.mint == ctx.accounts.pool_account.mint, CustomError::MintMismatch);
+        }
+        // Operators other than the provider draw down their allowance.
+        if ctx.accounts.authority.key() != ctx.accounts.provider.key() {
+            let allowance = ctx.accounts.allowance.as_mut().ok_or(CustomError::Unauthorized)?;
+            require!(allowance.remaining >= amount, CustomError::AllowanceExceeded);
+            allowance.remaining = allowance.remaining.checked_sub(amount).unwrap();
+        }
+        // Then perform the token transfer.
+        let pre_balance = ctx.accounts.pool_account.amount;
+        {
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_transfer_from_pool_context(signer_seeds);
+            token::transfer(transfer_ctx, amount)?;
+        }
+        ctx.accounts.pool_account.reload()?;
+        let post_balance = ctx.accounts.pool_account.amount;
+        // Finally, update the provider's position and the global state.
+        let principal = {
+            let position = &mut ctx.accounts.provider_position;
+            accrue_position_share_seconds(position, current_time)?;
+            let principal = principal_for_shares(position, shares);
+            position.amount = position.amount.checked_sub(principal).unwrap();
+            position.shares = position.shares.checked_sub(shares).unwrap();
+            principal
+        };
+        {
+            let state = &mut ctx.accounts.global_state;
+            accrue_total_share_seconds(state, current_time)?;
+            // Only the principal was counted as liquidity; earned fees paid on top never were,
+            // and a liquidation write-off may already have taken some of it out.
+            state.total_liquidity = state.total_liquidity.saturating_sub(principal);
+            state.total_shares = state.total_shares.checked_sub(shares).unwrap();
+        }
+        if events_enabled(&ctx.accounts.global_state) {
+            emit!(LiquidityWithdrawn {
+                provider: ctx.accounts.provider.key(),
+                amount,
+                pre_balance: event_detail(&ctx.accounts.global_state, pre_balance),
+                post_balance: event_detail(&ctx.accounts.global_state, post_balance),
+            });
+        }
+        // Sweep a position left worth less than the dust threshold and close it. The sweep
+        // goes to the same recipient and is not drawn from an operator's allowance.
+        let dust = {
+            let state = &ctx.accounts.global_state;
+            let position = &ctx.accounts.provider_position;
+            let value = position_value(state, position.shares, lp_value(state, post_balance, current_time)?);
+            if is_dust(state, value) { Some((value, position.shares, position.amount)) } else { None }
+        };
+        if let Some((dust, dust_shares, dust_principal)) = dust {
+            if dust > 0 {
+                let global_state_key = ctx.accounts.global_state.key();
+                let bump = [ctx.accounts.global_state.vault_authority_bump];
+                let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+                let transfer_ctx = ctx.accounts.into_transfer_from_pool_context(signer_seeds);
+                token::transfer(transfer_ctx, dust)?;
+            }
+            {
+                let state = &mut ctx.accounts.global_state;
+                state.total_liquidity = state.total_liquidity.saturating_sub(dust_principal);
+                state.total_shares = state.total_shares.checked_sub(dust_shares).unwrap();
+            }
+            ctx.accounts.provider_position.close(ctx.accounts.provider.to_account_info())?;
+            if events_enabled(&ctx.accounts.global_state) {
+                emit!(PositionDusted {
+                    owner: ctx.accounts.provider.key(),
+                    position: ctx.accounts.provider_position.key(),
+                    amount: dust,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Redeems the caller's whole position in each of several pools at once, paying out its
+    /// principal together with its share of the fees that pool earned, and closes it. Each
+    /// pool keeps its own `GlobalState`, vault and shares, so it is passed as five
+    /// `remaining_accounts` in order: the global state, the caller's position in it, the
+    /// pool vault, the vault's authority and the account receiving the payout.
+    // `usize::is_multiple_of` is newer than the Solana 1.18 toolchain.
+    #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+    pub fn withdraw_all_liquidity<'info>(ctx: Context<'_, '_, 'info, 'info, WithdrawAllLiquidity<'info>>) -> Result<()> {
+        let pools = ctx.remaining_accounts;
+        require!(!pools.is_empty() && pools.len() % 5 == 0, CustomError::PoolAccountsMismatch);
+        let current_time = Clock::get()?.unix_timestamp;
+        for pool in pools.chunks(5) {
+            ctx.accounts.redeem_position(pool, current_time)?;
+        }
+        Ok(())
+    }
+
+    /// Compounds the provider's share of the LP fees held in the fee vault back into their
+    /// position. The share is pro rata to the position's share-seconds, which are consumed;
+    /// the fees move into the pool and mint shares at the current price.
+    pub fn compound_lp_fees(ctx: Context<CompoundLpFees>) -> Result<()> {
+        require!(!ctx.accounts.global_state.paused, CustomError::ProgramPaused);
+        let current_time = Clock::get()?.unix_timestamp;
+        accrue_position_share_seconds(&mut ctx.accounts.provider_position, current_time)?;
+        accrue_total_share_seconds(&mut ctx.accounts.global_state, current_time)?;
+        let amount = accrued_lp_fees(&ctx.accounts.global_state, &ctx.accounts.provider_position);
+        require!(amount > 0, CustomError::NoRewards);
+        // Price the fees like a deposit, before they reach the pool.
+        let shares = {
+            let state = &ctx.accounts.global_state;
+            let lp_value = lp_value(state, ctx.accounts.pool_account.amount, current_time)?;
+            shares_for_deposit(amount, state.total_shares, lp_value)?
+        };
+        {
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_transfer_fees_to_pool_context(signer_seeds);
+            token::transfer(transfer_ctx, amount)?;
+        }
+        let consumed = {
+            let position = &mut ctx.accounts.provider_position;
+            let consumed = position.share_seconds;
+            position.share_seconds = 0;
+            position.amount = position.amount.checked_add(amount).unwrap();
+            position.shares = position.shares.checked_add(shares).unwrap();
+            consumed
+        };
+        {
+            let state = &mut ctx.accounts.global_state;
+            state.total_share_seconds = state.total_share_seconds.saturating_sub(consumed);
+            state.lp_vault_fees = state.lp_vault_fees.checked_sub(amount).unwrap();
+            state.total_liquidity = state.total_liquidity.checked_add(amount).unwrap();
+            state.total_shares = state.total_shares.checked_add(shares).unwrap();
+        }
+        if events_enabled(&ctx.accounts.global_state) {
+            emit!(LpFeesCompounded {
+                provider: ctx.accounts.provider.key(),
+                amount,
+                shares,
+            });
+        }
+        Ok(())
+    }
+
+    /// Lets `operator` withdraw up to `amount` from the signer's liquidity position.
+    /// Replaces any previously approved amount for the same operator.
+    pub fn approve_withdrawal(ctx: Context<ApproveWithdrawal>, operator: Pubkey, amount: u64) -> Result<()> {
+        {
+            let allowance = &mut ctx.accounts.allowance;
+            allowance.owner = *ctx.accounts.owner.key;
+            allowance.operator = operator;
+            allowance.remaining = amount;
+        }
+        Ok(())
+    }
+
+    /// Stake RYFT tokens for flash loan priority and yield.
+    pub fn stake(ctx: Context<Stake>, amount: u64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        require!(!ctx.accounts.global_state.paused, CustomError::ProgramPaused);
+        require!(!ctx.accounts.global_state.staking_paused, CustomError::StakingPaused);
+        require!(!ctx.accounts.global_state.winding_down, CustomError::WindingDown);
+        // Bind a newly created position to its staker; an existing one must already be theirs.
+        {
+            let user_stake = &mut ctx.accounts.user_stake;
+            if user_stake.owner == Pubkey::default() {
+                user_stake.owner = *ctx.accounts.user.key;
+                user_stake.version = USER_STAKE_VERSION;
+            }
+            require!(user_stake.owner == *ctx.accounts.user.key, CustomError::StakeOwnerMismatch);
+            require!(user_stake.version == USER_STAKE_VERSION, CustomError::UnsupportedVersion);
+        }
+        // New positions must meet the minimum; top-ups of any size are allowed.
+        if staked_balance(&ctx.accounts.user_stake) == 0 {
+            require!(amount >= ctx.accounts.global_state.min_stake, CustomError::StakeTooSmall);
+        }
+        // First, transfer tokens from the user to the stake vault.
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_to_stake_context();
+            token::transfer(transfer_ctx, amount)?;
+        }
+        // Then settle accrued rewards and update the user's stake.
+        update_emissions(&mut ctx.accounts.global_state, Clock::get()?.unix_timestamp);
+        let acc_reward_per_share = ctx.accounts.global_state.acc_reward_per_share;
+        {
+            let user_stake = &mut ctx.accounts.user_stake;
+            let current_time = Clock::get()?.unix_timestamp;
+            settle_rewards(user_stake, acc_reward_per_share)?;
+            if staked_balance(user_stake) == 0 {
+                user_stake.first_stake_timestamp = current_time;
+            }
+            user_stake.amount = user_stake.amount.checked_add(amount).unwrap();
+            user_stake.reward_debt = accrued_rewards(staked_balance(user_stake), acc_reward_per_share);
+            user_stake.last_modified_timestamp = current_time;
+        }
+        // And update the global staked total.
+        {
+            let state = &mut ctx.accounts.global_state;
+            state.total_staked = state.total_staked.checked_add(amount).unwrap();
+            state.total_reward_weight = state.total_reward_weight.checked_add(amount).unwrap();
+        }
+        ctx.accounts.stake_vault.reload()?;
+        assert_stake_solvency(&ctx.accounts.stake_vault, &ctx.accounts.global_state)?;
+        Ok(())
+    }
+
+    /// Unstake previously staked RYFT tokens.
+    /// Principal is withdrawn before compounded rewards; the event reports the split.
+    pub fn unstake(ctx: Context<Unstake>, amount: u64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        ctx.accounts.withdraw_stake(amount)
+    }
+
+    /// Unstakes the caller's whole balance, principal and compounded rewards alike, settling
+    /// pending rewards as `unstake` does. With `close`, the emptied position is closed and its
+    /// rent refunded, unless it still holds unclaimed rewards.
+    pub fn unstake_all(ctx: Context<Unstake>, close: bool) -> Result<()> {
+        let amount = staked_balance(&ctx.accounts.user_stake);
+        require!(amount > 0, CustomError::InsufficientStake);
+        ctx.accounts.withdraw_stake(amount)?;
+        // The dust sweep may already have closed the position.
+        let open = ctx.accounts.user_stake.to_account_info().lamports() > 0;
+        if close && open && ctx.accounts.user_stake.unclaimed_rewards == 0 {
+            ctx.accounts.user_stake.close(ctx.accounts.user.to_account_info())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the caller's entire stake without touching reward accounting, so principal
+    /// can be recovered even if the reward vault is empty or compromised. Pending rewards
+    /// are forfeited. Remains available while the program is paused. An early exit pays the
+    /// same penalty as `unstake`, so this is no way around it.
+    pub fn emergency_unstake(ctx: Context<Unstake>) -> Result<()> {
+        let amount = staked_balance(&ctx.accounts.user_stake);
+        require!(amount > 0, CustomError::InsufficientStake);
+        let penalty = {
+            let state = &ctx.accounts.global_state;
+            if is_early_unstake(state, ctx.accounts.user_stake.first_stake_timestamp, Clock::get()?.unix_timestamp) {
+                unstake_penalty(state, amount)
+            } else {
+                0
+            }
+        };
+        if penalty > 0 {
+            require!(ctx.accounts.treasury_token_account.is_some(), CustomError::InvalidTreasury);
+        }
+        {
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            if amount > penalty {
+                let transfer_ctx = ctx.accounts.into_transfer_from_stake_context(signer_seeds);
+                token::transfer(transfer_ctx, amount - penalty)?;
+            }
+            if penalty > 0 {
+                let penalty_ctx = ctx.accounts.into_transfer_penalty_context(signer_seeds);
+                token::transfer(penalty_ctx, penalty)?;
+            }
+        }
+        // Emissions up to now belong to the stakers before this exit.
+        update_emissions(&mut ctx.accounts.global_state, Clock::get()?.unix_timestamp);
+        // Clear the position, dropping any unclaimed rewards.
+        {
+            let user_stake = &mut ctx.accounts.user_stake;
+            user_stake.amount = 0;
+            user_stake.compounded_amount = 0;
+            user_stake.reward_debt = 0;
+            user_stake.unclaimed_rewards = 0;
+            user_stake.first_stake_timestamp = 0;
+            user_stake.last_modified_timestamp = Clock::get()?.unix_timestamp;
+        }
+        {
+            let state = &mut ctx.accounts.global_state;
+            state.total_staked = state.total_staked.checked_sub(amount).unwrap();
+            state.total_reward_weight = state.total_reward_weight.checked_sub(amount).unwrap();
+        }
+        Ok(())
+    }
+
+    /// Opens an additional stake position for the caller at `index`, locked for `lock_duration`
+    /// seconds (0 for a liquid position). Longer locks earn a larger reward boost.
+    pub fn open_position(ctx: Context<OpenPosition>, index: u8, amount: u64, lock_duration: i64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        require!(!ctx.accounts.global_state.paused, CustomError::ProgramPaused);
+        require!(!ctx.accounts.global_state.staking_paused, CustomError::StakingPaused);
+        require!(!ctx.accounts.global_state.winding_down, CustomError::WindingDown);
+        require!(amount >= ctx.accounts.global_state.min_stake, CustomError::StakeTooSmall);
+        require!(
+            (0..=MAX_STAKE_LOCK_DURATION).contains(&lock_duration),
+            CustomError::InvalidLockDuration
+        );
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_to_stake_context();
+            token::transfer(transfer_ctx, amount)?;
+        }
+        let current_time = Clock::get()?.unix_timestamp;
+        update_emissions(&mut ctx.accounts.global_state, current_time);
+        let acc_reward_per_share = ctx.accounts.global_state.acc_reward_per_share;
+        let weight = {
+            let position = &mut ctx.accounts.stake_position;
+            position.owner = *ctx.accounts.user.key;
+            position.index = index;
+            position.amount = amount;
+            position.lock_until = if lock_duration > 0 { current_time.checked_add(lock_duration).unwrap() } else { 0 };
+            position.boost_bps = lock_boost_bps(lock_duration);
+            position.reward_debt = accrued_rewards(position_weight(position), acc_reward_per_share);
+            position.unclaimed_rewards = 0;
+            position_weight(position)
+        };
+        {
+            let state = &mut ctx.accounts.global_state;
+            state.total_staked = state.total_staked.checked_add(amount).unwrap();
+            state.total_reward_weight = state.total_reward_weight.checked_add(weight).unwrap();
+        }
+        ctx.accounts.stake_vault.reload()?;
+        assert_stake_solvency(&ctx.accounts.stake_vault, &ctx.accounts.global_state)?;
+        Ok(())
+    }
+
+    /// Closes an unlocked stake position, returning its stake and paying out its rewards.
+    /// Any rewards paid count as a claim, so they wait out the claim cooldown.
+    pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
+        require!(!ctx.accounts.global_state.paused, CustomError::ProgramPaused);
+        let current_time = Clock::get()?.unix_timestamp;
+        require!(current_time >= ctx.accounts.stake_position.lock_until, CustomError::StakeLocked);
+        update_emissions(&mut ctx.accounts.global_state, current_time);
+        let acc_reward_per_share = ctx.accounts.global_state.acc_reward_per_share;
+        let (amount, weight, rewards) = {
+            let position = &ctx.accounts.stake_position;
+            let weight = position_weight(position);
+            assert_reward_debt(accrued_rewards(weight, acc_reward_per_share), position.reward_debt)?;
+            (position.amount, weight, pending_position_rewards(position, acc_reward_per_share))
+        };
+        // Paying out rewards counts as a claim; a position with none can always be closed.
+        if rewards > 0 {
+            start_claim_cooldown(&ctx.accounts.global_state, &mut ctx.accounts.user_stake, ctx.accounts.user.key, current_time)?;
+        }
+        {
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_transfer_from_stake_context(signer_seeds);
+            token::transfer(transfer_ctx, amount)?;
+        }
+        if rewards > 0 {
+            let transfer_ctx = ctx.accounts.into_transfer_rewards_to_user_context();
+            token::transfer(transfer_ctx, rewards)?;
+        }
+        {
+            let state = &mut ctx.accounts.global_state;
+            state.total_staked = state.total_staked.checked_sub(amount).unwrap();
+            state.total_reward_weight = state.total_reward_weight.checked_sub(weight).unwrap();
+        }
+        ctx.accounts.stake_vault.reload()?;
+        assert_stake_solvency(&ctx.accounts.stake_vault, &ctx.accounts.global_state)?;
+        Ok(())
+    }
+
+    /// Moves a stake position to `new_owner` without unstaking, closing the sender's position.
+    /// Stake, unclaimed rewards and the lock travel with it: the receiving position (at the
+    /// same index, created if needed) takes the later of the two locks, so a transfer can
+    /// never shorten one. Merging into an existing position blends the boosts so the
+    /// combined reward weight is unchanged, rounding down, and needs the new owner's signature,
+    /// since it may lengthen their lock.
+    pub fn transfer_stake(ctx: Context<TransferStake>, new_owner: Pubkey) -> Result<()> {
+        require!(!ctx.accounts.global_state.paused, CustomError::ProgramPaused);
+        require!(new_owner != *ctx.accounts.owner.key, CustomError::SelfTransfer);
+        let current_time = Clock::get()?.unix_timestamp;
+        update_emissions(&mut ctx.accounts.global_state, current_time);
+        let acc_reward_per_share = ctx.accounts.global_state.acc_reward_per_share;
+        let (amount, weight, rewards, lock_until) = {
+            let position = &ctx.accounts.stake_position;
+            let weight = position_weight(position);
+            assert_reward_debt(accrued_rewards(weight, acc_reward_per_share), position.reward_debt)?;
+            (position.amount, weight, pending_position_rewards(position, acc_reward_per_share), position.lock_until)
+        };
+        let (old_weight, new_weight) = {
+            let index = ctx.accounts.stake_position.index;
+            let target = &mut ctx.accounts.new_position;
+            // A freshly created position has not been claimed by an owner yet.
+            if target.owner == Pubkey::default() {
+                target.owner = new_owner;
+                target.index = index;
+                target.boost_bps = BPS_DENOMINATOR;
+            } else {
+                require!(ctx.accounts.new_owner_signer.is_some(), CustomError::ReceiverMustSign);
+            }
+            let target_weight = position_weight(target);
+            assert_reward_debt(accrued_rewards(target_weight, acc_reward_per_share), target.reward_debt)?;
+            let target_rewards = pending_position_rewards(target, acc_reward_per_share);
+            let combined_weight = target_weight.checked_add(weight).unwrap();
+            target.amount = target.amount.checked_add(amount).unwrap();
+            target.boost_bps = ((combined_weight as u128).checked_mul(BPS_DENOMINATOR as u128).unwrap()
+                / target.amount as u128) as u64;
+            target.lock_until = target.lock_until.max(lock_until);
+            target.unclaimed_rewards = target_rewards.checked_add(rewards).unwrap();
+            target.reward_debt = accrued_rewards(position_weight(target), acc_reward_per_share);
+            (combined_weight, position_weight(target))
+        };
+        {
+            let state = &mut ctx.accounts.global_state;
+            state.total_reward_weight = state
+                .total_reward_weight
+                .checked_sub(old_weight)
+                .unwrap()
+                .checked_add(new_weight)
+                .unwrap();
+        }
+        if events_enabled(&ctx.accounts.global_state) {
+            emit!(StakeTransferred {
+                from: ctx.accounts.owner.key(),
+                to: new_owner,
+                index: ctx.accounts.stake_position.index,
+                amount,
+                lock_until: ctx.accounts.new_position.lock_until,
+            });
+        }
+        Ok(())
+    }
+
+    /// Moves the caller's `UserStake` balance to `new_owner` without unstaking, closing the
+    /// sender's account. Principal, compounded stake and unclaimed rewards travel with it. The
+    /// receiving account (created if needed) keeps the later of the two `first_stake_timestamp`s,
+    /// so a transfer can never shorten the early-unstake window. Merging into an existing
+    /// balance needs the new owner's signature.
+    pub fn transfer_user_stake(ctx: Context<TransferUserStake>, new_owner: Pubkey) -> Result<()> {
+        require!(!ctx.accounts.global_state.paused, CustomError::ProgramPaused);
+        require!(new_owner != *ctx.accounts.owner.key, CustomError::SelfTransfer);
+        let current_time = Clock::get()?.unix_timestamp;
+        update_emissions(&mut ctx.accounts.global_state, current_time);
+        let acc_reward_per_share = ctx.accounts.global_state.acc_reward_per_share;
+        let (amount, compounded_amount, unclaimed_rewards, first_stake_timestamp, last_claim, last_compound) = {
+            let user_stake = &mut ctx.accounts.user_stake;
+            require!(user_stake.owner == *ctx.accounts.owner.key, CustomError::StakeOwnerMismatch);
+            require!(user_stake.version == USER_STAKE_VERSION, CustomError::UnsupportedVersion);
+            require!(staked_balance(user_stake) > 0, CustomError::InsufficientStake);
+            settle_rewards(user_stake, acc_reward_per_share)?;
+            (
+                user_stake.amount,
+                user_stake.compounded_amount,
+                user_stake.unclaimed_rewards,
+                user_stake.first_stake_timestamp,
+                user_stake.last_claim_timestamp,
+                user_stake.last_compound_timestamp,
+            )
+        };
+        {
+            let target = &mut ctx.accounts.new_user_stake;
+            // A freshly created account has not been claimed by an owner yet.
+            if target.owner == Pubkey::default() {
+                target.owner = new_owner;
+                target.version = USER_STAKE_VERSION;
+            } else {
+                require!(ctx.accounts.new_owner_signer.is_some(), CustomError::ReceiverMustSign);
+            }
+            require!(target.version == USER_STAKE_VERSION, CustomError::UnsupportedVersion);
+            settle_rewards(target, acc_reward_per_share)?;
+            target.first_stake_timestamp = if staked_balance(target) == 0 {
+                first_stake_timestamp
+            } else {
+                target.first_stake_timestamp.max(first_stake_timestamp)
+            };
+            target.amount = target.amount.checked_add(amount).unwrap();
+            target.compounded_amount = target.compounded_amount.checked_add(compounded_amount).unwrap();
+            target.unclaimed_rewards = target.unclaimed_rewards.checked_add(unclaimed_rewards).unwrap();
+            target.reward_debt = accrued_rewards(staked_balance(target), acc_reward_per_share);
+            target.last_modified_timestamp = current_time;
+            // Cooldowns carry over too, so a transfer can't skip one.
+            target.last_claim_timestamp = target.last_claim_timestamp.max(last_claim);
+            target.last_compound_timestamp = target.last_compound_timestamp.max(last_compound);
+        }
+        Ok(())
+    }
+
+    /// Executes an atomic flash loan. The borrowed funds must be repaid in the same transaction.
+    /// Features include reentrancy protection, whitelist check, time-limited execution, and collateral backing.
+    /// If `pool_account` alone can't cover `amount`, the remainder is drawn from extra vaults
+    /// registered to the pool with `add_pool_vault`, passed in `remaining_accounts` as
+    /// `[registration, vault]` pairs, in order, until the amount is covered.
+    /// A nonzero `max_fee` makes the loan revert if the fee owed would exceed it, guarding
+    /// against a fee change landing between signing and execution.
+    pub fn flash_loan<'info>(
+        ctx: Context<'_, '_, '_, 'info, FlashLoan<'info>>,
+        amount: u64,
+        collateral_amount: u64,
+        max_fee: u64,
+    ) -> Result<()> {
+        check_loan_limits(&ctx.accounts.global_state, amount, collateral_amount)?;
+        // Loan and collateral transfers must move funds between distinct accounts.
+        require!(
+            ctx.accounts.borrower_token_account.key() != ctx.accounts.pool_account.key(),
+            CustomError::SelfTransfer
+        );
+        require!(
+            ctx.accounts.borrower_collateral_account.key() != ctx.accounts.collateral_escrow.key(),
+            CustomError::SelfTransfer
+        );
+        require!(
+            is_accepted_collateral(
+                &ctx.accounts.global_state,
+                &ctx.accounts.pool_account.mint,
+                &ctx.accounts.collateral_escrow.mint,
+                &ctx.accounts.borrower_collateral_account.mint
+            ),
+            CustomError::CollateralMintMismatch
+        );
+        if let Some(recipient) = &ctx.accounts.recipient {
+            require!(recipient.mint == ctx.accounts.pool_account.mint, CustomError::MintMismatch);
+            require!(recipient.key() != ctx.accounts.pool_account.key(), CustomError::SelfTransfer);
+        }
+        require!(
+            is_pool_authority(&ctx.accounts.pool_account, ctx.accounts.pool_authority.key),
+            CustomError::InvalidPoolAuthority
+        );
+        // Reject reentrant loans up front; the flag itself is only set once every
+        // precondition below has passed, so no early return can leave it set.
+        require!(!ctx.accounts.global_state.is_flash_loan_active, CustomError::FlashLoanInProgress);
+        {
+            let reputation = ctx.accounts.borrower_reputation.as_ref().map_or(0, |r| r.reputation);
+            let compliance_program = ctx.accounts.compliance_program.as_ref().map(|p| p.to_account_info());
+            let now = Clock::get()?.unix_timestamp;
+            screen_borrower(
+                &ctx.accounts.global_state,
+                &ctx.accounts.borrower,
+                compliance_program.as_ref(),
+                reputation,
+                amount,
+                now,
+            )?;
+        }
+        // Check pool liquidity, drawing from the pool account first and then from extra vaults.
+        let mut sources = vec![LoanSource {
+            vault: ctx.accounts.pool_account.key(),
+            amount: amount.min(ctx.accounts.pool_account.amount),
+        }];
+        let mut extra_vaults = Vec::new();
+        let mut drawn = sources[0].amount;
+        for pair in ctx.remaining_accounts.chunks(2) {
+            if drawn == amount {
+                break;
+            }
+            let [registration, info] = pair else {
+                return err!(CustomError::UnregisteredPoolVault);
+            };
+            let vault = extra_pool_vault(
+                &ctx.accounts.global_state,
+                &ctx.accounts.pool,
+                registration,
+                info,
+                ctx.accounts.pool_authority.key,
+            )?;
+            require!(!sources.iter().any(|s| s.vault == *info.key), CustomError::DuplicateLoanSource);
+            require!(*info.key != ctx.accounts.proceeds_account().key(), CustomError::SelfTransfer);
+            require!(sources.len() < MAX_LOAN_SOURCES, CustomError::TooManyLoanSources);
+            let draw = (amount - drawn).min(vault.amount);
+            sources.push(LoanSource { vault: *info.key, amount: draw });
+            extra_vaults.push((info.clone(), draw));
+            drawn = drawn.checked_add(draw).unwrap();
+        }
+        require!(drawn >= amount, CustomError::InsufficientLiquidity);
+        let primary_draw = sources[0].amount;
+        // Transfer collateral (if provided).
+        if collateral_amount > 0 {
+            require!(
+                ctx.accounts.borrower_collateral_account.amount >= collateral_amount,
+                CustomError::InsufficientCollateral
+            );
+            {
+                let collateral_ctx = ctx.accounts.into_transfer_collateral_context();
+                token::transfer(collateral_ctx, collateral_amount)?;
+            }
+        }
+        // Read the fee rate from global state (immutable borrow) and compute fee,
+        // unless a reservation locked in an earlier rate and prepaid the fee.
+        let current_time = Clock::get()?.unix_timestamp;
+        // A voucher waives the fee outright and can't be combined with a reservation.
+        if let Some(voucher) = &mut ctx.accounts.voucher {
+            require!(ctx.accounts.reservation.is_none(), CustomError::InvalidVoucher);
+            require!(!voucher.consumed, CustomError::VoucherConsumed);
+            require!(current_time <= voucher.expiry, CustomError::VoucherExpired);
+            require!(voucher.amount == amount, CustomError::VoucherMismatch);
+            voucher.consumed = true;
+        }
+        if let Some(reservation) = &ctx.accounts.reservation {
+            require!(current_time <= reservation.expiry, CustomError::ReservationExpired);
+            require!(reservation.amount == amount, CustomError::ReservationMismatch);
+            require!(reservation.pool == ctx.accounts.pool.key(), CustomError::ReservationMismatch);
+        }
+        // An unpaid fee is priced by the same pipeline `quote_fee` reports: whatever tiers or
+        // vouchers brought it down to, it never drops below the floor, and part of it may be
+        // covered from the subsidy vault, paid into the pool now so LPs still receive the full
+        // fee while the borrower owes less.
+        let terms = {
+            let borrower_ctx = BorrowerFeeContext {
+                voucher: ctx.accounts.voucher.is_some(),
+                subsidized: ctx.accounts.subsidy_vault.is_some() && ctx.accounts.subsidy_vault_authority.is_some(),
+                stake: 0,
+            };
+            let reservation = ctx.accounts.reservation.as_deref();
+            issue_terms(&ctx.accounts.global_state, &ctx.accounts.pool, reservation, &borrower_ctx, amount)?
+        };
+        // A flat fee does not shrink with the loan, so the borrower must already hold it.
+        if terms.fixed_fee > 0 && !terms.fee_prepaid {
+            require!(ctx.accounts.borrower_token_account.amount >= terms.fee, CustomError::FeeNotCovered);
+        }
+        require!(max_fee == 0 || terms.net_fee() <= max_fee, CustomError::FeeExceedsMax);
+        if terms.subsidy > 0 {
+            let subsidy_mint = ctx.accounts.subsidy_vault.as_ref().unwrap().mint;
+            require!(subsidy_mint == ctx.accounts.pool_account.mint, CustomError::MintMismatch);
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_transfer_subsidy_context(signer_seeds);
+            token::transfer(transfer_ctx, terms.subsidy)?;
+            let state = &mut ctx.accounts.global_state;
+            state.subsidy_balance = state.subsidy_balance.checked_sub(terms.subsidy).unwrap();
+        }
+        // Record flash loan details, snapshotting the rate quoted at borrow time.
+        {
+            let borrower = *ctx.accounts.borrower.key;
+            let pool = ctx.accounts.pool.key();
+            let collateral = (ctx.accounts.collateral_escrow.mint, collateral_amount);
+            ctx.accounts.flash_loan_state.open(borrower, pool, &terms, collateral, sources, current_time);
+            activate_loan(&mut ctx.accounts.global_state, borrower, amount);
+        }
+        // Transfer the flash loan amount to the borrower, or to the recipient if given.
+        let pre_balance = ctx.accounts.pool_account.amount;
+        if primary_draw > 0 {
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_transfer_to_borrower_context(signer_seeds);
+            token::transfer(transfer_ctx, primary_draw)?;
+        }
+        for (vault, draw) in extra_vaults {
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_transfer_from_vault_context(vault, signer_seeds);
+            token::transfer(transfer_ctx, draw)?;
+        }
+        ctx.accounts.pool_account.reload()?;
+        let post_balance = ctx.accounts.pool_account.amount;
+        if let Some(history) = ctx.accounts.loan_history.as_mut() {
+            history.record(LoanRecord {
+                loan: ctx.accounts.flash_loan_state.key(),
+                borrower: *ctx.accounts.borrower.key,
+                amount,
+                fee: terms.fee,
+                timestamp: current_time,
+                status: LOAN_OPEN,
+            });
+        }
+        if events_enabled(&ctx.accounts.global_state) {
+            emit!(FlashLoanIssued {
+                borrower: *ctx.accounts.borrower.key,
+                amount,
+                fee: event_detail(&ctx.accounts.global_state, terms.fee),
+                pre_balance: event_detail(&ctx.accounts.global_state, pre_balance),
+                post_balance: event_detail(&ctx.accounts.global_state, post_balance),
+            });
+        }
+        Ok(())
+    }
+
+    /// Read-only dry run of `flash_loan`: runs its checks (pause, cap, reentrancy, whitelist,
+    /// liquidity, collateral balance) and quotes the fee without moving funds or creating
+    /// accounts. A rejection is reported in the result rather than failing the instruction.
+    /// Extra vaults are passed in `remaining_accounts` as for `flash_loan`. Reservations,
+    /// vouchers, subsidies and the compliance hook are not evaluated.
+    pub fn simulate_flash_loan<'info>(
+        ctx: Context<'_, '_, '_, 'info, SimulateFlashLoan<'info>>,
+        amount: u64,
+        collateral_amount: u64,
+    ) -> Result<SimResult> {
+        let state = &ctx.accounts.global_state;
+        let (fee_rate, fixed_fee) = quote_terms(state, Some(&ctx.accounts.pool), amount);
+        let mut available = ctx.accounts.pool_account.amount;
+        let outcome = (|| -> Result<()> {
+            require!(!state.paused, CustomError::ProgramPaused);
+            require!(!state.winding_down, CustomError::WindingDown);
+            require!(within_loan_cap(state, amount), CustomError::PerTxCapExceeded);
+            require!(within_collateral_cap(state, amount, collateral_amount), CustomError::ExcessiveCollateral);
+            require!(
+                ctx.accounts.borrower_token_account.key() != ctx.accounts.pool_account.key(),
+                CustomError::SelfTransfer
+            );
+            require!(
+                is_pool_authority(&ctx.accounts.pool_account, ctx.accounts.pool_authority.key),
+                CustomError::InvalidPoolAuthority
+            );
+            require!(!state.is_flash_loan_active, CustomError::FlashLoanInProgress);
+            let reputation = ctx.accounts.borrower_reputation.as_ref().map_or(0, |r| r.reputation);
+            require!(
+                is_borrower_allowed(state, ctx.accounts.borrower.key, ctx.accounts.borrower.owner, reputation, Clock::get()?.unix_timestamp),
+                CustomError::NotWhitelisted
+            );
+            require!(within_reputation_cap(state, reputation, amount), CustomError::ReputationCapExceeded);
+            let mut vaults = vec![ctx.accounts.pool_account.key()];
+            for pair in ctx.remaining_accounts.chunks(2) {
+                let [registration, info] = pair else {
+                    return err!(CustomError::UnregisteredPoolVault);
+                };
+                let vault = extra_pool_vault(state, &ctx.accounts.pool, registration, info, ctx.accounts.pool_authority.key)?;
+                require!(!vaults.contains(info.key), CustomError::DuplicateLoanSource);
+                require!(vaults.len() < MAX_LOAN_SOURCES, CustomError::TooManyLoanSources);
+                vaults.push(*info.key);
+                available = available.checked_add(vault.amount).unwrap();
+            }
+            require!(available >= amount, CustomError::InsufficientLiquidity);
+            if collateral_amount > 0 {
+                let collateral_mint = ctx.accounts.borrower_collateral_account.mint;
+                require!(
+                    is_accepted_collateral(state, &ctx.accounts.pool_account.mint, &collateral_mint, &collateral_mint),
+                    CustomError::CollateralMintMismatch
+                );
+                require!(
+                    ctx.accounts.borrower_collateral_account.amount >= collateral_amount,
+                    CustomError::InsufficientCollateral
+                );
+            }
+            Ok(())
+        })();
+        let error_code = match outcome {
+            Ok(()) => 0,
+            Err(Error::AnchorError(err)) => err.error_code_number,
+            Err(Error::ProgramError(_)) => u32::MAX,
+        };
+        Ok(SimResult {
+            accepted: error_code == 0,
+            error_code,
+            fee: apply_fee_floor(state, amount, fee_for_terms(amount, fee_rate, fixed_fee, state.round_fees_up)),
+            fee_rate,
+            available_liquidity: available,
+        })
+    }
+
+    /// Records a fee-waiver voucher signed off-chain by `voucher_signer`, granting the borrower
+    /// a zero-fee loan of exactly `amount` until `expiry`. The instruction immediately before
+    /// this one must be an Ed25519 program instruction verifying the signature over
+    /// `borrower || amount || expiry || nonce` (little-endian). Each nonce can be redeemed once.
+    pub fn redeem_voucher(ctx: Context<RedeemVoucher>, nonce: u64, amount: u64, expiry: i64) -> Result<()> {
+        let signer = ctx.accounts.global_state.voucher_signer.ok_or(CustomError::InvalidVoucher)?;
+        require!(Clock::get()?.unix_timestamp <= expiry, CustomError::VoucherExpired);
+        {
+            let message = voucher_message(ctx.accounts.borrower.key, amount, expiry, nonce);
+            let current = load_current_index_checked(&ctx.accounts.instructions)?;
+            require!(current > 0, CustomError::InvalidVoucher);
+            let ed25519_ix = load_instruction_at_checked((current - 1) as usize, &ctx.accounts.instructions)?;
+            require!(verifies_ed25519(&ed25519_ix, &signer, &message), CustomError::InvalidVoucher);
+        }
+        {
+            let voucher = &mut ctx.accounts.voucher;
+            voucher.borrower = *ctx.accounts.borrower.key;
+            voucher.nonce = nonce;
+            voucher.amount = amount;
+            voucher.expiry = expiry;
+            voucher.consumed = false;
+        }
+        Ok(())
+    }
+
+    /// Reserves a future flash loan of `amount` at the current fee rate until `expiry`.
+    /// The fee is prepaid into the fee vault now and consumed by the matching `flash_loan`.
+    pub fn reserve_loan(ctx: Context<ReserveLoan>, amount: u64, expiry: i64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        require!(!ctx.accounts.global_state.winding_down, CustomError::WindingDown);
+        let current_time = Clock::get()?.unix_timestamp;
+        require!(expiry > current_time, CustomError::InvalidExpiry);
+        let (fee_rate, fixed_fee) = quote_terms(&ctx.accounts.global_state, Some(&ctx.accounts.pool), amount);
+        let prepaid_fee = {
+            let state = &ctx.accounts.global_state;
+            apply_fee_floor(state, amount, fee_for_terms(amount, fee_rate, fixed_fee, state.round_fees_up))
+        };
+        // Prepay the fee into the fee vault.
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_to_fee_vault_context();
+            token::transfer(transfer_ctx, prepaid_fee)?;
+        }
+        {
+            let reservation = &mut ctx.accounts.reservation;
+            reservation.borrower = *ctx.accounts.borrower.key;
+            reservation.amount = amount;
+            reservation.fee_rate = fee_rate;
+            reservation.prepaid_fee = prepaid_fee;
+            reservation.expiry = expiry;
+            reservation.fixed_fee = fixed_fee;
+            reservation.time_priced = matches!(ctx.accounts.global_state.fee_model, FeeModel::TimeBased { .. });
+            reservation.pool = ctx.accounts.pool.key();
+        }
+        Ok(())
+    }
+
+    /// Refunds the prepaid fee of an unused reservation once it has expired.
+    pub fn cancel_reservation(ctx: Context<CancelReservation>) -> Result<()> {
+        let (expiry, prepaid_fee) = {
+            let reservation = &ctx.accounts.reservation;
+            (reservation.expiry, reservation.prepaid_fee)
+        };
+        require!(Clock::get()?.unix_timestamp > expiry, CustomError::ReservationActive);
+        {
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_refund_from_fee_vault_context(signer_seeds);
+            token::transfer(transfer_ctx, prepaid_fee)?;
+        }
+        Ok(())
+    }
+
+    /// Opt-in borrower protection: makes `repay_flash_loan` revert unless the borrower's token
+    /// account still holds at least `min_repay_surplus` after repaying, so a transaction whose
+    /// intermediate steps were sandwiched into a loss unwinds instead of closing the loan.
+    pub fn set_min_repay_surplus(ctx: Context<SetMinRepaySurplus>, min_repay_surplus: u64) -> Result<()> {
+        {
+            let flash_loan_state = &mut ctx.accounts.flash_loan_state;
+            flash_loan_state.min_repay_surplus = min_repay_surplus;
+        }
+        Ok(())
+    }
+
+    /// Repays a flash loan.
+    /// Enforces repayment within a time limit and updates the borrower's reputation.
+    /// The repayment may be funded by a `repayer` other than the borrower; the borrower
+    /// still receives the closed account's rent and the reputation credit.
+    /// A loan drawn from several vaults repays each of them in proportion to what it lent;
+    /// the extra vaults must be passed in `remaining_accounts` in the order they were drawn.
+    /// A time-priced loan pays only for the part of the window it used; the unused part of a
+    /// prepaid fee is refunded from the fee vault to `borrower_token_account`.
+    pub fn repay_flash_loan<'info>(ctx: Context<'_, '_, '_, 'info, RepayFlashLoan<'info>>) -> Result<()> {
+        let current_time = Clock::get()?.unix_timestamp;
+        let elapsed = elapsed_since(ctx.accounts.flash_loan_state.start_time, current_time)?;
+        require!(elapsed <= FLASH_LOAN_DURATION, CustomError::FlashLoanExpired);
+        let stake = ctx.accounts.borrower_stake.as_ref().map_or(0, |s| s.amount);
+        let settlement = settle_loan(&ctx.accounts.global_state, &mut ctx.accounts.flash_loan_state, stake, elapsed);
+        let outstanding = settlement.outstanding;
+        let flash_loan_state = &ctx.accounts.flash_loan_state;
+        let remaining_collateral = flash_loan_state.collateral.checked_sub(flash_loan_state.collateral_released).unwrap();
+        let gain = reputation_gain(&ctx.accounts.global_state, flash_loan_state.amount);
+        let max_reputation = ctx.accounts.global_state.max_reputation;
+        let sources = flash_loan_state.sources.clone();
+        // A freshly created reputation account has not been claimed by a borrower yet;
+        // an existing one must already belong to this borrower.
+        let is_new_borrower = ctx.accounts.borrower_reputation.borrower == Pubkey::default();
+        require!(
+            is_new_borrower || ctx.accounts.borrower_reputation.borrower == *ctx.accounts.borrower.key,
+            CustomError::ReputationMismatch
+        );
+        // Check the repayment source up front so a bad account fails with a clear error
+        // rather than an opaque token program failure mid-transfer.
+        {
+            let source = &ctx.accounts.borrower_token_account;
+            require!(source.mint == ctx.accounts.pool_account.mint, CustomError::MintMismatch);
+            require!(source.amount >= outstanding, CustomError::RepaymentInsufficient);
+        }
+        // Return whatever principal plus fee is still outstanding to the pool.
+        if outstanding > 0 && sources.len() <= 1 {
+            let transfer_ctx = ctx.accounts.into_transfer_repayment_context();
+            token::transfer(transfer_ctx, outstanding)?;
+        }
+        // Or split it across the vaults the loan was drawn from. Every vault is repaid in
+        // this one instruction: repaying only some of them is rejected outright.
+        if outstanding > 0 && sources.len() > 1 {
+            require!(
+                ctx.remaining_accounts.len() >= sources.len() - 1,
+                CustomError::PartialHopRepayment
+            );
+            require!(
+                ctx.remaining_accounts.len() == sources.len() - 1,
+                CustomError::LoanSourceMismatch
+            );
+            let shares = repayment_shares(outstanding, &sources);
+            let pool_account_key = ctx.accounts.pool_account.key();
+            for (i, (source, share)) in sources.iter().zip(shares).enumerate() {
+                if share == 0 {
+                    continue;
+                }
+                let vault = if i == 0 {
+                    require!(source.vault == pool_account_key, CustomError::LoanSourceMismatch);
+                    ctx.accounts.pool_account.to_account_info()
+                } else {
+                    let info = &ctx.remaining_accounts[i - 1];
+                    require!(source.vault == *info.key, CustomError::LoanSourceMismatch);
+                    info.clone()
+                };
+                let transfer_ctx = ctx.accounts.into_transfer_repayment_to_vault_context(vault);
+                token::transfer(transfer_ctx, share)?;
+            }
+        }
+        // Enforce the borrower's requested surplus, if any.
+        {
+            let min_repay_surplus = ctx.accounts.flash_loan_state.min_repay_surplus;
+            ctx.accounts.borrower_token_account.reload()?;
+            require!(
+                ctx.accounts.borrower_token_account.amount >= min_repay_surplus,
+                CustomError::InsufficientSurplus
+            );
+        }
+        // Collect a fee owed in the fee mint, route the treasury's and stakers' shares of a
+        // pool-paid fee, and refund the unused part of a prepaid one.
+        {
+            let global_state_key = ctx.accounts.global_state.key();
+            pay_settlement_fees(&ctx.accounts.settlement_accounts(), &ctx.accounts.global_state, &global_state_key, &settlement)?;
+        }
+        // Return whatever collateral is still held in escrow.
+        if remaining_collateral > 0 {
+            require!(
+                ctx.accounts.collateral_escrow.is_some()
+                    && ctx.accounts.escrow_authority.is_some()
+                    && ctx.accounts.borrower_collateral_account.is_some(),
+                CustomError::CollateralAccountsRequired
+            );
+            let bump = [ctx.bumps.escrow_authority];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"escrow_authority", &bump]];
+            let release_ctx = ctx.accounts.into_release_collateral_context(signer_seeds);
+            token::transfer(release_ctx, remaining_collateral)?;
+        }
+        {
+            let principal = ctx.accounts.flash_loan_state.amount;
+            book_settlement(&mut ctx.accounts.global_state, &mut ctx.accounts.pool, principal, &settlement, current_time)?;
+        }
+        {
+            let reputation = &mut ctx.accounts.borrower_reputation;
+            if is_new_borrower {
+                reputation.borrower = *ctx.accounts.borrower.key;
+            }
+            reputation.reputation = reputation.reputation.saturating_add(gain);
+            if max_reputation > 0 {
+                reputation.reputation = reputation.reputation.min(max_reputation);
+            }
+            reputation.last_repaid_at = current_time;
+        }
+        // Record first-time borrowers in the reputation index, if one is supplied.
+        if is_new_borrower {
+            let should_register = ctx.accounts.reputation_index.as_ref().is_some_and(|index| {
+                index.borrowers.len() < MAX_INDEXED_BORROWERS && !index.borrowers.contains(ctx.accounts.borrower.key)
+            });
+            if should_register {
+                ctx.accounts.grow_reputation_index()?;
+                let borrower = *ctx.accounts.borrower.key;
+                let index = ctx.accounts.reputation_index.as_mut().unwrap();
+                index.borrowers.push(borrower);
+            }
+        }
+        if let Some(history) = ctx.accounts.loan_history.as_mut() {
+            history.mark_repaid(&ctx.accounts.flash_loan_state.key());
+        }
+        Ok(())
+    }
+
+    /// Repays part of an open flash loan and releases collateral in proportion:
+    /// after repaying `repaid` of `amount + fee`, up to `collateral * repaid / (amount + fee)`
+    /// has been returned. The tranche that completes repayment releases exactly the remainder.
+    pub fn repay_partial(ctx: Context<RepayPartial>, amount: u64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        let current_time = Clock::get()?.unix_timestamp;
+        let (owed, collateral, repaid, collateral_released) = {
+            let flash_loan_state = &ctx.accounts.flash_loan_state;
+            let elapsed = elapsed_since(flash_loan_state.start_time, current_time)?;
+            require!(elapsed <= FLASH_LOAN_DURATION, CustomError::FlashLoanExpired);
+            require!(flash_loan_state.sources.len() <= 1, CustomError::MultiVaultLoan);
+            let owed = amount_owed(flash_loan_state);
+            let repaid = flash_loan_state.repaid.checked_add(amount).unwrap();
+            require!(repaid <= owed, CustomError::RepaymentExceedsOwed);
+            (owed, flash_loan_state.collateral, repaid, flash_loan_state.collateral_released)
+        };
+        // Transfer the tranche back to the pool.
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_repayment_context();
+            token::transfer(transfer_ctx, amount)?;
+        }
+        // Release collateral up to the share now covered by repayment.
+        let release_target = ((collateral as u128).checked_mul(repaid as u128).unwrap() / owed as u128) as u64;
+        let release = release_target.saturating_sub(collateral_released);
+        if release > 0 {
+            let bump = [ctx.bumps.escrow_authority];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"escrow_authority", &bump]];
+            let release_ctx = ctx.accounts.into_release_collateral_context(signer_seeds);
+            token::transfer(release_ctx, release)?;
+        }
+        {
+            let flash_loan_state = &mut ctx.accounts.flash_loan_state;
+            flash_loan_state.repaid = repaid;
+            flash_loan_state.collateral_released = collateral_released.checked_add(release).unwrap();
+        }
+        Ok(())
+    }
+
+    /// Escrows `amount` more collateral against the borrower's open, unexpired loan, within
+    /// the `max_collateral_bps` cap and in the accepted collateral mint.
+    pub fn add_collateral(ctx: Context<AddCollateral>, amount: u64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        let current_time = Clock::get()?.unix_timestamp;
+        let total_collateral = {
+            let flash_loan_state = &ctx.accounts.flash_loan_state;
+            let elapsed = elapsed_since(flash_loan_state.start_time, current_time)?;
+            require!(elapsed <= FLASH_LOAN_DURATION, CustomError::FlashLoanExpired);
+            flash_loan_state.collateral.checked_add(amount).unwrap()
+        };
+        let held = total_collateral.checked_sub(ctx.accounts.flash_loan_state.collateral_released).unwrap();
+        require!(
+            within_collateral_cap(&ctx.accounts.global_state, ctx.accounts.flash_loan_state.amount, held),
+            CustomError::ExcessiveCollateral
+        );
+        require!(
+            is_accepted_collateral(
+                &ctx.accounts.global_state,
+                &ctx.accounts.pool_account.mint,
+                &ctx.accounts.collateral_escrow.mint,
+                &ctx.accounts.borrower_collateral_account.mint
+            ),
+            CustomError::CollateralMintMismatch
+        );
+        require!(
+            ctx.accounts.borrower_collateral_account.amount >= amount,
+            CustomError::InsufficientCollateral
+        );
+        {
+            let collateral_ctx = ctx.accounts.into_transfer_collateral_context();
+            token::transfer(collateral_ctx, amount)?;
+        }
+        {
+            let flash_loan_state = &mut ctx.accounts.flash_loan_state;
+            flash_loan_state.collateral = total_collateral;
+        }
+        Ok(())
+    }
+
+    /// Permissionless instruction to seize the collateral still escrowed for a loan left
+    /// unrepaid past `FLASH_LOAN_DURATION`. The caller earns `liquidation_incentive_bps` of it;
+    /// the rest goes back to the pool when the collateral is in the pool's mint, and to the
+    /// treasury otherwise. Whatever principal the pool is still short of after that is written
+    /// off `total_liquidity` as a loss to LPs. Closing the loan frees the pool for the next
+    /// borrower.
+    pub fn liquidate_collateral(ctx: Context<LiquidateCollateral>) -> Result<()> {
+        let current_time = Clock::get()?.unix_timestamp;
+        let seized = {
+            let flash_loan_state = &ctx.accounts.flash_loan_state;
+            let elapsed = elapsed_since(flash_loan_state.start_time, current_time)?;
+            require!(elapsed > FLASH_LOAN_DURATION, CustomError::LoanNotExpired);
+            flash_loan_state.collateral.checked_sub(flash_loan_state.collateral_released).unwrap()
+        };
+        let incentive = ((seized as u128)
+            .checked_mul(ctx.accounts.global_state.liquidation_incentive_bps as u128)
+            .unwrap()
+            / BPS_DENOMINATOR as u128) as u64;
+        let remainder = seized.checked_sub(incentive).unwrap();
+        let to_pool = ctx.accounts.collateral_escrow.mint == ctx.accounts.pool_account.mint;
+        if remainder > 0 && !to_pool {
+            require!(ctx.accounts.treasury_token_account.is_some(), CustomError::InvalidTreasury);
+        }
+        let bump = [ctx.bumps.escrow_authority];
+        let signer_seeds: &[&[&[u8]]] = &[&[b"escrow_authority", &bump]];
+        if incentive > 0 {
+            let destination = ctx.accounts.liquidator_token_account.to_account_info();
+            let transfer_ctx = ctx.accounts.into_seize_collateral_context(destination, signer_seeds);
+            token::transfer(transfer_ctx, incentive)?;
+        }
+        if remainder > 0 {
+            let destination = if to_pool {
+                ctx.accounts.pool_account.to_account_info()
+            } else {
+                ctx.accounts.treasury_token_account.as_ref().unwrap().to_account_info()
+            };
+            let transfer_ctx = ctx.accounts.into_seize_collateral_context(destination, signer_seeds);
+            token::transfer(transfer_ctx, remainder)?;
+        }
+        // The fee was never counted as liquidity, so only the principal not yet returned,
+        // by partial repayments or the seized collateral, is lost.
+        let written_off = {
+            let loan = &ctx.accounts.flash_loan_state;
+            let returned = if to_pool { remainder } else { 0 };
+            loan.amount.saturating_sub(loan.repaid.saturating_add(returned))
+        };
+        {
+            let borrower = ctx.accounts.flash_loan_state.borrower;
+            let state = &mut ctx.accounts.global_state;
+            state.total_liquidity = state.total_liquidity.saturating_sub(written_off);
+            if state.is_flash_loan_active && state.active_borrower == borrower {
+                state.is_flash_loan_active = false;
+                state.active_borrower = Pubkey::default();
+                update_util_ema(state, 0);
+            }
+        }
+        if let Some(history) = ctx.accounts.loan_history.as_mut() {
+            history.mark_defaulted(&ctx.accounts.flash_loan_state.key());
+        }
+        if events_enabled(&ctx.accounts.global_state) {
+            emit!(CollateralLiquidated {
+                loan: ctx.accounts.flash_loan_state.key(),
+                borrower: ctx.accounts.flash_loan_state.borrower,
+                liquidator: *ctx.accounts.liquidator.key,
+                seized,
+                incentive,
+                remainder,
+                written_off,
+            });
+        }
+        Ok(())
+    }
+
+    /// Repays the borrower's current flash loan (principal plus fee) and atomically
+    /// issues a new loan of `new_amount`, reusing the same `FlashLoanState`.
+    /// `new_collateral` is escrowed on top of the collateral already held.
+    /// The old loan is settled exactly as `repay_flash_loan` settles it, and the new one is
+    /// screened and priced exactly as by `flash_loan`, including its `max_fee` guard.
+    pub fn rollover_flash_loan(
+        ctx: Context<RolloverFlashLoan>,
+        new_amount: u64,
+        new_collateral: u64,
+        max_fee: u64,
+    ) -> Result<()> {
+        // Collateral carried over counts toward the new loan's cap.
+        let total_collateral = {
+            let flash_loan_state = &ctx.accounts.flash_loan_state;
+            let retained = flash_loan_state.collateral.checked_sub(flash_loan_state.collateral_released).unwrap();
+            retained.checked_add(new_collateral).unwrap()
+        };
+        check_loan_limits(&ctx.accounts.global_state, new_amount, total_collateral)?;
+        require!(
+            ctx.accounts.borrower_token_account.key() != ctx.accounts.pool_account.key(),
+            CustomError::SelfTransfer
+        );
+        require!(
+            ctx.accounts.borrower_collateral_account.key() != ctx.accounts.collateral_escrow.key(),
+            CustomError::SelfTransfer
+        );
+        require!(
+            is_accepted_collateral(
+                &ctx.accounts.global_state,
+                &ctx.accounts.pool_account.mint,
+                &ctx.accounts.collateral_escrow.mint,
+                &ctx.accounts.borrower_collateral_account.mint
+            ),
+            CustomError::CollateralMintMismatch
+        );
+        require!(
+            is_pool_authority(&ctx.accounts.pool_account, ctx.accounts.pool_authority.key),
+            CustomError::InvalidPoolAuthority
+        );
+        // The current loan must still be within its repayment window.
+        let current_time = Clock::get()?.unix_timestamp;
+        let settlement = {
+            let elapsed = elapsed_since(ctx.accounts.flash_loan_state.start_time, current_time)?;
+            require!(elapsed <= FLASH_LOAN_DURATION, CustomError::FlashLoanExpired);
+            require!(ctx.accounts.flash_loan_state.sources.len() <= 1, CustomError::MultiVaultLoan);
+            let stake = ctx.accounts.borrower_stake.as_ref().map_or(0, |s| s.amount);
+            settle_loan(&ctx.accounts.global_state, &mut ctx.accounts.flash_loan_state, stake, elapsed)
+        };
+        {
+            let source = &ctx.accounts.borrower_token_account;
+            require!(source.mint == ctx.accounts.pool_account.mint, CustomError::MintMismatch);
+            require!(source.amount >= settlement.outstanding, CustomError::RepaymentInsufficient);
+        }
+        // Repay whatever principal plus fee is still outstanding on the current loan.
+        if settlement.outstanding > 0 {
+            let transfer_ctx = ctx.accounts.into_transfer_repayment_context();
+            token::transfer(transfer_ctx, settlement.outstanding)?;
+        }
+        {
+            let min_repay_surplus = ctx.accounts.flash_loan_state.min_repay_surplus;
+            ctx.accounts.borrower_token_account.reload()?;
+            require!(
+                ctx.accounts.borrower_token_account.amount >= min_repay_surplus,
+                CustomError::InsufficientSurplus
+            );
+        }
+        {
+            let global_state_key = ctx.accounts.global_state.key();
+            pay_settlement_fees(&ctx.accounts.settlement_accounts(), &ctx.accounts.global_state, &global_state_key, &settlement)?;
+        }
+        {
+            let principal = ctx.accounts.flash_loan_state.amount;
+            book_settlement(&mut ctx.accounts.global_state, &mut ctx.accounts.pool, principal, &settlement, current_time)?;
+        }
+        if let Some(history) = ctx.accounts.loan_history.as_mut() {
+            history.mark_repaid(&ctx.accounts.flash_loan_state.key());
+        }
+        // The new loan is subject to the same admission checks as `flash_loan`.
+        {
+            let reputation = ctx.accounts.borrower_reputation.as_ref().map_or(0, |r| r.reputation);
+            let compliance_program = ctx.accounts.compliance_program.as_ref().map(|p| p.to_account_info());
+            screen_borrower(
+                &ctx.accounts.global_state,
+                &ctx.accounts.borrower.to_account_info(),
+                compliance_program.as_ref(),
+                reputation,
+                new_amount,
+                current_time,
+            )?;
+        }
+        ctx.accounts.pool_account.reload()?;
+        require!(ctx.accounts.pool_account.amount >= new_amount, CustomError::InsufficientLiquidity);
+        let terms = issue_terms(
+            &ctx.accounts.global_state,
+            &ctx.accounts.pool,
+            None,
+            &BorrowerFeeContext::default(),
+            new_amount,
+        )?;
+        if terms.fixed_fee > 0 {
+            ctx.accounts.borrower_token_account.reload()?;
+            require!(ctx.accounts.borrower_token_account.amount >= terms.fee, CustomError::FeeNotCovered);
+        }
+        require!(max_fee == 0 || terms.net_fee() <= max_fee, CustomError::FeeExceedsMax);
+        if new_collateral > 0 {
+            let collateral_ctx = ctx.accounts.into_transfer_collateral_context();
+            token::transfer(collateral_ctx, new_collateral)?;
+        }
+        // Record the new loan in place of the old one.
+        {
+            let borrower = ctx.accounts.borrower.key();
+            let pool = ctx.accounts.pool.key();
+            let sources = vec![LoanSource {
+                vault: ctx.accounts.pool_account.key(),
+                amount: new_amount,
+            }];
+            let collateral = (ctx.accounts.collateral_escrow.mint, total_collateral);
+            ctx.accounts.flash_loan_state.open(borrower, pool, &terms, collateral, sources, current_time);
+            activate_loan(&mut ctx.accounts.global_state, borrower, new_amount);
+        }
+        if let Some(history) = ctx.accounts.loan_history.as_mut() {
+            history.record(LoanRecord {
+                loan: ctx.accounts.flash_loan_state.key(),
+                borrower: ctx.accounts.borrower.key(),
+                amount: new_amount,
+                fee: terms.fee,
+                timestamp: current_time,
+                status: LOAN_OPEN,
+            });
+        }
+        // Transfer the new loan amount to the borrower.
+        let pre_balance = ctx.accounts.pool_account.amount;
+        {
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_transfer_to_borrower_context(signer_seeds);
+            token::transfer(transfer_ctx, new_amount)?;
+        }
+        ctx.accounts.pool_account.reload()?;
+        let post_balance = ctx.accounts.pool_account.amount;
+        if events_enabled(&ctx.accounts.global_state) {
+            emit!(FlashLoanIssued {
+                borrower: ctx.accounts.borrower.key(),
+                amount: new_amount,
+                fee: event_detail(&ctx.accounts.global_state, terms.fee),
+                pre_balance: event_detail(&ctx.accounts.global_state, pre_balance),
+                post_balance: event_detail(&ctx.accounts.global_state, post_balance),
+            });
+        }
+        Ok(())
+    }
+
+    /// Closes a borrower's reputation account and refunds its rent.
+    /// Only allowed when the borrower has no open loan and the account is idle:
+    /// either it holds no reputation or the borrower has been inactive for
+    /// `REPUTATION_INACTIVITY_WINDOW`.
+    pub fn close_reputation(ctx: Context<CloseReputation>) -> Result<()> {
+        {
+            let state = &ctx.accounts.global_state;
+            let has_open_loan = state.is_flash_loan_active && state.active_borrower == *ctx.accounts.borrower.key;
+            require!(!has_open_loan, CustomError::ReputationInUse);
+        }
+        {
+            let reputation = &ctx.accounts.borrower_reputation;
+            let current_time = Clock::get()?.unix_timestamp;
+            let inactive = elapsed_since(reputation.last_repaid_at, current_time)? >= REPUTATION_INACTIVITY_WINDOW;
+            require!(reputation.reputation == 0 || inactive, CustomError::ReputationInUse);
+        }
+        Ok(())
+    }
+
+    /// Donates reward tokens to all current stakers, pro rata to their stake.
+    /// Donations are tracked separately from protocol-generated flash loan fees.
+    pub fn donate_rewards(ctx: Context<DonateRewards>, amount: u64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        // Donations can only be attributed if someone is staking.
+        {
+            let total_reward_weight = ctx.accounts.global_state.total_reward_weight;
+            require!(total_reward_weight > 0, CustomError::NoStakers);
+        }
+        record_emission(&mut ctx.accounts.global_state, amount, Clock::get()?.unix_timestamp)?;
+        // Transfer the donation into the reward vault.
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_to_reward_vault_context();
+            token::transfer(transfer_ctx, amount)?;
+        }
+        // Spread the donation across every unit of reward weight.
+        {
+            let state = &mut ctx.accounts.global_state;
+            let increment = (amount as u128).checked_mul(REWARD_PRECISION).unwrap() / state.total_reward_weight as u128;
+            state.acc_reward_per_share = state.acc_reward_per_share.checked_add(increment).unwrap();
+        }
+        if events_enabled(&ctx.accounts.global_state) {
+            emit!(RewardsDonated {
+                donor: ctx.accounts.donor.key(),
+                amount,
+            });
+        }
+        Ok(())
+    }
+
+    /// Adds `amount` to the fee subsidy balance, paid into the subsidy vault.
+    /// Anyone may fund subsidies.
+    pub fn fund_subsidy(ctx: Context<FundSubsidy>, amount: u64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_to_subsidy_vault_context();
+            token::transfer(transfer_ctx, amount)?;
+        }
+        {
+            let state = &mut ctx.accounts.global_state;
+            state.subsidy_balance = state.subsidy_balance.checked_add(amount).unwrap();
+        }
+        Ok(())
+    }
+
+    /// Admin-controlled instruction to fund a reward emission schedule: `amount` is paid
+    /// into the reward vault and streamed to stakers linearly over `duration` seconds.
+    /// Any unemitted rewards from a running schedule roll into the new one.
+    pub fn fund_emissions(ctx: Context<FundEmissions>, amount: u64, duration: i64) -> Result<()> {
+        require!(amount > 0, CustomError::ZeroAmount);
+        require!(ctx.accounts.global_state.admin == *ctx.accounts.admin.key, CustomError::Unauthorized);
+        require!(ctx.accounts.global_state.admin_threshold == 0, CustomError::MultisigRequired);
+        require!(duration > 0, CustomError::InvalidDuration);
+        record_emission(&mut ctx.accounts.global_state, amount, Clock::get()?.unix_timestamp)?;
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_to_reward_vault_context();
+            token::transfer(transfer_ctx, amount)?;
+        }
+        let current_time = Clock::get()?.unix_timestamp;
+        {
+            let state = &mut ctx.accounts.global_state;
+            update_emissions(state, current_time);
+            let leftover = if state.reward_period_end > current_time {
+                let remaining = (state.reward_period_end - current_time) as u64;
+                remaining.checked_mul(state.reward_rate_per_second).unwrap()
+            } else {
+                0
+            };
+            let total = amount.checked_add(leftover).unwrap();
+            let old_value = state.reward_rate_per_second;
+            state.reward_rate_per_second = total / duration as u64;
+            audit(ctx.accounts.admin.key(), AuditAction::FundEmissions, old_value, state.reward_rate_per_second)?;
+            state.reward_period_end = current_time.checked_add(duration).unwrap();
+            state.last_emission_update = current_time;
+        }
+        Ok(())
+    }
+
+    /// Folds rewards emitted since the last update into `acc_reward_per_share`.
+    /// Permissionless; stake changes and claims do this automatically.
+    pub fn sync_emissions(ctx: Context<SyncEmissions>) -> Result<()> {
+        update_emissions(&mut ctx.accounts.global_state, Clock::get()?.unix_timestamp);
+        Ok(())
+    }
+
+    /// Permissionless crank that credits the stakers' share of fees set aside since the last
+    /// run to `acc_reward_per_share`, at most once per `distribution_interval`. The cranker
+    /// is paid up to `crank_reward` out of the distributed amount, capped at
+    /// `MAX_CRANK_REWARD_BPS` of it.
+    pub fn crank_distribution(ctx: Context<CrankDistribution>) -> Result<()> {
+        require!(!ctx.accounts.global_state.paused, CustomError::ProgramPaused);
+        let current_time = Clock::get()?.unix_timestamp;
+        let (amount, crank_reward) = {
+            let state = &ctx.accounts.global_state;
+            let next_distribution = state.last_distribution.checked_add(state.distribution_interval).unwrap();
+            require!(current_time >= next_distribution, CustomError::DistributionTooEarly);
+            require!(state.pending_staker_fees > 0, CustomError::NoRewards);
+            require!(state.total_reward_weight > 0, CustomError::NoStakers);
+            let amount = state.pending_staker_fees;
+            (amount, state.crank_reward.min(compute_fee(amount, MAX_CRANK_REWARD_BPS, false)))
+        };
+        if crank_reward > 0 {
+            let global_state_key = ctx.accounts.global_state.key();
+            let bump = [ctx.accounts.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_transfer_crank_reward_context(signer_seeds);
+            token::transfer(transfer_ctx, crank_reward)?;
+        }
+        let distributed = amount.checked_sub(crank_reward).unwrap();
+        record_emission(&mut ctx.accounts.global_state, distributed, current_time)?;
+        update_emissions(&mut ctx.accounts.global_state, current_time);
+        {
+            let state = &mut ctx.accounts.global_state;
+            let increment = (distributed as u128).checked_mul(REWARD_PRECISION).unwrap() / state.total_reward_weight as u128;
+            state.acc_reward_per_share = state.acc_reward_per_share.checked_add(increment).unwrap();
+            // Consume only the snapshot distributed above, so fees set aside by a repayment
+            // landing after it wait for the next run instead of being swept unaccounted.
+            state.pending_staker_fees = state.pending_staker_fees.checked_sub(amount).unwrap();
+            state.staker_fees_distributed = state.staker_fees_distributed.checked_add(amount).unwrap();
+            state.last_distribution = current_time;
+        }
+        if events_enabled(&ctx.accounts.global_state) {
+            emit!(RewardsDistributed {
+                cranker: ctx.accounts.cranker.key(),
+                amount: distributed,
+                crank_reward: event_detail(&ctx.accounts.global_state, crank_reward),
+            });
+        }
+        Ok(())
+    }
+
+    /// Compound staking rewards by auto-reinvesting them.
+    pub fn compound_rewards(ctx: Context<CompoundRewards>) -> Result<()> {
+        require!(!ctx.accounts.global_state.paused, CustomError::ProgramPaused);
+        require!(!ctx.accounts.global_state.staking_paused, CustomError::StakingPaused);
+        let current_time = Clock::get()?.unix_timestamp;
+        require!(
+            cooldown_elapsed(
+                ctx.accounts.user_stake.last_compound_timestamp,
+                ctx.accounts.global_state.compound_cooldown,
+                current_time
+            ),
+            CustomError::ClaimTooSoon
+        );
+        // Settle accrued rewards so everything owed is in `unclaimed_rewards`.
+        update_emissions(&mut ctx.accounts.global_state, Clock::get()?.unix_timestamp);
+        let acc_reward_per_share = ctx.accounts.global_state.acc_reward_per_share;
+        let rewards = {
+            let user_stake = &mut ctx.accounts.user_stake;
+            settle_rewards(user_stake, acc_reward_per_share)?;
+            user_stake.unclaimed_rewards
+        };
+        require!(rewards > 0, CustomError::NoRewards);
+        // Move the rewards from the reward vault into the stake vault.
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_rewards_to_stake_context();
+            token::transfer(transfer_ctx, rewards)?;
+        }
+        // Restake the rewards on behalf of the user.
+        {
+            let user_stake = &mut ctx.accounts.user_stake;
+            user_stake.unclaimed_rewards = 0;
+            user_stake.compounded_amount = user_stake.compounded_amount.checked_add(rewards).unwrap();
+            user_stake.reward_debt = accrued_rewards(staked_balance(user_stake), acc_reward_per_share);
+            user_stake.last_modified_timestamp = current_time;
+            user_stake.last_compound_timestamp = current_time;
+        }
+        {
+            let state = &mut ctx.accounts.global_state;
+            state.total_staked = state.total_staked.checked_add(rewards).unwrap();
+            state.total_reward_weight = state.total_reward_weight.checked_add(rewards).unwrap();
+        }
+        ctx.accounts.stake_vault.reload()?;
+        assert_stake_solvency(&ctx.accounts.stake_vault, &ctx.accounts.global_state)?;
+        Ok(())
+    }
+
+    /// Pays out the caller's pending staking rewards to their reward token account.
+    pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
+        require!(!ctx.accounts.global_state.paused, CustomError::ProgramPaused);
+        let current_time = Clock::get()?.unix_timestamp;
+        require!(
+            cooldown_elapsed(
+                ctx.accounts.user_stake.last_claim_timestamp,
+                ctx.accounts.global_state.claim_cooldown,
+                current_time
+            ),
+            CustomError::ClaimTooSoon
+        );
+        update_emissions(&mut ctx.accounts.global_state, Clock::get()?.unix_timestamp);
+        let acc_reward_per_share = ctx.accounts.global_state.acc_reward_per_share;
+        let rewards = {
+            let user_stake = &mut ctx.accounts.user_stake;
+            settle_rewards(user_stake, acc_reward_per_share)?;
+            user_stake.unclaimed_rewards
+        };
+        require!(rewards > 0, CustomError::NoRewards);
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_rewards_to_user_context();
+            token::transfer(transfer_ctx, rewards)?;
+        }
+        {
+            let user_stake = &mut ctx.accounts.user_stake;
+            user_stake.unclaimed_rewards = 0;
+            user_stake.last_claim_timestamp = current_time;
+        }
+        Ok(())
+    }
+
+    /// Claims the pending rewards of every `StakePosition` passed in `remaining_accounts`
+    /// and pays their total in a single transfer. Each position must belong to `user`, and
+    /// the claim shares `claim_rewards`' cooldown.
+    pub fn claim_all_rewards<'info>(ctx: Context<'_, '_, 'info, 'info, ClaimAllRewards<'info>>) -> Result<()> {
+        require!(!ctx.accounts.global_state.paused, CustomError::ProgramPaused);
+        require!(!ctx.remaining_accounts.is_empty(), CustomError::NoRewards);
+        let current_time = Clock::get()?.unix_timestamp;
+        start_claim_cooldown(&ctx.accounts.global_state, &mut ctx.accounts.user_stake, ctx.accounts.user.key, current_time)?;
+        update_emissions(&mut ctx.accounts.global_state, current_time);
+        let acc_reward_per_share = ctx.accounts.global_state.acc_reward_per_share;
+        // Settle each position; writing it back before the next means a repeated account
+        // has nothing left to claim the second time.
+        let mut rewards: u64 = 0;
+        for info in ctx.remaining_accounts.iter() {
+            require!(info.is_writable, CustomError::InvalidStakePosition);
+            let mut position: Account<'info, StakePosition> = Account::try_from(info)?;
+            require!(position.owner == ctx.accounts.user.key(), CustomError::Unauthorized);
+            let accrued = accrued_rewards(position_weight(&position), acc_reward_per_share);
+            assert_reward_debt(accrued, position.reward_debt)?;
+            rewards = rewards.checked_add(pending_position_rewards(&position, acc_reward_per_share)).unwrap();
+            position.reward_debt = accrued;
+            position.unclaimed_rewards = 0;
+            position.exit(&crate::ID)?;
+        }
+        require!(rewards > 0, CustomError::NoRewards);
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_rewards_to_user_context();
+            token::transfer(transfer_ctx, rewards)?;
+        }
+        Ok(())
+    }
+
+    /// Claims pending rewards into the caller's vesting escrow instead of paying them out.
+    /// They release linearly over `duration` seconds from now, with nothing released before
+    /// `cliff`, through `claim_vested`. A previous schedule must be fully released first.
+    pub fn claim_to_vesting(ctx: Context<ClaimToVesting>, cliff: i64, duration: i64) -> Result<()> {
+        require!(!ctx.accounts.global_state.paused, CustomError::ProgramPaused);
+        require!(
+            duration > 0 && duration <= MAX_VESTING_DURATION && (0..=duration).contains(&cliff),
+            CustomError::InvalidDuration
+        );
+        require!(
+            ctx.accounts.vesting.released == ctx.accounts.vesting.total,
+            CustomError::VestingActive
+        );
+        let current_time = Clock::get()?.unix_timestamp;
+        require!(
+            cooldown_elapsed(
+                ctx.accounts.user_stake.last_claim_timestamp,
+                ctx.accounts.global_state.claim_cooldown,
+                current_time
+            ),
+            CustomError::ClaimTooSoon
+        );
+        update_emissions(&mut ctx.accounts.global_state, current_time);
+        let acc_reward_per_share = ctx.accounts.global_state.acc_reward_per_share;
+        let rewards = {
+            let user_stake = &mut ctx.accounts.user_stake;
+            settle_rewards(user_stake, acc_reward_per_share)?;
+            user_stake.unclaimed_rewards
+        };
+        require!(rewards > 0, CustomError::NoRewards);
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_rewards_to_vesting_context();
+            token::transfer(transfer_ctx, rewards)?;
+        }
+        {
+            let user_stake = &mut ctx.accounts.user_stake;
+            user_stake.unclaimed_rewards = 0;
+            user_stake.last_claim_timestamp = current_time;
+        }
+        {
+            let vesting = &mut ctx.accounts.vesting;
+            vesting.owner = *ctx.accounts.user.key;
+            vesting.total = rewards;
+            vesting.released = 0;
+            vesting.start = current_time;
+            vesting.cliff = cliff;
+            vesting.duration = duration;
+        }
+        Ok(())
+    }
+
+    /// Pays out the part of the caller's vesting schedule unlocked so far and not yet released.
+    pub fn claim_vested(ctx: Context<ClaimVested>) -> Result<()> {
+        let releasable = {
+            let vesting = &ctx.accounts.vesting;
+            vested_amount(vesting, Clock::get()?.unix_timestamp)?.checked_sub(vesting.released).unwrap()
+        };
+        require!(releasable > 0, CustomError::NothingVested);
+        {
+            let user_key = ctx.accounts.user.key();
+            let bump = [ctx.bumps.vesting];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vesting", user_key.as_ref(), &bump]];
+            let transfer_ctx = ctx.accounts.into_transfer_vested_context(signer_seeds);
+            token::transfer(transfer_ctx, releasable)?;
+        }
+        ctx.accounts.vesting.released = ctx.accounts.vesting.released.checked_add(releasable).unwrap();
+        Ok(())
+    }
+
+    /// Claims pending rewards and swaps them through the configured `swap_program`.
+    /// The route's accounts are passed in `remaining_accounts` and forwarded as-is; the
+    /// adapter receives `amount_in: u64, min_out: u64` (little-endian) as instruction data.
+    /// Reverts unless `user_output_account` grows by at least `min_out`.
+    pub fn claim_rewards_swapped<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClaimRewardsSwapped<'info>>,
+        min_out: u64,
+    ) -> Result<()> {
+        require!(!ctx.accounts.global_state.paused, CustomError::ProgramPaused);
+        let current_time = Clock::get()?.unix_timestamp;
+        require!(
+            cooldown_elapsed(
+                ctx.accounts.user_stake.last_claim_timestamp,
+                ctx.accounts.global_state.claim_cooldown,
+                current_time
+            ),
+            CustomError::ClaimTooSoon
+        );
+        update_emissions(&mut ctx.accounts.global_state, Clock::get()?.unix_timestamp);
+        let acc_reward_per_share = ctx.accounts.global_state.acc_reward_per_share;
+        let rewards = {
+            let user_stake = &mut ctx.accounts.user_stake;
+            settle_rewards(user_stake, acc_reward_per_share)?;
+            user_stake.unclaimed_rewards
+        };
+        require!(rewards > 0, CustomError::NoRewards);
+        // Claim into the user's reward token account first.
+        {
+            let transfer_ctx = ctx.accounts.into_transfer_rewards_to_user_context();
+            token::transfer(transfer_ctx, rewards)?;
+        }
+        {
+            let user_stake = &mut ctx.accounts.user_stake;
+            user_stake.unclaimed_rewards = 0;
+            user_stake.last_claim_timestamp = current_time;
+        }
+        // Swap the claimed rewards along the supplied route.
+        let pre_balance = ctx.accounts.user_output_account.amount;
+        {
+            let mut data = Vec::with_capacity(16);
+            data.extend_from_slice(&rewards.to_le_bytes());
+            data.extend_from_slice(&min_out.to_le_bytes());
+            let accounts = ctx
+                .remaining_accounts
+                .iter()
+                .map(|info| AccountMeta {
+                    pubkey: *info.key,
+                    is_signer: info.is_signer,
+                    is_writable: info.is_writable,
+                })
+                .collect();
+            let swap_ix = Instruction {
+                program_id: ctx.accounts.swap_program.key(),
+                accounts,
+                data,
+            };
+            let mut account_infos = ctx.remaining_accounts.to_vec();
+            account_infos.push(ctx.accounts.swap_program.to_account_info());
+            invoke(&swap_ix, &account_infos)?;
+        }
+        ctx.accounts.user_output_account.reload()?;
+        let received = ctx.accounts.user_output_account.amount.saturating_sub(pre_balance);
+        require!(received >= min_out, CustomError::SlippageExceeded);
+        Ok(())
+    }
+}
+
+//
+// Account Contexts & Helpers
+//
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(init, payer = admin, space = 8 + GlobalState::LEN)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    /// Treasury account for fee redistribution.
+    pub treasury: AccountInfo<'info>,
+    /// Vault holding reward tokens owed to stakers.
+    pub reward_vault: AccountInfo<'info>,
+    /// Vault holding fees prepaid by borrowers.
+    pub fee_vault: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> Initialize<'info> {
+    /// Sets up a fresh global state charging `fee_rate`, with defaults for everything else.
+    pub fn init_state(&mut self, fee_rate: u64) {
+        let state = &mut self.global_state;
+        state.admin = *self.admin.key;
+        state.pauser = *self.admin.key;
+        state.treasurer = *self.admin.key;
+        state.fee_rate = fee_rate;
+        state.total_liquidity = 0;
+        state.total_staked = 0;
+        state.total_reward_weight = 0;
+        state.accumulated_fees = 0;
+        state.version = GLOBAL_STATE_VERSION;
+        state.is_flash_loan_active = false;
+        state.treasury_account = self.treasury.key();
+        state.reward_vault = self.reward_vault.key();
+        state.fee_vault = self.fee_vault.key();
+        state.acc_reward_per_share = 0;
+        state.reputation_per_loan = 1;
+        // Initialize whitelist with an empty vector.
+        state.flash_loan_whitelist = Vec::new();
+        state.whitelist_entry_types = Vec::new();
+        state.whitelist_expiries = Vec::new();
+    }
+}
+
+#[derive(Accounts)]
+pub struct UpdateFeeRate<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePoolFeeRate<'info> {
+    #[account(constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    pub admin: Signer<'info>,
+    #[account(mut, constraint = pool.version == POOL_VERSION @ CustomError::UnsupportedVersion)]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct AddPoolVault<'info> {
+    #[account(constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub pool: Account<'info, Pool>,
+    #[account(
+        constraint = vault.mint == pool.mint @ CustomError::MintMismatch,
+        constraint = vault.key() != pool.pool_account @ CustomError::DuplicateLoanSource
+    )]
+    pub vault: Account<'info, TokenAccount>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + ExtraPoolVault::LEN,
+        seeds = [b"extra_pool_vault", pool.key().as_ref(), vault.key().as_ref()],
+        bump
+    )]
+    pub registration: Account<'info, ExtraPoolVault>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemovePoolVault<'info> {
+    #[account(constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(mut, close = admin)]
+    pub registration: Account<'info, ExtraPoolVault>,
+}
+
+#[derive(Accounts)]
+pub struct CreatePool<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + Pool::LEN,
+        seeds = [b"pool", pool_account.mint.as_ref()],
+        bump
+    )]
+    pub pool: Account<'info, Pool>,
+    /// Token account holding the pool's liquidity.
+    #[account(constraint = is_pool_vault(&global_state, &pool_account.key()) @ CustomError::InvalidPoolVault)]
+    pub pool_account: Account<'info, TokenAccount>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeVaults<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub pool_mint: Account<'info, Mint>,
+    pub fee_mint: Account<'info, Mint>,
+    pub stake_mint: Account<'info, Mint>,
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"pool_vault", global_state.key().as_ref(), pool_mint.key().as_ref()],
+        bump,
+        token::mint = pool_mint,
+        token::authority = vault_authority
+    )]
+    pub pool_vault: Account<'info, TokenAccount>,
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"fee_vault", global_state.key().as_ref(), fee_mint.key().as_ref()],
+        bump,
+        token::mint = fee_mint,
+        token::authority = vault_authority
+    )]
+    pub fee_vault: Account<'info, TokenAccount>,
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"stake_vault", global_state.key().as_ref(), stake_mint.key().as_ref()],
+        bump,
+        token::mint = stake_mint,
+        token::authority = vault_authority
+    )]
+    pub stake_vault: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns the canonical vaults and signs transfers out of them.
+    #[account(seeds = [b"vault_authority", global_state.key().as_ref()], bump)]
+    pub vault_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct CreateReputationIndex<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + ReputationIndex::space(0),
+        seeds = [b"reputation_index"],
+        bump
+    )]
+    pub reputation_index: Account<'info, ReputationIndex>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateLoanHistory<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(init, payer = admin, space = 8 + LoanHistory::LEN, seeds = [b"loan_history"], bump)]
+    pub loan_history: Account<'info, LoanHistory>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateCollateralEscrow<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub collateral_mint: Account<'info, Mint>,
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"collateral_escrow", collateral_mint.key().as_ref()],
+        bump,
+        token::mint = collateral_mint,
+        token::authority = escrow_authority
+    )]
+    pub collateral_escrow: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns every collateral escrow.
+    #[account(seeds = [b"escrow_authority"], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct GetPoolFees<'info> {
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct GetLoanHistory<'info> {
+    #[account(seeds = [b"loan_history"], bump)]
+    pub loan_history: Account<'info, LoanHistory>,
+}
+
+#[derive(Accounts)]
+pub struct GetRepaymentAmount<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(constraint = flash_loan_state.borrower == borrower.key() @ CustomError::Unauthorized)]
+    pub flash_loan_state: Account<'info, FlashLoanState>,
+    /// CHECK: The loan's borrower; only its key is used.
+    pub borrower: AccountInfo<'info>,
+    /// Borrower's stake; applies the fee rebate when provided.
+    #[account(seeds = [b"user_stake", borrower.key.as_ref()], bump)]
+    pub borrower_stake: Option<Account<'info, UserStake>>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteFee<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    /// CHECK: The prospective borrower; only its key is used.
+    pub borrower: AccountInfo<'info>,
+    /// Borrower's stake; applies the fee rebate when provided.
+    #[account(seeds = [b"user_stake", borrower.key.as_ref()], bump)]
+    pub borrower_stake: Option<Account<'info, UserStake>>,
+    /// Pool the loan would be drawn from; quotes at its fee rate when provided.
+    pub pool: Option<Account<'info, Pool>>,
+}
+
+#[derive(Accounts)]
+pub struct GetStakingApr<'info> {
+    pub global_state: Account<'info, GlobalState>,
+}
+
+#[derive(Accounts)]
+pub struct GetHealth<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(constraint = is_pool_vault(&global_state, &pool_account.key()) @ CustomError::InvalidPoolVault)]
+    pub pool_account: Account<'info, TokenAccount>,
+    #[account(constraint = is_stake_vault(&global_state, &stake_vault.key()) @ CustomError::InvalidStakeVault)]
+    pub stake_vault: Account<'info, TokenAccount>,
+    /// The open loan, if any.
+    pub active_loan: Option<Account<'info, FlashLoanState>>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeAdminAction<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub proposer: Signer<'info>,
+    #[account(init, payer = proposer, space = 8 + AdminAction::LEN)]
+    pub admin_action: Account<'info, AdminAction>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ApproveAdminAction<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    pub admin: Signer<'info>,
+    #[account(mut, has_one = global_state)]
+    pub admin_action: Account<'info, AdminAction>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteAdminAction<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut, has_one = global_state)]
+    pub admin_action: Account<'info, AdminAction>,
+    /// The pool an `UpdatePoolFeeRate` action sets the fee rate of; unused by other actions.
+    #[account(mut, constraint = pool.version == POOL_VERSION @ CustomError::UnsupportedVersion)]
+    pub pool: Option<Account<'info, Pool>>,
+}
+
+#[derive(Accounts)]
+pub struct SetPaused<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    pub pauser: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateStakeVault<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    pub admin: Signer<'info>,
+    #[account(mut, constraint = is_stake_vault(&global_state, &stake_vault.key()) @ CustomError::InvalidStakeVault)]
+    pub stake_vault: Account<'info, TokenAccount>,
+    /// CHECK: The authority controlling the stake vault; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), &stake_vault_authority) @ CustomError::Unauthorized)]
+    pub stake_vault_authority: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub new_vault: Account<'info, TokenAccount>,
+    /// The authority controlling the new vault.
+    pub new_vault_authority: Signer<'info>,
+    /// The approved `MigrateStakeVault` action; required once co-signing is set up.
+    #[account(mut, has_one = global_state)]
+    pub admin_action: Option<Account<'info, AdminAction>>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> MigrateStakeVault<'info> {
+    pub fn into_transfer_to_new_vault_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.stake_vault.to_account_info().clone(),
+            to: self.new_vault.to_account_info().clone(),
+            authority: self.stake_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+}
+
+#[derive(Accounts)]
+pub struct WithdrawFees<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    pub treasurer: Signer<'info>,
+    #[account(mut, address = global_state.fee_vault)]
+    pub fee_vault: Account<'info, TokenAccount>,
+    /// CHECK: The authority controlling the fee vault; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), &fee_vault_authority) @ CustomError::Unauthorized)]
+    pub fee_vault_authority: UncheckedAccount<'info>,
+    /// Account receiving the withdrawn fees.
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> WithdrawFees<'info> {
+    pub fn into_transfer_from_fee_vault_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.fee_vault.to_account_info().clone(),
+            to: self.destination.to_account_info().clone(),
+            authority: self.fee_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+}
+
+#[derive(Accounts)]
+pub struct MigrateGlobalState<'info> {
+    /// CHECK: Checked in the handler against the `GlobalState` discriminator and its admin.
+    #[account(mut, owner = crate::ID)]
+    pub global_state: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigratePool<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    /// CHECK: Checked in the handler against the `Pool` discriminator; the seeds bind it to the mint.
+    #[account(mut, owner = crate::ID, seeds = [b"pool", mint.key().as_ref()], bump)]
+    pub pool: UncheckedAccount<'info>,
+    pub mint: Account<'info, Mint>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateUserStake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+    /// CHECK: Checked in the handler against the `UserStake` discriminator; the seeds bind it to the user.
+    #[account(mut, owner = crate::ID, seeds = [b"user_stake", user.key.as_ref()], bump)]
+    pub user_stake: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositLiquidity<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub provider: Signer<'info>,
+    #[account(
+        init_if_needed,
+        payer = provider,
+        space = 8 + ProviderPosition::LEN,
+        seeds = [b"provider_position", global_state.key().as_ref(), provider.key.as_ref()],
+        bump
+    )]
+    pub provider_position: Account<'info, ProviderPosition>,
+    #[account(mut)]
+    pub provider_token_account: Account<'info, TokenAccount>,
+    #[account(mut, constraint = is_pool_vault(&global_state, &pool_account.key()) @ CustomError::InvalidPoolVault)]
+    pub pool_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> DepositLiquidity<'info> {
+    pub fn into_transfer_to_pool_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.provider_token_account.to_account_info().clone(),
+            to: self.pool_account.to_account_info().clone(),
+            authority: self.provider.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct CompoundLpFees<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    pub provider: Signer<'info>,
+    #[account(mut, seeds = [b"provider_position", global_state.key().as_ref(), provider.key.as_ref()], bump)]
+    pub provider_position: Account<'info, ProviderPosition>,
+    #[account(mut, address = global_state.fee_vault)]
+    pub fee_vault: Account<'info, TokenAccount>,
+    /// CHECK: The authority controlling the fee vault; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), &fee_vault_authority) @ CustomError::Unauthorized)]
+    pub fee_vault_authority: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        constraint = is_pool_vault(&global_state, &pool_account.key()) @ CustomError::InvalidPoolVault,
+        constraint = pool_account.mint == fee_vault.mint @ CustomError::MintMismatch
+    )]
+    pub pool_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> CompoundLpFees<'info> {
+    pub fn into_transfer_fees_to_pool_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.fee_vault.to_account_info().clone(),
+            to: self.pool_account.to_account_info().clone(),
+            authority: self.fee_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+}
+
+#[derive(Accounts)]
+pub struct SeedLiquidity<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    pub admin: Signer<'info>,
+    #[account(mut)]
+    pub admin_token_account: Account<'info, TokenAccount>,
+    #[account(mut, constraint = is_pool_vault(&global_state, &pool_account.key()) @ CustomError::InvalidPoolVault)]
+    pub pool_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> SeedLiquidity<'info> {
+    pub fn into_transfer_to_pool_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.admin_token_account.to_account_info().clone(),
+            to: self.pool_account.to_account_info().clone(),
+            authority: self.admin.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct ReconcileLiquidity<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    pub admin: Signer<'info>,
+    #[account(constraint = is_pool_vault(&global_state, &pool_account.key()) @ CustomError::InvalidPoolVault)]
+    pub pool_account: Account<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawProtocolLiquidity<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    pub admin: Signer<'info>,
+    #[account(mut, constraint = is_pool_vault(&global_state, &pool_account.key()) @ CustomError::InvalidPoolVault)]
+    pub pool_account: Account<'info, TokenAccount>,
+    /// CHECK: The authority controlling the pool account; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), &pool_authority) @ CustomError::Unauthorized)]
+    pub pool_authority: UncheckedAccount<'info>,
+    /// Account receiving the withdrawn liquidity.
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    /// The approved `WithdrawProtocolLiquidity` action; required once co-signing is set up.
+    #[account(mut, has_one = global_state)]
+    pub admin_action: Option<Account<'info, AdminAction>>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> WithdrawProtocolLiquidity<'info> {
+    pub fn into_transfer_from_pool_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.pool_account.to_account_info().clone(),
+            to: self.destination.to_account_info().clone(),
+            authority: self.pool_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+}
+
+#[derive(Accounts)]
+pub struct FinalizeWindDown<'info> {
+    #[account(
+        mut,
+        close = admin,
+        constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion
+    )]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(mut, constraint = is_pool_vault(&global_state, &pool_account.key()) @ CustomError::InvalidPoolVault)]
+    pub pool_account: Account<'info, TokenAccount>,
+    /// CHECK: The authority controlling the pool account; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), &pool_authority) @ CustomError::Unauthorized)]
+    pub pool_authority: UncheckedAccount<'info>,
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    /// The authority (PDA) controlling the reward vault.
+    pub reward_vault_authority: Signer<'info>,
+    /// Account receiving what is left in the pool.
+    #[account(mut)]
+    pub destination: Account<'info, TokenAccount>,
+    /// Account receiving what is left in the reward vault.
+    #[account(mut)]
+    pub reward_destination: Account<'info, TokenAccount>,
+    /// The approved `FinalizeWindDown` action; required once co-signing is set up.
+    #[account(mut, has_one = global_state)]
+    pub admin_action: Option<Account<'info, AdminAction>>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> FinalizeWindDown<'info> {
+    pub fn into_transfer_from_pool_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.pool_account.to_account_info().clone(),
+            to: self.destination.to_account_info().clone(),
+            authority: self.pool_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+
+    pub fn into_transfer_from_reward_vault_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.reward_vault.to_account_info().clone(),
+            to: self.reward_destination.to_account_info().clone(),
+            authority: self.reward_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct WithdrawLiquidity<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut, constraint = is_pool_vault(&global_state, &pool_account.key()) @ CustomError::InvalidPoolVault)]
+    pub pool_account: Account<'info, TokenAccount>,
+    /// CHECK: Owner of the position being withdrawn from; bound to it by seeds.
+    /// Receives the position's rent if it is closed as dust.
+    #[account(mut)]
+    pub provider: AccountInfo<'info>,
+    /// The provider, or an operator with a withdrawal allowance from the provider.
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"provider_position", global_state.key().as_ref(), provider.key.as_ref()],
+        bump,
+        constraint = provider_position.owner == provider.key() @ CustomError::Unauthorized
+    )]
+    pub provider_position: Account<'info, ProviderPosition>,
+    /// Required when `authority` is an operator rather than the provider.
+    #[account(mut, seeds = [b"allowance", provider.key.as_ref(), authority.key.as_ref()], bump)]
+    pub allowance: Option<Account<'info, WithdrawalAllowance>>,
+    #[account(mut)]
+    pub provider_token_account: Account<'info, TokenAccount>,
+    /// Optional third-party account to receive the withdrawal instead of the provider.
+    #[account(mut)]
+    pub destination: Option<Account<'info, TokenAccount>>,
+    /// CHECK: The authority controlling the pool account; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), &pool_authority) @ CustomError::Unauthorized)]
+    pub pool_authority: UncheckedAccount<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> WithdrawLiquidity<'info> {
+    pub fn into_transfer_from_pool_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let to = match &self.destination {
+            Some(destination) => destination.to_account_info(),
+            None => self.provider_token_account.to_account_info(),
+        };
+        let cpi_accounts = Transfer {
+            from: self.pool_account.to_account_info().clone(),
+            to,
+            authority: self.pool_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(operator: Pubkey)]
+pub struct ApproveWithdrawal<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        seeds = [b"provider_position", global_state.key().as_ref(), owner.key.as_ref()],
+        bump,
+        constraint = provider_position.owner == owner.key() @ CustomError::Unauthorized
+    )]
+    pub provider_position: Account<'info, ProviderPosition>,
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8 + WithdrawalAllowance::LEN,
+        seeds = [b"allowance", owner.key.as_ref(), operator.as_ref()],
+        bump
+    )]
+    pub allowance: Account<'info, WithdrawalAllowance>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Stake<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserStake::LEN,
+        seeds = [b"user_stake", user.key.as_ref()],
+        bump
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    #[account(mut, constraint = is_stake_vault(&global_state, &stake_vault.key()) @ CustomError::InvalidStakeVault)]
+    pub stake_vault: Account<'info, TokenAccount>,
+    /// The authority (often a PDA) that controls the stake vault.
+    pub stake_vault_authority: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+impl<'info> Stake<'info> {
+    pub fn into_transfer_to_stake_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.user_token_account.to_account_info().clone(),
+            to: self.stake_vault.to_account_info().clone(),
+            authority: self.user.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct Unstake<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user.key.as_ref()],
+        bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ CustomError::UnsupportedVersion
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut, constraint = is_stake_vault(&global_state, &stake_vault.key()) @ CustomError::InvalidStakeVault)]
+    pub stake_vault: Account<'info, TokenAccount>,
+    /// CHECK: The authority controlling the stake vault; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), &stake_vault_authority) @ CustomError::Unauthorized)]
+    pub stake_vault_authority: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    /// Receives the early-unstake penalty; required when one is charged.
+    #[account(mut, constraint = treasury_token_account.owner == global_state.treasury_account @ CustomError::InvalidTreasury)]
+    pub treasury_token_account: Option<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> Unstake<'info> {
+    pub fn into_transfer_from_stake_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.stake_vault.to_account_info().clone(),
+            to: self.user_token_account.to_account_info().clone(),
+            authority: self.stake_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+
+    pub fn into_transfer_penalty_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.stake_vault.to_account_info().clone(),
+            to: self.treasury_token_account.as_ref().unwrap().to_account_info().clone(),
+            authority: self.stake_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+
+    /// Returns `amount` of the user's stake, principal first, and settles their rewards.
+    /// An early unstake sends its penalty to the treasury and the rest to the user.
+    pub fn withdraw_stake(&mut self, amount: u64) -> Result<()> {
+        require!(!self.global_state.paused, CustomError::ProgramPaused);
+        // Ensure the user has enough staked tokens.
+        let (principal, compounded) = {
+            let user_stake = &self.user_stake;
+            require!(staked_balance(user_stake) >= amount, CustomError::InsufficientStake);
+            let principal = amount.min(user_stake.amount);
+            (principal, amount - principal)
+        };
+        let penalty = {
+            let state = &self.global_state;
+            if is_early_unstake(state, self.user_stake.first_stake_timestamp, Clock::get()?.unix_timestamp) {
+                unstake_penalty(state, amount)
+            } else {
+                0
+            }
+        };
+        if penalty > 0 {
+            require!(self.treasury_token_account.is_some(), CustomError::InvalidTreasury);
+        }
+        // Transfer tokens from the stake vault back to the user, less any penalty.
+        {
+            let global_state_key = self.global_state.key();
+            let bump = [self.global_state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+            if amount > penalty {
+                let transfer_ctx = self.into_transfer_from_stake_context(signer_seeds);
+                token::transfer(transfer_ctx, amount - penalty)?;
+            }
+            if penalty > 0 {
+                let penalty_ctx = self.into_transfer_penalty_context(signer_seeds);
+                token::transfer(penalty_ctx, penalty)?;
+            }
+        }
+        // Settle accrued rewards and update the user's stake.
+        update_emissions(&mut self.global_state, Clock::get()?.unix_timestamp);
+        let acc_reward_per_share = self.global_state.acc_reward_per_share;
+        {
+            let user_stake = &mut self.user_stake;
+            let current_time = Clock::get()?.unix_timestamp;
+            settle_rewards(user_stake, acc_reward_per_share)?;
+            user_stake.amount = user_stake.amount.checked_sub(principal).unwrap();
+            user_stake.compounded_amount = user_stake.compounded_amount.checked_sub(compounded).unwrap();
+            user_stake.reward_debt = accrued_rewards(staked_balance(user_stake), acc_reward_per_share);
+            // A fully exited position starts over on its next stake.
+            if staked_balance(user_stake) == 0 {
+                user_stake.first_stake_timestamp = 0;
+            }
+            user_stake.last_modified_timestamp = current_time;
+        }
+        // Update the global staked total.
+        {
+            let state = &mut self.global_state;
+            state.total_staked = state.total_staked.checked_sub(amount).unwrap();
+            state.total_reward_weight = state.total_reward_weight.checked_sub(amount).unwrap();
+        }
+        // Sweep a balance left below the dust threshold and close the position, unless it
+        // still holds unclaimed rewards.
+        let dust = {
+            let user_stake = &self.user_stake;
+            let remaining = staked_balance(user_stake);
+            if is_dust(&self.global_state, remaining) && user_stake.unclaimed_rewards == 0 {
+                Some(remaining)
+            } else {
+                None
+            }
+        };
+        if let Some(dust) = dust {
+            if dust > 0 {
+                let global_state_key = self.global_state.key();
+                let bump = [self.global_state.vault_authority_bump];
+                let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+                let transfer_ctx = self.into_transfer_from_stake_context(signer_seeds);
+                token::transfer(transfer_ctx, dust)?;
+            }
+            {
+                let state = &mut self.global_state;
+                state.total_staked = state.total_staked.checked_sub(dust).unwrap();
+                state.total_reward_weight = state.total_reward_weight.checked_sub(dust).unwrap();
+            }
+            self.user_stake.close(self.user.to_account_info())?;
+            if events_enabled(&self.global_state) {
+                emit!(PositionDusted {
+                    owner: *self.user.key,
+                    position: self.user_stake.key(),
+                    amount: dust,
+                });
+            }
+        }
+        self.stake_vault.reload()?;
+        assert_stake_solvency(&self.stake_vault, &self.global_state)?;
+        if events_enabled(&self.global_state) {
+            emit!(Unstaked {
+                user: *self.user.key,
+                principal,
+                compounded,
+                penalty,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct SimulateFlashLoan<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        seeds = [b"pool", pool_account.mint.as_ref()],
+        bump,
+        constraint = pool.pool_account == pool_account.key() @ CustomError::InvalidPool
+    )]
+    pub pool: Account<'info, Pool>,
+    #[account(constraint = is_pool_vault(&global_state, &pool_account.key()) @ CustomError::InvalidPoolVault)]
+    pub pool_account: Account<'info, TokenAccount>,
+    /// CHECK: The authority that would sign the real loan; only compared against the pool.
+    pub pool_authority: AccountInfo<'info>,
+    pub borrower_token_account: Account<'info, TokenAccount>,
+    /// CHECK: Borrower account; only its key is used.
+    pub borrower: AccountInfo<'info>,
+    /// Account collateral would be transferred from.
+    pub borrower_collateral_account: Account<'info, TokenAccount>,
+    /// Borrower's reputation, used for the whitelist bypass when provided.
+    #[account(seeds = [b"reputation", borrower.key.as_ref()], bump)]
+    pub borrower_reputation: Option<Account<'info, BorrowerReputation>>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u8)]
+pub struct OpenPosition<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        init,
+        payer = user,
+        space = 8 + StakePosition::LEN,
+        seeds = [b"stake", user.key.as_ref(), &[index]],
+        bump
+    )]
+    pub stake_position: Account<'info, StakePosition>,
+    #[account(mut)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    #[account(mut, constraint = is_stake_vault(&global_state, &stake_vault.key()) @ CustomError::InvalidStakeVault)]
+    pub stake_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> OpenPosition<'info> {
+    pub fn into_transfer_to_stake_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.user_token_account.to_account_info().clone(),
+            to: self.stake_vault.to_account_info().clone(),
+            authority: self.user.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(new_owner: Pubkey)]
+pub struct TransferStake<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(
+        mut,
+        close = owner,
+        seeds = [b"stake", owner.key.as_ref(), &[stake_position.index]],
+        bump
+    )]
+    pub stake_position: Account<'info, StakePosition>,
+    /// The new owner's position at the same index; created if it doesn't exist yet.
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8 + StakePosition::LEN,
+        seeds = [b"stake", new_owner.as_ref(), &[stake_position.index]],
+        bump
+    )]
+    pub new_position: Account<'info, StakePosition>,
+    /// The new owner; must sign to merge into a position they already hold.
+    #[account(address = new_owner)]
+    pub new_owner_signer: Option<Signer<'info>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(new_owner: Pubkey)]
+pub struct TransferUserStake<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(mut, close = owner, seeds = [b"user_stake", owner.key.as_ref()], bump)]
+    pub user_stake: Account<'info, UserStake>,
+    /// The new owner's stake account; created if it doesn't exist yet.
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = 8 + UserStake::LEN,
+        seeds = [b"user_stake", new_owner.as_ref()],
+        bump
+    )]
+    pub new_user_stake: Account<'info, UserStake>,
+    /// The new owner; must sign to merge into a balance they already hold.
+    #[account(address = new_owner)]
+    pub new_owner_signer: Option<Signer<'info>>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        close = user,
+        seeds = [b"stake", user.key.as_ref(), &[stake_position.index]],
+        bump
+    )]
+    pub stake_position: Account<'info, StakePosition>,
+    /// The caller's stake record, which holds the claim cooldown; created if needed.
+    #[account(init_if_needed, payer = user, space = 8 + UserStake::LEN, seeds = [b"user_stake", user.key.as_ref()], bump)]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut, constraint = is_stake_vault(&global_state, &stake_vault.key()) @ CustomError::InvalidStakeVault)]
+    pub stake_vault: Account<'info, TokenAccount>,
+    /// CHECK: The authority controlling the stake vault; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), &stake_vault_authority) @ CustomError::Unauthorized)]
+    pub stake_vault_authority: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub user_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    /// The authority (PDA) controlling the reward vault.
+    pub reward_vault_authority: Signer<'info>,
+    /// Account the position's rewards are paid into.
+    #[account(mut)]
+    pub user_reward_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> ClosePosition<'info> {
+    pub fn into_transfer_from_stake_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.stake_vault.to_account_info().clone(),
+            to: self.user_token_account.to_account_info().clone(),
+            authority: self.stake_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+
+    pub fn into_transfer_rewards_to_user_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.reward_vault.to_account_info().clone(),
+            to: self.user_reward_account.to_account_info().clone(),
+            authority: self.reward_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct FlashLoan<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(
+        seeds = [b"pool", pool_account.mint.as_ref()],
+        bump,
+        constraint = pool.pool_account == pool_account.key() @ CustomError::InvalidPool
+    )]
+    pub pool: Account<'info, Pool>,
+    #[account(mut, constraint = is_pool_vault(&global_state, &pool_account.key()) @ CustomError::InvalidPoolVault)]
+    pub pool_account: Account<'info, TokenAccount>,
+    /// CHECK: The authority controlling the pool account; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), &pool_authority) @ CustomError::Unauthorized)]
+    pub pool_authority: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub borrower_token_account: Account<'info, TokenAccount>,
+    /// CHECK: Borrower account (used only for receiving tokens). Marked mutable as it also pays for the new account.
+    #[account(mut)]
+    pub borrower: AccountInfo<'info>,
+    #[account(init, payer = borrower, space = 8 + FlashLoanState::LEN)]
+    pub flash_loan_state: Account<'info, FlashLoanState>,
+    /// Account from which collateral will be transferred.
+    #[account(mut)]
+    pub borrower_collateral_account: Account<'info, TokenAccount>,
+    /// Program-owned collateral escrow for the collateral mint.
+    #[account(mut, seeds = [b"collateral_escrow", collateral_escrow.mint.as_ref()], bump)]
+    pub collateral_escrow: Account<'info, TokenAccount>,
+    /// Borrower's reputation, used for the whitelist bypass when provided.
+    #[account(seeds = [b"reputation", borrower.key.as_ref()], bump)]
+    pub borrower_reputation: Option<Account<'info, BorrowerReputation>>,
+    /// A prior reservation locking in the fee rate; consumed by this loan.
+    #[account(mut, close = borrower, seeds = [b"reservation", borrower.key.as_ref()], bump)]
+    pub reservation: Option<Account<'info, LoanReservation>>,
+    /// CHECK: Compliance hook program; must match `global_state.compliance_hook` when set.
+    pub compliance_program: Option<UncheckedAccount<'info>>,
+    /// A redeemed fee-waiver voucher; marked consumed by this loan.
+    #[account(mut, constraint = voucher.borrower == borrower.key() @ CustomError::VoucherMismatch)]
+    pub voucher: Option<Account<'info, Voucher>>,
+    /// Vault funding fee subsidies; the loan is subsidized when it and its authority are given.
+    #[account(mut, address = global_state.subsidy_vault)]
+    pub subsidy_vault: Option<Account<'info, TokenAccount>>,
+    /// CHECK: The authority controlling the subsidy vault; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), subsidy_vault_authority) @ CustomError::Unauthorized)]
+    pub subsidy_vault_authority: Option<UncheckedAccount<'info>>,
+    /// Receives the loan proceeds instead of `borrower_token_account` when given, e.g. a
+    /// strategy vault. The borrower still owes the repayment and earns the reputation.
+    #[account(mut)]
+    pub recipient: Option<Account<'info, TokenAccount>>,
+    /// Loan history; the loan is recorded in it when provided.
+    #[account(mut, seeds = [b"loan_history"], bump)]
+    pub loan_history: Option<Account<'info, LoanHistory>>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+impl<'info> FlashLoan<'info> {
+    /// Account the loan proceeds are paid into: the recipient if one is given, otherwise
+    /// the borrower's own token account.
+    pub fn proceeds_account(&self) -> AccountInfo<'info> {
+        match &self.recipient {
+            Some(recipient) => recipient.to_account_info(),
+            None => self.borrower_token_account.to_account_info(),
+        }
+    }
+
+    pub fn into_transfer_to_borrower_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.pool_account.to_account_info().clone(),
+            to: self.proceeds_account(),
+            authority: self.pool_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+    pub fn into_transfer_collateral_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.borrower_collateral_account.to_account_info().clone(),
+            to: self.collateral_escrow.to_account_info().clone(),
+            authority: self.borrower.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+    pub fn into_transfer_subsidy_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.subsidy_vault.as_ref().unwrap().to_account_info().clone(),
+            to: self.pool_account.to_account_info().clone(),
+            authority: self.subsidy_vault_authority.as_ref().unwrap().to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+
+    pub fn into_transfer_from_vault_context<'a>(&self, vault: AccountInfo<'info>, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: vault,
+            to: self.proceeds_account(),
+            authority: self.pool_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(nonce: u64)]
+pub struct RedeemVoucher<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub borrower: Signer<'info>,
+    /// Created once per nonce, so a voucher can't be redeemed twice.
+    #[account(
+        init,
+        payer = borrower,
+        space = 8 + Voucher::LEN,
+        seeds = [b"voucher", nonce.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub voucher: Account<'info, Voucher>,
+    /// CHECK: The instructions sysvar, used to introspect the Ed25519 verification.
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ReserveLoan<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub borrower: Signer<'info>,
+    #[account(
+        init,
+        payer = borrower,
+        space = 8 + LoanReservation::LEN,
+        seeds = [b"reservation", borrower.key.as_ref()],
+        bump
+    )]
+    pub reservation: Account<'info, LoanReservation>,
+    /// The pool the loan will be drawn from, whose fee rate is locked.
+    #[account(constraint = pool.version == POOL_VERSION @ CustomError::UnsupportedVersion)]
+    pub pool: Account<'info, Pool>,
+    #[account(mut)]
+    pub borrower_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = global_state.fee_vault)]
+    pub fee_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> ReserveLoan<'info> {
+    pub fn into_transfer_to_fee_vault_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.borrower_token_account.to_account_info().clone(),
+            to: self.fee_vault.to_account_info().clone(),
+            authority: self.borrower.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct CancelReservation<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub borrower: Signer<'info>,
+    #[account(mut, close = borrower, seeds = [b"reservation", borrower.key.as_ref()], bump)]
+    pub reservation: Account<'info, LoanReservation>,
+    #[account(mut)]
+    pub borrower_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = global_state.fee_vault)]
+    pub fee_vault: Account<'info, TokenAccount>,
+    /// CHECK: The authority controlling the fee vault; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), &fee_vault_authority) @ CustomError::Unauthorized)]
+    pub fee_vault_authority: UncheckedAccount<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> CancelReservation<'info> {
+    pub fn into_refund_from_fee_vault_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.fee_vault.to_account_info().clone(),
+            to: self.borrower_token_account.to_account_info().clone(),
+            authority: self.fee_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+}
+
+#[derive(Accounts)]
+pub struct SetMinRepaySurplus<'info> {
+    pub borrower: Signer<'info>,
+    #[account(
+        mut,
+        constraint = flash_loan_state.borrower == borrower.key() @ CustomError::Unauthorized
+    )]
+    pub flash_loan_state: Account<'info, FlashLoanState>,
+}
+
+#[derive(Accounts)]
+pub struct RepayFlashLoan<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    /// The pool the loan was drawn from.
+    #[account(
+        mut,
+        address = flash_loan_state.pool @ CustomError::InvalidPool,
+        constraint = pool.pool_account == pool_account.key() @ CustomError::InvalidPool,
+        constraint = pool.version == POOL_VERSION @ CustomError::UnsupportedVersion
+    )]
+    pub pool: Account<'info, Pool>,
+    #[account(mut, constraint = is_pool_vault(&global_state, &pool_account.key()) @ CustomError::InvalidPoolVault)]
+    pub pool_account: Account<'info, TokenAccount>,
+    /// CHECK: The authority controlling the pool account; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), &pool_authority) @ CustomError::Unauthorized)]
+    pub pool_authority: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        close = borrower,
+        constraint = flash_loan_state.borrower == borrower.key() @ CustomError::Unauthorized
+    )]
+    pub flash_loan_state: Account<'info, FlashLoanState>,
+    /// CHECK: The loan's borrower; receives the lamports from closing the flash loan state
+    /// and is credited with the reputation, whoever repays.
+    #[account(mut)]
+    pub borrower: AccountInfo<'info>,
+    /// Funds the repayment and pays any rent; may be the borrower or a third party such as a relayer.
+    #[account(mut)]
+    pub repayer: Signer<'info>,
+    /// Account the repayment is drawn from; the repayer must be its owner or delegate.
+    #[account(mut)]
+    pub borrower_token_account: Account<'info, TokenAccount>,
+    /// Borrower's reputation account.
+    #[account(init_if_needed, payer = repayer, space = 8 + BorrowerReputation::LEN, seeds = [b"reputation", borrower.key.as_ref()], bump)]
+    pub borrower_reputation: Account<'info, BorrowerReputation>,
+    /// Index of borrowers; first-time borrowers are appended when provided.
+    #[account(mut, seeds = [b"reputation_index"], bump)]
+    pub reputation_index: Option<Account<'info, ReputationIndex>>,
+    /// Loan history; the loan's entry is marked repaid when provided.
+    #[account(mut, seeds = [b"loan_history"], bump)]
+    pub loan_history: Option<Account<'info, LoanHistory>>,
+    /// Borrower's stake; earns a fee rebate when provided.
+    #[account(seeds = [b"user_stake", borrower.key.as_ref()], bump)]
+    pub borrower_stake: Option<Account<'info, UserStake>>,
+    /// Receives the fee when fees are collected in a separate fee mint, and refunds the unused
+    /// part of a prepaid time-priced fee.
+    #[account(mut, address = global_state.fee_vault)]
+    pub fee_vault: Option<Account<'info, TokenAccount>>,
+    /// Account the fee is drawn from when fees are collected in a separate fee mint;
+    /// the repayer must be its owner or delegate.
+    #[account(mut)]
+    pub borrower_fee_account: Option<Account<'info, TokenAccount>>,
+    /// The fee mint; required to burn `burn_share_bps` of a fee collected in it.
+    #[account(mut)]
+    pub fee_mint_account: Option<Account<'info, Mint>>,
+    /// Program-owned collateral escrow; required when collateral is still held.
+    #[account(
+        mut,
+        seeds = [b"collateral_escrow", collateral_escrow.mint.as_ref()],
+        bump,
+        constraint = collateral_escrow.mint == flash_loan_state.collateral_mint @ CustomError::CollateralMintMismatch
+    )]
+    pub collateral_escrow: Option<Account<'info, TokenAccount>>,
+    /// CHECK: PDA that owns every collateral escrow; signs releases.
+    #[account(seeds = [b"escrow_authority"], bump)]
+    pub escrow_authority: Option<UncheckedAccount<'info>>,
+    /// Account receiving the returned collateral.
+    #[account(mut, constraint = borrower_collateral_account.mint == flash_loan_state.collateral_mint @ CustomError::CollateralMintMismatch)]
+    pub borrower_collateral_account: Option<Account<'info, TokenAccount>>,
+    /// Treasury token account receiving the POL share of the fee, when routed.
+    #[account(mut, constraint = treasury_token_account.owner == global_state.treasury_account @ CustomError::InvalidTreasury)]
+    pub treasury_token_account: Option<Account<'info, TokenAccount>>,
+    /// Receives the stakers' share of the fee when `staker_fee_bps` is set.
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Option<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+impl<'info> RepayFlashLoan<'info> {
+    /// Accounts the fee is settled through; the repayer funds any fee-mint payment.
+    pub fn settlement_accounts(&self) -> SettlementAccounts<'_, 'info> {
+        SettlementAccounts {
+            token_program: self.token_program.to_account_info(),
+            pool_account: self.pool_account.to_account_info(),
+            pool_authority: self.pool_authority.to_account_info(),
+            payer: self.repayer.to_account_info(),
+            borrower_token_account: self.borrower_token_account.to_account_info(),
+            borrower_fee_account: self.borrower_fee_account.as_ref(),
+            fee_vault: self.fee_vault.as_ref(),
+            fee_mint_account: self.fee_mint_account.as_ref(),
+            treasury_token_account: self.treasury_token_account.as_ref(),
+            reward_vault: self.reward_vault.as_ref(),
+        }
+    }
+    pub fn into_release_collateral_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.collateral_escrow.as_ref().unwrap().to_account_info().clone(),
+            to: self.borrower_collateral_account.as_ref().unwrap().to_account_info().clone(),
+            authority: self.escrow_authority.as_ref().unwrap().to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+    /// Grows the reputation index by one entry, topping up its rent from the repayer.
+    pub fn grow_reputation_index(&self) -> Result<()> {
+        let index = self.reputation_index.as_ref().unwrap();
+        let new_len = 8 + ReputationIndex::space(index.borrowers.len() + 1);
+        let index_info = index.to_account_info();
+        let rent_due = Rent::get()?.minimum_balance(new_len).saturating_sub(index_info.lamports());
+        if rent_due > 0 {
+            let cpi_accounts = system_program::Transfer {
+                from: self.repayer.to_account_info().clone(),
+                to: index_info.clone(),
+            };
+            let cpi_ctx = CpiContext::new(self.system_program.to_account_info().clone(), cpi_accounts);
+            system_program::transfer(cpi_ctx, rent_due)?;
+        }
+        index_info.realloc(new_len, false)?;
+        Ok(())
+    }
+
+    pub fn into_transfer_repayment_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.borrower_token_account.to_account_info().clone(),
+            to: self.pool_account.to_account_info().clone(),
+            authority: self.repayer.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+    pub fn into_transfer_repayment_to_vault_context(&self, vault: AccountInfo<'info>) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.borrower_token_account.to_account_info().clone(),
+            to: vault,
+            authority: self.repayer.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct AddCollateral<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    /// The pool the loan was drawn from.
+    #[account(
+        address = flash_loan_state.pool @ CustomError::InvalidPool,
+        constraint = pool.pool_account == pool_account.key() @ CustomError::InvalidPool
+    )]
+    pub pool: Account<'info, Pool>,
+    pub pool_account: Account<'info, TokenAccount>,
+    pub borrower: Signer<'info>,
+    #[account(mut, constraint = flash_loan_state.borrower == borrower.key() @ CustomError::Unauthorized)]
+    pub flash_loan_state: Account<'info, FlashLoanState>,
+    /// Account the extra collateral is drawn from.
+    #[account(mut)]
+    pub borrower_collateral_account: Account<'info, TokenAccount>,
+    /// Program-owned collateral escrow for the collateral mint.
+    #[account(
+        mut,
+        seeds = [b"collateral_escrow", collateral_escrow.mint.as_ref()],
+        bump,
+        constraint = collateral_escrow.mint == flash_loan_state.collateral_mint @ CustomError::CollateralMintMismatch
+    )]
+    pub collateral_escrow: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> AddCollateral<'info> {
+    pub fn into_transfer_collateral_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.borrower_collateral_account.to_account_info().clone(),
+            to: self.collateral_escrow.to_account_info().clone(),
+            authority: self.borrower.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct LiquidateCollateral<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    /// The pool the loan was drawn from.
+    #[account(
+        address = flash_loan_state.pool @ CustomError::InvalidPool,
+        constraint = pool.pool_account == pool_account.key() @ CustomError::InvalidPool
+    )]
+    pub pool: Account<'info, Pool>,
+    /// Receives the remaining collateral when it is in the pool's mint.
+    #[account(mut)]
+    pub pool_account: Account<'info, TokenAccount>,
+    #[account(mut, close = borrower)]
+    pub flash_loan_state: Account<'info, FlashLoanState>,
+    /// CHECK: The defaulted loan's borrower; receives the lamports from closing the flash loan state.
+    #[account(mut, address = flash_loan_state.borrower @ CustomError::Unauthorized)]
+    pub borrower: AccountInfo<'info>,
+    /// Anyone may liquidate an expired loan.
+    pub liquidator: Signer<'info>,
+    /// Receives the liquidation incentive.
+    #[account(mut, constraint = liquidator_token_account.mint == collateral_escrow.mint @ CustomError::MintMismatch)]
+    pub liquidator_token_account: Account<'info, TokenAccount>,
+    /// Program-owned collateral escrow for the collateral mint.
+    #[account(
+        mut,
+        seeds = [b"collateral_escrow", collateral_escrow.mint.as_ref()],
+        bump,
+        constraint = collateral_escrow.mint == flash_loan_state.collateral_mint @ CustomError::CollateralMintMismatch
+    )]
+    pub collateral_escrow: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns every collateral escrow; signs the seizure.
+    #[account(seeds = [b"escrow_authority"], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    /// Receives the remaining collateral when it is not in the pool's mint.
+    #[account(
+        mut,
+        constraint = treasury_token_account.owner == global_state.treasury_account @ CustomError::InvalidTreasury,
+        constraint = treasury_token_account.mint == flash_loan_state.collateral_mint @ CustomError::CollateralMintMismatch
+    )]
+    pub treasury_token_account: Option<Account<'info, TokenAccount>>,
+    /// Loan history; the loan's entry is marked defaulted when provided.
+    #[account(mut, seeds = [b"loan_history"], bump)]
+    pub loan_history: Option<Account<'info, LoanHistory>>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> LiquidateCollateral<'info> {
+    pub fn into_seize_collateral_context<'a>(&self, destination: AccountInfo<'info>, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.collateral_escrow.to_account_info().clone(),
+            to: destination,
+            authority: self.escrow_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+}
+
+#[derive(Accounts)]
+pub struct RepayPartial<'info> {
+    /// The pool the loan was drawn from.
+    #[account(
+        address = flash_loan_state.pool @ CustomError::InvalidPool,
+        constraint = pool.pool_account == pool_account.key() @ CustomError::InvalidPool
+    )]
+    pub pool: Account<'info, Pool>,
+    #[account(mut)]
+    pub pool_account: Account<'info, TokenAccount>,
+    pub borrower: Signer<'info>,
+    #[account(mut)]
+    pub borrower_token_account: Account<'info, TokenAccount>,
+    #[account(mut, constraint = flash_loan_state.borrower == borrower.key() @ CustomError::Unauthorized)]
+    pub flash_loan_state: Account<'info, FlashLoanState>,
+    /// Program-owned collateral escrow for the collateral mint.
+    #[account(
+        mut,
+        seeds = [b"collateral_escrow", collateral_escrow.mint.as_ref()],
+        bump,
+        constraint = collateral_escrow.mint == flash_loan_state.collateral_mint @ CustomError::CollateralMintMismatch
+    )]
+    pub collateral_escrow: Account<'info, TokenAccount>,
+    /// CHECK: PDA that owns every collateral escrow; signs releases.
+    #[account(seeds = [b"escrow_authority"], bump)]
+    pub escrow_authority: AccountInfo<'info>,
+    /// Account receiving released collateral.
+    #[account(mut, constraint = borrower_collateral_account.mint == flash_loan_state.collateral_mint @ CustomError::CollateralMintMismatch)]
+    pub borrower_collateral_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> RepayPartial<'info> {
+    pub fn into_transfer_repayment_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.borrower_token_account.to_account_info().clone(),
+            to: self.pool_account.to_account_info().clone(),
+            authority: self.borrower.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+    pub fn into_release_collateral_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.collateral_escrow.to_account_info().clone(),
+            to: self.borrower_collateral_account.to_account_info().clone(),
+            authority: self.escrow_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+}
+
+#[derive(Accounts)]
+pub struct RolloverFlashLoan<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    /// The pool the loan was drawn from; the new loan is drawn from the same pool.
+    #[account(
+        mut,
+        address = flash_loan_state.pool @ CustomError::InvalidPool,
+        constraint = pool.pool_account == pool_account.key() @ CustomError::InvalidPool,
+        constraint = pool.version == POOL_VERSION @ CustomError::UnsupportedVersion
+    )]
+    pub pool: Account<'info, Pool>,
+    #[account(mut, constraint = is_pool_vault(&global_state, &pool_account.key()) @ CustomError::InvalidPoolVault)]
+    pub pool_account: Account<'info, TokenAccount>,
+    /// CHECK: The authority controlling the pool account; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), &pool_authority) @ CustomError::Unauthorized)]
+    pub pool_authority: UncheckedAccount<'info>,
+    pub borrower: Signer<'info>,
+    #[account(mut)]
+    pub borrower_token_account: Account<'info, TokenAccount>,
+    #[account(mut, constraint = flash_loan_state.borrower == borrower.key() @ CustomError::Unauthorized)]
+    pub flash_loan_state: Account<'info, FlashLoanState>,
+    /// Account from which additional collateral will be transferred.
+    #[account(mut)]
+    pub borrower_collateral_account: Account<'info, TokenAccount>,
+    /// Program-owned collateral escrow for the collateral mint.
+    #[account(
+        mut,
+        seeds = [b"collateral_escrow", collateral_escrow.mint.as_ref()],
+        bump,
+        constraint = collateral_escrow.mint == flash_loan_state.collateral_mint @ CustomError::CollateralMintMismatch
+    )]
+    pub collateral_escrow: Account<'info, TokenAccount>,
+    /// Borrower's reputation, used for the whitelist bypass when provided.
+    #[account(seeds = [b"reputation", borrower.key.as_ref()], bump)]
+    pub borrower_reputation: Option<Account<'info, BorrowerReputation>>,
+    /// CHECK: Compliance hook program; must match `global_state.compliance_hook` when set.
+    pub compliance_program: Option<UncheckedAccount<'info>>,
+    /// Loan history; the old loan is marked repaid and the new one recorded when provided.
+    #[account(mut, seeds = [b"loan_history"], bump)]
+    pub loan_history: Option<Account<'info, LoanHistory>>,
+    /// Borrower's stake; earns a rebate on the old loan's fee when provided.
+    #[account(seeds = [b"user_stake", borrower.key.as_ref()], bump)]
+    pub borrower_stake: Option<Account<'info, UserStake>>,
+    /// Receives the fee when fees are collected in a separate fee mint, and refunds the unused
+    /// part of a prepaid time-priced fee.
+    #[account(mut, address = global_state.fee_vault)]
+    pub fee_vault: Option<Account<'info, TokenAccount>>,
+    /// Account the fee is drawn from when fees are collected in a separate fee mint.
+    #[account(mut)]
+    pub borrower_fee_account: Option<Account<'info, TokenAccount>>,
+    /// The fee mint; required to burn `burn_share_bps` of a fee collected in it.
+    #[account(mut)]
+    pub fee_mint_account: Option<Account<'info, Mint>>,
+    /// Treasury token account receiving the POL share of the fee, when routed.
+    #[account(mut, constraint = treasury_token_account.owner == global_state.treasury_account @ CustomError::InvalidTreasury)]
+    pub treasury_token_account: Option<Account<'info, TokenAccount>>,
+    /// Receives the stakers' share of the fee when `staker_fee_bps` is set.
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Option<Account<'info, TokenAccount>>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> RolloverFlashLoan<'info> {
+    /// Accounts the old loan's fee is settled through; the borrower funds any fee-mint payment.
+    pub fn settlement_accounts(&self) -> SettlementAccounts<'_, 'info> {
+        SettlementAccounts {
+            token_program: self.token_program.to_account_info(),
+            pool_account: self.pool_account.to_account_info(),
+            pool_authority: self.pool_authority.to_account_info(),
+            payer: self.borrower.to_account_info(),
+            borrower_token_account: self.borrower_token_account.to_account_info(),
+            borrower_fee_account: self.borrower_fee_account.as_ref(),
+            fee_vault: self.fee_vault.as_ref(),
+            fee_mint_account: self.fee_mint_account.as_ref(),
+            treasury_token_account: self.treasury_token_account.as_ref(),
+            reward_vault: self.reward_vault.as_ref(),
+        }
+    }
+    pub fn into_transfer_repayment_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.borrower_token_account.to_account_info().clone(),
+            to: self.pool_account.to_account_info().clone(),
+            authority: self.borrower.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+    pub fn into_transfer_to_borrower_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.pool_account.to_account_info().clone(),
+            to: self.borrower_token_account.to_account_info().clone(),
+            authority: self.pool_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+    pub fn into_transfer_collateral_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.borrower_collateral_account.to_account_info().clone(),
+            to: self.collateral_escrow.to_account_info().clone(),
+            authority: self.borrower.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct CloseReputation<'info> {
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub borrower: Signer<'info>,
+    #[account(mut, close = borrower, seeds = [b"reputation", borrower.key.as_ref()], bump)]
+    pub borrower_reputation: Account<'info, BorrowerReputation>,
+}
+
+#[derive(Accounts)]
+pub struct DonateRewards<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub donor: Signer<'info>,
+    #[account(mut)]
+    pub donor_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> DonateRewards<'info> {
+    pub fn into_transfer_to_reward_vault_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.donor_token_account.to_account_info().clone(),
+            to: self.reward_vault.to_account_info().clone(),
+            authority: self.donor.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct FundSubsidy<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    pub funder: Signer<'info>,
+    #[account(mut)]
+    pub funder_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = global_state.subsidy_vault)]
+    pub subsidy_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> FundSubsidy<'info> {
+    pub fn into_transfer_to_subsidy_vault_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.funder_token_account.to_account_info().clone(),
+            to: self.subsidy_vault.to_account_info().clone(),
+            authority: self.funder.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct FundEmissions<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    pub admin: Signer<'info>,
+    #[account(mut)]
+    pub admin_token_account: Account<'info, TokenAccount>,
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> FundEmissions<'info> {
+    pub fn into_transfer_to_reward_vault_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.admin_token_account.to_account_info().clone(),
+            to: self.reward_vault.to_account_info().clone(),
+            authority: self.admin.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct SyncEmissions<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+}
+
+#[derive(Accounts)]
+pub struct CrankDistribution<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    pub cranker: Signer<'info>,
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    /// CHECK: The authority controlling the reward vault; must sign unless it is the `vault_authority`
+    /// PDA, which the program signs for.
+    #[account(constraint = is_vault_signer(&global_state, &global_state.key(), &reward_vault_authority) @ CustomError::Unauthorized)]
+    pub reward_vault_authority: UncheckedAccount<'info>,
+    /// Account the crank reward is paid into.
+    #[account(mut)]
+    pub cranker_token_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> CrankDistribution<'info> {
+    pub fn into_transfer_crank_reward_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.reward_vault.to_account_info().clone(),
+            to: self.cranker_token_account.to_account_info().clone(),
+            authority: self.reward_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+}
+
+#[derive(Accounts)]
+pub struct CompoundRewards<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user.key.as_ref()],
+        bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ CustomError::UnsupportedVersion
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    /// The authority (PDA) controlling the reward vault.
+    pub reward_vault_authority: Signer<'info>,
+    #[account(mut, constraint = is_stake_vault(&global_state, &stake_vault.key()) @ CustomError::InvalidStakeVault)]
+    pub stake_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> CompoundRewards<'info> {
+    pub fn into_transfer_rewards_to_stake_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.reward_vault.to_account_info().clone(),
+            to: self.stake_vault.to_account_info().clone(),
+            authority: self.reward_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct ClaimRewards<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user.key.as_ref()],
+        bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ CustomError::UnsupportedVersion
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    /// The authority (PDA) controlling the reward vault.
+    pub reward_vault_authority: Signer<'info>,
+    /// Account the rewards are paid into.
+    #[account(mut)]
+    pub user_reward_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimAllRewards<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    /// The caller's stake record, which holds the claim cooldown; created if needed.
+    #[account(init_if_needed, payer = user, space = 8 + UserStake::LEN, seeds = [b"user_stake", user.key.as_ref()], bump)]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    /// The authority (PDA) controlling the reward vault.
+    pub reward_vault_authority: Signer<'info>,
+    /// Account the rewards are paid into.
+    #[account(mut)]
+    pub user_reward_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+impl<'info> ClaimAllRewards<'info> {
+    pub fn into_transfer_rewards_to_user_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.reward_vault.to_account_info().clone(),
+            to: self.user_reward_account.to_account_info().clone(),
+            authority: self.reward_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+impl<'info> ClaimRewards<'info> {
+    pub fn into_transfer_rewards_to_user_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.reward_vault.to_account_info().clone(),
+            to: self.user_reward_account.to_account_info().clone(),
+            authority: self.reward_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct ClaimToVesting<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
+    pub global_state: Account<'info, GlobalState>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user.key.as_ref()],
+        bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ CustomError::UnsupportedVersion
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    /// The authority (PDA) controlling the reward vault.
+    pub reward_vault_authority: Signer<'info>,
+    #[account(address = reward_vault.mint)]
+    pub reward_mint: Account<'info, Mint>,
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + VestingAccount::LEN,
+        seeds = [b"vesting", user.key.as_ref()],
+        bump
+    )]
+    pub vesting: Account<'info, VestingAccount>,
+    /// Escrow holding the vesting rewards, owned by the `vesting` PDA.
+    #[account(
+        init_if_needed,
+        payer = user,
+        seeds = [b"vesting_vault", user.key.as_ref()],
+        bump,
+        token::mint = reward_mint,
+        token::authority = vesting
+    )]
+    pub vesting_vault: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+impl<'info> ClaimToVesting<'info> {
+    pub fn into_transfer_rewards_to_vesting_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.reward_vault.to_account_info().clone(),
+            to: self.vesting_vault.to_account_info().clone(),
+            authority: self.reward_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct ClaimVested<'info> {
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"vesting", user.key.as_ref()],
+        bump,
+        constraint = vesting.owner == user.key() @ CustomError::Unauthorized
+    )]
+    pub vesting: Account<'info, VestingAccount>,
+    #[account(mut, seeds = [b"vesting_vault", user.key.as_ref()], bump)]
+    pub vesting_vault: Account<'info, TokenAccount>,
+    /// Account the released rewards are paid into.
     #[account(mut)]
+    pub user_reward_account: Account<'info, TokenAccount>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> ClaimVested<'info> {
+    pub fn into_transfer_vested_context<'a>(&self, signer_seeds: &'a [&'a [&'a [u8]]]) -> CpiContext<'_, '_, 'a, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.vesting_vault.to_account_info().clone(),
+            to: self.user_reward_account.to_account_info().clone(),
+            authority: self.vesting.to_account_info().clone(),
+        };
+        CpiContext::new_with_signer(self.token_program.to_account_info().clone(), cpi_accounts, signer_seeds)
+    }
+}
+
+#[derive(Accounts)]
+pub struct ClaimRewardsSwapped<'info> {
+    #[account(mut, constraint = global_state.version == GLOBAL_STATE_VERSION @ CustomError::UnsupportedVersion)]
     pub global_state: Account<'info, GlobalState>,
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"user_stake", user.key.as_ref()],
+        bump,
+        constraint = user_stake.version == USER_STAKE_VERSION @ CustomError::UnsupportedVersion
+    )]
+    pub user_stake: Account<'info, UserStake>,
+    #[account(mut, address = global_state.reward_vault)]
+    pub reward_vault: Account<'info, TokenAccount>,
+    /// The authority (PDA) controlling the reward vault.
+    pub reward_vault_authority: Signer<'info>,
+    /// Account the rewards are claimed into before being swapped.
     #[account(mut)]
-    pub provider: Signer<'info>,
+    pub user_reward_account: Account<'info, TokenAccount>,
+    /// Account receiving the swapped tokens; its balance increase is checked against `min_out`.
     #[account(mut)]
-    pub provider_token_account: Account<'info, TokenAccount>,
+    pub user_output_account: Account<'info, TokenAccount>,
+    /// CHECK: Must be the swap program configured in global state.
+    #[account(
+        address = global_state.swap_program,
+        constraint = global_state.swap_program != Pubkey::default() @ CustomError::SwapNotConfigured
+    )]
+    pub swap_program: AccountInfo<'info>,
+    pub token_program: Program<'info, Token>,
+}
+
+impl<'info> ClaimRewardsSwapped<'info> {
+    pub fn into_transfer_rewards_to_user_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.reward_vault.to_account_info().clone(),
+            to: self.user_reward_account.to_account_info().clone(),
+            authority: self.reward_vault_authority.to_account_info().clone(),
+        };
+        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    }
+}
+
+//
+// On–chain State Accounts
+//
+
+#[account]
+pub struct GlobalState {
+    pub admin: Pubkey,
+    pub fee_rate: u64,         // in basis points
+    pub total_liquidity: u64,  // tokens in the liquidity pool
+    pub total_staked: u64,     // tokens staked by users
+    pub accumulated_fees: u64, // fees collected from flash loans across all pools
+    pub is_flash_loan_active: bool, // reentrancy guard flag
+    pub treasury_account: Pubkey,   // for fee redistribution
+    pub flash_loan_whitelist: Vec<Pubkey>, // optional whitelist for borrowers
+    pub reward_vault: Pubkey,              // holds reward tokens owed to stakers
+    pub fee_vault: Pubkey,                 // holds fees prepaid by borrowers
+    pub acc_reward_per_share: u128,        // scaled by REWARD_PRECISION
+    pub active_borrower: Pubkey,           // borrower of the in-flight loan, if any
+    pub auto_whitelist_threshold: u64,     // reputation that bypasses the whitelist (0 = off)
+    pub config_locked: bool,               // once set, economic parameters are immutable
+    pub min_stake: u64,                    // minimum amount to open a stake position
+    pub reputation_per_loan: u64,          // reputation earned per repaid loan (and per size bucket)
+    pub reputation_size_bucket: u64,       // principal per extra reputation increment (0 = flat)
+    pub max_reputation: u64,               // cap on any borrower's reputation (0 = uncapped)
+    pub swap_program: Pubkey,              // DEX adapter used by `claim_rewards_swapped` (default = unset)
+    pub per_tx_loan_cap: u64,              // max principal borrowed per transaction (0 = uncapped)
+    pub fee_mint: Option<Pubkey>,          // mint fees are collected in, if not the loan mint
+    pub fee_mint_price: u64,               // fee-mint units per loan-mint unit, scaled by PRICE_PRECISION
+    pub paused: bool,                      // emergency stop; only emergency exits remain available
+    pub protocol_owned_liquidity: u64,     // part of total_liquidity seeded by the protocol (no LP position)
+    pub route_pol_fees_to_treasury: bool,  // send the POL share of each fee to the treasury
+    pub reward_rate_per_second: u64,       // scheduled reward emission rate
+    pub reward_period_end: i64,            // emissions stop accruing after this timestamp
+    pub last_emission_update: i64,         // emissions are folded into the accumulator up to here
+    pub compliance_hook: Option<Pubkey>,   // program screening every borrower at borrow time
+    pub total_shares: u64,                 // LP shares outstanding, including MINIMUM_LIQUIDITY
+    pub total_reward_weight: u64,          // boosted stake across all positions; divides rewards
+    pub fee_tiers: Vec<FeeTier>,           // amount brackets overriding `fee_rate`, ascending
+    pub voucher_signer: Option<Pubkey>,    // key whose signed vouchers waive loan fees
+    pub epoch_emission_cap: u64,           // max rewards injected per emission epoch (0 = uncapped)
+    pub epoch_emitted: u64,                // rewards injected in the current emission epoch
+    pub emission_epoch_start: i64,         // when the current emission epoch began
+    pub emission_epoch_length: i64,        // length of an emission epoch, in seconds
+    pub version: u8,                       // layout version, see GLOBAL_STATE_VERSION
+    pub max_collateral_bps: u64,           // collateral cap as bps of principal (0 = uncapped)
+    pub fee_unlock_period: i64,            // seconds over which each LP fee unlocks (0 = immediate)
+    pub locked_fees: u64,                  // LP fees still locked as of `fees_locked_at`
+    pub fees_locked_at: i64,               // when `locked_fees` was last settled
+    pub total_share_seconds: u128,         // integral of total_shares over time
+    pub last_share_accrual: i64,           // `total_share_seconds` is accrued up to here
+    pub pauser: Pubkey,                    // may pause and unpause (default = admin)
+    pub treasurer: Pubkey,                 // may withdraw collected fees (default = admin)
+    pub total_loan_volume: u128,           // lifetime principal of repaid loans; saturates
+    pub rebate_cap_bps: u64,               // max fee rebate for staking borrowers, in bps (0 = off)
+    pub dust_threshold: u64,               // positions left below this are swept and closed (0 = off)
+    pub subsidy_vault: Pubkey,             // holds tokens that subsidize borrower fees
+    pub subsidy_balance: u64,              // subsidy funds still available
+    pub subsidy_per_loan: u64,             // most of one loan's fee the subsidy covers
+    pub staker_fee_bps: u64,               // share of each LP fee set aside for stakers
+    pub pending_staker_fees: u64,          // staker fees in the reward vault awaiting a crank
+    pub distribution_interval: i64,        // minimum seconds between cranks
+    pub last_distribution: i64,            // when the crank last ran
+    pub crank_reward: u64,                 // most a cranker is paid per run
+    pub round_fees_up: bool,               // round fee remainders up for LPs instead of down
+    pub winding_down: bool,                // sunset in progress: only exits remain available
+    pub lp_share_bps: u64,                 // LPs' share of each fee under the three-way split
+    pub staker_share_bps: u64,             // stakers' share; the treasury gets the remainder
+    pub whitelist_entry_types: Vec<u8>,    // WHITELIST_ENTRY_* for each `flash_loan_whitelist` entry
+    pub min_effective_fee_bps: u64,        // floor on any loan's final fee, in bps (0 = off)
+    pub stake_vault: Pubkey,               // holds staked tokens (default = not yet recorded)
+    pub burn_share_bps: u64,               // share of fee-mint fees burned rather than kept
+    pub claim_cooldown: i64,               // min seconds between a staker's reward claims (0 = none)
+    pub compound_cooldown: i64,            // min seconds between a staker's compounds (0 = none)
+    pub reserve_bps: u64,                  // share of total liquidity LP withdrawals must leave behind
+    pub pool_vault: Pubkey,                // canonical pool vault (default = not yet created)
+    pub canonical_vaults: bool,            // the canonical vaults exist and are the only ones accepted
+    pub vault_authority_bump: u8,          // bump of the `vault_authority` PDA owning the canonical vaults
+    pub pool_vault_bump: u8,               // bump of the canonical pool vault
+    pub fee_vault_bump: u8,                // bump of the canonical fee vault
+    pub stake_vault_bump: u8,              // bump of the canonical stake vault
+    pub rep_base_cap: u64,                 // largest loan for a borrower with no reputation (0 = caps off)
+    pub rep_cap_per_point: u64,            // extra loan size allowed per point of reputation
+    pub rep_cap_ceiling: u64,              // absolute limit on any reputation cap (0 = none)
+    pub lp_vault_fees: u64,                // LPs' share of prepaid fees, held in the fee vault until compounded
+    pub staker_fees_accrued: u64,          // lifetime staker fees set aside for the crank
+    pub staker_fees_distributed: u64,      // lifetime staker fees consumed by the crank, crank rewards included
+    pub fee_model: FeeModel,               // how loan fees are priced; see `set_fee_model`
+    pub admins: Vec<Pubkey>,               // co-signers of `AdminAction`s
+    pub admin_threshold: u8,               // approvals an `AdminAction` needs (0 = the single admin acts alone)
+    pub util_ema: u64,                     // moving average of pool utilization (bps), sampled on each loan and repayment
+    pub util_smoothing_bps: u64,           // weight of each new sample in `util_ema`
+    pub liquidation_incentive_bps: u64,    // share of seized collateral paid to the liquidator
+    pub event_level: EventLevel,           // how much instructions log through events
+    pub accepted_collateral_mint: Pubkey,  // mint loans are collateralized in (default = the pool's mint)
+    pub staking_paused: bool,              // new stake and compounding are rejected; exits stay open
+    pub whitelist_expiries: Vec<i64>,      // expiry of each `flash_loan_whitelist` entry (0 = none)
+    pub paused_at: i64,                    // when the program was last paused
+    pub unpause_delay: i64,                // seconds a pause must last before unpausing (0 = none)
+    pub early_unstake_period: i64,         // unstaking this soon after opening a position is penalized (0 = off)
+    pub unstake_penalty_bps: u64,          // early-unstake penalty, in bps of the amount, rounded up
+    pub min_unstake_penalty: u64,          // floor on any early-unstake penalty
+}
+
+impl GlobalState {
+    // For the vector, we add 4 bytes for length and assume up to MAX_WHITELIST_LEN addresses.
+    pub const LEN: usize = 32 + 8 + 8 + 8 + 8 + 1 + 32 + (4 + MAX_WHITELIST_LEN * 32) + 32 + 32 + 16 + 32 + 8 + 1 + 8 + 8 + 8 + 8 + 32 + 8 + (1 + 32) + 8 + 1 + 8 + 1 + 8 + 8 + 8 + (1 + 32) + 8 + 8 + (4 + MAX_FEE_TIERS * FeeTier::LEN) + (1 + 32) + 8 + 8 + 8 + 8 + 1 + 8 + 8 + 8 + 8 + 16 + 8 + 32 + 32 + 16 + 8 + 8 + 32 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 1 + 1 + 8 + 8 + (4 + MAX_WHITELIST_LEN) + 8 + 32 + 8 + 8 + 8 + 8 + 32 + 1 + 1 + 1 + 1 + 1 + 8 + 8 + 8 + 8 + 8 + 8 + FeeModel::LEN + (4 + MAX_ADMINS * 32) + 1 + 8 + 8 + 8 + 1 + 32 + 1 + (4 + MAX_WHITELIST_LEN * 8) + 8 + 8 + 8 + 8 + 8;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq, Default)]
+pub struct FeeTier {
+    pub threshold: u64, // minimum loan amount for this tier
+    pub fee_bps: u64,   // fee rate (bps) charged in this tier
+}
+
+impl FeeTier {
+    pub const LEN: usize = 8 + 8;
+}
+
+/// A sensitive change carried out through the `AdminAction` co-signing flow.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum AdminActionKind {
+    UpdateFeeRate(u64),
+    SetPauser(Pubkey),
+    SetTreasurer(Pubkey),
+    SetAdmins { admins: Vec<Pubkey>, threshold: u8 },
+    LockConfig,
+    SetFeeTiers(Vec<FeeTier>),
+    SetFeeModel(FeeModel),
+    SetFeeSplit { lp_share_bps: u64, staker_share_bps: u64 },
+    SetComplianceHook(Option<Pubkey>),
+    SetVoucherSigner(Option<Pubkey>),
+    SetSwapProgram(Pubkey),
+    InitiateWindDown,
+    UpdatePoolFeeRate { pool: Pubkey, fee_rate: u64 },
+    // Carried out by their own instructions, which consume the approved action.
+    MigrateStakeVault { new_vault: Pubkey },
+    WithdrawProtocolLiquidity { amount: u64, destination: Pubkey },
+    FinalizeWindDown { destination: Pubkey, reward_destination: Pubkey },
+}
+
+impl AdminActionKind {
+    /// Serialized size of the largest variant, `SetAdmins` or `SetFeeTiers`.
+    pub const MAX_LEN: usize = {
+        let set_admins = (4 + MAX_ADMINS * 32) + 1;
+        let set_fee_tiers = 4 + MAX_FEE_TIERS * FeeTier::LEN;
+        1 + if set_admins > set_fee_tiers { set_admins } else { set_fee_tiers }
+    };
+}
+
+/// Privileged instruction recorded by an `AdminAudit` event. Changes made through
+/// `execute_admin_action` are recorded under the instruction they stand in for.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    CreatePool,
+    UpdateFeeRate,
+    UpdatePoolFeeRate,
+    SetFeeTiers,
+    SetFeeModel,
+    SetUtilSmoothing,
+    SetEventLevel,
+    SetPaused,
+    SetStakingPaused,
+    SetUnpauseDelay,
+    SetUnstakePenalty,
+    SetPauser,
+    SetTreasurer,
+    SetAdmins,
+    LockConfig,
+    AddToWhitelist,
+    AddToWhitelistWithExpiry,
+    AddProgramToWhitelist,
+    SetWhitelist,
+    AddManyToWhitelist,
+    RemoveFromWhitelist,
+    WithdrawFees,
+    MigrateStakeVault,
+    SetRoutePolFees,
+    SetComplianceHook,
+    SetVoucherSigner,
+    SetMinStake,
+    SetSwapProgram,
+    SetPerTxLoanCap,
+    SetMaxCollateralBps,
+    SetAcceptedCollateralMint,
+    SetLiquidationIncentive,
+    SetRebateCapBps,
+    SetDustThreshold,
+    SetMinEffectiveFeeBps,
+    SetReserveBps,
+    SetRoundFeesUp,
+    SetFeeUnlockPeriod,
+    SetBurnShareBps,
+    SetAutoWhitelistThreshold,
+    SetEmissionCap,
+    SetReputationCaps,
+    SetReputationParams,
+    SetSubsidy,
+    SetDistributionParams,
+    SetFeeSplit,
+    SetClaimCooldowns,
+    SetFeeMint,
+    SeedLiquidity,
+    WithdrawProtocolLiquidity,
+    ReconcileLiquidity,
+    FundEmissions,
+    InitiateWindDown,
+    FinalizeWindDown,
+    MigratePool,
+    AddPoolVault,
+    RemovePoolVault,
+}
+
+/// Before or after value of an audited change. Settings made of several fields are
+/// recorded as `Numbers`, in the order the instruction takes them.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum AuditValue {
+    None,
+    Number(u64),
+    Signed(i64),
+    Flag(bool),
+    Key(Pubkey),
+    Numbers(Vec<u64>),
+    Keys(Vec<Pubkey>),
+    Keyed(Pubkey, u64), // an account and its amount, e.g. the subsidy vault and per-loan subsidy
+    Admins(Vec<Pubkey>, u8),
+    FeeModel(FeeModel),
+    EventLevel(EventLevel),
+}
+
+impl From<u64> for AuditValue {
+    fn from(value: u64) -> Self {
+        AuditValue::Number(value)
+    }
+}
+
+impl From<i64> for AuditValue {
+    fn from(value: i64) -> Self {
+        AuditValue::Signed(value)
+    }
+}
+
+impl From<bool> for AuditValue {
+    fn from(value: bool) -> Self {
+        AuditValue::Flag(value)
+    }
+}
+
+impl From<Pubkey> for AuditValue {
+    fn from(value: Pubkey) -> Self {
+        AuditValue::Key(value)
+    }
+}
+
+impl From<Option<Pubkey>> for AuditValue {
+    fn from(value: Option<Pubkey>) -> Self {
+        value.map_or(AuditValue::None, AuditValue::Key)
+    }
+}
+
+impl From<Vec<Pubkey>> for AuditValue {
+    fn from(value: Vec<Pubkey>) -> Self {
+        AuditValue::Keys(value)
+    }
+}
+
+impl From<FeeModel> for AuditValue {
+    fn from(value: FeeModel) -> Self {
+        AuditValue::FeeModel(value)
+    }
+}
+
+impl From<EventLevel> for AuditValue {
+    fn from(value: EventLevel) -> Self {
+        AuditValue::EventLevel(value)
+    }
+}
+
+impl From<&[FeeTier]> for AuditValue {
+    /// Flattened as `threshold, fee_bps` pairs.
+    fn from(tiers: &[FeeTier]) -> Self {
+        AuditValue::Numbers(tiers.iter().flat_map(|tier| [tier.threshold, tier.fee_bps]).collect())
+    }
+}
+
+/// How the flash loan fee is priced.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FeeModel {
+    #[default]
+    Proportional,       // `fee_rate` (or the matching fee tier) in bps of the amount
+    FixedAbsolute(u64), // the same flat fee on every loan, whatever its size
+    Utilization { base_bps: u64, slope_bps: u64 }, // `base_bps + slope_bps * util_ema / BPS_DENOMINATOR`
+    TimeBased { max_fee_bps: u64 }, // `max_fee_bps` for the full `FLASH_LOAN_DURATION`, pro-rated at repayment
+}
+
+impl FeeModel {
+    /// Serialized size of the largest variant.
+    pub const LEN: usize = 1 + 8 + 8;
+}
+
+#[derive(Accounts)]
+pub struct WithdrawAllLiquidity<'info> {
+    /// Owner of every position redeemed; receives their rent as they are closed.
     #[account(mut)]
-    pub pool_account: Account<'info, TokenAccount>,
+    pub provider: Signer<'info>,
     pub token_program: Program<'info, Token>,
 }
 
-impl<'info> DepositLiquidity<'info> {
-    pub fn into_transfer_to_pool_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.provider_token_account.to_account_info().clone(),
-            to: self.pool_account.to_account_info().clone(),
-            authority: self.provider.to_account_info().clone(),
-        };
-        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+impl<'info> WithdrawAllLiquidity<'info> {
+    /// Redeems the provider's whole position in one pool, given as `[global_state, position,
+    /// pool_account, pool_authority, recipient]`, and closes it.
+    pub fn redeem_position(&self, pool: &'info [AccountInfo<'info>], now: i64) -> Result<()> {
+        let [state_info, position_info, pool_info, pool_authority, recipient_info] = pool else {
+            return err!(CustomError::PoolAccountsMismatch);
+        };
+        let mut state: Account<'info, GlobalState> = Account::try_from(state_info)?;
+        require!(state.version == GLOBAL_STATE_VERSION, CustomError::UnsupportedVersion);
+        let mut position: Account<'info, ProviderPosition> = Account::try_from(position_info)?;
+        require!(position.owner == self.provider.key(), CustomError::Unauthorized);
+        let seeds: &[&[u8]] = &[b"provider_position", state_info.key.as_ref(), self.provider.key.as_ref()];
+        let (expected_position, _) = Pubkey::find_program_address(seeds, &crate::ID);
+        require!(*position_info.key == expected_position, CustomError::Unauthorized);
+        let mut pool_account: Account<'info, TokenAccount> = Account::try_from(pool_info)?;
+        require!(is_pool_vault(&state, pool_info.key), CustomError::InvalidPoolVault);
+        require!(is_vault_signer(&state, state_info.key, pool_authority), CustomError::Unauthorized);
+        require!(is_pool_authority(&pool_account, pool_authority.key), CustomError::InvalidPoolAuthority);
+        let recipient: Account<'info, TokenAccount> = Account::try_from(recipient_info)?;
+        require!(recipient.mint == pool_account.mint, CustomError::MintMismatch);
+
+        let shares = position.shares;
+        let amount = position_value(&state, shares, lp_value(&state, pool_account.amount, now)?);
+        require!(amount > 0, CustomError::InsufficientPosition);
+        require!(within_reserve(&state, pool_account.amount, amount), CustomError::ReserveBreached);
+        let pre_balance = pool_account.amount;
+        {
+            let bump = [state.vault_authority_bump];
+            let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", state_info.key.as_ref(), &bump]];
+            let cpi_accounts = Transfer {
+                from: pool_info.clone(),
+                to: recipient_info.clone(),
+                authority: pool_authority.clone(),
+            };
+            let transfer_ctx = CpiContext::new_with_signer(self.token_program.to_account_info(), cpi_accounts, signer_seeds);
+            token::transfer(transfer_ctx, amount)?;
+        }
+        pool_account.reload()?;
+        let post_balance = pool_account.amount;
+        // The closed position's share-seconds leave the total with it, so they no longer
+        // dilute the LP fee share of the positions still open.
+        accrue_position_share_seconds(&mut position, now)?;
+        accrue_total_share_seconds(&mut state, now)?;
+        state.total_share_seconds = state.total_share_seconds.saturating_sub(position.share_seconds);
+        // Only the principal was counted as liquidity; earned fees paid on top never were,
+        // and a liquidation write-off may already have taken some of it out.
+        state.total_liquidity = state.total_liquidity.saturating_sub(position.amount);
+        state.total_shares = state.total_shares.checked_sub(shares).unwrap();
+        state.exit(&crate::ID)?;
+        if events_enabled(&state) {
+            emit!(LiquidityWithdrawn {
+                provider: self.provider.key(),
+                amount,
+                pre_balance: event_detail(&state, pre_balance),
+                post_balance: event_detail(&state, post_balance),
+            });
+        }
+        position.close(self.provider.to_account_info())
+    }
+}
+
+/// How much instructions log through events.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EventLevel {
+    #[default]
+    Full,    // every event, with balances and fees
+    Minimal, // every event, with only the actor and amount filled in
+    Off,     // no events
+}
+
+#[account]
+pub struct Pool {
+    pub mint: Pubkey,
+    pub pool_account: Pubkey,  // token account holding this pool's liquidity
+    pub accumulated_fees: u64, // fees collected from this pool's flash loans
+    pub total_loans: u64,      // loans repaid against this pool
+    pub fee_rate: u64,         // this pool's fee rate (bps), in place of the global `fee_rate`
+    pub version: u8,           // layout version, see POOL_VERSION; always the last field
+}
+
+impl Pool {
+    pub const LEN: usize = 32 + 32 + 8 + 8 + 8 + 1;
+}
+
+/// An extra vault flash loans from `pool` may draw from, registered by `add_pool_vault`.
+#[account]
+pub struct ExtraPoolVault {
+    pub pool: Pubkey,
+    pub vault: Pubkey,
+}
+
+impl ExtraPoolVault {
+    pub const LEN: usize = 32 + 32;
+}
+
+#[account]
+pub struct ProviderPosition {
+    pub owner: Pubkey,
+    pub amount: u64, // liquidity deposited and not yet withdrawn
+    pub shares: u64, // LP shares held, redeemable for a pro-rata slice of the pool
+    pub share_seconds: u128, // integral of `shares` over time: the position's time-weighted liquidity
+    pub last_accrual: i64,   // `share_seconds` is accrued up to here
+}
+
+impl ProviderPosition {
+    pub const LEN: usize = 32 + 8 + 8 + 16 + 8;
+}
+
+#[account]
+pub struct WithdrawalAllowance {
+    pub owner: Pubkey,    // provider whose position may be withdrawn from
+    pub operator: Pubkey, // delegate allowed to withdraw
+    pub remaining: u64,   // amount the operator may still withdraw
+}
+
+impl WithdrawalAllowance {
+    pub const LEN: usize = 32 + 32 + 8;
+}
+
+#[account]
+pub struct VestingAccount {
+    pub owner: Pubkey,
+    pub total: u64,     // rewards placed under the current schedule
+    pub released: u64,  // part of `total` already paid out
+    pub start: i64,     // when the schedule began
+    pub cliff: i64,     // seconds after `start` before anything releases
+    pub duration: i64,  // seconds after `start` until `total` has fully released
+}
+
+impl VestingAccount {
+    pub const LEN: usize = 32 + 8 + 8 + 8 + 8 + 8;
+}
+
+#[account]
+pub struct UserStake {
+    pub owner: Pubkey,
+    pub amount: u64,
+    pub reward_debt: u64,             // accrued rewards already accounted for
+    pub first_stake_timestamp: i64,   // when the current position was opened (0 if empty)
+    pub last_modified_timestamp: i64, // last stake, unstake, or compound
+    pub unclaimed_rewards: u64,       // rewards settled but not yet claimed
+    pub compounded_amount: u64,       // restaked rewards, tracked apart from deposited principal
+    pub last_claim_timestamp: i64,    // last reward claim, for the claim cooldown
+    pub last_compound_timestamp: i64, // last compound, for the compound cooldown
+    pub version: u8,                  // layout version, see USER_STAKE_VERSION; always the last field
+}
+
+impl UserStake {
+    pub const LEN: usize = 32 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 1;
+}
+
+/// One of possibly several stake buckets held by a user, each with its own lock.
+/// Positions earn rewards on `amount` scaled by `boost_bps`.
+#[account]
+pub struct StakePosition {
+    pub owner: Pubkey,
+    pub index: u8,
+    pub amount: u64,
+    pub lock_until: i64,        // no withdrawal before this time (0 if liquid)
+    pub boost_bps: u64,         // reward weight multiplier, BPS_DENOMINATOR = 1x
+    pub reward_debt: u64,       // accrued rewards already accounted for
+    pub unclaimed_rewards: u64, // rewards settled but not yet claimed
+}
+
+impl StakePosition {
+    pub const LEN: usize = 32 + 1 + 8 + 8 + 8 + 8 + 8;
+}
+
+#[account]
+pub struct FlashLoanState {
+    pub borrower: Pubkey,
+    pub pool: Pubkey, // pool the loan was drawn from
+    pub amount: u64,
+    pub fee: u64,
+    pub start_time: i64, // timestamp when the flash loan was issued
+    pub collateral: u64, // collateral amount provided
+    pub collateral_mint: Pubkey, // mint of the collateral; only its escrow may release or seize it
+    pub fee_rate: u64,   // fee rate (bps) snapshotted at borrow time
+    pub repaid: u64,              // principal plus fee repaid so far via partial repayments
+    pub collateral_released: u64, // collateral returned so far via partial repayments
+    pub fee_prepaid: bool,        // fee was prepaid through a reservation
+    pub sources: Vec<LoanSource>, // vaults the principal was drawn from, pool account first
+    pub min_repay_surplus: u64,   // balance the borrower's token account must keep after repaying
+    pub fixed_fee: u64,           // flat fee quoted at borrow time under `FeeModel::FixedAbsolute`, else 0
+    pub time_priced: bool,        // priced under `FeeModel::TimeBased`; the fee is pro-rated at repayment
+}
+
+impl FlashLoanState {
+    pub const LEN: usize = 32 + 32 + 8 + 8 + 8 + 8 + 32 + 8 + 8 + 8 + 1 + (4 + MAX_LOAN_SOURCES * LoanSource::LEN) + 8 + 8 + 1;
+
+    /// Records a newly issued loan on `terms`, snapshotting what it was priced on and
+    /// clearing anything a previous loan left behind.
+    pub fn open(&mut self, borrower: Pubkey, pool: Pubkey, terms: &LoanTerms, collateral: (Pubkey, u64), sources: Vec<LoanSource>, now: i64) {
+        self.borrower = borrower;
+        self.pool = pool;
+        self.amount = terms.amount;
+        self.fee = terms.net_fee();
+        self.fee_rate = terms.fee_rate;
+        self.fixed_fee = terms.fixed_fee;
+        self.time_priced = terms.time_priced;
+        self.fee_prepaid = terms.fee_prepaid;
+        self.start_time = now;
+        (self.collateral_mint, self.collateral) = collateral;
+        self.repaid = 0;
+        self.collateral_released = 0;
+        self.min_repay_surplus = 0;
+        self.sources = sources;
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct LoanSource {
+    pub vault: Pubkey, // pool vault the principal was drawn from
+    pub amount: u64,   // principal drawn from this vault
+}
+
+impl LoanSource {
+    pub const LEN: usize = 32 + 8;
+}
+
+/// Settings applied by `initialize_with_config`. Each field means what it does in its
+/// setter; zero leaves a cap or feature off.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct InitConfig {
+    pub fee_rate: u64,                    // see `update_fee_rate`
+    pub round_fees_up: bool,              // see `set_round_fees_up`
+    pub route_pol_fees_to_treasury: bool, // see `set_route_pol_fees`
+    pub per_tx_loan_cap: u64,             // see `set_per_tx_loan_cap`
+    pub max_collateral_bps: u64,          // see `set_max_collateral_bps`
+    pub rebate_cap_bps: u64,              // see `set_rebate_cap_bps`
+    pub min_effective_fee_bps: u64,       // see `set_min_effective_fee_bps`
+    pub reserve_bps: u64,                 // see `set_reserve_bps`
+    pub lp_share_bps: u64,                // see `set_fee_split`
+    pub staker_share_bps: u64,            // see `set_fee_split`
+    pub staker_fee_bps: u64,              // see `set_distribution_params`
+    pub burn_share_bps: u64,              // see `set_burn_share_bps`
+    pub min_stake: u64,                   // see `set_min_stake`
+    pub dust_threshold: u64,              // see `set_dust_threshold`
+    pub rep_base_cap: u64,                // see `set_reputation_caps`
+    pub rep_cap_per_point: u64,           // see `set_reputation_caps`
+    pub rep_cap_ceiling: u64,             // see `set_reputation_caps`
+    pub epoch_emission_cap: u64,          // see `set_emission_cap`
+    pub epoch_length: i64,                // see `set_emission_cap`; must be positive
+    pub fee_unlock_period: i64,           // see `set_fee_unlock_period`
+    pub distribution_interval: i64,       // see `set_distribution_params`
+    pub claim_cooldown: i64,              // see `set_claim_cooldowns`
+    pub compound_cooldown: i64,           // see `set_claim_cooldowns`
+}
+
+impl InitConfig {
+    /// Checks every field together, against the same rules as its setter.
+    pub fn validate(&self) -> Result<()> {
+        let bps = [
+            self.fee_rate,
+            self.rebate_cap_bps,
+            self.min_effective_fee_bps,
+            self.reserve_bps,
+            self.staker_fee_bps,
+            self.burn_share_bps,
+            self.lp_share_bps.saturating_add(self.staker_share_bps),
+        ];
+        require!(bps.iter().all(|bps| *bps <= BPS_DENOMINATOR), CustomError::InvalidBps);
+        require!(self.epoch_length > 0, CustomError::InvalidDuration);
+        let durations = [
+            self.fee_unlock_period,
+            self.distribution_interval,
+            self.claim_cooldown,
+            self.compound_cooldown,
+        ];
+        require!(durations.iter().all(|duration| *duration >= 0), CustomError::InvalidDuration);
+        Ok(())
+    }
+
+    /// Writes every field into a freshly initialized `state`.
+    pub fn apply(&self, state: &mut GlobalState, now: i64) {
+        state.fee_rate = self.fee_rate;
+        state.round_fees_up = self.round_fees_up;
+        state.route_pol_fees_to_treasury = self.route_pol_fees_to_treasury;
+        state.per_tx_loan_cap = self.per_tx_loan_cap;
+        state.max_collateral_bps = self.max_collateral_bps;
+        state.rebate_cap_bps = self.rebate_cap_bps;
+        state.min_effective_fee_bps = self.min_effective_fee_bps;
+        state.reserve_bps = self.reserve_bps;
+        state.lp_share_bps = self.lp_share_bps;
+        state.staker_share_bps = self.staker_share_bps;
+        state.staker_fee_bps = self.staker_fee_bps;
+        state.burn_share_bps = self.burn_share_bps;
+        state.min_stake = self.min_stake;
+        state.dust_threshold = self.dust_threshold;
+        state.rep_base_cap = self.rep_base_cap;
+        state.rep_cap_per_point = self.rep_cap_per_point;
+        state.rep_cap_ceiling = self.rep_cap_ceiling;
+        state.epoch_emission_cap = self.epoch_emission_cap;
+        state.emission_epoch_length = self.epoch_length;
+        state.emission_epoch_start = now;
+        state.fee_unlock_period = self.fee_unlock_period;
+        state.fees_locked_at = now;
+        state.distribution_interval = self.distribution_interval;
+        state.claim_cooldown = self.claim_cooldown;
+        state.compound_cooldown = self.compound_cooldown;
+    }
+}
+
+/// One loan in the `LoanHistory`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct LoanRecord {
+    pub loan: Pubkey,     // the loan's `FlashLoanState`, which its repayment is matched by
+    pub borrower: Pubkey,
+    pub amount: u64,
+    pub fee: u64,         // fee charged at borrow time
+    pub timestamp: i64,   // when the loan was issued
+    pub status: u8,       // LOAN_OPEN, LOAN_REPAID or LOAN_DEFAULTED
+}
+
+impl LoanRecord {
+    pub const LEN: usize = 32 + 32 + 8 + 8 + 8 + 1;
+}
+
+/// Outcome of `simulate_flash_loan`, returned as return data.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct SimResult {
+    pub accepted: bool,
+    pub error_code: u32,          // program error the real loan would fail with (0 if accepted)
+    pub fee: u64,                 // fee the loan would be charged
+    pub fee_rate: u64,            // fee rate (bps) the fee was quoted at
+    pub available_liquidity: u64, // liquidity across the pool and any extra vaults
+}
+
+/// How a loan's fee is built up, as charged by `flash_loan` and returned by `quote_fee`.
+/// `net = base - discount + floor raise - subsidy`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct FeeBreakdown {
+    pub base: u64,           // fee on the quoted terms, before any modifier
+    pub discount: u64,       // taken off by a voucher, time-based pro-rating and the staking rebate
+    pub subsidy: u64,        // covered by the subsidy vault
+    pub floor_applied: bool, // the `min_effective_fee_bps` floor raised the fee
+    pub net: u64,            // what the borrower pays
+}
+
+/// The borrower-specific inputs to `effective_fee`.
+#[derive(Clone, Copy, Default)]
+pub struct BorrowerFeeContext {
+    pub voucher: bool,    // a voucher waives the fee
+    pub subsidized: bool, // the subsidy vault covers what it can
+    pub stake: u64,       // borrower's stake, earning a rebate at repayment
+}
+
+/// Terms a new loan is issued on, as `issue_terms` prices them.
+#[derive(Clone, Copy, Default)]
+pub struct LoanTerms {
+    pub amount: u64,
+    pub fee_rate: u64,     // rate (bps) snapshotted for repayment
+    pub fixed_fee: u64,    // flat fee snapshotted under `FeeModel::FixedAbsolute`, else 0
+    pub time_priced: bool, // priced under `FeeModel::TimeBased`; the fee is pro-rated at repayment
+    pub fee_prepaid: bool, // the fee was prepaid through a reservation
+    pub fee: u64,          // fee on the loan, including the part the subsidy vault covers
+    pub subsidy: u64,      // covered by the subsidy vault
+}
+
+impl LoanTerms {
+    /// What the borrower owes on top of the principal.
+    pub fn net_fee(&self) -> u64 {
+        self.fee.checked_sub(self.subsidy).unwrap()
+    }
+}
+
+/// How a loan is settled on full repayment, as `settle_loan` works it out.
+#[derive(Clone, Copy, Default)]
+pub struct Settlement {
+    pub outstanding: u64,               // loan-mint principal and fee still owed
+    pub fee: u64,                       // fee earned, after pro-rating, the floor and the staking rebate
+    pub fee_in_fee_mint: u64,           // owed in the fee mint instead, when fees are collected separately
+    pub fee_prepaid: bool,              // the fee was prepaid through a reservation
+    pub fee_paid_to_pool: bool,         // the fee is repaid into the pool with the principal
+    pub prepaid_refund: u64,            // unused part of a prepaid time-priced fee
+    pub split: Option<(u64, u64, u64)>, // `(lp, staker, treasury)` split of a pool-paid fee, see `fee_split`
+    pub pol_fee: u64,                   // forwarded to the treasury
+    pub staker_fee: u64,                // set aside in the reward vault for stakers
+}
+
+/// The accounts a settled fee moves through, taken from the settling instruction.
+pub struct SettlementAccounts<'a, 'info> {
+    pub token_program: AccountInfo<'info>,
+    pub pool_account: AccountInfo<'info>,
+    pub pool_authority: AccountInfo<'info>,
+    pub payer: AccountInfo<'info>, // owner or delegate of `borrower_fee_account`
+    pub borrower_token_account: AccountInfo<'info>,
+    pub borrower_fee_account: Option<&'a Account<'info, TokenAccount>>,
+    pub fee_vault: Option<&'a Account<'info, TokenAccount>>,
+    pub fee_mint_account: Option<&'a Account<'info, Mint>>,
+    pub treasury_token_account: Option<&'a Account<'info, TokenAccount>>,
+    pub reward_vault: Option<&'a Account<'info, TokenAccount>>,
+}
+
+/// Outcome of `get_health`, returned as return data.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct HealthReport {
+    pub pool_balance: u64,
+    pub total_liquidity: u64,
+    pub accumulated_fees: u64,
+    pub outstanding_loans: u64, // unrepaid principal of the open loan
+    pub utilization_bps: u64,
+    pub stake_vault_balance: u64,
+    pub total_staked: u64,
+    pub pool_solvent: bool,  // pool balance plus outstanding loans covers `total_liquidity`
+    pub stake_solvent: bool, // stake vault balance covers `total_staked`
+    pub is_solvent: bool,    // both of the above
+}
+
+#[account]
+pub struct LoanReservation {
+    pub borrower: Pubkey,
+    pub amount: u64,      // loan amount the reservation covers
+    pub fee_rate: u64,    // fee rate (bps) locked at reservation time
+    pub prepaid_fee: u64, // fee already paid into the fee vault
+    pub expiry: i64,      // reservation is unusable after this timestamp
+    pub fixed_fee: u64,   // flat fee locked at reservation time, 0 under the proportional model
+    pub time_priced: bool, // locked under `FeeModel::TimeBased`; the unused part of the fee is refunded
+    pub pool: Pubkey,     // pool whose rate was locked; the loan must be drawn from it
+}
+
+impl LoanReservation {
+    pub const LEN: usize = 32 + 8 + 8 + 8 + 8 + 8 + 1 + 32;
+}
+
+#[account]
+pub struct Voucher {
+    pub borrower: Pubkey,
+    pub nonce: u64,
+    pub amount: u64,    // loan amount the fee waiver covers
+    pub expiry: i64,    // voucher is unusable after this timestamp
+    pub consumed: bool, // set once a loan has used the waiver
+}
+
+impl Voucher {
+    pub const LEN: usize = 32 + 8 + 8 + 8 + 1;
+}
+
+#[account]
+pub struct BorrowerReputation {
+    pub borrower: Pubkey,
+    pub reputation: u64,
+    pub last_repaid_at: i64, // timestamp of the most recent repayment
+}
+
+impl BorrowerReputation {
+    pub const LEN: usize = 32 + 8 + 8;
+}
+
+/// Append-only list of every borrower that has earned reputation, so frontends can
+/// enumerate `BorrowerReputation` PDAs without a getProgramAccounts scan.
+#[account]
+pub struct ReputationIndex {
+    pub borrowers: Vec<Pubkey>,
+}
+
+impl ReputationIndex {
+    /// Serialized size of an index holding `len` borrowers.
+    pub const fn space(len: usize) -> usize {
+        4 + len * 32
+    }
+}
+
+/// A proposed sensitive change and the admins who have approved it.
+#[account]
+pub struct AdminAction {
+    pub global_state: Pubkey,
+    pub proposer: Pubkey,
+    pub action: AdminActionKind,
+    pub approvals: Vec<Pubkey>, // distinct admins that approved, the proposer first
+    pub executed: bool,
+}
+
+impl AdminAction {
+    pub const LEN: usize = 32 + 32 + AdminActionKind::MAX_LEN + (4 + MAX_ADMINS * 32) + 1;
+}
+
+/// Ring buffer of the last `LOAN_HISTORY_LEN` loans.
+#[account]
+pub struct LoanHistory {
+    pub total_loans: u64,         // loans ever recorded; the next goes to slot `total_loans % LOAN_HISTORY_LEN`
+    pub records: Vec<LoanRecord>, // grows to `LOAN_HISTORY_LEN`, then the oldest is overwritten
+}
+impl LoanHistory {
+    pub const LEN: usize = 8 + 4 + LOAN_HISTORY_LEN * LoanRecord::LEN;
+
+    /// Records a new loan, overwriting the oldest entry once the buffer is full.
+    pub fn record(&mut self, record: LoanRecord) {
+        let slot = (self.total_loans % LOAN_HISTORY_LEN as u64) as usize;
+        if slot < self.records.len() {
+            self.records[slot] = record;
+        } else {
+            self.records.push(record);
+        }
+        self.total_loans = self.total_loans.checked_add(1).unwrap();
+    }
+
+    /// Marks the open entry for `loan` repaid, if it is still in the buffer.
+    pub fn mark_repaid(&mut self, loan: &Pubkey) {
+        if let Some(record) = self.records.iter_mut().find(|r| r.loan == *loan && r.status == LOAN_OPEN) {
+            record.status = LOAN_REPAID;
+        }
+    }
+
+    /// Marks the still-open entry for `loan`, if it is retained, as defaulted.
+    pub fn mark_defaulted(&mut self, loan: &Pubkey) {
+        if let Some(record) = self.records.iter_mut().find(|r| r.loan == *loan && r.status == LOAN_OPEN) {
+            record.status = LOAN_DEFAULTED;
+        }
+    }
+
+    /// Up to `limit` records, newest first, after skipping the `offset` most recent. Open
+    /// loans past `FLASH_LOAN_DURATION` at `now` are reported as defaulted.
+    pub fn recent(&self, offset: u64, limit: usize, now: i64) -> Vec<LoanRecord> {
+        let held = self.records.len() as u64;
+        (offset..held)
+            .take(limit)
+            .map(|back| {
+                let slot = ((self.total_loans - 1 - back) % LOAN_HISTORY_LEN as u64) as usize;
+                let mut record = self.records[slot].clone();
+                if record.status == LOAN_OPEN && now.saturating_sub(record.timestamp) > FLASH_LOAN_DURATION {
+                    record.status = LOAN_DEFAULTED;
+                }
+                record
+            })
+            .collect()
+    }
+}
+
+//
+// Time
+//
+
+/// Seconds elapsed from `since` to `now`. A clock that appears to have gone
+/// backwards is treated as an error rather than a negative elapsed time.
+pub fn elapsed_since(since: i64, now: i64) -> Result<i64> {
+    require!(now >= since, CustomError::InvalidClock);
+    now.checked_sub(since).ok_or_else(|| error!(CustomError::InvalidClock))
+}
+
+//
+// Access Control
+//
+
+/// Whether `authority` can move funds out of `pool_account`, as its owner or delegate.
+pub fn is_pool_authority(pool_account: &TokenAccount, authority: &Pubkey) -> bool {
+    pool_account.owner == *authority || pool_account.delegate == COption::Some(*authority)
+}
+
+/// Whether `vault` is the protocol's stake vault. Until a vault is recorded by
+/// `migrate_stake_vault`, any vault is accepted as before.
+pub fn is_stake_vault(state: &GlobalState, vault: &Pubkey) -> bool {
+    state.stake_vault == Pubkey::default() || state.stake_vault == *vault
+}
+
+/// Whether `vault` may be used as the pool account: any vault until the canonical pool
+/// vault is created, and only that one afterwards.
+pub fn is_pool_vault(state: &GlobalState, vault: &Pubkey) -> bool {
+    state.pool_vault == Pubkey::default() || state.pool_vault == *vault
+}
+
+/// Whether `vault` is one of the protocol's own stake, fee, reward or subsidy vaults. These
+/// share the pool vault's authority but hold other parties' funds, so they are never lent out.
+pub fn is_protocol_vault(state: &GlobalState, vault: &Pubkey) -> bool {
+    [state.stake_vault, state.fee_vault, state.reward_vault, state.subsidy_vault].contains(vault)
+}
+
+/// Checks that `info` is an extra vault of `pool`, as recorded by the `ExtraPoolVault`
+/// `registration`, in the pool's mint and movable by `authority`, and returns it.
+pub fn extra_pool_vault(
+    state: &GlobalState,
+    pool: &Account<Pool>,
+    registration: &AccountInfo,
+    info: &AccountInfo,
+    authority: &Pubkey,
+) -> Result<TokenAccount> {
+    require!(*registration.owner == crate::ID, CustomError::UnregisteredPoolVault);
+    let entry = ExtraPoolVault::try_deserialize(&mut &registration.try_borrow_data()?[..])?;
+    require!(entry.pool == pool.key() && entry.vault == *info.key, CustomError::UnregisteredPoolVault);
+    require!(!is_protocol_vault(state, info.key), CustomError::InvalidPoolVault);
+    require!(*info.owner == token::ID, CustomError::MintMismatch);
+    let vault = TokenAccount::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+    require!(vault.mint == pool.mint, CustomError::MintMismatch);
+    require!(is_pool_authority(&vault, authority), CustomError::InvalidPoolAuthority);
+    Ok(vault)
+}
+
+/// Whether `authority` may move tokens out of a vault: either it signed, or it is the
+/// `vault_authority` PDA of `global_state`, which the program signs for.
+pub fn is_vault_signer(state: &GlobalState, global_state: &Pubkey, authority: &AccountInfo) -> bool {
+    if authority.is_signer {
+        return true;
+    }
+    let seeds: &[&[u8]] = &[b"vault_authority", global_state.as_ref(), &[state.vault_authority_bump]];
+    state.canonical_vaults && Pubkey::create_program_address(seeds, &crate::ID).ok() == Some(*authority.key)
+}
+
+/// Whether `borrower` may take a flash loan at `now`. An empty whitelist admits everyone;
+/// otherwise the borrower must be listed, be owned by a listed program, or have reached
+/// the auto-whitelist threshold. Entries past their expiry admit no one.
+pub fn is_borrower_allowed(state: &GlobalState, borrower: &Pubkey, borrower_owner: &Pubkey, reputation: u64, now: i64) -> bool {
+    if state.flash_loan_whitelist.is_empty() {
+        return true;
+    }
+    let listed = state.flash_loan_whitelist.iter().enumerate().any(|(i, entry)| {
+        let expiry = whitelist_entry_expiry(state, i);
+        if expiry != 0 && now > expiry {
+            return false;
+        }
+        match whitelist_entry_type(state, i) {
+            WHITELIST_ENTRY_PROGRAM => entry == borrower_owner,
+            _ => entry == borrower,
+        }
+    });
+    if listed {
+        return true;
+    }
+    state.auto_whitelist_threshold > 0 && reputation >= state.auto_whitelist_threshold
+}
+
+/// Type of the whitelist entry at `index`. Entries listed before types were recorded
+/// are key entries.
+pub fn whitelist_entry_type(state: &GlobalState, index: usize) -> u8 {
+    state.whitelist_entry_types.get(index).copied().unwrap_or(WHITELIST_ENTRY_KEY)
+}
+
+/// Expiry of the whitelist entry at `index` (0 = none). Entries listed before expiries
+/// were recorded never lapse.
+pub fn whitelist_entry_expiry(state: &GlobalState, index: usize) -> i64 {
+    state.whitelist_expiries.get(index).copied().unwrap_or(0)
+}
+
+/// Lists `key` with `entry_type` until `expiry` (0 = none), updating both if it is
+/// already listed.
+pub fn set_whitelist_entry(state: &mut GlobalState, key: Pubkey, entry_type: u8, expiry: i64) -> Result<()> {
+    let len = state.flash_loan_whitelist.len();
+    state.whitelist_entry_types.resize(len, WHITELIST_ENTRY_KEY);
+    state.whitelist_expiries.resize(len, 0);
+    match state.flash_loan_whitelist.iter().position(|entry| *entry == key) {
+        Some(index) => {
+            state.whitelist_entry_types[index] = entry_type;
+            state.whitelist_expiries[index] = expiry;
+        }
+        None => {
+            require!(len < MAX_WHITELIST_LEN, CustomError::WhitelistFull);
+            state.flash_loan_whitelist.push(key);
+            state.whitelist_entry_types.push(entry_type);
+            state.whitelist_expiries.push(expiry);
+        }
+    }
+    Ok(())
+}
+
+/// Whether borrowing `amount` in one transaction stays within `per_tx_loan_cap`.
+pub fn within_loan_cap(state: &GlobalState, amount: u64) -> bool {
+    state.per_tx_loan_cap == 0 || amount <= state.per_tx_loan_cap
+}
+
+/// Whether a borrower with `reputation` may borrow `amount` under the reputation caps:
+/// `rep_base_cap + reputation * rep_cap_per_point`, limited to `rep_cap_ceiling` when set.
+pub fn within_reputation_cap(state: &GlobalState, reputation: u64, amount: u64) -> bool {
+    if state.rep_base_cap == 0 {
+        return true;
+    }
+    let mut cap = state.rep_base_cap.saturating_add(reputation.saturating_mul(state.rep_cap_per_point));
+    if state.rep_cap_ceiling > 0 {
+        cap = cap.min(state.rep_cap_ceiling);
+    }
+    amount <= cap
+}
+
+/// Whether withdrawing `amount` leaves `total_liquidity` covering what is lent out plus the
+/// `reserve_bps` reserve. Loans are not tracked in aggregate, so the amount lent out is taken
+/// as the liquidity missing from the pool account (`pool_balance`).
+pub fn within_reserve(state: &GlobalState, pool_balance: u64, amount: u64) -> bool {
+    let outstanding = state.total_liquidity.saturating_sub(pool_balance) as u128;
+    let reserve = (state.total_liquidity as u128).checked_mul(state.reserve_bps as u128).unwrap() / BPS_DENOMINATOR as u128;
+    state.total_liquidity.saturating_sub(amount) as u128 >= outstanding + reserve
+}
+
+/// Whether unstaking at `now` from a position opened at `opened_at` is early and so penalized.
+pub fn is_early_unstake(state: &GlobalState, opened_at: i64, now: i64) -> bool {
+    state.early_unstake_period > 0 && now < opened_at.saturating_add(state.early_unstake_period)
+}
+
+/// Penalty on an early unstake of `amount`: `unstake_penalty_bps` of it rounded up, so small
+/// chunks cannot round it away, and at least `min_unstake_penalty`, but never more than `amount`.
+pub fn unstake_penalty(state: &GlobalState, amount: u64) -> u64 {
+    compute_fee(amount, state.unstake_penalty_bps, true)
+        .max(state.min_unstake_penalty)
+        .min(amount)
+}
+
+/// Whether a position balance of `amount` is dust to be swept and closed.
+pub fn is_dust(state: &GlobalState, amount: u64) -> bool {
+    state.dust_threshold > 0 && amount < state.dust_threshold
+}
+
+/// Whether escrowing `collateral` against a loan of `amount` stays within `max_collateral_bps`.
+pub fn within_collateral_cap(state: &GlobalState, amount: u64, collateral: u64) -> bool {
+    let cap = (amount as u128).checked_mul(state.max_collateral_bps as u128).unwrap() / BPS_DENOMINATOR as u128;
+    state.max_collateral_bps == 0 || collateral as u128 <= cap
+}
+
+/// Checks a co-signing admin set: at most `MAX_ADMINS` distinct keys and a threshold they
+/// can meet. An empty set with a zero threshold hands control back to the single admin.
+pub fn validate_admins(admins: &[Pubkey], threshold: u8) -> Result<()> {
+    require!(admins.len() <= MAX_ADMINS, CustomError::TooManyAdmins);
+    require!(
+        admins.iter().enumerate().all(|(i, key)| !admins[..i].contains(key)),
+        CustomError::InvalidAdminSet
+    );
+    let valid_threshold = if admins.is_empty() { threshold == 0 } else { threshold > 0 && threshold as usize <= admins.len() };
+    require!(valid_threshold, CustomError::InvalidAdminSet);
+    Ok(())
+}
+
+/// Checks a fee schedule: at most `MAX_FEE_TIERS` tiers, with strictly increasing
+/// thresholds and rates no higher than `BPS_DENOMINATOR`.
+pub fn validate_fee_tiers(tiers: &[FeeTier]) -> Result<()> {
+    require!(tiers.len() <= MAX_FEE_TIERS, CustomError::TooManyFeeTiers);
+    require!(
+        tiers.windows(2).all(|pair| pair[0].threshold < pair[1].threshold),
+        CustomError::InvalidFeeTiers
+    );
+    require!(tiers.iter().all(|tier| tier.fee_bps <= BPS_DENOMINATOR), CustomError::InvalidFeeTiers);
+    Ok(())
+}
+
+/// Checks a fee model: a flat fee must be nonzero and rates must stay within `BPS_DENOMINATOR`.
+pub fn validate_fee_model(fee_model: FeeModel) -> Result<()> {
+    require!(fee_model != FeeModel::FixedAbsolute(0), CustomError::InvalidFixedFee);
+    if let FeeModel::Utilization { base_bps, slope_bps } = fee_model {
+        require!(base_bps.saturating_add(slope_bps) <= BPS_DENOMINATOR, CustomError::InvalidBps);
+    }
+    if let FeeModel::TimeBased { max_fee_bps } = fee_model {
+        require!(max_fee_bps <= BPS_DENOMINATOR, CustomError::InvalidBps);
+    }
+    Ok(())
+}
+
+/// Checks a fee split: the LP and staker shares may not add up to more than the whole fee.
+pub fn validate_fee_split(lp_share_bps: u64, staker_share_bps: u64) -> Result<()> {
+    require!(
+        lp_share_bps.checked_add(staker_share_bps).unwrap() <= BPS_DENOMINATOR,
+        CustomError::InvalidBps
+    );
+    Ok(())
+}
+
+/// Runs the checks the matching single-admin instruction applies to its arguments, so a
+/// proposal that could never execute is rejected up front.
+pub fn validate_admin_action(action: &AdminActionKind) -> Result<()> {
+    match action {
+        AdminActionKind::UpdateFeeRate(fee_rate) | AdminActionKind::UpdatePoolFeeRate { fee_rate, .. } => {
+            require!(*fee_rate <= BPS_DENOMINATOR, CustomError::InvalidBps);
+            Ok(())
+        }
+        AdminActionKind::SetAdmins { admins, threshold } => validate_admins(admins, *threshold),
+        AdminActionKind::SetFeeTiers(tiers) => validate_fee_tiers(tiers),
+        AdminActionKind::SetFeeModel(fee_model) => validate_fee_model(*fee_model),
+        AdminActionKind::SetFeeSplit { lp_share_bps, staker_share_bps } => {
+            validate_fee_split(*lp_share_bps, *staker_share_bps)
+        }
+        AdminActionKind::WithdrawProtocolLiquidity { amount, .. } => {
+            require!(*amount > 0, CustomError::ZeroAmount);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fails unless `admin_action` is still pending and approved by `admin_threshold` current
+/// admins. Approvals from keys since removed from `admins` no longer count.
+pub fn check_admin_approvals(state: &GlobalState, admin_action: &AdminAction) -> Result<()> {
+    require!(!admin_action.executed, CustomError::ActionAlreadyExecuted);
+    let approvals = admin_action.approvals.iter().filter(|key| state.admins.contains(key)).count();
+    require!(approvals >= state.admin_threshold as usize, CustomError::ThresholdNotMet);
+    Ok(())
+}
+
+/// Authorizes an instruction that moves tokens, and so runs with its own accounts rather than
+/// through `execute_admin_action`. Without co-signing there is nothing to check; with it, the
+/// call needs an approved `admin_action` for exactly `kind`, which it marks executed.
+pub fn consume_admin_action(
+    state: &GlobalState,
+    admin_action: Option<&mut Account<AdminAction>>,
+    kind: &AdminActionKind,
+) -> Result<()> {
+    if state.admin_threshold == 0 {
+        return Ok(());
+    }
+    let admin_action = admin_action.ok_or(error!(CustomError::MultisigRequired))?;
+    check_admin_approvals(state, admin_action)?;
+    require!(admin_action.action == *kind, CustomError::ActionMismatch);
+    admin_action.executed = true;
+    Ok(())
+}
+
+/// Starts the wind-down on behalf of `admin`, who is recorded in the audit and event.
+pub fn wind_down(state: &mut GlobalState, admin: Pubkey) -> Result<()> {
+    audit(admin, AuditAction::InitiateWindDown, state.winding_down, true)?;
+    state.winding_down = true;
+    if events_enabled(state) {
+        emit!(WindDownInitiated {
+            admin,
+            total_liquidity: state.total_liquidity,
+            total_staked: state.total_staked,
+        });
+    }
+    Ok(())
+}
+
+/// Whether collateral moving between an escrow in `escrow_mint` and a borrower account in
+/// `account_mint` is accepted for a pool in `pool_mint`: both must be in the configured
+/// `accepted_collateral_mint`, or the pool's own mint while none is set.
+pub fn is_accepted_collateral(state: &GlobalState, pool_mint: &Pubkey, escrow_mint: &Pubkey, account_mint: &Pubkey) -> bool {
+    let accepted = if state.accepted_collateral_mint == Pubkey::default() {
+        *pool_mint
+    } else {
+        state.accepted_collateral_mint
+    };
+    *escrow_mint == accepted && *account_mint == accepted
+}
+
+/// Resets settings a partially migrated or corrupted state could hold in a pool-bricking form:
+/// basis-point settings beyond `BPS_DENOMINATOR` (or the liquidation cap) turn off, a fee mint
+/// without a price reverts fees to the loan mint, and an emission cap without an epoch length
+/// is lifted. Settings already in range are left alone.
+pub fn apply_safe_defaults(state: &mut GlobalState) {
+    for bps in [
+        &mut state.rebate_cap_bps,
+        &mut state.staker_fee_bps,
+        &mut state.min_effective_fee_bps,
+        &mut state.burn_share_bps,
+        &mut state.reserve_bps,
+        &mut state.util_smoothing_bps,
+        &mut state.unstake_penalty_bps,
+    ] {
+        if *bps > BPS_DENOMINATOR {
+            *bps = 0;
+        }
+    }
+    if state.liquidation_incentive_bps > MAX_LIQUIDATION_INCENTIVE_BPS {
+        state.liquidation_incentive_bps = 0;
+    }
+    if state.lp_share_bps.saturating_add(state.staker_share_bps) > BPS_DENOMINATOR {
+        state.lp_share_bps = 0;
+        state.staker_share_bps = 0;
+    }
+    if state.fee_mint.is_some() && state.fee_mint_price == 0 {
+        state.fee_mint = None;
+    }
+    if state.epoch_emission_cap > 0 && state.emission_epoch_length <= 0 {
+        state.epoch_emission_cap = 0;
+    }
+    if !(0..=MAX_UNPAUSE_DELAY).contains(&state.unpause_delay) {
+        state.unpause_delay = 0;
+    }
+    if state.early_unstake_period < 0 {
+        state.early_unstake_period = 0;
+    }
+}
+
+/// The key holding `role`, falling back to the admin while the role is unset
+/// (as it is on states migrated from before the role existed).
+pub fn role_holder(state: &GlobalState, role: Pubkey) -> Pubkey {
+    if role == Pubkey::default() {
+        state.admin
+    } else {
+        role
+    }
+}
+
+//
+// Solvency
+//
+
+/// Fails unless the stake vault physically holds at least `total_staked`.
+/// Compounded rewards are counted in `total_staked` once moved into the vault, and any
+/// other tokens in the vault are surplus, so holding more than `total_staked` is fine.
+pub fn assert_stake_solvency(stake_vault: &TokenAccount, state: &GlobalState) -> Result<()> {
+    require!(stake_vault.amount >= state.total_staked, CustomError::StakeVaultInsolvent);
+    Ok(())
+}
+
+/// Fails if a position's `reward_debt` exceeds the rewards it has accrued. The accumulator
+/// only grows, so this means the accounting is corrupt; settling would otherwise hide it.
+pub fn assert_reward_debt(accrued: u64, reward_debt: u64) -> Result<()> {
+    require!(accrued >= reward_debt, CustomError::RewardAccountingError);
+    Ok(())
+}
+
+//
+// Versioning
+//
+
+/// Whether at least `cooldown` seconds have passed since `last`.
+pub fn cooldown_elapsed(last: i64, cooldown: i64, now: i64) -> bool {
+    now >= last.saturating_add(cooldown)
+}
+
+/// Checks `user`'s claim cooldown at `now` and restarts it. A stake record created just to
+/// hold the cooldown is bound to `user` first.
+pub fn start_claim_cooldown(state: &GlobalState, user_stake: &mut UserStake, user: &Pubkey, now: i64) -> Result<()> {
+    if user_stake.owner == Pubkey::default() {
+        user_stake.owner = *user;
+        user_stake.version = USER_STAKE_VERSION;
+    }
+    require!(user_stake.version == USER_STAKE_VERSION, CustomError::UnsupportedVersion);
+    require!(cooldown_elapsed(user_stake.last_claim_timestamp, state.claim_cooldown, now), CustomError::ClaimTooSoon);
+    user_stake.last_claim_timestamp = now;
+    Ok(())
+}
+
+/// Grows a fixed-layout program account to `new_len`, topping up rent from `payer` and
+/// zeroing the new tail. Fails unless the account carries `discriminator`.
+pub fn grow_account<'info>(
+    account: &AccountInfo<'info>,
+    discriminator: &[u8; 8],
+    new_len: usize,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+) -> Result<()> {
+    require!(
+        account.try_borrow_data()?.get(..8) == Some(discriminator.as_ref()),
+        CustomError::UnsupportedVersion
+    );
+    if account.data_len() < new_len {
+        let rent_due = Rent::get()?.minimum_balance(new_len).saturating_sub(account.lamports());
+        if rent_due > 0 {
+            let cpi_accounts = system_program::Transfer {
+                from: payer.to_account_info(),
+                to: account.clone(),
+            };
+            let cpi_ctx = CpiContext::new(system_program.to_account_info(), cpi_accounts);
+            system_program::transfer(cpi_ctx, rent_due)?;
+        }
+        account.realloc(new_len, true)?;
+    }
+    Ok(())
+}
+
+/// Grows a fixed-layout program account to `new_len` (see `grow_account`) and stamps
+/// `version` into its last byte.
+pub fn migrate_account<'info>(
+    account: &AccountInfo<'info>,
+    discriminator: &[u8; 8],
+    new_len: usize,
+    version: u8,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+) -> Result<()> {
+    grow_account(account, discriminator, new_len, payer, system_program)?;
+    account.try_borrow_mut_data()?[new_len - 1] = version;
+    Ok(())
+}
+
+//
+// Vouchers
+//
+
+/// The message a voucher signer signs: `borrower || amount || expiry || nonce`.
+pub fn voucher_message(borrower: &Pubkey, amount: u64, expiry: i64, nonce: u64) -> Vec<u8> {
+    let mut message = Vec::with_capacity(56);
+    message.extend_from_slice(borrower.as_ref());
+    message.extend_from_slice(&amount.to_le_bytes());
+    message.extend_from_slice(&expiry.to_le_bytes());
+    message.extend_from_slice(&nonce.to_le_bytes());
+    message
+}
+
+/// True if `ix` is an Ed25519 program instruction verifying exactly one signature by
+/// `signer` over `message`, with all data carried in the instruction itself.
+pub fn verifies_ed25519(ix: &Instruction, signer: &Pubkey, message: &[u8]) -> bool {
+    // Layout: count (u8), padding (u8), then seven u16 offsets per signature.
+    let data = &ix.data;
+    if ix.program_id != ed25519_program::ID || data.len() < 16 || data[0] != 1 {
+        return false;
+    }
+    let read_u16 = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+    let in_this_ix = [4, 8, 14].iter().all(|at| read_u16(*at) == u16::MAX);
+    let key_start = read_u16(6) as usize;
+    let message_start = read_u16(10) as usize;
+    let message_end = message_start + read_u16(12) as usize;
+    in_this_ix
+        && data.get(key_start..key_start + 32) == Some(signer.as_ref())
+        && data.get(message_start..message_end) == Some(message)
+}
+
+//
+// Liquidity Shares
+//
+
+/// LP shares minted for depositing `amount` into a pool whose LP-owned value is `lp_value`.
+/// Rounds down, so dust stays with the pool. The first deposit mints `amount - MINIMUM_LIQUIDITY`
+/// (the remainder is locked), and a deposit too small to mint a share is rejected.
+pub fn shares_for_deposit(amount: u64, total_shares: u64, lp_value: u64) -> Result<u64> {
+    let shares = if total_shares == 0 {
+        amount.checked_sub(MINIMUM_LIQUIDITY).ok_or(CustomError::ZeroShares)?
+    } else {
+        require!(lp_value > 0, CustomError::ZeroShares);
+        ((amount as u128).checked_mul(total_shares as u128).unwrap() / lp_value as u128) as u64
+    };
+    require!(shares > 0, CustomError::ZeroShares);
+    Ok(shares)
+}
+
+/// LP shares burned to withdraw `amount` tokens. Rounds up, so the withdrawer never
+/// receives more than their shares are worth.
+pub fn shares_for_withdrawal(amount: u64, total_shares: u64, lp_value: u64) -> Result<u64> {
+    require!(lp_value > 0, CustomError::InsufficientLiquidity);
+    let numerator = (amount as u128).checked_mul(total_shares as u128).unwrap();
+    let shares = numerator.checked_add(lp_value as u128 - 1).unwrap() / lp_value as u128;
+    Ok(shares as u64)
+}
+
+/// The part of `position`'s principal behind `shares` of it, pro rata and rounding down,
+/// so burning every share releases exactly `position.amount`.
+pub fn principal_for_shares(position: &ProviderPosition, shares: u64) -> u64 {
+    if shares >= position.shares {
+        return position.amount;
+    }
+    ((position.amount as u128).checked_mul(shares as u128).unwrap() / position.shares as u128) as u64
+}
+
+/// What `shares` redeem for out of `lp_value`, rounding down.
+pub fn position_value(state: &GlobalState, shares: u64, lp_value: u64) -> u64 {
+    if state.total_shares == 0 {
+        return 0;
+    }
+    ((shares as u128).checked_mul(lp_value as u128).unwrap() / state.total_shares as u128) as u64
+}
+
+/// LP fees not yet unlocked at `now`. Each fee unlocks linearly over `fee_unlock_period`,
+/// so liquidity only earns it in proportion to how long it stays in the pool.
+pub fn locked_lp_fees(state: &GlobalState, now: i64) -> Result<u64> {
+    let elapsed = elapsed_since(state.fees_locked_at, now)?;
+    if state.fee_unlock_period <= 0 || elapsed >= state.fee_unlock_period {
+        return Ok(0);
+    }
+    let remaining = (state.fee_unlock_period - elapsed) as u128;
+    Ok(((state.locked_fees as u128).checked_mul(remaining).unwrap() / state.fee_unlock_period as u128) as u64)
+}
+
+/// Adds `fee` to the locked LP fees, restarting the unlock from `now`.
+pub fn lock_lp_fees(state: &mut GlobalState, fee: u64, now: i64) -> Result<()> {
+    state.locked_fees = locked_lp_fees(state, now)?.checked_add(fee).unwrap();
+    state.fees_locked_at = now;
+    Ok(())
+}
+
+/// Splits `fee` into its (LP, staker, treasury) portions, or `None` when no three-way
+/// split is configured. Rounding dust falls to the treasury.
+pub fn fee_split(state: &GlobalState, fee: u64) -> Option<(u64, u64, u64)> {
+    if state.lp_share_bps == 0 && state.staker_share_bps == 0 {
+        return None;
+    }
+    let lp_fee = ((fee as u128).checked_mul(state.lp_share_bps as u128).unwrap() / BPS_DENOMINATOR as u128) as u64;
+    let staker_fee = ((fee as u128).checked_mul(state.staker_share_bps as u128).unwrap() / BPS_DENOMINATOR as u128) as u64;
+    let treasury_fee = fee.checked_sub(lp_fee).unwrap().checked_sub(staker_fee).unwrap();
+    Some((lp_fee, staker_fee, treasury_fee))
+}
+
+/// Value redeemable by LP shares: the pool balance less POL and fees still locked.
+pub fn lp_value(state: &GlobalState, pool_balance: u64, now: i64) -> Result<u64> {
+    Ok(pool_balance
+        .saturating_sub(state.protocol_owned_liquidity)
+        .saturating_sub(locked_lp_fees(state, now)?))
+}
+
+/// The share of `fee` owed to LPs rather than protocol-owned liquidity.
+pub fn lp_fee_share(state: &GlobalState, fee: u64) -> u64 {
+    if state.total_liquidity == 0 {
+        return 0;
+    }
+    let lp_liquidity = state.total_liquidity.saturating_sub(state.protocol_owned_liquidity);
+    ((fee as u128).checked_mul(lp_liquidity as u128).unwrap() / state.total_liquidity as u128) as u64
+}
+
+/// The position's pro-rata slice of `lp_vault_fees` by share-seconds, which must be accrued first.
+pub fn accrued_lp_fees(state: &GlobalState, position: &ProviderPosition) -> u64 {
+    let fees = state.lp_vault_fees as u128;
+    let mut whole = state.total_share_seconds;
+    let mut part = position.share_seconds.min(whole);
+    // Only the ratio matters, so scale both integrals down until the product fits.
+    while part.checked_mul(fees).is_none() {
+        part >>= 1;
+        whole >>= 1;
+    }
+    if whole == 0 {
+        return 0;
+    }
+    (part * fees / whole) as u64
+}
+
+/// Accrues `shares * elapsed` into the position's time-weighted liquidity.
+pub fn accrue_position_share_seconds(position: &mut ProviderPosition, now: i64) -> Result<()> {
+    if position.last_accrual > 0 {
+        let elapsed = elapsed_since(position.last_accrual, now)? as u128;
+        let accrued = (position.shares as u128).checked_mul(elapsed).unwrap();
+        saturating_count(&mut position.share_seconds, accrued, "share_seconds");
+    }
+    position.last_accrual = now;
+    Ok(())
+}
+
+/// Accrues `total_shares * elapsed` into the pool-wide time-weighted liquidity.
+pub fn accrue_total_share_seconds(state: &mut GlobalState, now: i64) -> Result<()> {
+    if state.last_share_accrual > 0 {
+        let elapsed = elapsed_since(state.last_share_accrual, now)? as u128;
+        let accrued = (state.total_shares as u128).checked_mul(elapsed).unwrap();
+        saturating_count(&mut state.total_share_seconds, accrued, "total_share_seconds");
+    }
+    state.last_share_accrual = now;
+    Ok(())
+}
+
+//
+// Analytics
+//
+
+/// Adds `amount` to a lifetime analytics counter, saturating at `u128::MAX` instead of
+/// failing. Emits `CounterSaturated` the first time the counter reaches the maximum.
+pub fn saturating_count(counter: &mut u128, amount: u128, name: &str) {
+    if *counter == u128::MAX {
+        return;
+    }
+    *counter = counter.saturating_add(amount);
+    if *counter == u128::MAX {
+        emit!(CounterSaturated { counter: name.to_string() });
+    }
+}
+
+/// Emits `AdminAudit` for a privileged change. Unlike other events this is not gated on
+/// `event_level`, so turning events off cannot hide configuration changes.
+pub fn audit(
+    actor: Pubkey,
+    action: AuditAction,
+    old_value: impl Into<AuditValue>,
+    new_value: impl Into<AuditValue>,
+) -> Result<()> {
+    emit!(AdminAudit {
+        actor,
+        action,
+        old_value: old_value.into(),
+        new_value: new_value.into(),
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+    Ok(())
+}
+
+//
+// Reputation
+//
+
+/// Reputation earned for repaying a loan of `amount`.
+pub fn reputation_gain(state: &GlobalState, amount: u64) -> u64 {
+    let buckets = match state.reputation_size_bucket {
+        0 => 0,
+        size_bucket => amount / size_bucket,
+    };
+    state.reputation_per_loan.saturating_mul(buckets.saturating_add(1))
+}
+
+//
+// Loan Lifecycle
+//
+
+/// Limits every new loan is held to: lending is open and the loan fits the per-transaction
+/// and collateral caps.
+pub fn check_loan_limits(state: &GlobalState, amount: u64, collateral: u64) -> Result<()> {
+    require!(amount > 0, CustomError::ZeroAmount);
+    require!(!state.paused, CustomError::ProgramPaused);
+    require!(!state.winding_down, CustomError::WindingDown);
+    require!(within_loan_cap(state, amount), CustomError::PerTxCapExceeded);
+    require!(within_collateral_cap(state, amount, collateral), CustomError::ExcessiveCollateral);
+    Ok(())
+}
+
+/// Screens the borrower of a new loan: the whitelist (or its reputation bypass), the
+/// reputation-scaled cap and, when one is configured, the compliance hook.
+pub fn screen_borrower<'info>(
+    state: &GlobalState,
+    borrower: &AccountInfo<'info>,
+    compliance_program: Option<&AccountInfo<'info>>,
+    reputation: u64,
+    amount: u64,
+    now: i64,
+) -> Result<()> {
+    let allowed = is_borrower_allowed(state, borrower.key, borrower.owner, reputation, now);
+    require!(allowed, CustomError::NotWhitelisted);
+    require!(within_reputation_cap(state, reputation, amount), CustomError::ReputationCapExceeded);
+    if let Some(hook) = state.compliance_hook {
+        let hook_program = compliance_program.ok_or(CustomError::ComplianceRejected)?;
+        require!(*hook_program.key == hook, CustomError::ComplianceRejected);
+        let mut data = Vec::with_capacity(40);
+        data.extend_from_slice(borrower.key.as_ref());
+        data.extend_from_slice(&amount.to_le_bytes());
+        let hook_ix = Instruction {
+            program_id: hook,
+            accounts: vec![AccountMeta::new_readonly(*borrower.key, false)],
+            data,
+        };
+        invoke(&hook_ix, &[borrower.clone(), hook_program.clone()])?;
+        // The hook approves by returning a single nonzero byte.
+        let approved = matches!(
+            get_return_data(),
+            Some((program_id, ret)) if program_id == hook && ret.first().is_some_and(|b| *b != 0)
+        );
+        require!(approved, CustomError::ComplianceRejected);
+    }
+    Ok(())
+}
+
+/// Terms a loan of `amount` from `pool` is issued on. A reservation's locked-in terms and
+/// prepaid fee take precedence; otherwise the loan is priced by `effective_fee`, and a
+/// voucher snapshots no rate at all.
+pub fn issue_terms(
+    state: &GlobalState,
+    pool: &Pool,
+    reservation: Option<&LoanReservation>,
+    borrower_ctx: &BorrowerFeeContext,
+    amount: u64,
+) -> Result<LoanTerms> {
+    if let Some(reservation) = reservation {
+        return Ok(LoanTerms {
+            amount,
+            fee_rate: reservation.fee_rate,
+            fixed_fee: reservation.fixed_fee,
+            time_priced: reservation.time_priced,
+            fee_prepaid: true,
+            fee: reservation.prepaid_fee,
+            subsidy: 0,
+        });
+    }
+    let (fee_rate, fixed_fee) = if borrower_ctx.voucher { (0, 0) } else { quote_terms(state, Some(pool), amount) };
+    let breakdown = effective_fee(state, Some(pool), borrower_ctx, amount, None)?;
+    Ok(LoanTerms {
+        amount,
+        fee_rate,
+        fixed_fee,
+        time_priced: !borrower_ctx.voucher && matches!(state.fee_model, FeeModel::TimeBased { .. }),
+        fee_prepaid: false,
+        fee: breakdown.net.checked_add(breakdown.subsidy).unwrap(),
+        subsidy: breakdown.subsidy,
+    })
+}
+
+/// Marks `borrower`'s new loan of `amount` as the active one.
+pub fn activate_loan(state: &mut GlobalState, borrower: Pubkey, amount: u64) {
+    state.is_flash_loan_active = true;
+    state.active_borrower = borrower;
+    // The loan was priced on the average before it; only now is it sampled.
+    let utilization = spot_utilization(state, amount);
+    update_util_ema(state, utilization);
+}
+
+/// Works out how a loan repaid after `elapsed` seconds settles. A time-priced loan's
+/// recorded fee is pro-rated in place, so `flash_loan_state` must be saved afterwards.
+pub fn settle_loan(state: &GlobalState, flash_loan_state: &mut FlashLoanState, stake: u64, elapsed: i64) -> Settlement {
+    // A loan already partly repaid settles at its quoted fee.
+    let prorated = flash_loan_state.time_priced && flash_loan_state.repaid == 0;
+    let prepaid_refund = if prorated {
+        let floor = apply_fee_floor(state, flash_loan_state.amount, 0);
+        let quoted = flash_loan_state.fee;
+        flash_loan_state.fee = prorate_fee(quoted, elapsed).max(floor).min(quoted);
+        if flash_loan_state.fee_prepaid { quoted - flash_loan_state.fee } else { 0 }
+    } else {
+        0
+    };
+    let (outstanding, rebate, fee_in_fee_mint) = repayment_due(state, flash_loan_state, stake);
+    // Charge the rate quoted at borrow time, even if `fee_rate` has since changed.
+    let amount = flash_loan_state.amount;
+    let quoted_fee = fee_for_terms(amount, flash_loan_state.fee_rate, flash_loan_state.fixed_fee, state.round_fees_up);
+    let fee = apply_fee_floor(state, amount, if prorated { prorate_fee(quoted_fee, elapsed) } else { quoted_fee })
+        .saturating_sub(rebate);
+    // With a three-way split configured, a pool-paid fee is divided between LPs, stakers
+    // and the treasury by the configured ratios, replacing the POL and staker routing below.
+    let fee_paid_to_pool = fee_in_fee_mint == 0 && !flash_loan_state.fee_prepaid;
+    let split = if fee_paid_to_pool { fee_split(state, fee) } else { None };
+    // Forward the protocol-owned share of a pool-paid fee to the treasury.
+    let pol_fee = if let Some((_, _, treasury_fee)) = split {
+        treasury_fee
+    } else if state.route_pol_fees_to_treasury && fee_paid_to_pool && state.total_liquidity > 0 {
+        ((fee as u128).checked_mul(state.protocol_owned_liquidity as u128).unwrap() / state.total_liquidity as u128) as u64
+    } else {
+        0
+    };
+    // Set the stakers' share of the rest aside in the reward vault for the next crank.
+    let staker_fee = if let Some((_, staker_fee, _)) = split {
+        staker_fee
+    } else if fee_paid_to_pool {
+        let lp_fee = fee.checked_sub(pol_fee).unwrap();
+        ((lp_fee as u128).checked_mul(state.staker_fee_bps as u128).unwrap() / BPS_DENOMINATOR as u128) as u64
+    } else {
+        0
+    };
+    Settlement {
+        outstanding,
+        fee,
+        fee_in_fee_mint,
+        fee_prepaid: flash_loan_state.fee_prepaid,
+        fee_paid_to_pool,
+        prepaid_refund,
+        split,
+        pol_fee,
+        staker_fee,
+    }
+}
+
+/// Moves a settled fee where `settlement` routes it: a fee owed in the fee mint is partly
+/// burned and the rest paid into the fee vault, the treasury's and stakers' shares of a
+/// pool-paid fee leave the pool, and the unused part of a prepaid fee is refunded.
+pub fn pay_settlement_fees(
+    accounts: &SettlementAccounts,
+    state: &GlobalState,
+    global_state_key: &Pubkey,
+    settlement: &Settlement,
+) -> Result<()> {
+    let bump = [state.vault_authority_bump];
+    let signer_seeds: &[&[&[u8]]] = &[&[b"vault_authority", global_state_key.as_ref(), &bump]];
+    if settlement.fee_in_fee_mint > 0 {
+        let fee_mint = state.fee_mint.unwrap();
+        let fee_vault = accounts.fee_vault.ok_or(CustomError::FeeMintMismatch)?;
+        let borrower_fee_account = accounts.borrower_fee_account.ok_or(CustomError::FeeMintMismatch)?;
+        require!(fee_vault.mint == fee_mint, CustomError::FeeMintMismatch);
+        require!(borrower_fee_account.mint == fee_mint, CustomError::FeeMintMismatch);
+        // Burn the configured share straight from the payer's fee account; only the rest
+        // reaches the fee vault.
+        let burn_amount = ((settlement.fee_in_fee_mint as u128)
+            .checked_mul(state.burn_share_bps as u128)
+            .unwrap()
+            / BPS_DENOMINATOR as u128) as u64;
+        if burn_amount > 0 {
+            let fee_mint_account = accounts.fee_mint_account.ok_or(CustomError::FeeBurnUnavailable)?;
+            require!(fee_mint_account.key() == fee_mint, CustomError::FeeMintMismatch);
+            let cpi_accounts = Burn {
+                mint: fee_mint_account.to_account_info(),
+                from: borrower_fee_account.to_account_info(),
+                authority: accounts.payer.clone(),
+            };
+            token::burn(CpiContext::new(accounts.token_program.clone(), cpi_accounts), burn_amount)?;
+        }
+        let vault_amount = settlement.fee_in_fee_mint.checked_sub(burn_amount).unwrap();
+        if vault_amount > 0 {
+            let cpi_accounts = Transfer {
+                from: borrower_fee_account.to_account_info(),
+                to: fee_vault.to_account_info(),
+                authority: accounts.payer.clone(),
+            };
+            token::transfer(CpiContext::new(accounts.token_program.clone(), cpi_accounts), vault_amount)?;
+        }
+    }
+    if settlement.pol_fee > 0 {
+        let treasury = accounts.treasury_token_account.ok_or(CustomError::InvalidTreasury)?;
+        let cpi_accounts = Transfer {
+            from: accounts.pool_account.clone(),
+            to: treasury.to_account_info(),
+            authority: accounts.pool_authority.clone(),
+        };
+        let transfer_ctx = CpiContext::new_with_signer(accounts.token_program.clone(), cpi_accounts, signer_seeds);
+        token::transfer(transfer_ctx, settlement.pol_fee)?;
+    }
+    if settlement.staker_fee > 0 {
+        let reward_vault = accounts.reward_vault.ok_or(CustomError::RewardVaultRequired)?;
+        let cpi_accounts = Transfer {
+            from: accounts.pool_account.clone(),
+            to: reward_vault.to_account_info(),
+            authority: accounts.pool_authority.clone(),
+        };
+        let transfer_ctx = CpiContext::new_with_signer(accounts.token_program.clone(), cpi_accounts, signer_seeds);
+        token::transfer(transfer_ctx, settlement.staker_fee)?;
+    }
+    // The fee vault shares the pool's vault authority.
+    if settlement.prepaid_refund > 0 {
+        let fee_vault = accounts.fee_vault.ok_or(CustomError::FeeVaultRequired)?;
+        let cpi_accounts = Transfer {
+            from: fee_vault.to_account_info(),
+            to: accounts.borrower_token_account.clone(),
+            authority: accounts.pool_authority.clone(),
+        };
+        let transfer_ctx = CpiContext::new_with_signer(accounts.token_program.clone(), cpi_accounts, signer_seeds);
+        token::transfer(transfer_ctx, settlement.prepaid_refund)?;
+    }
+    Ok(())
+}
+
+/// Books a settled loan of `principal`: the protocol's, LPs' and stakers' shares of the fee,
+/// the pool's counters, and the end of the active loan.
+pub fn book_settlement(state: &mut GlobalState, pool: &mut Pool, principal: u64, settlement: &Settlement, now: i64) -> Result<()> {
+    let fee = settlement.fee;
+    // Under a split only the treasury's portion is booked as protocol fees. Of a
+    // prepaid fee, the LP-owned share stays in the fee vault for `compound_lp_fees`.
+    let booked_fee = settlement.split.map_or(fee, |(_, _, treasury_fee)| treasury_fee);
+    let lp_vault_fee = if settlement.fee_prepaid { lp_fee_share(state, fee) } else { 0 };
+    state.accumulated_fees = state.accumulated_fees.checked_add(booked_fee - lp_vault_fee).unwrap();
+    state.lp_vault_fees = state.lp_vault_fees.checked_add(lp_vault_fee).unwrap();
+    saturating_count(&mut state.total_loan_volume, principal as u128, "total_loan_volume");
+    // The LP share of a pool-paid fee unlocks gradually rather than all at once.
+    if settlement.fee_paid_to_pool {
+        let lp_fee = fee.checked_sub(settlement.pol_fee).unwrap().checked_sub(settlement.staker_fee).unwrap();
+        lock_lp_fees(state, lp_fee, now)?;
+    }
+    // A split credits stakers immediately; otherwise their share waits for the crank.
+    if settlement.split.is_some() && state.total_reward_weight > 0 {
+        update_emissions(state, now);
+        let increment = (settlement.staker_fee as u128).checked_mul(REWARD_PRECISION).unwrap() / state.total_reward_weight as u128;
+        state.acc_reward_per_share = state.acc_reward_per_share.checked_add(increment).unwrap();
+    } else {
+        state.pending_staker_fees = state.pending_staker_fees.checked_add(settlement.staker_fee).unwrap();
+        state.staker_fees_accrued = state.staker_fees_accrued.checked_add(settlement.staker_fee).unwrap();
+    }
+    state.is_flash_loan_active = false;
+    state.active_borrower = Pubkey::default();
+    // Nothing is lent out once the loan is closed.
+    update_util_ema(state, 0);
+    // Attribute the fee to the pool the loan was drawn from.
+    pool.accumulated_fees = pool.accumulated_fees.checked_add(fee).unwrap();
+    pool.total_loans = pool.total_loans.checked_add(1).unwrap();
+    Ok(())
+}
+
+//
+// Fee Accounting
+//
+
+/// Fee rate (bps) for a loan of `amount`: the highest fee tier it reaches, else `fee_rate`.
+pub fn fee_rate_for(state: &GlobalState, pool: Option<&Pool>, amount: u64) -> u64 {
+    state
+        .fee_tiers
+        .iter()
+        .rev()
+        .find(|tier| amount >= tier.threshold)
+        .map_or(pool.map_or(state.fee_rate, |pool| pool.fee_rate), |tier| tier.fee_bps)
+}
+
+/// Terms quoted for a loan of `amount` under the current fee model, as `(fee_rate, fixed_fee)`.
+/// Exactly one of them applies: a flat fee is quoted with a zero rate and vice versa.
+pub fn quote_terms(state: &GlobalState, pool: Option<&Pool>, amount: u64) -> (u64, u64) {
+    match state.fee_model {
+        FeeModel::Proportional => (fee_rate_for(state, pool, amount), 0),
+        FeeModel::FixedAbsolute(fee) => (0, fee),
+        FeeModel::Utilization { base_bps, slope_bps } => {
+            let premium = (slope_bps as u128).checked_mul(state.util_ema as u128).unwrap() / BPS_DENOMINATOR as u128;
+            (base_bps.checked_add(premium as u64).unwrap(), 0)
+        }
+        FeeModel::TimeBased { max_fee_bps } => (max_fee_bps, 0),
+    }
+}
+
+/// The full fee pipeline for a loan of `amount`, shared by `flash_loan` and `quote_fee` so
+/// quotes and charges cannot diverge. The base fee on the quoted terms is waived by a
+/// voucher, raised to the floor and partly covered by the subsidy vault, which is what
+/// `flash_loan` records. With `elapsed` set, the adjustments made on repaying after that
+/// many seconds follow, as `repay_flash_loan` applies them: time-based pro-rating and the
+/// staking rebate, neither taking the fee below the floor.
+pub fn effective_fee(
+    state: &GlobalState,
+    pool: Option<&Pool>,
+    borrower_ctx: &BorrowerFeeContext,
+    amount: u64,
+    elapsed: Option<i64>,
+) -> Result<FeeBreakdown> {
+    let (fee_rate, fixed_fee) = quote_terms(state, pool, amount);
+    let base = fee_for_terms(amount, fee_rate, fixed_fee, state.round_fees_up);
+    let unfloored = if borrower_ctx.voucher { 0 } else { base };
+    let fee = apply_fee_floor(state, amount, unfloored);
+    let subsidy = if borrower_ctx.subsidized {
+        fee.min(state.subsidy_per_loan).min(state.subsidy_balance)
+    } else {
+        0
+    };
+    let mut discount = base - unfloored;
+    let mut net = fee.checked_sub(subsidy).unwrap();
+    if let Some(elapsed) = elapsed {
+        let floor = apply_fee_floor(state, amount, 0);
+        if !borrower_ctx.voucher && matches!(state.fee_model, FeeModel::TimeBased { .. }) {
+            let prorated = prorate_fee(net, elapsed).max(floor).min(net);
+            discount = discount.checked_add(net - prorated).unwrap();
+            net = prorated;
+        }
+        let rebate = stake_rebate(state, borrower_ctx.stake, net).min(net.saturating_sub(floor));
+        discount = discount.checked_add(rebate).unwrap();
+        net -= rebate;
+    }
+    Ok(FeeBreakdown {
+        base,
+        discount,
+        subsidy,
+        floor_applied: fee > unfloored,
+        net,
+    })
+}
+
+/// Fee for a loan of `amount` on the quoted terms: the flat fee if one was quoted, else
+/// `compute_fee` at `fee_rate`. The floor is applied separately.
+pub fn fee_for_terms(amount: u64, fee_rate: u64, fixed_fee: u64, round_up: bool) -> u64 {
+    if fixed_fee > 0 {
+        fixed_fee
+    } else {
+        compute_fee(amount, fee_rate, round_up)
+    }
+}
+
+/// Share of a time-based `fee`, quoted for the whole `FLASH_LOAN_DURATION`, owed by a loan
+/// repaid after `elapsed` seconds. Every loan pays for at least one second.
+pub fn prorate_fee(fee: u64, elapsed: i64) -> u64 {
+    let used = elapsed.clamp(1, FLASH_LOAN_DURATION) as u128;
+    ((fee as u128).checked_mul(used).unwrap() / FLASH_LOAN_DURATION as u128) as u64
+}
+
+/// Utilization (bps) of the pool's liquidity with `outstanding` lent out, capped at 100%.
+pub fn spot_utilization(state: &GlobalState, outstanding: u64) -> u64 {
+    if state.total_liquidity == 0 {
+        return 0;
+    }
+    let utilization = (outstanding as u128).checked_mul(BPS_DENOMINATOR as u128).unwrap() / state.total_liquidity as u128;
+    utilization.min(BPS_DENOMINATOR as u128) as u64
+}
+
+/// Folds a utilization sample (bps) into `util_ema` with weight `util_smoothing_bps`, so a
+/// single transient loan moves the average only part of the way.
+pub fn update_util_ema(state: &mut GlobalState, sample: u64) {
+    let weight = state.util_smoothing_bps as u128;
+    let blended = (state.util_ema as u128)
+        .checked_mul(BPS_DENOMINATOR as u128 - weight)
+        .unwrap()
+        .checked_add((sample as u128).checked_mul(weight).unwrap())
+        .unwrap();
+    state.util_ema = (blended / BPS_DENOMINATOR as u128) as u64;
+}
+
+/// Whether instructions emit events at all under the configured `event_level`.
+pub fn events_enabled(state: &GlobalState) -> bool {
+    state.event_level != EventLevel::Off
+}
+
+/// `value` for an event's balance or fee field: under `EventLevel::Minimal` only the actor
+/// and amount are reported, so detail fields are zeroed.
+pub fn event_detail(state: &GlobalState, value: u64) -> u64 {
+    if state.event_level == EventLevel::Full {
+        value
+    } else {
+        0
+    }
+}
+
+/// Flash loan fee for `amount` at `fee_rate` basis points. Truncates in the borrower's
+/// favor unless `round_up`, in which case any remainder adds exactly one unit for LPs.
+pub fn compute_fee(amount: u64, fee_rate: u64, round_up: bool) -> u64 {
+    let product = amount.checked_mul(fee_rate).unwrap();
+    let carry = if round_up { BPS_DENOMINATOR - 1 } else { 0 };
+    product.checked_add(carry).unwrap() / BPS_DENOMINATOR
+}
+
+/// Raises `fee` on a loan of `amount` to the `min_effective_fee_bps` floor. Applied last,
+/// after tiers, vouchers and rebates.
+pub fn apply_fee_floor(state: &GlobalState, amount: u64, fee: u64) -> u64 {
+    fee.max(compute_fee(amount, state.min_effective_fee_bps, state.round_fees_up))
+}
+
+/// Part of `fee` rebated to a borrower with `stake` staked: the fee times the borrower's
+/// share of all stake, capped at `rebate_cap_bps`.
+pub fn stake_rebate(state: &GlobalState, stake: u64, fee: u64) -> u64 {
+    if state.total_staked == 0 {
+        return 0;
     }
+    let share_bps = (stake as u128).checked_mul(BPS_DENOMINATOR as u128).unwrap() / state.total_staked as u128;
+    let rebate_bps = share_bps.min(state.rebate_cap_bps as u128);
+    ((fee as u128).checked_mul(rebate_bps).unwrap() / BPS_DENOMINATOR as u128) as u64
 }
 
-#[derive(Accounts)]
-pub struct WithdrawLiquidity<'info> {
-    #[account(mut)]
-    pub global_state: Account<'info, GlobalState>,
-    #[account(mut)]
-    pub pool_account: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub provider_token_account: Account<'info, TokenAccount>,
-    /// The authority for the pool account (typically a PDA) that must sign.
-    pub pool_authority: Signer<'info>,
-    pub token_program: Program<'info, Token>,
+/// Converts a fee in loan-mint units to fee-mint units at `fee_mint_price`.
+pub fn convert_fee(fee: u64, fee_mint_price: u64) -> u64 {
+    ((fee as u128).checked_mul(fee_mint_price as u128).unwrap() / PRICE_PRECISION as u128) as u64
 }
 
-impl<'info> WithdrawLiquidity<'info> {
-    pub fn into_transfer_from_pool_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.pool_account.to_account_info().clone(),
-            to: self.provider_token_account.to_account_info().clone(),
-            authority: self.pool_authority.to_account_info().clone(),
-        };
-        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
-    }
+/// Principal plus any fee not already prepaid through a reservation.
+pub fn amount_owed(flash_loan_state: &FlashLoanState) -> u64 {
+    let fee = if flash_loan_state.fee_prepaid { 0 } else { flash_loan_state.fee };
+    flash_loan_state.amount.checked_add(fee).unwrap()
 }
 
-#[derive(Accounts)]
-pub struct Stake<'info> {
-    #[account(mut)]
-    pub global_state: Account<'info, GlobalState>,
-    #[account(mut)]
-    pub user: Signer<'info>,
-    #[account(
-        init_if_needed,
-        payer = user,
-        space = 8 + UserStake::LEN,
-        seeds = [b"user_stake", user.key.as_ref()],
-        bump
-    )]
-    pub user_stake: Account<'info, UserStake>,
-    #[account(mut)]
-    pub user_token_account: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub stake_vault: Account<'info, TokenAccount>,
-    /// The authority (often a PDA) that controls the stake vault.
-    pub stake_vault_authority: AccountInfo<'info>,
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
+/// What fully repaying a loan costs now, as `(outstanding, rebate, fee_in_fee_mint)`:
+/// the loan-mint amount still owed, the staking rebate taken off an unpaid fee, and the
+/// fee owed in the fee mint instead when fees are collected separately.
+pub fn repayment_due(state: &GlobalState, flash_loan_state: &FlashLoanState, stake: u64) -> (u64, u64, u64) {
+    // Stakers get part of an unpaid fee back in proportion to their stake, but never so
+    // much that the fee falls below the floor.
+    let rebate = if flash_loan_state.fee_prepaid {
+        0
+    } else {
+        let headroom = flash_loan_state.fee.saturating_sub(apply_fee_floor(state, flash_loan_state.amount, 0));
+        stake_rebate(state, stake, flash_loan_state.fee).min(headroom)
+    };
+    let outstanding = amount_owed(flash_loan_state).checked_sub(flash_loan_state.repaid).unwrap();
+    // With a separate fee mint, an unpaid fee goes to the fee vault in that mint instead.
+    match state.fee_mint {
+        Some(_) if !flash_loan_state.fee_prepaid => {
+            let net_fee = flash_loan_state.fee.checked_sub(rebate).unwrap();
+            (
+                outstanding.saturating_sub(flash_loan_state.fee),
+                rebate,
+                convert_fee(net_fee, state.fee_mint_price),
+            )
+        }
+        _ => (outstanding.saturating_sub(rebate), rebate, 0),
+    }
 }
 
-impl<'info> Stake<'info> {
-    pub fn into_transfer_to_stake_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.user_token_account.to_account_info().clone(),
-            to: self.stake_vault.to_account_info().clone(),
-            authority: self.user.to_account_info().clone(),
+/// Splits `outstanding` across loan sources in proportion to the principal each lent.
+/// The last source absorbs the rounding remainder so the shares sum to `outstanding`.
+pub fn repayment_shares(outstanding: u64, sources: &[LoanSource]) -> Vec<u64> {
+    let principal: u64 = sources.iter().map(|s| s.amount).sum();
+    let mut shares = Vec::with_capacity(sources.len());
+    let mut assigned: u64 = 0;
+    for (i, source) in sources.iter().enumerate() {
+        let share = if i + 1 == sources.len() {
+            outstanding.checked_sub(assigned).unwrap()
+        } else {
+            ((outstanding as u128).checked_mul(source.amount as u128).unwrap() / principal as u128) as u64
         };
-        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+        assigned = assigned.checked_add(share).unwrap();
+        shares.push(share);
     }
+    shares
 }
 
-#[derive(Accounts)]
-pub struct Unstake<'info> {
-    #[account(mut)]
-    pub global_state: Account<'info, GlobalState>,
-    #[account(mut)]
-    pub user: Signer<'info>,
-    #[account(mut, seeds = [b"user_stake", user.key.as_ref()], bump)]
-    pub user_stake: Account<'info, UserStake>,
-    #[account(mut)]
-    pub stake_vault: Account<'info, TokenAccount>,
-    /// The authority (PDA) controlling the stake vault.
-    pub stake_vault_authority: Signer<'info>,
-    #[account(mut)]
-    pub user_token_account: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
+//
+// Reward Accounting
+//
+
+/// Rewards earned by `amount` staked tokens at the given accumulator value.
+pub fn accrued_rewards(amount: u64, acc_reward_per_share: u128) -> u64 {
+    ((amount as u128).checked_mul(acc_reward_per_share).unwrap() / REWARD_PRECISION) as u64
 }
 
-impl<'info> Unstake<'info> {
-    pub fn into_transfer_from_stake_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.stake_vault.to_account_info().clone(),
-            to: self.user_token_account.to_account_info().clone(),
-            authority: self.stake_vault_authority.to_account_info().clone(),
-        };
-        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+/// Part of a vesting schedule's `total` unlocked at `now`: none before the cliff, then
+/// linear in the time since `start`, and all of it once `duration` has passed.
+pub fn vested_amount(vesting: &VestingAccount, now: i64) -> Result<u64> {
+    let elapsed = elapsed_since(vesting.start, now)?;
+    Ok(if elapsed < vesting.cliff {
+        0
+    } else if elapsed >= vesting.duration {
+        vesting.total
+    } else {
+        ((vesting.total as u128).checked_mul(elapsed as u128).unwrap() / vesting.duration as u128) as u64
+    })
+}
+
+/// Tokens earning rewards for a position: principal plus compounded rewards.
+pub fn staked_balance(user_stake: &UserStake) -> u64 {
+    user_stake.amount.checked_add(user_stake.compounded_amount).unwrap()
+}
+
+/// Rewards currently owed to a staker, including previously settled amounts.
+/// A `reward_debt` above what has accrued yields nothing new rather than underflowing.
+pub fn pending_rewards(user_stake: &UserStake, acc_reward_per_share: u128) -> u64 {
+    let accrued = accrued_rewards(staked_balance(user_stake), acc_reward_per_share);
+    let earned = accrued.saturating_sub(user_stake.reward_debt);
+    user_stake.unclaimed_rewards.checked_add(earned).unwrap()
+}
+
+/// Rewards currently owed to a stake position, including previously settled amounts.
+pub fn pending_position_rewards(position: &StakePosition, acc_reward_per_share: u128) -> u64 {
+    let accrued = accrued_rewards(position_weight(position), acc_reward_per_share);
+    let earned = accrued.saturating_sub(position.reward_debt);
+    position.unclaimed_rewards.checked_add(earned).unwrap()
+}
+
+/// Reward weight multiplier (bps) for a position locked for `lock_duration` seconds.
+pub fn lock_boost_bps(lock_duration: i64) -> u64 {
+    let boost = (MAX_LOCK_BOOST_BPS as u128).checked_mul(lock_duration as u128).unwrap()
+        / MAX_STAKE_LOCK_DURATION as u128;
+    BPS_DENOMINATOR.checked_add(boost as u64).unwrap()
+}
+
+/// Reward weight of a stake position: its amount scaled by its boost.
+pub fn position_weight(position: &StakePosition) -> u64 {
+    ((position.amount as u128).checked_mul(position.boost_bps as u128).unwrap() / BPS_DENOMINATOR as u128) as u64
+}
+
+/// Folds rewards emitted between `last_emission_update` and `now` (capped at the end of the
+/// schedule) into `acc_reward_per_share`. Emissions while nothing is staked are not carried over.
+pub fn update_emissions(state: &mut GlobalState, now: i64) {
+    let end = now.min(state.reward_period_end);
+    if end > state.last_emission_update && state.total_reward_weight > 0 {
+        let elapsed = (end - state.last_emission_update) as u64;
+        let emitted = elapsed.checked_mul(state.reward_rate_per_second).unwrap();
+        let increment = (emitted as u128).checked_mul(REWARD_PRECISION).unwrap() / state.total_reward_weight as u128;
+        state.acc_reward_per_share = state.acc_reward_per_share.checked_add(increment).unwrap();
     }
+    state.last_emission_update = state.last_emission_update.max(now);
 }
 
-#[derive(Accounts)]
-pub struct FlashLoan<'info> {
-    #[account(mut)]
-    pub global_state: Account<'info, GlobalState>,
-    #[account(mut)]
-    pub pool_account: Account<'info, TokenAccount>,
-    /// The authority controlling the pool account (typically a PDA).
-    pub pool_authority: Signer<'info>,
-    #[account(mut)]
-    pub borrower_token_account: Account<'info, TokenAccount>,
-    /// CHECK: Borrower account (used only for receiving tokens). Marked mutable as it also pays for the new account.
-    #[account(mut)]
-    pub borrower: AccountInfo<'info>,
-    #[account(init, payer = borrower, space = 8 + FlashLoanState::LEN)]
-    pub flash_loan_state: Account<'info, FlashLoanState>,
-    /// Account from which collateral will be transferred.
-    #[account(mut)]
-    pub borrower_collateral_account: Account<'info, TokenAccount>,
-    /// Collateral escrow account.
-    #[account(mut)]
-    pub collateral_escrow: Account<'info, TokenAccount>,
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
+/// Annualized emission rate per unit of reward weight, in basis points.
+pub fn staking_apr_bps(state: &GlobalState, now: i64) -> u64 {
+    if state.total_reward_weight == 0 || now >= state.reward_period_end {
+        return 0;
+    }
+    let yearly = (state.reward_rate_per_second as u128).checked_mul(SECONDS_PER_YEAR as u128).unwrap();
+    let apr = yearly.checked_mul(BPS_DENOMINATOR as u128).unwrap() / state.total_reward_weight as u128;
+    apr.min(u64::MAX as u128) as u64
 }
 
-impl<'info> FlashLoan<'info> {
-    pub fn into_transfer_to_borrower_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.pool_account.to_account_info().clone(),
-            to: self.borrower_token_account.to_account_info().clone(),
-            authority: self.pool_authority.to_account_info().clone(),
-        };
-        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+/// Counts `amount` of injected rewards against the current epoch's emission cap,
+/// rolling over to a new epoch once the current one has elapsed.
+pub fn record_emission(state: &mut GlobalState, amount: u64, now: i64) -> Result<()> {
+    if state.epoch_emission_cap == 0 {
+        return Ok(());
     }
-    pub fn into_transfer_collateral_context(&self) -> CpiContext<'_, '_, '_, 'info, Transfer<'info>> {
-        let cpi_accounts = Transfer {
-            from: self.borrower_collateral_account.to_account_info().clone(),
-            to: self.collateral_escrow.to_account_info().clone(),
-            authority: self.borrower.to_account_info().clone(),
-        };
-        CpiContext::new(self.token_program.to_account_info().clone(), cpi_accounts)
+    if elapsed_since(state.emission_epoch_start, now)? >= state.emission_epoch_length {
+        state.emission_epoch_start = now;
+        state.epoch_emitted = 0;
     }
+    let emitted = state.epoch_emitted.checked_add(amount).unwrap();
+    require!(emitted <= state.epoch_emission_cap, CustomError::EmissionCapExceeded);
+    state.epoch_emitted = emitted;
+    Ok(())
 }
 
-#[derive(Accounts)]
-pub struct RepayFlashLoan<'info> {
-    #[account(mut)]
-    pub global_state: Account<'info, GlobalState>,
-    #[account(mut)]
-    pub pool_account: Account<'info, TokenAccount>,
-    /// The pool authority must sign the repayment.
-    pub pool_authority: Signer<'info>,
-    #[account(mut, close = borrower)]
-    pub flash_loan_state: Account<'info, FlashLoanState>,
-    /// CHECK: This account receives lamports from closing the flash loan state.
-    #[account(mut)]
-    pub borrower: AccountInfo<'info>,
-    /// Borrower's reputation account.
-    #[account(init_if_needed, payer = borrower, space = 8 + BorrowerReputation::LEN, seeds = [b"reputation", borrower.key.as_ref()], bump)]
-    pub borrower_reputation: Account<'info, BorrowerReputation>,
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
+/// Moves rewards earned since the last checkpoint into `unclaimed_rewards`.
+/// Must be called before changing the position's staked balance.
+fn settle_rewards(user_stake: &mut UserStake, acc_reward_per_share: u128) -> Result<()> {
+    let accrued = accrued_rewards(staked_balance(user_stake), acc_reward_per_share);
+    assert_reward_debt(accrued, user_stake.reward_debt)?;
+    user_stake.unclaimed_rewards = pending_rewards(user_stake, acc_reward_per_share);
+    user_stake.reward_debt = accrued;
+    Ok(())
 }
 
-#[derive(Accounts)]
-pub struct DistributeRewards<'info> {
-    #[account(mut)]
-    pub global_state: Account<'info, GlobalState>,
+//
+// Events
+//
+
+// `pre_balance` and `post_balance` are the pool token account's balance around
+// the transfer, letting indexers reconcile physical movements against `amount`.
+
+#[event]
+pub struct LiquidityDeposited {
+    pub provider: Pubkey,
+    pub amount: u64,
+    pub pre_balance: u64,
+    pub post_balance: u64,
 }
 
-#[derive(Accounts)]
-pub struct CompoundRewards<'info> {
-    #[account(mut)]
-    pub global_state: Account<'info, GlobalState>,
-    #[account(mut)]
-    pub user: Signer<'info>,
-    #[account(mut, seeds = [b"user_stake", user.key.as_ref()], bump)]
-    pub user_stake: Account<'info, UserStake>,
-    // Account for reward tokens, etc.
-    pub token_program: Program<'info, Token>,
+#[event]
+pub struct LiquidityWithdrawn {
+    pub provider: Pubkey,
+    pub amount: u64,
+    pub pre_balance: u64,
+    pub post_balance: u64,
 }
 
-#[derive(Accounts)]
-pub struct MultiHopFlashLoan<'info> {
-    #[account(mut)]
-    pub global_state: Account<'info, GlobalState>,
-    // Additional accounts for multiple pools would be specified here.
-    pub token_program: Program<'info, Token>,
+#[event]
+pub struct FlashLoanIssued {
+    pub borrower: Pubkey,
+    pub amount: u64,
+    pub fee: u64,
+    pub pre_balance: u64,
+    pub post_balance: u64,
 }
 
-//
-// On–chain State Accounts
-//
+#[event]
+pub struct Unstaked {
+    pub user: Pubkey,
+    pub principal: u64,  // deposited principal withdrawn
+    pub compounded: u64, // compounded rewards withdrawn
+    pub penalty: u64,    // early-unstake penalty, paid to the treasury out of the amount withdrawn
+}
 
-#[account]
-pub struct GlobalState {
-    pub admin: Pubkey,
-    pub fee_rate: u64,         // in basis points
-    pub total_liquidity: u64,  // tokens in the liquidity pool
-    pub total_staked: u64,     // tokens staked by users
-    pub accumulated_fees: u64, // fees collected from flash loans
-    pub is_flash_loan_active: bool, // reentrancy guard flag
-    pub treasury_account: Pubkey,   // for fee redistribution
-    pub flash_loan_whitelist: Vec<Pubkey>, // optional whitelist for borrowers
+#[event]
+pub struct RewardsDonated {
+    pub donor: Pubkey,
+    pub amount: u64,
 }
 
-impl GlobalState {
-    // For the vector, we add 4 bytes for length and assume up to 10 addresses.
-    pub const LEN: usize = 32 + 8 + 8 + 8 + 8 + 1 + 32 + (4 + 10 * 32);
+#[event]
+pub struct RewardsDistributed {
+    pub cranker: Pubkey,
+    pub amount: u64,       // credited to stakers
+    pub crank_reward: u64, // paid to the cranker
 }
 
-#[account]
-pub struct UserStake {
+#[event]
+pub struct PositionDusted {
     pub owner: Pubkey,
-    pub amount: u64,
-    pub reward_debt: u64,          // if using an accrual model
-    pub last_stake_timestamp: i64, // for proportional rewards
+    pub position: Pubkey, // the closed UserStake or ProviderPosition
+    pub amount: u64,      // remainder swept to the owner
 }
 
-impl UserStake {
-    pub const LEN: usize = 32 + 8 + 8 + 8;
+#[event]
+pub struct CounterSaturated {
+    pub counter: String,
 }
 
-#[account]
-pub struct FlashLoanState {
+#[event]
+pub struct WindDownInitiated {
+    pub admin: Pubkey,
+    pub total_liquidity: u64, // LP liquidity still to be withdrawn
+    pub total_staked: u64,    // stake still to be unstaked
+}
+
+#[event]
+pub struct WindDownFinalized {
+    pub admin: Pubkey,
+    pub pool_residual: u64,   // swept from the pool
+    pub reward_residual: u64, // swept from the reward vault
+}
+
+#[event]
+pub struct StakeVaultMigrated {
+    pub old_vault: Pubkey,
+    pub new_vault: Pubkey,
+    pub amount: u64, // tokens moved to the new vault
+}
+
+#[event]
+pub struct StakeTransferred {
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub index: u8,
     pub amount: u64,
-    pub fee: u64,
-    pub start_time: i64, // timestamp when the flash loan was issued
-    pub collateral: u64, // collateral amount provided
+    pub lock_until: i64, // lock on the receiving position after the transfer
 }
 
-impl FlashLoanState {
-    pub const LEN: usize = 8 + 8 + 8 + 8;
+#[event]
+pub struct LpFeesCompounded {
+    pub provider: Pubkey,
+    pub amount: u64,
+    pub shares: u64, // LP shares minted for the compounded fees
 }
 
-#[account]
-pub struct BorrowerReputation {
+#[event]
+pub struct LiquidityReconciled {
+    pub admin: Pubkey,
+    pub old_total: u64,
+    pub new_total: u64,
+    pub delta: i128, // `new_total - old_total`
+}
+
+#[event]
+pub struct CollateralLiquidated {
+    pub loan: Pubkey,
     pub borrower: Pubkey,
-    pub reputation: u64,
+    pub liquidator: Pubkey,
+    pub seized: u64,
+    pub incentive: u64,
+    pub remainder: u64,
+    pub written_off: u64, // principal the pool did not get back, taken off `total_liquidity`
 }
 
-impl BorrowerReputation {
-    pub const LEN: usize = 32 + 8;
+#[event]
+pub struct AdminAudit {
+    pub actor: Pubkey, // signer of the change; the proposer for co-signed actions
+    pub action: AuditAction,
+    pub old_value: AuditValue,
+    pub new_value: AuditValue,
+    pub timestamp: i64,
 }
 
 //
@@ -505,4 +7340,413 @@ pub enum CustomError {
     NotWhitelisted,
     #[msg("Unauthorized.")]
     Unauthorized,
+    #[msg("No tokens are staked to receive rewards.")]
+    NoStakers,
+    #[msg("Reputation account is still in use.")]
+    ReputationInUse,
+    #[msg("No rewards available.")]
+    NoRewards,
+    #[msg("Insufficient liquidity in the provider's position.")]
+    InsufficientPosition,
+    #[msg("Token account mint does not match the pool.")]
+    MintMismatch,
+    #[msg("Flash loan whitelist is full.")]
+    WhitelistFull,
+    #[msg("Configuration is permanently locked.")]
+    ConfigLocked,
+    #[msg("Stake amount is below the minimum.")]
+    StakeTooSmall,
+    #[msg("Pool does not match the loan or token account.")]
+    InvalidPool,
+    #[msg("Withdrawal exceeds the operator's allowance.")]
+    AllowanceExceeded,
+    #[msg("Clock is earlier than a recorded timestamp.")]
+    InvalidClock,
+    #[msg("Repayment exceeds the amount owed.")]
+    RepaymentExceedsOwed,
+    #[msg("Pool authority is neither the owner nor the delegate of the pool account.")]
+    InvalidPoolAuthority,
+    #[msg("Expiry must be in the future.")]
+    InvalidExpiry,
+    #[msg("Loan reservation has expired.")]
+    ReservationExpired,
+    #[msg("Loan does not match the reservation.")]
+    ReservationMismatch,
+    #[msg("Loan reservation has not expired yet.")]
+    ReservationActive,
+    #[msg("Vault appears more than once in the loan sources.")]
+    DuplicateLoanSource,
+    #[msg("Too many vaults for a single flash loan.")]
+    TooManyLoanSources,
+    #[msg("Repayment accounts do not match the loan sources.")]
+    LoanSourceMismatch,
+    #[msg("Operation is not supported for loans drawn from multiple vaults.")]
+    MultiVaultLoan,
+    #[msg("No swap program has been configured.")]
+    SwapNotConfigured,
+    #[msg("Swap returned less than the minimum output.")]
+    SlippageExceeded,
+    #[msg("Loan exceeds the per-transaction cap.")]
+    PerTxCapExceeded,
+    #[msg("Reputation account belongs to a different borrower.")]
+    ReputationMismatch,
+    #[msg("Fee accounts do not match the configured fee mint.")]
+    FeeMintMismatch,
+    #[msg("A fee mint requires a nonzero price.")]
+    InvalidFeeMintPrice,
+    #[msg("Program is paused.")]
+    ProgramPaused,
+    #[msg("Collateral escrow accounts are required to return collateral.")]
+    CollateralAccountsRequired,
+    #[msg("Treasury token account is missing or not owned by the treasury.")]
+    InvalidTreasury,
+    #[msg("Source and destination token accounts are the same.")]
+    SelfTransfer,
+    #[msg("Duration must be positive.")]
+    InvalidDuration,
+    #[msg("Stake vault holds less than the total staked.")]
+    StakeVaultInsolvent,
+    #[msg("Borrower was rejected by the compliance hook.")]
+    ComplianceRejected,
+    #[msg("Deposit is too small to mint any LP shares.")]
+    ZeroShares,
+    #[msg("Lock duration is negative or exceeds the maximum.")]
+    InvalidLockDuration,
+    #[msg("Stake position is still locked.")]
+    StakeLocked,
+    #[msg("Collateral account balance is below the collateral amount.")]
+    InsufficientCollateral,
+    #[msg("Too many fee tiers.")]
+    TooManyFeeTiers,
+    #[msg("Fee tiers must have ascending thresholds and rates of at most 10000 bps.")]
+    InvalidFeeTiers,
+    #[msg("Stake position belongs to a different user.")]
+    StakeOwnerMismatch,
+    #[msg("Voucher is not signed by the voucher signer or can't be used here.")]
+    InvalidVoucher,
+    #[msg("Voucher has expired.")]
+    VoucherExpired,
+    #[msg("Voucher has already been used.")]
+    VoucherConsumed,
+    #[msg("Voucher does not match this loan.")]
+    VoucherMismatch,
+    #[msg("Reward injection would exceed this epoch's emission cap.")]
+    EmissionCapExceeded,
+    #[msg("Borrower's balance after repayment is below the requested surplus.")]
+    InsufficientSurplus,
+    #[msg("Account is at an unsupported layout version and must be migrated.")]
+    UnsupportedVersion,
+    #[msg("Collateral exceeds the maximum allowed for this loan.")]
+    ExcessiveCollateral,
+    #[msg("Amount must be greater than zero.")]
+    ZeroAmount,
+    #[msg("Basis points must not exceed 10000.")]
+    InvalidBps,
+    #[msg("Stake position account is invalid.")]
+    InvalidStakePosition,
+    #[msg("A multi-vault loan must be repaid to every vault at once.")]
+    PartialHopRepayment,
+    #[msg("Reward debt exceeds accrued rewards.")]
+    RewardAccountingError,
+    #[msg("The distribution interval has not elapsed yet.")]
+    DistributionTooEarly,
+    #[msg("The reward vault is required to set aside the stakers' share of the fee.")]
+    RewardVaultRequired,
+    #[msg("The protocol is winding down; only withdrawals and claims are accepted.")]
+    WindingDown,
+    #[msg("The protocol is not winding down.")]
+    NotWindingDown,
+    #[msg("Wind-down can't be finalized while liquidity, stakes or loans remain.")]
+    WindDownIncomplete,
+    #[msg("The repayment account does not hold the principal and fee owed.")]
+    RepaymentInsufficient,
+    #[msg("The borrower has no open flash loan.")]
+    NoOpenLoan,
+    #[msg("The account is not the protocol's stake vault.")]
+    InvalidStakeVault,
+    #[msg("The fee mint account is required to burn the configured share of the fee.")]
+    FeeBurnUnavailable,
+    #[msg("The cooldown since the last claim has not elapsed yet.")]
+    ClaimTooSoon,
+    #[msg("The withdrawal would leave less than the outstanding loans plus the liquidity reserve.")]
+    ReserveBreached,
+    #[msg("The pool account is not the canonical pool vault.")]
+    InvalidPoolVault,
+    #[msg("The canonical vaults can only be created once, before any liquidity or stake is deposited.")]
+    VaultsAlreadyInitialized,
+    #[msg("The loan exceeds the borrower's reputation-based cap.")]
+    ReputationCapExceeded,
+    #[msg("The fee vault does not hold enough fees outside the LPs' share.")]
+    InsufficientFees,
+    #[msg("A fixed fee must be greater than zero.")]
+    InvalidFixedFee,
+    #[msg("The borrower does not hold enough to cover the fixed fee.")]
+    FeeNotCovered,
+    #[msg("This instruction must go through the admin co-signing flow.")]
+    MultisigRequired,
+    #[msg("No co-signing admins are configured.")]
+    MultisigNotEnabled,
+    #[msg("Too many co-signing admins.")]
+    TooManyAdmins,
+    #[msg("The admins must be distinct and the threshold between 1 and their number.")]
+    InvalidAdminSet,
+    #[msg("The admin action has already been executed.")]
+    ActionAlreadyExecuted,
+    #[msg("This admin has already approved the action.")]
+    AlreadyApproved,
+    #[msg("The admin action does not have enough approvals yet.")]
+    ThresholdNotMet,
+    #[msg("The program must be paused first.")]
+    NotPaused,
+    #[msg("The loan fee exceeds the borrower's max_fee.")]
+    FeeExceedsMax,
+    #[msg("The loan has not expired yet.")]
+    LoanNotExpired,
+    #[msg("The fee vault is required to refund a prepaid fee.")]
+    FeeVaultRequired,
+    #[msg("Collateral is not in the accepted collateral mint.")]
+    CollateralMintMismatch,
+    #[msg("Staking is paused.")]
+    StakingPaused,
+    #[msg("The unpause delay has not elapsed since the program was paused.")]
+    UnpauseTooSoon,
+    #[msg("The previous vesting schedule has not fully released.")]
+    VestingActive,
+    #[msg("Nothing has vested since the last release.")]
+    NothingVested,
+    #[msg("Merging into an existing stake needs the receiving owner's signature.")]
+    ReceiverMustSign,
+    #[msg("This action runs through its own instruction, which consumes the approval.")]
+    ActionNeedsAccounts,
+    #[msg("The admin action does not match this call.")]
+    ActionMismatch,
+    #[msg("Each pool must be passed as its global state, position, vault, vault authority and recipient.")]
+    PoolAccountsMismatch,
+    #[msg("Extra vaults must be registered to the pool and passed after their registration.")]
+    UnregisteredPoolVault,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_invalid_clock<T>(result: Result<T>) -> bool {
+        result.is_err_and(|err| err == CustomError::InvalidClock.into())
+    }
+
+    #[test]
+    fn elapsed_since_counts_forward_time() {
+        assert_eq!(elapsed_since(100, 100).unwrap(), 0);
+        assert_eq!(elapsed_since(100, 160).unwrap(), 60);
+        assert_eq!(elapsed_since(i64::MIN, -1).unwrap(), i64::MAX);
+    }
+
+    #[test]
+    fn elapsed_since_rejects_a_backwards_clock() {
+        assert!(is_invalid_clock(elapsed_since(100, 99)));
+        assert!(is_invalid_clock(elapsed_since(i64::MAX, i64::MIN)));
+    }
+
+    #[test]
+    fn vested_amount_rejects_a_backwards_clock() {
+        let vesting = VestingAccount {
+            owner: Pubkey::default(),
+            total: 1_000,
+            released: 0,
+            start: 100,
+            cliff: 0,
+            duration: 100,
+        };
+        assert_eq!(vested_amount(&vesting, 150).unwrap(), 500);
+        assert!(is_invalid_clock(vested_amount(&vesting, 99)));
+    }
+
+    #[test]
+    fn share_seconds_reject_a_backwards_clock() {
+        let mut position = ProviderPosition {
+            owner: Pubkey::default(),
+            amount: 10,
+            shares: 10,
+            share_seconds: 0,
+            last_accrual: 100,
+        };
+        accrue_position_share_seconds(&mut position, 110).unwrap();
+        assert_eq!(position.share_seconds, 100);
+        assert!(is_invalid_clock(accrue_position_share_seconds(&mut position, 109)));
+    }
+
+    /// xorshift64* generator, so the property loops below are random but reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        /// Uniform in `lo..=hi`, near enough for these checks.
+        fn range(&mut self, lo: u64, hi: u64) -> u64 {
+            lo + self.next() % (hi - lo + 1)
+        }
+
+        fn coin(&mut self) -> bool {
+            self.next() >> 63 == 1
+        }
+    }
+
+    const CASES: usize = 5_000;
+
+    /// A `GlobalState` with every field zeroed: no caps, floors, splits or fee mint.
+    fn blank_state() -> GlobalState {
+        <GlobalState as AnchorDeserialize>::deserialize(&mut &[0u8; GlobalState::LEN][..]).unwrap()
+    }
+
+    #[test]
+    fn deposits_never_dilute_existing_shares() {
+        let mut rng = Rng(0x5eed_0001);
+        let mut state = blank_state();
+        for _ in 0..CASES {
+            // Bounded so the shares minted always fit in a u64.
+            let total_shares = rng.range(1, 1_000_000_000);
+            let lp_value = rng.range(1, 1_000_000_000_000);
+            let amount = rng.range(1, 1_000_000_000);
+            let Ok(shares) = shares_for_deposit(amount, total_shares, lp_value) else {
+                continue;
+            };
+            state.total_shares = total_shares + shares;
+            let new_value = lp_value + amount;
+            assert!(position_value(&state, shares, new_value) <= amount);
+            assert!(position_value(&state, total_shares, new_value) >= lp_value);
+        }
+    }
+
+    #[test]
+    fn withdrawals_burn_at_least_what_they_pay() {
+        let mut rng = Rng(0x5eed_0002);
+        let mut state = blank_state();
+        for _ in 0..CASES {
+            let total_shares = rng.range(1, 1_000_000_000_000);
+            let lp_value = rng.range(1, 1_000_000_000_000);
+            let amount = rng.range(1, lp_value);
+            state.total_shares = total_shares;
+            let burned = shares_for_withdrawal(amount, total_shares, lp_value).unwrap();
+            assert!(burned <= total_shares);
+            assert!(position_value(&state, burned, lp_value) >= amount);
+        }
+    }
+
+    #[test]
+    fn burning_every_share_releases_exactly_the_principal() {
+        let mut rng = Rng(0x5eed_0003);
+        for _ in 0..CASES / 10 {
+            let amount = rng.range(0, 1_000_000_000_000);
+            let mut position = ProviderPosition {
+                owner: Pubkey::default(),
+                amount,
+                shares: rng.range(1, 1_000_000_000_000),
+                share_seconds: 0,
+                last_accrual: 0,
+            };
+            let mut released = 0;
+            while position.shares > 0 {
+                let shares = rng.range(1, position.shares);
+                let principal = principal_for_shares(&position, shares);
+                assert!(principal <= position.amount);
+                position.amount -= principal;
+                position.shares -= shares;
+                released += principal;
+            }
+            assert_eq!(position.amount, 0);
+            assert_eq!(released, amount);
+        }
+    }
+
+    #[test]
+    fn settlement_routes_every_part_of_the_fee_once() {
+        let mut rng = Rng(0x5eed_0004);
+        for _ in 0..CASES {
+            let mut state = blank_state();
+            state.round_fees_up = rng.coin();
+            state.staker_fee_bps = rng.range(0, BPS_DENOMINATOR);
+            state.total_liquidity = rng.range(1, 1_000_000_000_000);
+            state.protocol_owned_liquidity = rng.range(0, state.total_liquidity);
+            state.route_pol_fees_to_treasury = rng.coin();
+            if rng.coin() {
+                state.lp_share_bps = rng.range(0, BPS_DENOMINATOR);
+                state.staker_share_bps = rng.range(0, BPS_DENOMINATOR - state.lp_share_bps);
+            }
+            let mut loan = <FlashLoanState as AnchorDeserialize>::deserialize(&mut &[0u8; FlashLoanState::LEN][..]).unwrap();
+            loan.amount = rng.range(1, 1_000_000_000_000);
+            loan.fee_rate = rng.range(0, BPS_DENOMINATOR);
+            loan.fee = fee_for_terms(loan.amount, loan.fee_rate, 0, state.round_fees_up);
+
+            let settlement = settle_loan(&state, &mut loan, 0, 1);
+            assert_eq!(settlement.fee, loan.fee);
+            assert_eq!(settlement.outstanding, loan.amount + loan.fee);
+            assert!(settlement.fee_paid_to_pool);
+            let lp_fee = settlement.fee - settlement.pol_fee - settlement.staker_fee;
+            if let Some((split_lp, split_staker, split_treasury)) = settlement.split {
+                assert_eq!((split_lp, split_staker, split_treasury), (lp_fee, settlement.staker_fee, settlement.pol_fee));
+            }
+
+            let mut pool = Pool {
+                mint: Pubkey::default(),
+                pool_account: Pubkey::default(),
+                accumulated_fees: 0,
+                total_loans: 0,
+                fee_rate: 0,
+                version: POOL_VERSION,
+            };
+            state.is_flash_loan_active = true;
+            book_settlement(&mut state, &mut pool, loan.amount, &settlement, 1).unwrap();
+            let booked = settlement.split.map_or(settlement.fee, |(_, _, treasury_fee)| treasury_fee);
+            assert_eq!(state.accumulated_fees, booked);
+            assert_eq!(state.locked_fees, lp_fee);
+            assert_eq!(state.pending_staker_fees, settlement.staker_fee);
+            assert_eq!(pool.accumulated_fees, settlement.fee);
+            assert_eq!(pool.total_loans, 1);
+            assert!(!state.is_flash_loan_active);
+        }
+    }
+
+    #[test]
+    fn staking_rewards_accrue_without_loss_or_invention() {
+        let mut rng = Rng(0x5eed_0005);
+        for _ in 0..CASES {
+            let a = rng.range(0, 1_000_000_000_000);
+            let b = rng.range(0, 1_000_000_000_000);
+            let acc_before = rng.range(0, 1_000_000_000_000_000) as u128;
+            let acc_after = acc_before + rng.range(0, 1_000_000_000_000_000) as u128;
+            // Splitting a stake loses at most a unit to rounding and never gains one.
+            let whole = accrued_rewards(a + b, acc_after);
+            let parts = accrued_rewards(a, acc_after) + accrued_rewards(b, acc_after);
+            assert!(whole >= parts && whole - parts <= 1);
+
+            // A settled stake is owed exactly what accrued since it was settled.
+            let user_stake = UserStake {
+                owner: Pubkey::default(),
+                amount: a,
+                reward_debt: accrued_rewards(a + b, acc_before),
+                first_stake_timestamp: 0,
+                last_modified_timestamp: 0,
+                unclaimed_rewards: rng.range(0, 1_000_000),
+                compounded_amount: b,
+                last_claim_timestamp: 0,
+                last_compound_timestamp: 0,
+                version: USER_STAKE_VERSION,
+            };
+            let earned = accrued_rewards(a + b, acc_after) - accrued_rewards(a + b, acc_before);
+            assert_eq!(pending_rewards(&user_stake, acc_after), user_stake.unclaimed_rewards + earned);
+            assert_eq!(pending_rewards(&user_stake, acc_before), user_stake.unclaimed_rewards);
+
+            // Longer locks never earn a smaller boost, which stays within its bounds.
+            let short = rng.range(0, MAX_STAKE_LOCK_DURATION as u64) as i64;
+            let long = rng.range(short as u64, MAX_STAKE_LOCK_DURATION as u64) as i64;
+            assert!(BPS_DENOMINATOR <= lock_boost_bps(short));
+            assert!(lock_boost_bps(short) <= lock_boost_bps(long));
+            assert!(lock_boost_bps(long) <= BPS_DENOMINATOR + MAX_LOCK_BOOST_BPS);
+        }
+    }
 }
@@ -6,6 +6,12 @@ describe("RYFT Tests", () => {
   let liquidityProvider: web3.Keypair;
   let stakeVault: web3.Keypair;
   let borrower: web3.Keypair;
+  let rewardVault: web3.Keypair;
+  let feeVault: web3.Keypair;
+  let poolMint: web3.Keypair;
+  let poolPda: web3.PublicKey;
+  let collateralEscrowPda: web3.PublicKey;
+  let escrowAuthorityPda: web3.PublicKey;
   let splToken: typeof import("@solana/spl-token");
 
   before(async () => {
@@ -14,6 +20,22 @@ describe("RYFT Tests", () => {
     liquidityProvider = new web3.Keypair();
     stakeVault = new web3.Keypair();
     borrower = new web3.Keypair();
+    rewardVault = new web3.Keypair();
+    feeVault = new web3.Keypair();
+    poolMint = new web3.Keypair();
+    [poolPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("pool"), poolMint.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    // Collateral is posted in the pool mint.
+    [collateralEscrowPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("collateral_escrow"), poolMint.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    [escrowAuthorityPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("escrow_authority")],
+      pg.program.programId
+    );
 
     // Import SPL Token dynamically to avoid module errors
     splToken = await import("@solana/spl-token");
@@ -28,6 +50,8 @@ describe("RYFT Tests", () => {
         globalState: globalStateKp.publicKey,
         admin: pg.wallet.publicKey,
         treasury: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        feeVault: feeVault.publicKey,
         systemProgram: web3.SystemProgram.programId,
       })
       .signers([globalStateKp])
@@ -44,17 +68,61 @@ describe("RYFT Tests", () => {
     assert(globalState.feeRate.eq(feeRate));
   });
 
+  it("Create Pool", async () => {
+    const txHash = await pg.program.methods
+      .createPool()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    console.log(`Create Pool TX: ${txHash}`);
+    await pg.connection.confirmTransaction(txHash);
+
+    const pool = await pg.program.account.pool.fetch(poolPda);
+    assert(pool.poolAccount.equals(poolAccount.publicKey));
+  });
+
+  it("Create Collateral Escrow", async () => {
+    await pg.program.methods
+      .createCollateralEscrow()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        collateralMint: poolMint.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        escrowAuthority: escrowAuthorityPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    const escrow = await splToken.getAccount(pg.connection, collateralEscrowPda);
+    assert(escrow.mint.equals(poolMint.publicKey));
+    assert(escrow.owner.equals(escrowAuthorityPda));
+  });
+
   it("Deposit Liquidity", async () => {
-    const depositAmount = new BN(1000);
+    const depositAmount = new BN(10000);
+    const [providerPositionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("provider_position"), globalStateKp.publicKey.toBuffer(), liquidityProvider.publicKey.toBuffer()],
+      pg.program.programId
+    );
 
     const txHash = await pg.program.methods
       .depositLiquidity(depositAmount)
       .accounts({
         globalState: globalStateKp.publicKey,
         provider: liquidityProvider.publicKey,
+        providerPosition: providerPositionPda,
         providerTokenAccount: pg.wallet.publicKey,
         poolAccount: poolAccount.publicKey,
         tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
       })
       .signers([liquidityProvider])
       .rpc();
@@ -63,6 +131,48 @@ describe("RYFT Tests", () => {
     await pg.connection.confirmTransaction(txHash);
   });
 
+  it("Withdraw Liquidity To Third-Party Destination", async () => {
+    const withdrawAmount = new BN(100);
+    const destination = new web3.Keypair();
+    const [providerPositionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("provider_position"), globalStateKp.publicKey.toBuffer(), liquidityProvider.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const positionBefore = await pg.program.account.providerPosition.fetch(
+      providerPositionPda
+    );
+
+    const txHash = await pg.program.methods
+      .withdrawLiquidity(withdrawAmount)
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        poolAccount: poolAccount.publicKey,
+        provider: liquidityProvider.publicKey,
+        authority: liquidityProvider.publicKey,
+        providerPosition: providerPositionPda,
+        allowance: null,
+        providerTokenAccount: pg.wallet.publicKey,
+        destination: destination.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .signers([liquidityProvider])
+      .rpc();
+
+    console.log(`Withdraw To Destination TX: ${txHash}`);
+    await pg.connection.confirmTransaction(txHash);
+
+    // The provider's position is debited even though funds went elsewhere.
+    const positionAfter = await pg.program.account.providerPosition.fetch(
+      providerPositionPda
+    );
+    assert(positionBefore.amount.sub(positionAfter.amount).eq(withdrawAmount));
+    const destinationBalance = await pg.connection.getTokenAccountBalance(
+      destination.publicKey
+    );
+    assert(new BN(destinationBalance.value.amount).eq(withdrawAmount));
+  });
+
   it("Stake RYFT Tokens", async () => {
     const stakeAmount = new BN(500);
 
@@ -83,27 +193,68 @@ describe("RYFT Tests", () => {
     await pg.connection.confirmTransaction(txHash);
   });
 
+  it("Donate Rewards", async () => {
+    const donationAmount = new BN(1000);
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+
+    const stateBefore = await pg.program.account.globalState.fetch(
+      globalStateKp.publicKey
+    );
+    const stakeBefore = await pg.program.account.userStake.fetch(userStakePda);
+
+    const txHash = await pg.program.methods
+      .donateRewards(donationAmount)
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        donor: pg.wallet.publicKey,
+        donorTokenAccount: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    console.log(`Donate Rewards TX: ${txHash}`);
+    await pg.connection.confirmTransaction(txHash);
+
+    const stateAfter = await pg.program.account.globalState.fetch(
+      globalStateKp.publicKey
+    );
+    const stakeAfter = await pg.program.account.userStake.fetch(userStakePda);
+
+    // Each staker's pending rewards grow by donation * stake / total_reward_weight.
+    const gained = pendingRewards(stakeAfter, stateAfter.accRewardPerShare).sub(
+      pendingRewards(stakeBefore, stateBefore.accRewardPerShare)
+    );
+    const expected = donationAmount
+      .mul(stakedBalance(stakeAfter))
+      .div(stateAfter.totalRewardWeight);
+    assert(gained.sub(expected).abs().lte(new BN(1)));
+  });
+
   it("Flash Loan Execution", async () => {
     const loanAmount = new BN(200);
     const collateralAmount = new BN(100);
     const flashLoanStateKp = new web3.Keypair();
-    const collateralEscrowKp = new web3.Keypair();
 
     const txHash = await pg.program.methods
-      .flashLoan(loanAmount, collateralAmount)
+      .flashLoan(loanAmount, collateralAmount, new BN(0))
       .accounts({
         globalState: globalStateKp.publicKey,
+        pool: poolPda,
         poolAccount: poolAccount.publicKey,
         poolAuthority: pg.wallet.publicKey,
         borrowerTokenAccount: pg.wallet.publicKey,
         borrower: borrower.publicKey,
         flashLoanState: flashLoanStateKp.publicKey,
         borrowerCollateralAccount: pg.wallet.publicKey,
-        collateralEscrow: collateralEscrowKp.publicKey,
+        collateralEscrow: collateralEscrowPda,
         tokenProgram: splToken.TOKEN_PROGRAM_ID,
         systemProgram: web3.SystemProgram.programId,
       })
-      .signers([borrower, flashLoanStateKp, collateralEscrowKp])
+      .signers([borrower, flashLoanStateKp])
       .rpc();
 
     console.log(`Flash Loan TX: ${txHash}`);
@@ -118,10 +269,13 @@ describe("RYFT Tests", () => {
       .repayFlashLoan()
       .accounts({
         globalState: globalStateKp.publicKey,
+        pool: poolPda,
         poolAccount: poolAccount.publicKey,
         poolAuthority: pg.wallet.publicKey,
         flashLoanState: flashLoanStateKp.publicKey,
         borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
         borrowerReputation: borrowerReputationKp.publicKey,
         tokenProgram: splToken.TOKEN_PROGRAM_ID,
         systemProgram: web3.SystemProgram.programId,
@@ -132,4 +286,7920 @@ describe("RYFT Tests", () => {
     console.log(`Repay Flash Loan TX: ${txHash}`);
     await pg.connection.confirmTransaction(txHash);
   });
-});
+
+  it("Repay Honors Borrow-Time Fee Rate", async () => {
+    const loanAmount = new BN(1000);
+    const flashLoanStateKp = new web3.Keypair();
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+
+    const borrowTimeRate = (
+      await pg.program.account.globalState.fetch(globalStateKp.publicKey)
+    ).feeRate;
+
+    await pg.program.methods
+      .flashLoan(loanAmount, new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+
+    // Raise the fee rate while the loan is outstanding.
+    await pg.program.methods
+      .updateFeeRate(borrowTimeRate.muln(2))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+      })
+      .rpc();
+
+    const feesBefore = (
+      await pg.program.account.globalState.fetch(globalStateKp.publicKey)
+    ).accumulatedFees;
+
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+
+    const feesAfter = (
+      await pg.program.account.globalState.fetch(globalStateKp.publicKey)
+    ).accumulatedFees;
+    assert(
+      feesAfter.sub(feesBefore).eq(loanAmount.mul(borrowTimeRate).divn(10000))
+    );
+
+    // Restore the original rate for subsequent tests.
+    await pg.program.methods
+      .updateFeeRate(borrowTimeRate)
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+      })
+      .rpc();
+  });
+
+  it("Close Reputation Rejected While Loan Is Open", async () => {
+    const flashLoanStateKp = new web3.Keypair();
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+
+    await pg.program.methods
+      .flashLoan(new BN(100), new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+
+    await expectError(
+      pg.program.methods
+        .closeReputation()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          borrower: borrower.publicKey,
+          borrowerReputation: borrowerReputationPda,
+        })
+        .signers([borrower])
+        .rpc(),
+      "ReputationInUse"
+    );
+
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+  });
+
+  it("Close Reputation Rejected For Recently Active Borrower", async () => {
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const reputation = await pg.program.account.borrowerReputation.fetch(
+      borrowerReputationPda
+    );
+    assert(reputation.reputation.gtn(0));
+
+    // The borrower just repaid, so their reputation is neither zero nor idle.
+    await expectError(
+      pg.program.methods
+        .closeReputation()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          borrower: borrower.publicKey,
+          borrowerReputation: borrowerReputationPda,
+        })
+        .signers([borrower])
+        .rpc(),
+      "ReputationInUse"
+    );
+  });
+
+  it("Stake Timestamps Track Position Lifecycle", async () => {
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const stakeAccounts = {
+      globalState: globalStateKp.publicKey,
+      user: pg.wallet.publicKey,
+      userStake: userStakePda,
+      userTokenAccount: pg.wallet.publicKey,
+      stakeVault: stakeVault.publicKey,
+      stakeVaultAuthority: pg.wallet.publicKey,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+    };
+    const opened = await pg.program.account.userStake.fetch(userStakePda);
+    assert(opened.firstStakeTimestamp.gtn(0));
+
+    // Topping up keeps the opening time but refreshes the modification time.
+    await pg.program.methods.stake(new BN(100)).accounts(stakeAccounts).rpc();
+    const toppedUp = await pg.program.account.userStake.fetch(userStakePda);
+    assert(toppedUp.firstStakeTimestamp.eq(opened.firstStakeTimestamp));
+    assert(toppedUp.lastModifiedTimestamp.gte(opened.lastModifiedTimestamp));
+
+    // A partial unstake also refreshes only the modification time.
+    await pg.program.methods
+      .unstake(new BN(100))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        user: pg.wallet.publicKey,
+        userStake: userStakePda,
+        stakeVault: stakeVault.publicKey,
+        stakeVaultAuthority: pg.wallet.publicKey,
+        userTokenAccount: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const unstaked = await pg.program.account.userStake.fetch(userStakePda);
+    assert(unstaked.firstStakeTimestamp.eq(opened.firstStakeTimestamp));
+    assert(unstaked.lastModifiedTimestamp.gte(toppedUp.lastModifiedTimestamp));
+
+    // Compounding restakes rewards and refreshes the modification time.
+    await pg.program.methods
+      .compoundRewards()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        user: pg.wallet.publicKey,
+        userStake: userStakePda,
+        rewardVault: rewardVault.publicKey,
+        rewardVaultAuthority: pg.wallet.publicKey,
+        stakeVault: stakeVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const compounded = await pg.program.account.userStake.fetch(userStakePda);
+    assert(compounded.firstStakeTimestamp.eq(opened.firstStakeTimestamp));
+    assert(compounded.lastModifiedTimestamp.gte(unstaked.lastModifiedTimestamp));
+    assert(compounded.unclaimedRewards.eqn(0));
+  });
+
+  it("Reputation Bypasses Whitelist Above Threshold", async () => {
+    const lowRepBorrower = new web3.Keypair();
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const flashLoanAccounts = (
+      who: web3.PublicKey,
+      flashLoanState: web3.PublicKey,
+      borrowerReputation: web3.PublicKey | null
+    ) => ({
+      globalState: globalStateKp.publicKey,
+      pool: poolPda,
+      poolAccount: poolAccount.publicKey,
+      poolAuthority: pg.wallet.publicKey,
+      borrowerTokenAccount: pg.wallet.publicKey,
+      borrower: who,
+      flashLoanState,
+      borrowerCollateralAccount: pg.wallet.publicKey,
+      collateralEscrow: collateralEscrowPda,
+      borrowerReputation,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+    });
+
+    // Make the pool permissioned without listing either borrower.
+    await pg.program.methods
+      .addToWhitelist(pg.wallet.publicKey)
+      .accounts(adminAccounts)
+      .rpc();
+    await pg.program.methods
+      .setAutoWhitelistThreshold(new BN(1))
+      .accounts(adminAccounts)
+      .rpc();
+
+    // The borrower has repaid before, so their reputation clears the threshold.
+    const highRepState = new web3.Keypair();
+    await pg.program.methods
+      .flashLoan(new BN(100), new BN(0), new BN(0))
+      .accounts(
+        flashLoanAccounts(
+          borrower.publicKey,
+          highRepState.publicKey,
+          borrowerReputationPda
+        )
+      )
+      .signers([borrower, highRepState])
+      .rpc();
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: highRepState.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+
+    // A fresh borrower has no reputation and is not listed.
+    const lowRepState = new web3.Keypair();
+    await expectError(
+      pg.program.methods
+        .flashLoan(new BN(100), new BN(0), new BN(0))
+        .accounts(
+          flashLoanAccounts(lowRepBorrower.publicKey, lowRepState.publicKey, null)
+        )
+        .signers([lowRepBorrower, lowRepState])
+        .rpc(),
+      "NotWhitelisted"
+    );
+
+    // Restore an open pool for subsequent tests.
+    await pg.program.methods
+      .removeFromWhitelist(pg.wallet.publicKey)
+      .accounts(adminAccounts)
+      .rpc();
+    await pg.program.methods
+      .setAutoWhitelistThreshold(new BN(0))
+      .accounts(adminAccounts)
+      .rpc();
+  });
+
+  it("Liquidity Events Carry Pool Balance Deltas", async () => {
+    const amount = new BN(250);
+    const [providerPositionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("provider_position"), globalStateKp.publicKey.toBuffer(), liquidityProvider.publicKey.toBuffer()],
+      pg.program.programId
+    );
+
+    const depositTx = await pg.program.methods
+      .depositLiquidity(amount)
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        provider: liquidityProvider.publicKey,
+        providerPosition: providerPositionPda,
+        providerTokenAccount: pg.wallet.publicKey,
+        poolAccount: poolAccount.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([liquidityProvider])
+      .rpc();
+    const [deposited] = await fetchEvents(depositTx, "liquidityDeposited");
+    assert(deposited.postBalance.sub(deposited.preBalance).eq(amount));
+
+    const withdrawTx = await pg.program.methods
+      .withdrawLiquidity(amount)
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        poolAccount: poolAccount.publicKey,
+        provider: liquidityProvider.publicKey,
+        authority: liquidityProvider.publicKey,
+        providerPosition: providerPositionPda,
+        allowance: null,
+        providerTokenAccount: pg.wallet.publicKey,
+        destination: null,
+        poolAuthority: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .signers([liquidityProvider])
+      .rpc();
+    const [withdrawn] = await fetchEvents(withdrawTx, "liquidityWithdrawn");
+    assert(withdrawn.preBalance.sub(withdrawn.postBalance).eq(amount));
+  });
+
+  it("Locked Config Rejects Fee Changes", async () => {
+    // Use a separate deployment so locking doesn't affect other tests.
+    const lockedStateKp = new web3.Keypair();
+    const adminAccounts = {
+      globalState: lockedStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+
+    await pg.program.methods
+      .initialize(new BN(500))
+      .accounts({
+        globalState: lockedStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        treasury: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        feeVault: feeVault.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([lockedStateKp])
+      .rpc();
+
+    await pg.program.methods.lockConfig().accounts(adminAccounts).rpc();
+
+    await expectError(
+      pg.program.methods.updateFeeRate(new BN(900)).accounts(adminAccounts).rpc(),
+      "ConfigLocked"
+    );
+    const lockedState = await pg.program.account.globalState.fetch(
+      lockedStateKp.publicKey
+    );
+    assert(lockedState.configLocked);
+    assert(lockedState.feeRate.eqn(500));
+  });
+
+  it("Rollover Flash Loan", async () => {
+    const flashLoanStateKp = new web3.Keypair();
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+
+    await pg.program.methods
+      .flashLoan(new BN(100), new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+
+    const feesBefore = (
+      await pg.program.account.globalState.fetch(globalStateKp.publicKey)
+    ).accumulatedFees;
+    const oldLoan = await pg.program.account.flashLoanState.fetch(
+      flashLoanStateKp.publicKey
+    );
+
+    const txHash = await pg.program.methods
+      .rolloverFlashLoan(new BN(150), new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .signers([borrower])
+      .rpc();
+
+    console.log(`Rollover TX: ${txHash}`);
+    await pg.connection.confirmTransaction(txHash);
+
+    // The old loan's fee is booked and the same account now tracks the new loan.
+    const feesAfter = (
+      await pg.program.account.globalState.fetch(globalStateKp.publicKey)
+    ).accumulatedFees;
+    assert(feesAfter.sub(feesBefore).eq(oldLoan.fee));
+    const newLoan = await pg.program.account.flashLoanState.fetch(
+      flashLoanStateKp.publicKey
+    );
+    assert(newLoan.amount.eqn(150));
+
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+  });
+
+  it("Minimum Stake Applies Only To New Positions", async () => {
+    const newStaker = new web3.Keypair();
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const stakeAccounts = (user: web3.PublicKey) => ({
+      globalState: globalStateKp.publicKey,
+      user,
+      userStake: web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("user_stake"), user.toBuffer()],
+        pg.program.programId
+      )[0],
+      userTokenAccount: pg.wallet.publicKey,
+      stakeVault: stakeVault.publicKey,
+      stakeVaultAuthority: pg.wallet.publicKey,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+    });
+
+    await pg.program.methods
+      .setMinStake(new BN(100))
+      .accounts(adminAccounts)
+      .rpc();
+
+    await expectError(
+      pg.program.methods
+        .stake(new BN(99))
+        .accounts(stakeAccounts(newStaker.publicKey))
+        .signers([newStaker])
+        .rpc(),
+      "StakeTooSmall"
+    );
+
+    // The wallet already has a position, so a tiny top-up is accepted.
+    await pg.program.methods
+      .stake(new BN(1))
+      .accounts(stakeAccounts(pg.wallet.publicKey))
+      .rpc();
+
+    await pg.program.methods.setMinStake(new BN(0)).accounts(adminAccounts).rpc();
+  });
+
+  it("Fees Are Attributed Per Pool", async () => {
+    const otherMint = new web3.Keypair();
+    const otherPoolAccount = new web3.Keypair();
+    const [otherPoolPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("pool"), otherMint.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+
+    await pg.program.methods
+      .createPool()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        pool: otherPoolPda,
+        poolAccount: otherPoolAccount.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    const borrowAndRepay = async (
+      pool: web3.PublicKey,
+      poolTokenAccount: web3.PublicKey,
+      amount: BN
+    ) => {
+      const flashLoanStateKp = new web3.Keypair();
+      await pg.program.methods
+        .flashLoan(amount, new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool,
+          poolAccount: poolTokenAccount,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+      await pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool,
+          poolAccount: poolTokenAccount,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          repayer: borrower.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrowerReputation: borrowerReputationPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower])
+        .rpc();
+    };
+
+    const poolFeesBefore = await pg.program.methods
+      .getPoolFees()
+      .accounts({ pool: poolPda })
+      .view();
+
+    await borrowAndRepay(poolPda, poolAccount.publicKey, new BN(200));
+    await borrowAndRepay(otherPoolPda, otherPoolAccount.publicKey, new BN(400));
+
+    const feeRate = (
+      await pg.program.account.globalState.fetch(globalStateKp.publicKey)
+    ).feeRate;
+    const poolFeesAfter = await pg.program.methods
+      .getPoolFees()
+      .accounts({ pool: poolPda })
+      .view();
+    const otherPoolFees = await pg.program.methods
+      .getPoolFees()
+      .accounts({ pool: otherPoolPda })
+      .view();
+    assert(poolFeesAfter.sub(poolFeesBefore).eq(new BN(200).mul(feeRate).divn(10000)));
+    assert(otherPoolFees.eq(new BN(400).mul(feeRate).divn(10000)));
+  });
+
+  it("Operator Withdrawals Draw Down Allowance", async () => {
+    const operator = new web3.Keypair();
+    const [providerPositionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("provider_position"), globalStateKp.publicKey.toBuffer(), liquidityProvider.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const [allowancePda] = web3.PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("allowance"),
+        liquidityProvider.publicKey.toBuffer(),
+        operator.publicKey.toBuffer(),
+      ],
+      pg.program.programId
+    );
+    const operatorWithdraw = (amount: BN) =>
+      pg.program.methods
+        .withdrawLiquidity(amount)
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          poolAccount: poolAccount.publicKey,
+          provider: liquidityProvider.publicKey,
+          authority: operator.publicKey,
+          providerPosition: providerPositionPda,
+          allowance: allowancePda,
+          providerTokenAccount: pg.wallet.publicKey,
+          destination: null,
+          poolAuthority: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers([operator])
+        .rpc();
+
+    await pg.program.methods
+      .approveWithdrawal(operator.publicKey, new BN(100))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        owner: liquidityProvider.publicKey,
+        providerPosition: providerPositionPda,
+        allowance: allowancePda,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([liquidityProvider])
+      .rpc();
+
+    // A partial withdrawal leaves the rest of the allowance available.
+    await operatorWithdraw(new BN(60));
+    const partial = await pg.program.account.withdrawalAllowance.fetch(
+      allowancePda
+    );
+    assert(partial.remaining.eqn(40));
+
+    // Exceeding what remains is rejected.
+    await expectError(operatorWithdraw(new BN(41)), "AllowanceExceeded");
+
+    // Using exactly what remains exhausts the allowance.
+    await operatorWithdraw(new BN(40));
+    const exhausted = await pg.program.account.withdrawalAllowance.fetch(
+      allowancePda
+    );
+    assert(exhausted.remaining.eqn(0));
+    await expectError(operatorWithdraw(new BN(1)), "AllowanceExceeded");
+  });
+
+  it("Larger Loans Earn More Reputation", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const reputationAfterLoan = async (amount: BN) => {
+      const flashLoanStateKp = new web3.Keypair();
+      await pg.program.methods
+        .flashLoan(amount, new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+      await pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          repayer: borrower.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrowerReputation: borrowerReputationPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower])
+        .rpc();
+      return (
+        await pg.program.account.borrowerReputation.fetch(borrowerReputationPda)
+      ).reputation;
+    };
+
+    // One point per loan plus one per full 100 tokens of principal.
+    await pg.program.methods
+      .setReputationParams(new BN(1), new BN(100), new BN(0))
+      .accounts(adminAccounts)
+      .rpc();
+
+    const start = (
+      await pg.program.account.borrowerReputation.fetch(borrowerReputationPda)
+    ).reputation;
+    const afterSmall = await reputationAfterLoan(new BN(50));
+    const afterLarge = await reputationAfterLoan(new BN(500));
+    const smallGain = afterSmall.sub(start);
+    const largeGain = afterLarge.sub(afterSmall);
+    assert(smallGain.eqn(1));
+    assert(largeGain.eqn(6));
+
+    // The cap bounds the score regardless of loan size.
+    await pg.program.methods
+      .setReputationParams(new BN(1), new BN(100), afterLarge.addn(2))
+      .accounts(adminAccounts)
+      .rpc();
+    const capped = await reputationAfterLoan(new BN(500));
+    assert(capped.eq(afterLarge.addn(2)));
+
+    await pg.program.methods
+      .setReputationParams(new BN(1), new BN(0), new BN(0))
+      .accounts(adminAccounts)
+      .rpc();
+  });
+
+  it("Partial Repayments Release Collateral Proportionally", async () => {
+    const flashLoanStateKp = new web3.Keypair();
+
+    await pg.program.methods
+      .flashLoan(new BN(1000), new BN(100), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+
+    const loan = await pg.program.account.flashLoanState.fetch(
+      flashLoanStateKp.publicKey
+    );
+    const owed = loan.amount.add(loan.fee);
+    const firstHalf = owed.divn(2);
+    const secondHalf = owed.sub(firstHalf);
+    const repayPartial = (amount: BN, collateralEscrow = collateralEscrowPda, borrowerCollateralAccount = pg.wallet.publicKey) =>
+      pg.program.methods
+        .repayPartial(amount)
+        .accounts({
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          borrower: borrower.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          collateralEscrow,
+          escrowAuthority: escrowAuthorityPda,
+          borrowerCollateralAccount,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers([borrower])
+        .rpc();
+
+    // Another mint's escrow, holding other borrowers' collateral, releases nothing for this loan.
+    const otherMint = await splToken.createMint(pg.connection, pg.wallet.keypair, pg.wallet.publicKey, null, 0);
+    const [otherEscrow] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("collateral_escrow"), otherMint.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .createCollateralEscrow()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        collateralMint: otherMint,
+        collateralEscrow: otherEscrow,
+        escrowAuthority: escrowAuthorityPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    await splToken.mintTo(pg.connection, pg.wallet.keypair, otherMint, otherEscrow, pg.wallet.keypair, 10_000);
+    const otherReceiver = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      otherMint,
+      borrower.publicKey,
+      new web3.Keypair()
+    );
+    await expectError(repayPartial(firstHalf, otherEscrow, otherReceiver), "CollateralMintMismatch");
+    await expectError(repayPartial(firstHalf, collateralEscrowPda, otherReceiver), "CollateralMintMismatch");
+
+    await repayPartial(firstHalf);
+    const afterFirst = await pg.program.account.flashLoanState.fetch(
+      flashLoanStateKp.publicKey
+    );
+    const firstRelease = afterFirst.collateralReleased;
+    assert(firstRelease.eq(loan.collateral.mul(firstHalf).div(owed)));
+
+    // The final tranche releases exactly what remains in escrow.
+    await repayPartial(secondHalf);
+    const afterSecond = await pg.program.account.flashLoanState.fetch(
+      flashLoanStateKp.publicKey
+    );
+    assert(afterSecond.collateralReleased.eq(loan.collateral));
+    assert(afterSecond.collateralReleased.sub(firstRelease).sub(firstRelease).abs().lten(1));
+
+    await expectError(repayPartial(new BN(1)), "RepaymentExceedsOwed");
+  });
+
+  it("Pool Authority Must Own Or Be Delegated The Pool", async () => {
+    const delegate = new web3.Keypair();
+    const stranger = new web3.Keypair();
+    const [providerPositionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("provider_position"), globalStateKp.publicKey.toBuffer(), liquidityProvider.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const withdrawWith = (poolAuthority: web3.Keypair | null) =>
+      pg.program.methods
+        .withdrawLiquidity(new BN(10))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          poolAccount: poolAccount.publicKey,
+          provider: liquidityProvider.publicKey,
+          authority: liquidityProvider.publicKey,
+          providerPosition: providerPositionPda,
+          allowance: null,
+          providerTokenAccount: pg.wallet.publicKey,
+          destination: null,
+          poolAuthority: poolAuthority
+            ? poolAuthority.publicKey
+            : pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers(
+          poolAuthority ? [liquidityProvider, poolAuthority] : [liquidityProvider]
+        )
+        .rpc();
+
+    // Owner: the wallet owns the pool token account.
+    await withdrawWith(null);
+
+    // Delegate: approved by the owner to move pool funds.
+    await splToken.approve(
+      pg.connection,
+      pg.wallet.keypair,
+      poolAccount.publicKey,
+      delegate.publicKey,
+      pg.wallet.publicKey,
+      10
+    );
+    await withdrawWith(delegate);
+
+    // Unauthorized: neither owner nor delegate.
+    await expectError(withdrawWith(stranger), "InvalidPoolAuthority");
+  });
+
+  it("Accounting Invariants Hold Across Random Operation Sequences", async () => {
+    const random = mulberry32(0x52594654);
+    const randomAmount = (max: number) => new BN(1 + Math.floor(random() * max));
+    const [providerPositionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("provider_position"), globalStateKp.publicKey.toBuffer(), liquidityProvider.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+
+    const operations = {
+      deposit: () =>
+        pg.program.methods
+          .depositLiquidity(randomAmount(100))
+          .accounts({
+            globalState: globalStateKp.publicKey,
+            provider: liquidityProvider.publicKey,
+            providerPosition: providerPositionPda,
+            providerTokenAccount: pg.wallet.publicKey,
+            poolAccount: poolAccount.publicKey,
+            tokenProgram: splToken.TOKEN_PROGRAM_ID,
+            systemProgram: web3.SystemProgram.programId,
+          })
+          .signers([liquidityProvider])
+          .rpc(),
+      withdraw: async () => {
+        const position = await pg.program.account.providerPosition.fetch(
+          providerPositionPda
+        );
+        if (position.amount.isZero()) return;
+        await pg.program.methods
+          .withdrawLiquidity(BN.min(position.amount, randomAmount(100)))
+          .accounts({
+            globalState: globalStateKp.publicKey,
+            poolAccount: poolAccount.publicKey,
+            provider: liquidityProvider.publicKey,
+            authority: liquidityProvider.publicKey,
+            providerPosition: providerPositionPda,
+            allowance: null,
+            providerTokenAccount: pg.wallet.publicKey,
+            destination: null,
+            poolAuthority: pg.wallet.publicKey,
+            tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          })
+          .signers([liquidityProvider])
+          .rpc();
+      },
+      stake: () =>
+        pg.program.methods
+          .stake(randomAmount(50))
+          .accounts({
+            globalState: globalStateKp.publicKey,
+            user: pg.wallet.publicKey,
+            userStake: userStakePda,
+            userTokenAccount: pg.wallet.publicKey,
+            stakeVault: stakeVault.publicKey,
+            stakeVaultAuthority: pg.wallet.publicKey,
+            tokenProgram: splToken.TOKEN_PROGRAM_ID,
+            systemProgram: web3.SystemProgram.programId,
+          })
+          .rpc(),
+      unstake: async () => {
+        const userStake = await pg.program.account.userStake.fetch(userStakePda);
+        if (stakedBalance(userStake).isZero()) return;
+        await pg.program.methods
+          .unstake(BN.min(stakedBalance(userStake), randomAmount(50)))
+          .accounts({
+            globalState: globalStateKp.publicKey,
+            user: pg.wallet.publicKey,
+            userStake: userStakePda,
+            stakeVault: stakeVault.publicKey,
+            stakeVaultAuthority: pg.wallet.publicKey,
+            userTokenAccount: pg.wallet.publicKey,
+            tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          })
+          .rpc();
+      },
+      loanAndRepay: async () => {
+        const flashLoanStateKp = new web3.Keypair();
+        await pg.program.methods
+          .flashLoan(randomAmount(100), new BN(0), new BN(0))
+          .accounts({
+            globalState: globalStateKp.publicKey,
+            pool: poolPda,
+            poolAccount: poolAccount.publicKey,
+            poolAuthority: pg.wallet.publicKey,
+            borrowerTokenAccount: pg.wallet.publicKey,
+            borrower: borrower.publicKey,
+            flashLoanState: flashLoanStateKp.publicKey,
+            borrowerCollateralAccount: pg.wallet.publicKey,
+            collateralEscrow: collateralEscrowPda,
+            tokenProgram: splToken.TOKEN_PROGRAM_ID,
+            systemProgram: web3.SystemProgram.programId,
+          })
+          .signers([borrower, flashLoanStateKp])
+          .rpc();
+        await pg.program.methods
+          .repayFlashLoan()
+          .accounts({
+            globalState: globalStateKp.publicKey,
+            pool: poolPda,
+            poolAccount: poolAccount.publicKey,
+            poolAuthority: pg.wallet.publicKey,
+            flashLoanState: flashLoanStateKp.publicKey,
+            borrower: borrower.publicKey,
+            repayer: borrower.publicKey,
+            borrowerTokenAccount: pg.wallet.publicKey,
+            borrowerReputation: borrowerReputationPda,
+            tokenProgram: splToken.TOKEN_PROGRAM_ID,
+            systemProgram: web3.SystemProgram.programId,
+          })
+          .signers([borrower])
+          .rpc();
+      },
+    };
+    const names = Object.keys(operations);
+
+    let previousFees = (
+      await pg.program.account.globalState.fetch(globalStateKp.publicKey)
+    ).accumulatedFees;
+    for (let step = 0; step < 25; step++) {
+      const name = names[Math.floor(random() * names.length)];
+      await operations[name]();
+
+      const state = await pg.program.account.globalState.fetch(
+        globalStateKp.publicKey
+      );
+      const poolBalance = new BN(
+        (await pg.connection.getTokenAccountBalance(poolAccount.publicKey)).value
+          .amount
+      );
+      const stakes = await pg.program.account.userStake.all();
+      const stakeSum = stakes.reduce((sum, s) => sum.add(stakedBalance(s.account)), new BN(0));
+
+      assert(state.totalLiquidity.lte(poolBalance), `step ${step} (${name}): liquidity exceeds pool`);
+      assert(state.totalStaked.eq(stakeSum), `step ${step} (${name}): total_staked drifted`);
+      assert(state.accumulatedFees.gte(previousFees), `step ${step} (${name}): fees decreased`);
+      previousFees = state.accumulatedFees;
+    }
+  });
+
+  it("Loan Reservation Honors Locked Fee Rate", async () => {
+    const loanAmount = new BN(1000);
+    const flashLoanStateKp = new web3.Keypair();
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [reservationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reservation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const lockedRate = (await pg.program.account.pool.fetch(poolPda)).feeRate;
+    const expiry = new BN(Math.floor(Date.now() / 1000) + 600);
+
+    await pg.program.methods
+      .reserveLoan(loanAmount, expiry)
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        borrower: borrower.publicKey,
+        reservation: reservationPda,
+        pool: poolPda,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        feeVault: feeVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+
+    // Raise the rate after the reservation was made.
+    await pg.program.methods
+      .updatePoolFeeRate(lockedRate.muln(3))
+      .accounts({ ...adminAccounts, pool: poolPda })
+      .rpc();
+
+    await pg.program.methods
+      .flashLoan(loanAmount, new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        reservation: reservationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+
+    const loan = await pg.program.account.flashLoanState.fetch(
+      flashLoanStateKp.publicKey
+    );
+    assert(loan.feeRate.eq(lockedRate));
+    assert(loan.fee.eq(loanAmount.mul(lockedRate).divn(10000)));
+    assert(loan.feePrepaid);
+    // The reservation is consumed by the loan.
+    assert((await pg.connection.getAccountInfo(reservationPda)) === null);
+
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+    await pg.program.methods
+      .updatePoolFeeRate(lockedRate)
+      .accounts({ ...adminAccounts, pool: poolPda })
+      .rpc();
+  });
+
+  it("Flash Loan Draws Across Multiple Vaults", async () => {
+    // A second vault of the pool mint, also controlled by the pool authority.
+    const secondVault = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      pg.wallet.publicKey,
+      new web3.Keypair()
+    );
+    const flashLoanStateKp = new web3.Keypair();
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const balanceOf = async (account: web3.PublicKey) =>
+      new BN((await pg.connection.getTokenAccountBalance(account)).value.amount);
+
+    await splToken.mintTo(pg.connection, pg.wallet.keypair, poolMint.publicKey, secondVault, pg.wallet.keypair, 1_000);
+    const primaryBefore = await balanceOf(poolAccount.publicKey);
+    const secondBefore = await balanceOf(secondVault);
+    // More than the pool account holds, so the second vault must be drained too.
+    const loanAmount = primaryBefore.add(secondBefore);
+    const vaultAccounts = [{ pubkey: secondVault, isWritable: true, isSigner: false }];
+    const borrow = (registration: web3.PublicKey) =>
+      pg.program.methods
+        .flashLoan(loanAmount, new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .remainingAccounts([{ pubkey: registration, isWritable: false, isSigner: false }, ...vaultAccounts])
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+
+    // Only vaults registered to the pool are drawn from, and the protocol's own vaults,
+    // though controlled by the same authority, can't be registered.
+    const [unregistered] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("extra_pool_vault"), poolPda.toBuffer(), secondVault.toBuffer()],
+      pg.program.programId
+    );
+    await expectError(borrow(unregistered), "UnregisteredPoolVault");
+    const { stakeVault: protocolStakeVault } = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    if (!protocolStakeVault.equals(web3.PublicKey.default)) {
+      await expectError(registerPoolVault(globalStateKp.publicKey, poolPda, protocolStakeVault), "InvalidPoolVault");
+    }
+    const registration = await registerPoolVault(globalStateKp.publicKey, poolPda, secondVault);
+    await borrow(registration);
+
+    const loan = await pg.program.account.flashLoanState.fetch(
+      flashLoanStateKp.publicKey
+    );
+    assert(loan.sources.length === 2);
+    assert(loan.sources[0].vault.equals(poolAccount.publicKey));
+    assert(loan.sources[0].amount.eq(primaryBefore));
+    assert(loan.sources[1].vault.equals(secondVault));
+    assert(loan.sources[1].amount.eq(secondBefore));
+    assert((await balanceOf(poolAccount.publicKey)).isZero());
+    assert((await balanceOf(secondVault)).isZero());
+
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .remainingAccounts(vaultAccounts)
+      .signers([borrower])
+      .rpc();
+
+    // Each vault gets back at least its principal, and together they receive principal plus fee.
+    const primaryAfter = await balanceOf(poolAccount.publicKey);
+    const secondAfter = await balanceOf(secondVault);
+    assert(primaryAfter.gte(primaryBefore));
+    assert(secondAfter.gte(secondBefore));
+    assert(primaryAfter.add(secondAfter).eq(loanAmount.add(loan.fee)));
+  });
+
+  it("Reputation Index Records First-Time Borrowers", async () => {
+    const [reputationIndexPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation_index")],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .createReputationIndex()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        reputationIndex: reputationIndexPda,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    const newBorrowers = [new web3.Keypair(), new web3.Keypair(), new web3.Keypair()];
+    for (const who of newBorrowers) {
+      const sig = await pg.connection.requestAirdrop(
+        who.publicKey,
+        web3.LAMPORTS_PER_SOL
+      );
+      await pg.connection.confirmTransaction(sig);
+
+      const flashLoanStateKp = new web3.Keypair();
+      const [reputationPda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("reputation"), who.publicKey.toBuffer()],
+        pg.program.programId
+      );
+      await pg.program.methods
+        .flashLoan(new BN(100), new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: who.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([who, flashLoanStateKp])
+        .rpc();
+      await pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: who.publicKey,
+          repayer: who.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrowerReputation: reputationPda,
+          reputationIndex: reputationIndexPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([who])
+        .rpc();
+    }
+
+    const index = await pg.program.account.reputationIndex.fetch(reputationIndexPda);
+    assert(index.borrowers.length === newBorrowers.length);
+    newBorrowers.forEach((who, i) => {
+      assert(index.borrowers[i].equals(who.publicKey));
+    });
+  });
+
+  it("Claim Rewards Swapped Enforces Minimum Output", async () => {
+    // The SPL Memo program accepts any UTF-8 data and moves no tokens, so it stands in
+    // for a swap adapter that returns nothing.
+    const mockSwapProgram = new web3.PublicKey(
+      "MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr"
+    );
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const donate = (amount: BN) =>
+      pg.program.methods
+        .donateRewards(amount)
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          donor: pg.wallet.publicKey,
+          donorTokenAccount: pg.wallet.publicKey,
+          rewardVault: rewardVault.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    const claimAccounts = {
+      globalState: globalStateKp.publicKey,
+      user: pg.wallet.publicKey,
+      userStake: userStakePda,
+      rewardVault: rewardVault.publicKey,
+      rewardVaultAuthority: pg.wallet.publicKey,
+      userRewardAccount: pg.wallet.publicKey,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+    };
+    const swappedAccounts = {
+      ...claimAccounts,
+      userOutputAccount: pg.wallet.publicKey,
+      swapProgram: mockSwapProgram,
+    };
+
+    // Without a configured swap program, only the plain claim is available.
+    await donate(new BN(100));
+    await expectError(
+      pg.program.methods.claimRewardsSwapped(new BN(0)).accounts(swappedAccounts).rpc(),
+      "SwapNotConfigured"
+    );
+    await pg.program.methods.claimRewards().accounts(claimAccounts).rpc();
+    const claimed = await pg.program.account.userStake.fetch(userStakePda);
+    assert(claimed.unclaimedRewards.eqn(0));
+
+    await pg.program.methods
+      .setSwapProgram(mockSwapProgram)
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+      })
+      .rpc();
+
+    // A small donation keeps the adapter's instruction data valid UTF-8.
+    await donate(new BN(50));
+    await expectError(
+      pg.program.methods.claimRewardsSwapped(new BN(1)).accounts(swappedAccounts).rpc(),
+      "SlippageExceeded"
+    );
+    // The reverted claim left the rewards pending; with no minimum the swap goes through.
+    await pg.program.methods
+      .claimRewardsSwapped(new BN(0))
+      .accounts(swappedAccounts)
+      .rpc();
+    const swapped = await pg.program.account.userStake.fetch(userStakePda);
+    assert(swapped.unclaimedRewards.eqn(0));
+  });
+
+  it("Per-Transaction Loan Cap", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const cap = new BN(100);
+    await pg.program.methods.setPerTxLoanCap(cap).accounts(adminAccounts).rpc();
+
+    // A single loan above the cap is rejected even though the pool could fund it.
+    const flashLoanStateKp = new web3.Keypair();
+    await expectError(
+      pg.program.methods
+        .flashLoan(cap.addn(1), new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc(),
+      "PerTxCapExceeded"
+    );
+
+    // Zero disables the cap.
+    await pg.program.methods.setPerTxLoanCap(new BN(0)).accounts(adminAccounts).rpc();
+    const quote = await pg.program.methods
+      .simulateFlashLoan(cap.addn(1), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        borrowerReputation: null,
+      })
+      .view();
+    assert(quote.accepted);
+  });
+
+  it("Repayment Rejects Another Borrower's Reputation", async () => {
+    const flashLoanStateKp = new web3.Keypair();
+    // The wallet's reputation account, not the borrower's.
+    const [otherReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const repayAccounts = (borrowerReputation: web3.PublicKey) => ({
+      globalState: globalStateKp.publicKey,
+      pool: poolPda,
+      poolAccount: poolAccount.publicKey,
+      poolAuthority: pg.wallet.publicKey,
+      flashLoanState: flashLoanStateKp.publicKey,
+      borrower: borrower.publicKey,
+      repayer: borrower.publicKey,
+      borrowerTokenAccount: pg.wallet.publicKey,
+      borrowerReputation,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+    });
+
+    await pg.program.methods
+      .flashLoan(new BN(100), new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+
+    const before = await pg.program.account.borrowerReputation.fetch(
+      borrowerReputationPda
+    );
+    // The seeds bind the reputation account to the borrower, so it is rejected
+    // before the program ever credits the wrong account.
+    await expectError(
+      pg.program.methods
+        .repayFlashLoan()
+        .accounts(repayAccounts(otherReputationPda))
+        .signers([borrower])
+        .rpc(),
+      "ConstraintSeeds"
+    );
+
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts(repayAccounts(borrowerReputationPda))
+      .signers([borrower])
+      .rpc();
+    const after = await pg.program.account.borrowerReputation.fetch(
+      borrowerReputationPda
+    );
+    assert(after.borrower.equals(borrower.publicKey));
+    assert(after.reputation.gt(before.reputation));
+  });
+
+  it("Fees Can Be Collected In A Separate Fee Mint", async () => {
+    const PRICE_PRECISION = new BN(1_000_000_000);
+    const loanAmount = new BN(1000);
+    const flashLoanStateKp = new web3.Keypair();
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const balanceOf = async (account: web3.PublicKey) =>
+      new BN((await pg.connection.getTokenAccountBalance(account)).value.amount);
+
+    // Collect fees in the fee vault's mint, at two fee-mint units per loan-mint unit.
+    const feeMint = (await splToken.getAccount(pg.connection, feeVault.publicKey)).mint;
+    const price = PRICE_PRECISION.muln(2);
+    await pg.program.methods
+      .setFeeMint(feeMint, price)
+      .accounts(adminAccounts)
+      .rpc();
+
+    await pg.program.methods
+      .flashLoan(loanAmount, new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const loan = await pg.program.account.flashLoanState.fetch(
+      flashLoanStateKp.publicKey
+    );
+
+    const poolBefore = await balanceOf(poolAccount.publicKey);
+    const feeVaultBefore = await balanceOf(feeVault.publicKey);
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        feeVault: feeVault.publicKey,
+        borrowerFeeAccount: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+
+    // Principal returns to the pool in the loan mint; the fee lands in the fee vault.
+    assert((await balanceOf(poolAccount.publicKey)).sub(poolBefore).eq(loanAmount));
+    assert(
+      (await balanceOf(feeVault.publicKey))
+        .sub(feeVaultBefore)
+        .eq(loan.fee.mul(price).div(PRICE_PRECISION))
+    );
+
+    await pg.program.methods.setFeeMint(null, new BN(0)).accounts(adminAccounts).rpc();
+  });
+
+  it("Emergency Unstake Returns Principal From A Drained Reward Vault", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const unstakeAccounts = {
+      globalState: globalStateKp.publicKey,
+      user: pg.wallet.publicKey,
+      userStake: userStakePda,
+      stakeVault: stakeVault.publicKey,
+      stakeVaultAuthority: pg.wallet.publicKey,
+      userTokenAccount: pg.wallet.publicKey,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+    };
+    const balanceOf = async (account: web3.PublicKey) =>
+      new BN((await pg.connection.getTokenAccountBalance(account)).value.amount);
+
+    // Drain the reward vault into a side account.
+    const rewardMint = (await splToken.getAccount(pg.connection, rewardVault.publicKey)).mint;
+    const sideAccount = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      rewardMint,
+      pg.wallet.publicKey,
+      new web3.Keypair()
+    );
+    const drained = await balanceOf(rewardVault.publicKey);
+    if (!drained.isZero()) {
+      await splToken.transfer(
+        pg.connection,
+        pg.wallet.keypair,
+        rewardVault.publicKey,
+        sideAccount,
+        pg.wallet.publicKey,
+        BigInt(drained.toString())
+      );
+    }
+
+    const pauserAccounts = {
+      globalState: globalStateKp.publicKey,
+      pauser: pg.wallet.publicKey,
+    };
+    await pg.program.methods.setPaused(true).accounts(pauserAccounts).rpc();
+    await expectError(
+      pg.program.methods.unstake(new BN(1)).accounts(unstakeAccounts).rpc(),
+      "ProgramPaused"
+    );
+
+    const position = await pg.program.account.userStake.fetch(userStakePda);
+    const stateBefore = await pg.program.account.globalState.fetch(
+      globalStateKp.publicKey
+    );
+    const vaultBefore = await balanceOf(stakeVault.publicKey);
+    await pg.program.methods.emergencyUnstake().accounts(unstakeAccounts).rpc();
+
+    const cleared = await pg.program.account.userStake.fetch(userStakePda);
+    const stateAfter = await pg.program.account.globalState.fetch(
+      globalStateKp.publicKey
+    );
+    assert(cleared.amount.eqn(0));
+    assert(cleared.unclaimedRewards.eqn(0));
+    assert(cleared.compoundedAmount.eqn(0));
+    assert(vaultBefore.sub(await balanceOf(stakeVault.publicKey)).eq(stakedBalance(position)));
+    assert(stateBefore.totalStaked.sub(stateAfter.totalStaked).eq(stakedBalance(position)));
+
+    // Restore the reward vault and resume normal operation.
+    await pg.program.methods.setPaused(false).accounts(pauserAccounts).rpc();
+    if (!drained.isZero()) {
+      await splToken.transfer(
+        pg.connection,
+        pg.wallet.keypair,
+        sideAccount,
+        rewardVault.publicKey,
+        pg.wallet.publicKey,
+        BigInt(drained.toString())
+      );
+    }
+  });
+
+  it("Borrower-Owned Collateral Escrow Is Rejected", async () => {
+    const flashLoanStateKp = new web3.Keypair();
+    // An ordinary token account the borrower controls, in the collateral mint.
+    const borrowerEscrow = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      borrower.publicKey,
+      new web3.Keypair()
+    );
+    const flashLoanAccounts = (collateralEscrow: web3.PublicKey) => ({
+      globalState: globalStateKp.publicKey,
+      pool: poolPda,
+      poolAccount: poolAccount.publicKey,
+      poolAuthority: pg.wallet.publicKey,
+      borrowerTokenAccount: pg.wallet.publicKey,
+      borrower: borrower.publicKey,
+      flashLoanState: flashLoanStateKp.publicKey,
+      borrowerCollateralAccount: pg.wallet.publicKey,
+      collateralEscrow,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+    });
+
+    await expectError(
+      pg.program.methods
+        .flashLoan(new BN(100), new BN(10), new BN(0))
+        .accounts(flashLoanAccounts(borrowerEscrow))
+        .signers([borrower, flashLoanStateKp])
+        .rpc(),
+      "ConstraintSeeds"
+    );
+
+    // The program-owned escrow is accepted and holds the collateral.
+    const escrowBefore = (await splToken.getAccount(pg.connection, collateralEscrowPda)).amount;
+    await pg.program.methods
+      .flashLoan(new BN(100), new BN(10), new BN(0))
+      .accounts(flashLoanAccounts(collateralEscrowPda))
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const escrowAfter = (await splToken.getAccount(pg.connection, collateralEscrowPda)).amount;
+    assert(escrowAfter - escrowBefore === BigInt(10));
+
+    // Full repayment returns the collateral from the escrow.
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        collateralEscrow: collateralEscrowPda,
+        escrowAuthority: escrowAuthorityPda,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+    const escrowRepaid = (await splToken.getAccount(pg.connection, collateralEscrowPda)).amount;
+    assert(escrowRepaid === escrowBefore);
+  });
+
+  it("Compounded Rewards Are Tracked Apart From Principal", async () => {
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const stakeAmount = new BN(500);
+
+    // A fresh stake increments principal only.
+    const before = await pg.program.account.userStake.fetch(userStakePda);
+    await pg.program.methods
+      .stake(stakeAmount)
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        user: pg.wallet.publicKey,
+        userTokenAccount: pg.wallet.publicKey,
+        stakeVault: stakeVault.publicKey,
+        stakeVaultAuthority: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    const staked = await pg.program.account.userStake.fetch(userStakePda);
+    assert(staked.amount.sub(before.amount).eq(stakeAmount));
+    assert(staked.compoundedAmount.eq(before.compoundedAmount));
+
+    // Compounding increments the compounded balance only.
+    await pg.program.methods
+      .donateRewards(new BN(100))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        donor: pg.wallet.publicKey,
+        donorTokenAccount: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const pending = pendingRewards(staked, state.accRewardPerShare);
+    await pg.program.methods
+      .compoundRewards()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        user: pg.wallet.publicKey,
+        userStake: userStakePda,
+        rewardVault: rewardVault.publicKey,
+        rewardVaultAuthority: pg.wallet.publicKey,
+        stakeVault: stakeVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const compounded = await pg.program.account.userStake.fetch(userStakePda);
+    assert(compounded.amount.eq(staked.amount));
+    assert(compounded.compoundedAmount.sub(staked.compoundedAmount).eq(pending));
+
+    // Unstaking everything reports principal and compounded rewards separately.
+    const txHash = await pg.program.methods
+      .unstake(stakedBalance(compounded))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        user: pg.wallet.publicKey,
+        userStake: userStakePda,
+        stakeVault: stakeVault.publicKey,
+        stakeVaultAuthority: pg.wallet.publicKey,
+        userTokenAccount: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const [unstaked] = await fetchEvents(txHash, "unstaked");
+    assert(unstaked.principal.eq(compounded.amount));
+    assert(unstaked.compounded.eq(compounded.compoundedAmount));
+  });
+
+  it("Protocol-Owned Liquidity Fees Route To Treasury", async () => {
+    const seedAmount = new BN(5000);
+    const loanAmount = new BN(1000);
+    const flashLoanStateKp = new web3.Keypair();
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const balanceOf = async (account: web3.PublicKey) =>
+      new BN((await pg.connection.getTokenAccountBalance(account)).value.amount);
+    // A pool-mint token account owned by the treasury (the wallet).
+    const treasuryTokenAccount = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      pg.wallet.publicKey,
+      new web3.Keypair()
+    );
+
+    const stateBefore = await pg.program.account.globalState.fetch(
+      globalStateKp.publicKey
+    );
+    await pg.program.methods
+      .seedLiquidity(seedAmount)
+      .accounts({
+        ...adminAccounts,
+        adminTokenAccount: pg.wallet.publicKey,
+        poolAccount: poolAccount.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const seeded = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(seeded.totalLiquidity.sub(stateBefore.totalLiquidity).eq(seedAmount));
+    assert(
+      seeded.protocolOwnedLiquidity.sub(stateBefore.protocolOwnedLiquidity).eq(seedAmount)
+    );
+    await pg.program.methods.setRoutePolFees(true).accounts(adminAccounts).rpc();
+
+    await pg.program.methods
+      .flashLoan(loanAmount, new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const loan = await pg.program.account.flashLoanState.fetch(
+      flashLoanStateKp.publicKey
+    );
+
+    const poolBefore = await balanceOf(poolAccount.publicKey);
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        treasuryTokenAccount,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+
+    // The treasury gets the POL share of the fee; LPs keep the rest in the pool.
+    const polFee = loan.fee
+      .mul(seeded.protocolOwnedLiquidity)
+      .div(seeded.totalLiquidity);
+    assert((await balanceOf(treasuryTokenAccount)).eq(polFee));
+    assert(
+      (await balanceOf(poolAccount.publicKey))
+        .sub(poolBefore)
+        .eq(loanAmount.add(loan.fee).sub(polFee))
+    );
+
+    // The protocol can take its liquidity back out.
+    await pg.program.methods
+      .withdrawProtocolLiquidity(seedAmount)
+      .accounts({
+        ...adminAccounts,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        destination: treasuryTokenAccount,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const withdrawn = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(withdrawn.protocolOwnedLiquidity.eq(stateBefore.protocolOwnedLiquidity));
+    assert(withdrawn.totalLiquidity.eq(stateBefore.totalLiquidity));
+    await pg.program.methods.setRoutePolFees(false).accounts(adminAccounts).rpc();
+  });
+
+  it("Self-Referential Borrow Reverts", async () => {
+    const flashLoanStateKp = new web3.Keypair();
+    const flashLoanAccounts = {
+      globalState: globalStateKp.publicKey,
+      pool: poolPda,
+      poolAccount: poolAccount.publicKey,
+      poolAuthority: pg.wallet.publicKey,
+      borrowerTokenAccount: pg.wallet.publicKey,
+      borrower: borrower.publicKey,
+      flashLoanState: flashLoanStateKp.publicKey,
+      borrowerCollateralAccount: pg.wallet.publicKey,
+      collateralEscrow: collateralEscrowPda,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+    };
+    const stateBefore = await pg.program.account.globalState.fetch(
+      globalStateKp.publicKey
+    );
+
+    // Borrowing into the pool account itself would be a no-op transfer.
+    await expectError(
+      pg.program.methods
+        .flashLoan(new BN(100), new BN(0), new BN(0))
+        .accounts({ ...flashLoanAccounts, borrowerTokenAccount: poolAccount.publicKey })
+        .signers([borrower, flashLoanStateKp])
+        .rpc(),
+      "SelfTransfer"
+    );
+    // Likewise for posting collateral from the escrow into itself.
+    await expectError(
+      pg.program.methods
+        .flashLoan(new BN(100), new BN(10), new BN(0))
+        .accounts({ ...flashLoanAccounts, borrowerCollateralAccount: collateralEscrowPda })
+        .signers([borrower, flashLoanStateKp])
+        .rpc(),
+      "SelfTransfer"
+    );
+
+    // Nothing was recorded.
+    const stateAfter = await pg.program.account.globalState.fetch(
+      globalStateKp.publicKey
+    );
+    assert(!stateAfter.isFlashLoanActive);
+    assert(stateAfter.activeBorrower.equals(stateBefore.activeBorrower));
+    assert((await pg.connection.getAccountInfo(flashLoanStateKp.publicKey)) === null);
+  });
+
+  it("Reward Emissions Accrue Linearly And Halt At Period End", async () => {
+    const duration = 6;
+    const emission = new BN(600);
+    const syncAccounts = { globalState: globalStateKp.publicKey };
+    const fetchState = () =>
+      pg.program.account.globalState.fetch(globalStateKp.publicKey);
+
+    // Emissions are only attributed while something is staked.
+    await pg.program.methods
+      .stake(new BN(500))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        user: pg.wallet.publicKey,
+        userTokenAccount: pg.wallet.publicKey,
+        stakeVault: stakeVault.publicKey,
+        stakeVaultAuthority: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    await pg.program.methods
+      .fundEmissions(emission, new BN(duration))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        adminTokenAccount: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const funded = await fetchState();
+    assert(funded.rewardRatePerSecond.eq(emission.divn(duration)));
+    assert(funded.rewardPeriodEnd.eq(funded.lastEmissionUpdate.addn(duration)));
+
+    // Mid-period, the accumulator grows by rate * elapsed / total_reward_weight.
+    await sleep(2000);
+    await pg.program.methods.syncEmissions().accounts(syncAccounts).rpc();
+    const mid = await fetchState();
+    const elapsed = mid.lastEmissionUpdate.sub(funded.lastEmissionUpdate);
+    assert(elapsed.gtn(0));
+    const expectedMid = funded.rewardRatePerSecond
+      .mul(elapsed)
+      .mul(REWARD_PRECISION)
+      .div(funded.totalRewardWeight);
+    assert(mid.accRewardPerShare.sub(funded.accRewardPerShare).eq(expectedMid));
+
+    // Past the period end, emission stops at exactly the scheduled total.
+    await sleep((duration + 1) * 1000);
+    await pg.program.methods.syncEmissions().accounts(syncAccounts).rpc();
+    const ended = await fetchState();
+    const expectedTotal = funded.rewardRatePerSecond
+      .muln(duration)
+      .mul(REWARD_PRECISION)
+      .div(funded.totalRewardWeight);
+    assert(
+      ended.accRewardPerShare.sub(funded.accRewardPerShare).sub(expectedTotal).abs().lten(1)
+    );
+
+    await sleep(1000);
+    await pg.program.methods.syncEmissions().accounts(syncAccounts).rpc();
+    const after = await fetchState();
+    assert(after.accRewardPerShare.eq(ended.accRewardPerShare));
+  });
+
+  it("Stake Operations Assert Stake Vault Solvency", async () => {
+    const stakeAccounts = {
+      globalState: globalStateKp.publicKey,
+      user: pg.wallet.publicKey,
+      userTokenAccount: pg.wallet.publicKey,
+      stakeVault: stakeVault.publicKey,
+      stakeVaultAuthority: pg.wallet.publicKey,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+    };
+
+    // Solvent: the vault backs every staked token.
+    await pg.program.methods.stake(new BN(10)).accounts(stakeAccounts).rpc();
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const vault = await splToken.getAccount(pg.connection, stakeVault.publicKey);
+    assert(new BN(vault.amount.toString()).gte(state.totalStaked));
+
+    // Desynced: move tokens out of the vault behind the program's back.
+    const sideAccount = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      vault.mint,
+      pg.wallet.publicKey,
+      new web3.Keypair()
+    );
+    await splToken.transfer(
+      pg.connection,
+      pg.wallet.keypair,
+      stakeVault.publicKey,
+      sideAccount,
+      pg.wallet.publicKey,
+      vault.amount
+    );
+    await expectError(
+      pg.program.methods.stake(new BN(10)).accounts(stakeAccounts).rpc(),
+      "StakeVaultInsolvent"
+    );
+
+    // Restore the vault.
+    await splToken.transfer(
+      pg.connection,
+      pg.wallet.keypair,
+      sideAccount,
+      stakeVault.publicKey,
+      pg.wallet.publicKey,
+      vault.amount
+    );
+    await pg.program.methods.stake(new BN(10)).accounts(stakeAccounts).rpc();
+  });
+
+  it("Compliance Hook Screens Borrowers", async () => {
+    // The SPL Noop program never returns an approval, so it acts as a hook
+    // that denies every borrower.
+    const denyAllHook = new web3.PublicKey(
+      "noopb9bkMVfRPU8AsbpTUg8AQkHtKwMYZiFUjNRtMmV"
+    );
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const flashLoanStateKp = new web3.Keypair();
+    const flashLoanAccounts = (complianceProgram: web3.PublicKey | null) => ({
+      globalState: globalStateKp.publicKey,
+      pool: poolPda,
+      poolAccount: poolAccount.publicKey,
+      poolAuthority: pg.wallet.publicKey,
+      borrowerTokenAccount: pg.wallet.publicKey,
+      borrower: borrower.publicKey,
+      flashLoanState: flashLoanStateKp.publicKey,
+      borrowerCollateralAccount: pg.wallet.publicKey,
+      collateralEscrow: collateralEscrowPda,
+      complianceProgram: complianceProgram,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+    });
+    const borrow = (complianceProgram: web3.PublicKey | null) =>
+      pg.program.methods
+        .flashLoan(new BN(100), new BN(0), new BN(0))
+        .accounts(flashLoanAccounts(complianceProgram))
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+
+    await pg.program.methods
+      .setComplianceHook(denyAllHook)
+      .accounts(adminAccounts)
+      .rpc();
+    await expectError(borrow(denyAllHook), "ComplianceRejected");
+    // Omitting the hook program does not skip screening.
+    await expectError(borrow(null), "ComplianceRejected");
+
+    // With the hook cleared, the same borrower is admitted.
+    await pg.program.methods.setComplianceHook(null).accounts(adminAccounts).rpc();
+    await borrow(null);
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+  });
+  it("First-Deposit Donation Attack Is Mitigated", async () => {
+    // A fresh state and pool so the attacker makes the first deposit.
+    const stateKp = new web3.Keypair();
+    const attacker = new web3.Keypair();
+    const victim = new web3.Keypair();
+    for (const user of [attacker, victim]) {
+      await pg.connection.confirmTransaction(
+        await pg.connection.requestAirdrop(user.publicKey, web3.LAMPORTS_PER_SOL)
+      );
+    }
+    await pg.program.methods
+      .initialize(new BN(500))
+      .accounts({
+        globalState: stateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        treasury: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        feeVault: feeVault.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([stateKp])
+      .rpc();
+    const mint = await splToken.createMint(
+      pg.connection,
+      pg.wallet.keypair,
+      pg.wallet.publicKey,
+      null,
+      0
+    );
+    const tokenAccount = async (owner: web3.PublicKey, amount: number) => {
+      const account = await splToken.createAccount(
+        pg.connection,
+        pg.wallet.keypair,
+        mint,
+        owner,
+        new web3.Keypair()
+      );
+      if (amount > 0) {
+        await splToken.mintTo(
+          pg.connection,
+          pg.wallet.keypair,
+          mint,
+          account,
+          pg.wallet.keypair,
+          amount
+        );
+      }
+      return account;
+    };
+    const pool = await tokenAccount(pg.wallet.publicKey, 0);
+    const attackerTokens = await tokenAccount(attacker.publicKey, 200_000);
+    const victimTokens = await tokenAccount(victim.publicKey, 50_000);
+    const positionOf = (user: web3.Keypair) =>
+      web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("provider_position"), stateKp.publicKey.toBuffer(), user.publicKey.toBuffer()],
+        pg.program.programId
+      )[0];
+    const deposit = (user: web3.Keypair, from: web3.PublicKey, amount: number) =>
+      pg.program.methods
+        .depositLiquidity(new BN(amount))
+        .accounts({
+          globalState: stateKp.publicKey,
+          provider: user.publicKey,
+          providerPosition: positionOf(user),
+          providerTokenAccount: from,
+          poolAccount: pool,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+
+    // A first deposit that can't cover the locked minimum is rejected.
+    await expectError(deposit(attacker, attackerTokens, 1000), "ZeroShares");
+    // The attacker takes a single share, then donates straight to the pool.
+    const attackerCost = 1001 + 100_000;
+    await deposit(attacker, attackerTokens, 1001);
+    await splToken.transfer(
+      pg.connection,
+      pg.wallet.keypair,
+      attackerTokens,
+      pool,
+      attacker,
+      100_000
+    );
+    await deposit(victim, victimTokens, 50_000);
+
+    const state = await pg.program.account.globalState.fetch(stateKp.publicKey);
+    const poolValue = new BN(
+      (await pg.connection.getTokenAccountBalance(pool)).value.amount
+    );
+    const redeemable = async (user: web3.Keypair) => {
+      const position = await pg.program.account.providerPosition.fetch(positionOf(user));
+      return position.shares.mul(poolValue).div(state.totalShares);
+    };
+    // Without the locked minimum the victim would mint zero shares and lose the deposit.
+    const victimValue = await redeemable(victim);
+    assert(!victimValue.isZero());
+    assert(new BN(50_000).sub(victimValue).muln(1000).lten(50_000), victimValue.toString());
+    // The donation mostly accrues to the locked shares, so the attack loses money.
+    assert((await redeemable(attacker)).ltn(attackerCost));
+  });
+
+  it("Liquid And Locked Stake Positions Earn Different Boosts", async () => {
+    const amount = new BN(1000);
+    const positionPda = (index: number) =>
+      web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("stake"), pg.wallet.publicKey.toBuffer(), Buffer.from([index])],
+        pg.program.programId
+      )[0];
+    const open = (index: number, lockDuration: number) =>
+      pg.program.methods
+        .openPosition(index, amount, new BN(lockDuration))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: pg.wallet.publicKey,
+          stakePosition: positionPda(index),
+          userTokenAccount: pg.wallet.publicKey,
+          stakeVault: stakeVault.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+    const close = (index: number) =>
+      pg.program.methods
+        .closePosition()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: pg.wallet.publicKey,
+          stakePosition: positionPda(index),
+          stakeVault: stakeVault.publicKey,
+          stakeVaultAuthority: pg.wallet.publicKey,
+          userTokenAccount: pg.wallet.publicKey,
+          rewardVault: rewardVault.publicKey,
+          rewardVaultAuthority: pg.wallet.publicKey,
+          userRewardAccount: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    const earned = (position: any, accRewardPerShare: BN) =>
+      position.amount
+        .mul(position.boostBps)
+        .divn(10000)
+        .mul(accRewardPerShare)
+        .div(REWARD_PRECISION)
+        .sub(position.rewardDebt);
+
+    const stateBefore = await pg.program.account.globalState.fetch(
+      globalStateKp.publicKey
+    );
+    await open(0, 0);
+    await open(1, 30 * 24 * 60 * 60);
+    const liquid = await pg.program.account.stakePosition.fetch(positionPda(0));
+    const locked = await pg.program.account.stakePosition.fetch(positionPda(1));
+    assert(liquid.boostBps.eqn(10000));
+    assert(locked.boostBps.gtn(10000));
+    assert(locked.lockUntil.gtn(0));
+    const opened = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    // Both positions count toward total_staked at face value.
+    assert(opened.totalStaked.sub(stateBefore.totalStaked).eq(amount.muln(2)));
+
+    await pg.program.methods
+      .donateRewards(new BN(10000))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        donor: pg.wallet.publicKey,
+        donorTokenAccount: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const { accRewardPerShare } = await pg.program.account.globalState.fetch(
+      globalStateKp.publicKey
+    );
+    const liquidEarned = earned(liquid, accRewardPerShare);
+    const lockedEarned = earned(locked, accRewardPerShare);
+    assert(lockedEarned.gt(liquidEarned));
+    // Rewards scale with the boost.
+    assert(
+      lockedEarned
+        .muln(10000)
+        .sub(liquidEarned.mul(locked.boostBps))
+        .abs()
+        .lte(locked.boostBps.addn(10000))
+    );
+
+    // The locked position can't be closed early; the liquid one can.
+    await expectError(close(1), "StakeLocked");
+    await close(0);
+    assert((await pg.connection.getAccountInfo(positionPda(0))) === null);
+  });
+
+  it("Simulated Flash Loan Predicts The Real Outcome", async () => {
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const simulate = (amount: BN) =>
+      pg.program.methods
+        .simulateFlashLoan(amount, new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          borrowerReputation: null,
+        })
+        .view();
+    const borrow = (amount: BN, flashLoanStateKp: web3.Keypair) =>
+      pg.program.methods
+        .flashLoan(amount, new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+
+    // An affordable loan is predicted to succeed at the fee it is then charged.
+    const okAmount = new BN(100);
+    const accepted = await simulate(okAmount);
+    assert(accepted.accepted);
+    assert(accepted.errorCode === 0);
+    const flashLoanStateKp = new web3.Keypair();
+    await borrow(okAmount, flashLoanStateKp);
+    const loan = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+    assert(loan.fee.eq(accepted.fee));
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+
+    // A loan beyond the available liquidity is predicted to fail with the same error.
+    const tooMuch = accepted.availableLiquidity.addn(1);
+    const rejected = await simulate(tooMuch);
+    assert(!rejected.accepted);
+    const insufficient = pg.program.idl.errors.find(
+      (err) => err.name === "InsufficientLiquidity"
+    );
+    assert(rejected.errorCode === insufficient.code);
+    await expectError(borrow(tooMuch, new web3.Keypair()), "InsufficientLiquidity");
+  });
+
+  it("Fee Tiers Apply Across Bracket Boundaries", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const tier = (threshold: number, feeBps: number) => ({
+      threshold: new BN(threshold),
+      feeBps: new BN(feeBps),
+    });
+    const quote = async (amount: number) =>
+      (
+        await pg.program.methods
+          .simulateFlashLoan(new BN(amount), new BN(0))
+          .accounts({
+            globalState: globalStateKp.publicKey,
+            pool: poolPda,
+            poolAccount: poolAccount.publicKey,
+            poolAuthority: pg.wallet.publicKey,
+            borrowerTokenAccount: pg.wallet.publicKey,
+            borrower: borrower.publicKey,
+            borrowerCollateralAccount: pg.wallet.publicKey,
+            borrowerReputation: null,
+          })
+          .view()
+      ).feeRate.toNumber();
+
+    // Tiers must be sorted by threshold and capped at 100%.
+    await expectError(
+      pg.program.methods
+        .setFeeTiers([tier(1000, 100), tier(100, 300)])
+        .accounts(adminAccounts)
+        .rpc(),
+      "InvalidFeeTiers"
+    );
+    await expectError(
+      pg.program.methods.setFeeTiers([tier(100, 10001)]).accounts(adminAccounts).rpc(),
+      "InvalidFeeTiers"
+    );
+
+    const { feeRate } = await pg.program.account.globalState.fetch(
+      globalStateKp.publicKey
+    );
+    await pg.program.methods
+      .setFeeTiers([tier(100, 300), tier(1000, 100)])
+      .accounts(adminAccounts)
+      .rpc();
+    // Below the first threshold the flat rate applies.
+    assert((await quote(99)) === feeRate.toNumber());
+    assert((await quote(100)) === 300);
+    assert((await quote(999)) === 300);
+    assert((await quote(1000)) === 100);
+
+    // A real loan at the boundary is charged the tier rate.
+    const flashLoanStateKp = new web3.Keypair();
+    await pg.program.methods
+      .flashLoan(new BN(1000), new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const loan = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+    assert(loan.feeRate.eqn(100));
+    assert(loan.fee.eqn(10));
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+
+    await pg.program.methods.setFeeTiers([]).accounts(adminAccounts).rpc();
+    assert((await quote(1000)) === feeRate.toNumber());
+  });
+
+  it("Staking Into Another User's Stake Account Reverts", async () => {
+    const intruder = new web3.Keypair();
+    await pg.connection.confirmTransaction(
+      await pg.connection.requestAirdrop(intruder.publicKey, web3.LAMPORTS_PER_SOL)
+    );
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    // The existing position is bound to the wallet that opened it.
+    const position = await pg.program.account.userStake.fetch(userStakePda);
+    assert(position.owner.equals(pg.wallet.publicKey));
+
+    await expectError(
+      pg.program.methods
+        .stake(new BN(500))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: intruder.publicKey,
+          userStake: userStakePda,
+          userTokenAccount: pg.wallet.publicKey,
+          stakeVault: stakeVault.publicKey,
+          stakeVaultAuthority: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([intruder])
+        .rpc(),
+      "ConstraintSeeds"
+    );
+    const after = await pg.program.account.userStake.fetch(userStakePda);
+    assert(after.owner.equals(pg.wallet.publicKey));
+    assert(stakedBalance(after).eq(stakedBalance(position)));
+  });
+
+  it("Signed Vouchers Waive Fees Once", async () => {
+    const voucherSigner = new web3.Keypair();
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const amount = new BN(100);
+    const expiry = new BN(Math.floor(Date.now() / 1000) + 600);
+    const nonce = new BN(Date.now());
+    const [voucherPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("voucher"), nonce.toArrayLike(Buffer, "le", 8)],
+      pg.program.programId
+    );
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const redeem = (signer: web3.Keypair) =>
+      pg.program.methods
+        .redeemVoucher(nonce, amount, expiry)
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          borrower: borrower.publicKey,
+          voucher: voucherPda,
+          instructions: web3.SYSVAR_INSTRUCTIONS_PUBKEY,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .preInstructions([
+          web3.Ed25519Program.createInstructionWithPrivateKey({
+            privateKey: signer.secretKey,
+            message: Buffer.concat([
+              borrower.publicKey.toBuffer(),
+              amount.toArrayLike(Buffer, "le", 8),
+              expiry.toArrayLike(Buffer, "le", 8),
+              nonce.toArrayLike(Buffer, "le", 8),
+            ]),
+          }),
+        ])
+        .signers([borrower])
+        .rpc();
+    const borrowWithVoucher = (flashLoanStateKp: web3.Keypair) =>
+      pg.program.methods
+        .flashLoan(amount, new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          voucher: voucherPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+
+    await pg.program.methods
+      .setVoucherSigner(voucherSigner.publicKey)
+      .accounts(adminAccounts)
+      .rpc();
+    // A voucher signed by any other key is rejected.
+    await expectError(redeem(new web3.Keypair()), "InvalidVoucher");
+    await redeem(voucherSigner);
+
+    const flashLoanStateKp = new web3.Keypair();
+    await borrowWithVoucher(flashLoanStateKp);
+    const loan = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+    assert(loan.fee.isZero());
+    assert((await pg.program.account.voucher.fetch(voucherPda)).consumed);
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+
+    // Replaying the voucher fails both at redemption and at borrow time.
+    await expectError(redeem(voucherSigner), "already in use");
+    await expectError(borrowWithVoucher(new web3.Keypair()), "VoucherConsumed");
+
+    await pg.program.methods.setVoucherSigner(null).accounts(adminAccounts).rpc();
+  });
+
+  it("Reward Injections Halt At The Epoch Cap", async () => {
+    const epochLength = 3;
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const donate = (amount: number) =>
+      pg.program.methods
+        .donateRewards(new BN(amount))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          donor: pg.wallet.publicKey,
+          donorTokenAccount: pg.wallet.publicKey,
+          rewardVault: rewardVault.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    await pg.program.methods
+      .setEmissionCap(new BN(1000), new BN(epochLength))
+      .accounts(adminAccounts)
+      .rpc();
+    await donate(600);
+    // Funding a schedule counts against the same cap.
+    await expectError(
+      pg.program.methods
+        .fundEmissions(new BN(600), new BN(10))
+        .accounts({
+          ...adminAccounts,
+          adminTokenAccount: pg.wallet.publicKey,
+          rewardVault: rewardVault.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc(),
+      "EmissionCapExceeded"
+    );
+    await expectError(donate(600), "EmissionCapExceeded");
+    await donate(400);
+    const capped = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(capped.epochEmitted.eqn(1000));
+
+    // Changing the cap alone keeps counting what this epoch has already emitted.
+    await pg.program.methods
+      .setEmissionCap(new BN(1500), new BN(epochLength))
+      .accounts(adminAccounts)
+      .rpc();
+    const raised = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(raised.epochEmitted.eqn(1000));
+    assert(raised.emissionEpochStart.eq(capped.emissionEpochStart));
+
+    // The cap resets once the epoch has elapsed.
+    await sleep((epochLength + 1) * 1000);
+    await donate(600);
+    const next = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(next.epochEmitted.eqn(600));
+    assert(next.emissionEpochStart.gt(capped.emissionEpochStart));
+
+    await pg.program.methods
+      .setEmissionCap(new BN(0), new BN(epochLength))
+      .accounts(adminAccounts)
+      .rpc();
+  });
+
+  it("Repayment Enforces The Borrower's Minimum Surplus", async () => {
+    const flashLoanStateKp = new web3.Keypair();
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .flashLoan(new BN(100), new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const loan = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+    const balance = new BN(
+      (await pg.connection.getTokenAccountBalance(pg.wallet.publicKey)).value.amount
+    );
+    const afterRepayment = balance.sub(loan.amount).sub(loan.fee);
+    const setSurplus = (surplus: BN) =>
+      pg.program.methods
+        .setMinRepaySurplus(surplus)
+        .accounts({
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+        })
+        .signers([borrower])
+        .rpc();
+    const repay = () =>
+      pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          repayer: borrower.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrowerReputation: borrowerReputationPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower])
+        .rpc();
+
+    // One token more than will be left after repaying is not met.
+    await setSurplus(afterRepayment.addn(1));
+    await expectError(repay(), "InsufficientSurplus");
+    // Exactly what will be left is.
+    await setSurplus(afterRepayment);
+    await repay();
+  });
+
+  it("Accounts Carry Their Layout Version Through Migration", async () => {
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const versions = async () => [
+      (await pg.program.account.globalState.fetch(globalStateKp.publicKey)).version,
+      (await pg.program.account.pool.fetch(poolPda)).version,
+      (await pg.program.account.userStake.fetch(userStakePda)).version,
+    ];
+    // Accounts created by this program version start at the current layout.
+    assert.deepEqual(await versions(), [2, 2, 2]);
+
+    // Migrating an up-to-date account is a no-op that leaves it usable.
+    await pg.program.methods
+      .migrateGlobalState()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    const { feeRate: poolFeeRate } = await pg.program.account.pool.fetch(poolPda);
+    const migrateTx = await pg.program.methods
+      .migratePool()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        pool: poolPda,
+        mint: poolMint.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    await pg.connection.confirmTransaction(migrateTx, "confirmed");
+    // The audit records the pool's version and fee rate before and after.
+    const [audit] = await fetchEvents(migrateTx, "adminAudit");
+    assert("migratePool" in audit.action, JSON.stringify(audit.action));
+    const expected = ["2", poolFeeRate.toString()];
+    assert.deepEqual(audit.oldValue.numbers["0"].map((n: BN) => n.toString()), expected);
+    assert.deepEqual(audit.newValue.numbers["0"].map((n: BN) => n.toString()), expected);
+    await pg.program.methods
+      .migrateUserStake()
+      .accounts({
+        user: pg.wallet.publicKey,
+        userStake: userStakePda,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    assert.deepEqual(await versions(), [2, 2, 2]);
+    await pg.program.methods
+      .setPaused(false)
+      .accounts({ globalState: globalStateKp.publicKey, pauser: pg.wallet.publicKey })
+      .rpc();
+  });
+
+  it("Staking APR Reflects The Running Emission Rate", async () => {
+    const getApr = () =>
+      pg.program.methods
+        .getStakingApr()
+        .accounts({ globalState: globalStateKp.publicKey })
+        .view();
+
+    // 100 tokens per second for 1000 seconds.
+    await pg.program.methods
+      .fundEmissions(new BN(100_000), new BN(1000))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        adminTokenAccount: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(state.rewardRatePerSecond.eqn(100));
+    // rate * seconds per year * 10_000 bps / staked weight.
+    const expected = new BN(100)
+      .mul(new BN(365 * 24 * 60 * 60))
+      .muln(10000)
+      .div(state.totalRewardWeight);
+    assert((await getApr()).eq(expected));
+  });
+
+  it("Collateral Is Capped Relative To The Loan", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const loanAmount = new BN(100);
+    const simulate = (collateral: number) =>
+      pg.program.methods
+        .simulateFlashLoan(loanAmount, new BN(collateral))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          borrowerReputation: null,
+        })
+        .view();
+    const flashLoanStateKp = new web3.Keypair();
+
+    // Collateral of at most half the principal.
+    await pg.program.methods
+      .setMaxCollateralBps(new BN(5000))
+      .accounts(adminAccounts)
+      .rpc();
+    assert((await simulate(50)).accepted);
+    const over = await simulate(51);
+    assert(!over.accepted);
+    const excessive = pg.program.idl.errors.find((err) => err.name === "ExcessiveCollateral");
+    assert(over.errorCode === excessive.code);
+    await expectError(
+      pg.program.methods
+        .flashLoan(loanAmount, new BN(51), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc(),
+      "ExcessiveCollateral"
+    );
+
+    // Zero disables the cap.
+    await pg.program.methods.setMaxCollateralBps(new BN(0)).accounts(adminAccounts).rpc();
+    assert((await simulate(51)).accepted);
+  });
+
+  it("A Relayer Can Repay On The Borrower's Behalf", async () => {
+    // The wallet acts as the relayer, funding the repayment from its own account.
+    const relayer = pg.wallet.publicKey;
+    const flashLoanStateKp = new web3.Keypair();
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .flashLoan(new BN(100), new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const reputationBefore = await pg.program.account.borrowerReputation.fetch(
+      borrowerReputationPda
+    );
+    const borrowerLamportsBefore = await pg.connection.getBalance(borrower.publicKey);
+
+    // The borrower does not sign the repayment.
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: relayer,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    // Rent and reputation go to the borrower, not the relayer.
+    assert((await pg.connection.getAccountInfo(flashLoanStateKp.publicKey)) === null);
+    assert((await pg.connection.getBalance(borrower.publicKey)) > borrowerLamportsBefore);
+    const reputationAfter = await pg.program.account.borrowerReputation.fetch(
+      borrowerReputationPda
+    );
+    assert(reputationAfter.borrower.equals(borrower.publicKey));
+    assert(reputationAfter.reputation.gt(reputationBefore.reputation));
+  });
+
+  it("Zero Amounts Are Rejected", async () => {
+    const zero = new BN(0);
+    const [providerPositionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("provider_position"), globalStateKp.publicKey.toBuffer(), liquidityProvider.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const flashLoanStateKp = new web3.Keypair();
+
+    await expectError(
+      pg.program.methods
+        .depositLiquidity(zero)
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          provider: liquidityProvider.publicKey,
+          providerPosition: providerPositionPda,
+          providerTokenAccount: pg.wallet.publicKey,
+          poolAccount: poolAccount.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([liquidityProvider])
+        .rpc(),
+      "ZeroAmount"
+    );
+    await expectError(
+      pg.program.methods
+        .stake(zero)
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: pg.wallet.publicKey,
+          userTokenAccount: pg.wallet.publicKey,
+          stakeVault: stakeVault.publicKey,
+          stakeVaultAuthority: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc(),
+      "ZeroAmount"
+    );
+    await expectError(
+      pg.program.methods
+        .flashLoan(zero, zero, new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc(),
+      "ZeroAmount"
+    );
+    // The rejected loan never created its state account.
+    assert((await pg.connection.getAccountInfo(flashLoanStateKp.publicKey)) === null);
+  });
+
+
+  it("Fees Favor Long-Term Liquidity Over Just-In-Time Deposits", async () => {
+    // A fresh state and pool so the two providers hold all the shares.
+    const stateKp = new web3.Keypair();
+    const longTerm = new web3.Keypair();
+    const jit = new web3.Keypair();
+    const loanBorrower = new web3.Keypair();
+    for (const user of [longTerm, jit, loanBorrower]) {
+      await pg.connection.confirmTransaction(
+        await pg.connection.requestAirdrop(user.publicKey, web3.LAMPORTS_PER_SOL)
+      );
+    }
+    await pg.program.methods
+      .initialize(new BN(500))
+      .accounts({
+        globalState: stateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        treasury: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        feeVault: feeVault.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([stateKp])
+      .rpc();
+    const mint = await splToken.createMint(
+      pg.connection,
+      pg.wallet.keypair,
+      pg.wallet.publicKey,
+      null,
+      0
+    );
+    const tokenAccount = async (owner: web3.PublicKey, amount: number) => {
+      const account = await splToken.createAccount(
+        pg.connection,
+        pg.wallet.keypair,
+        mint,
+        owner,
+        new web3.Keypair()
+      );
+      if (amount > 0) {
+        await splToken.mintTo(
+          pg.connection,
+          pg.wallet.keypair,
+          mint,
+          account,
+          pg.wallet.keypair,
+          amount
+        );
+      }
+      return account;
+    };
+    const pool = await tokenAccount(pg.wallet.publicKey, 0);
+    const [pda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("pool"), mint.toBuffer()],
+      pg.program.programId
+    );
+    const [escrow] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("collateral_escrow"), mint.toBuffer()],
+      pg.program.programId
+    );
+    const adminAccounts = { globalState: stateKp.publicKey, admin: pg.wallet.publicKey };
+    await pg.program.methods
+      .createPool()
+      .accounts({
+        ...adminAccounts,
+        pool: pda,
+        poolAccount: pool,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    await pg.program.methods
+      .createCollateralEscrow()
+      .accounts({
+        ...adminAccounts,
+        collateralMint: mint,
+        collateralEscrow: escrow,
+        escrowAuthority: escrowAuthorityPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    const unlockPeriod = 20;
+    await pg.program.methods
+      .setFeeUnlockPeriod(new BN(unlockPeriod))
+      .accounts(adminAccounts)
+      .rpc();
+
+    const deposit = 100_000;
+    const tokens = new Map<web3.Keypair, web3.PublicKey>();
+    for (const user of [longTerm, jit]) {
+      tokens.set(user, await tokenAccount(user.publicKey, deposit));
+    }
+    const positionOf = (user: web3.Keypair) =>
+      web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("provider_position"), stateKp.publicKey.toBuffer(), user.publicKey.toBuffer()],
+        pg.program.programId
+      )[0];
+    const depositFor = (user: web3.Keypair) =>
+      pg.program.methods
+        .depositLiquidity(new BN(deposit))
+        .accounts({
+          globalState: stateKp.publicKey,
+          provider: user.publicKey,
+          providerPosition: positionOf(user),
+          providerTokenAccount: tokens.get(user),
+          poolAccount: pool,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([user])
+        .rpc();
+    const withdrawFor = (user: web3.Keypair, amount: number) =>
+      pg.program.methods
+        .withdrawLiquidity(new BN(amount))
+        .accounts({
+          globalState: stateKp.publicKey,
+          poolAccount: pool,
+          provider: user.publicKey,
+          authority: user.publicKey,
+          providerPosition: positionOf(user),
+          allowance: null,
+          providerTokenAccount: tokens.get(user),
+          destination: null,
+          poolAuthority: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers([user])
+        .rpc();
+
+    // The long-term LP is in well before the loan; the JIT LP arrives just ahead of it.
+    await depositFor(longTerm);
+    await depositFor(jit);
+
+    const borrowerTokens = await tokenAccount(loanBorrower.publicKey, 10_000);
+    const flashLoanStateKp = new web3.Keypair();
+    const [reputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), loanBorrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .flashLoan(new BN(deposit), new BN(0), new BN(0))
+      .accounts({
+        globalState: stateKp.publicKey,
+        pool: pda,
+        poolAccount: pool,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: borrowerTokens,
+        borrower: loanBorrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: borrowerTokens,
+        collateralEscrow: escrow,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([loanBorrower, flashLoanStateKp])
+      .rpc();
+    const fee = (
+      await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey)
+    ).fee.toNumber();
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: stateKp.publicKey,
+        pool: pda,
+        poolAccount: pool,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: loanBorrower.publicKey,
+        repayer: loanBorrower.publicKey,
+        borrowerTokenAccount: borrowerTokens,
+        borrowerReputation: reputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([loanBorrower])
+      .rpc();
+    const locked = await pg.program.account.globalState.fetch(stateKp.publicKey);
+    assert(locked.lockedFees.eqn(fee));
+
+    // Leaving straight away, the JIT LP gets its deposit back but almost none of the fee,
+    // even though it held half the pool's shares when the fee was paid.
+    await expectError(withdrawFor(jit, deposit + fee / 4), "InsufficientPosition");
+    await withdrawFor(jit, deposit);
+
+    // Once the fee has unlocked, the long-term LP can redeem nearly all of it.
+    await sleep((unlockPeriod + 1) * 1000);
+    await withdrawFor(longTerm, deposit - 1000 + (fee * 3) / 4);
+
+    // Each position's time-weighted liquidity reflects how long its shares were held.
+    const longTermPosition = await pg.program.account.providerPosition.fetch(positionOf(longTerm));
+    const jitPosition = await pg.program.account.providerPosition.fetch(positionOf(jit));
+    assert(longTermPosition.shareSeconds.gt(jitPosition.shareSeconds));
+  });
+
+  it("Pauser And Treasurer Roles Are Separate", async () => {
+    const pauser = new web3.Keypair();
+    const treasurer = new web3.Keypair();
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    await pg.program.methods.setPauser(pauser.publicKey).accounts(adminAccounts).rpc();
+    await pg.program.methods.setTreasurer(treasurer.publicKey).accounts(adminAccounts).rpc();
+    const feeMint = (await splToken.getAccount(pg.connection, feeVault.publicKey)).mint;
+    const destination = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      feeMint,
+      pg.wallet.publicKey,
+      new web3.Keypair()
+    );
+    const withdrawFees = (signer: web3.Keypair) =>
+      pg.program.methods
+        .withdrawFees(new BN(1))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          treasurer: signer.publicKey,
+          feeVault: feeVault.publicKey,
+          feeVaultAuthority: pg.wallet.publicKey,
+          destination,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers([signer])
+        .rpc();
+    const setPaused = (signer: web3.PublicKey, paused: boolean, signers: web3.Keypair[]) =>
+      pg.program.methods
+        .setPaused(paused)
+        .accounts({ globalState: globalStateKp.publicKey, pauser: signer })
+        .signers(signers)
+        .rpc();
+
+    // The pauser can't move funds, and the treasurer can't pause.
+    await expectError(withdrawFees(pauser), "Unauthorized");
+    await expectError(setPaused(treasurer.publicKey, true, [treasurer]), "Unauthorized");
+    // Once delegated, the admin can't pause either.
+    await expectError(setPaused(pg.wallet.publicKey, true, []), "Unauthorized");
+
+    // Each role can still do its own job.
+    await setPaused(pauser.publicKey, true, [pauser]);
+    assert((await pg.program.account.globalState.fetch(globalStateKp.publicKey)).paused);
+    await setPaused(pauser.publicKey, false, [pauser]);
+    // The treasurer's withdrawal is audited with the fee vault's balance either side of it.
+    const vaultBalance = async () =>
+      new BN((await pg.connection.getTokenAccountBalance(feeVault.publicKey)).value.amount);
+    const vaultBefore = await vaultBalance();
+    const [audit] = await fetchEvents(await withdrawFees(treasurer), "adminAudit");
+    const vaultAfter = await vaultBalance();
+    assert(vaultBefore.sub(vaultAfter).eqn(1));
+    assert(audit.actor.equals(treasurer.publicKey));
+    assert(audit.oldValue.number["0"].eq(vaultBefore));
+    assert(audit.newValue.number["0"].eq(vaultAfter));
+
+    // Hand both roles back to the admin.
+    await pg.program.methods.setPauser(pg.wallet.publicKey).accounts(adminAccounts).rpc();
+    await pg.program.methods.setTreasurer(pg.wallet.publicKey).accounts(adminAccounts).rpc();
+  });
+
+  it("Loan Volume Counts Repaid Principal Without Saturating", async () => {
+    const flashLoanStateKp = new web3.Keypair();
+    const loanAmount = new BN(100);
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const before = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    await pg.program.methods
+      .flashLoan(loanAmount, new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const repayTx = await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+
+    const after = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(after.totalLoanVolume.sub(before.totalLoanVolume).eq(loanAmount));
+    // A counter far from u128::MAX keeps counting without reporting saturation.
+    // (A state seeded near the maximum can't be fabricated from a client.)
+    assert.equal((await fetchEvents(repayTx, "counterSaturated")).length, 0);
+  });
+
+  it("Staking Borrowers Get A Fee Rebate", async () => {
+    const loanAmount = new BN(10_000);
+    const rebateCapBps = 5000;
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods.setRebateCapBps(new BN(rebateCapBps)).accounts(adminAccounts).rpc();
+
+    // Borrows and repays `loanAmount`, returning the net fee booked to the pool.
+    const netFee = async (signer: web3.Keypair | null, stake: web3.PublicKey | null) => {
+      const borrowerKey = signer ? signer.publicKey : pg.wallet.publicKey;
+      const signers = signer ? [signer] : [];
+      const flashLoanStateKp = new web3.Keypair();
+      const [reputationPda] = web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("reputation"), borrowerKey.toBuffer()],
+        pg.program.programId
+      );
+      await pg.program.methods
+        .flashLoan(loanAmount, new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrowerKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([...signers, flashLoanStateKp])
+        .rpc();
+      const before = await pg.program.account.pool.fetch(poolPda);
+      await pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrowerKey,
+          repayer: borrowerKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrowerReputation: reputationPda,
+          borrowerStake: stake,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers(signers)
+        .rpc();
+      const after = await pg.program.account.pool.fetch(poolPda);
+      return after.accumulatedFees.sub(before.accumulatedFees);
+    };
+
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const stake = await pg.program.account.userStake.fetch(userStakePda);
+    const fullFee = await netFee(borrower, null);
+    const stakerFee = await netFee(null, userStakePda);
+
+    // The staker's rebate is its stake share of the fee, capped at rebate_cap_bps.
+    const shareBps = BN.min(
+      stake.amount.muln(10_000).div(state.totalStaked),
+      new BN(rebateCapBps)
+    );
+    const rebate = fullFee.mul(shareBps).divn(10_000);
+    assert(!rebate.isZero());
+    assert(stakerFee.eq(fullFee.sub(rebate)), stakerFee.toString());
+
+    await expectError(
+      pg.program.methods.setRebateCapBps(new BN(10_001)).accounts(adminAccounts).rpc(),
+      "InvalidBps"
+    );
+    await pg.program.methods.setRebateCapBps(new BN(0)).accounts(adminAccounts).rpc();
+  });
+
+  it("Claim All Rewards Pays Every Position At Once", async () => {
+    const indices = [2, 3, 4];
+    const positionPda = (owner: web3.PublicKey, index: number) =>
+      web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("stake"), owner.toBuffer(), Buffer.from([index])],
+        pg.program.programId
+      )[0];
+    for (const [i, index] of indices.entries()) {
+      await pg.program.methods
+        .openPosition(index, new BN(1000 * (i + 1)), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: pg.wallet.publicKey,
+          stakePosition: positionPda(pg.wallet.publicKey, index),
+          userTokenAccount: pg.wallet.publicKey,
+          stakeVault: stakeVault.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+    }
+    await pg.program.methods
+      .donateRewards(new BN(10000))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        donor: pg.wallet.publicKey,
+        donorTokenAccount: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const claimAll = (user: web3.Keypair | null, positions: web3.PublicKey[]) =>
+      pg.program.methods
+        .claimAllRewards()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: user ? user.publicKey : pg.wallet.publicKey,
+          rewardVault: rewardVault.publicKey,
+          rewardVaultAuthority: pg.wallet.publicKey,
+          userRewardAccount: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .remainingAccounts(
+          positions.map((pubkey) => ({ pubkey, isSigner: false, isWritable: true }))
+        )
+        .signers(user ? [user] : [])
+        .rpc();
+    const positions = indices.map((index) => positionPda(pg.wallet.publicKey, index));
+
+    // Someone else can't claim the wallet's positions.
+    const stranger = new web3.Keypair();
+    await pg.connection.confirmTransaction(
+      await pg.connection.requestAirdrop(stranger.publicKey, web3.LAMPORTS_PER_SOL)
+    );
+    await expectError(claimAll(stranger, positions), "Unauthorized");
+
+    const before = await Promise.all(
+      positions.map((pda) => pg.program.account.stakePosition.fetch(pda))
+    );
+    const vaultBefore = new BN(
+      (await pg.connection.getTokenAccountBalance(rewardVault.publicKey)).value.amount
+    );
+    await claimAll(null, positions);
+    const { accRewardPerShare } = await pg.program.account.globalState.fetch(
+      globalStateKp.publicKey
+    );
+
+    // One transfer pays what each position would have claimed on its own.
+    const owed = (position: any) =>
+      position.amount
+        .mul(position.boostBps)
+        .divn(10000)
+        .mul(accRewardPerShare)
+        .div(REWARD_PRECISION)
+        .sub(position.rewardDebt)
+        .add(position.unclaimedRewards);
+    const expected = before.reduce((sum, position) => sum.add(owed(position)), new BN(0));
+    assert(!expected.isZero());
+    const vaultAfter = new BN(
+      (await pg.connection.getTokenAccountBalance(rewardVault.publicKey)).value.amount
+    );
+    assert(vaultBefore.sub(vaultAfter).eq(expected));
+    for (const pda of positions) {
+      const position = await pg.program.account.stakePosition.fetch(pda);
+      assert(position.unclaimedRewards.isZero());
+    }
+  });
+
+  it("Multi-Vault Loans Can't Be Repaid One Vault At A Time", async () => {
+    const secondVault = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      pg.wallet.publicKey,
+      new web3.Keypair()
+    );
+    await splToken.mintTo(pg.connection, pg.wallet.keypair, poolMint.publicKey, secondVault, pg.wallet.keypair, 1_000);
+    const registration = await registerPoolVault(globalStateKp.publicKey, poolPda, secondVault);
+    const flashLoanStateKp = new web3.Keypair();
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const balanceOf = async (account: web3.PublicKey) =>
+      new BN((await pg.connection.getTokenAccountBalance(account)).value.amount);
+    const loanAmount = (await balanceOf(poolAccount.publicKey)).add(await balanceOf(secondVault));
+    const vaultAccounts = [{ pubkey: secondVault, isWritable: true, isSigner: false }];
+    await pg.program.methods
+      .flashLoan(loanAmount, new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .remainingAccounts([{ pubkey: registration, isWritable: false, isSigner: false }, ...vaultAccounts])
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const repay = (remaining: typeof vaultAccounts) =>
+      pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          repayer: borrower.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrowerReputation: borrowerReputationPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .remainingAccounts(remaining)
+        .signers([borrower])
+        .rpc();
+
+    // Repaying only the primary vault reverts and leaves the loan open.
+    await expectError(repay([]), "PartialHopRepayment");
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(state.isFlashLoanActive);
+    assert(state.activeBorrower.equals(borrower.publicKey));
+    // Partial repayments aren't allowed on multi-vault loans either.
+    await expectError(
+      pg.program.methods
+        .repayPartial(new BN(1))
+        .accounts({
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          borrower: borrower.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          escrowAuthority: escrowAuthorityPda,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers([borrower])
+        .rpc(),
+      "MultiVaultLoan"
+    );
+
+    await repay(vaultAccounts);
+    const after = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(!after.isFlashLoanActive);
+  });
+
+  it("Withdrawing Down To Dust Closes The Position", async () => {
+    // A fresh state and pool so the provider's share value is easy to predict.
+    const stateKp = new web3.Keypair();
+    const provider = new web3.Keypair();
+    await pg.connection.confirmTransaction(
+      await pg.connection.requestAirdrop(provider.publicKey, web3.LAMPORTS_PER_SOL)
+    );
+    await pg.program.methods
+      .initialize(new BN(500))
+      .accounts({
+        globalState: stateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        treasury: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        feeVault: feeVault.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([stateKp])
+      .rpc();
+    await pg.program.methods
+      .setDustThreshold(new BN(10))
+      .accounts({ globalState: stateKp.publicKey, admin: pg.wallet.publicKey })
+      .rpc();
+    const mint = await splToken.createMint(
+      pg.connection,
+      pg.wallet.keypair,
+      pg.wallet.publicKey,
+      null,
+      0
+    );
+    const pool = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      mint,
+      pg.wallet.publicKey,
+      new web3.Keypair()
+    );
+    const providerTokens = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      mint,
+      provider.publicKey,
+      new web3.Keypair()
+    );
+    await splToken.mintTo(
+      pg.connection,
+      pg.wallet.keypair,
+      mint,
+      providerTokens,
+      pg.wallet.keypair,
+      10_000
+    );
+    const [positionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("provider_position"), stateKp.publicKey.toBuffer(), provider.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .depositLiquidity(new BN(10_000))
+      .accounts({
+        globalState: stateKp.publicKey,
+        provider: provider.publicKey,
+        providerPosition: positionPda,
+        providerTokenAccount: providerTokens,
+        poolAccount: pool,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([provider])
+      .rpc();
+
+    // 9,000 shares are worth 9,000 tokens; taking 8,995 leaves 5 behind, under the threshold.
+    const withdrawTx = await pg.program.methods
+      .withdrawLiquidity(new BN(8_995))
+      .accounts({
+        globalState: stateKp.publicKey,
+        poolAccount: pool,
+        provider: provider.publicKey,
+        authority: provider.publicKey,
+        providerPosition: positionPda,
+        allowance: null,
+        providerTokenAccount: providerTokens,
+        destination: null,
+        poolAuthority: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .signers([provider])
+      .rpc();
+
+    const [dusted] = await fetchEvents(withdrawTx, "positionDusted");
+    assert(dusted.owner.equals(provider.publicKey));
+    assert(dusted.position.equals(positionPda));
+    assert(dusted.amount.eqn(5));
+    assert((await pg.connection.getAccountInfo(positionPda)) === null);
+    const balance = await pg.connection.getTokenAccountBalance(providerTokens);
+    assert.equal(balance.value.amount, "9000");
+    // Only the locked minimum is left in the pool.
+    const state = await pg.program.account.globalState.fetch(stateKp.publicKey);
+    assert(state.totalShares.eqn(1000));
+  });
+
+  it("Subsidized Loans Cost The Borrower Less Until The Subsidy Runs Out", async () => {
+    const loanAmount = new BN(10_000);
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    // A pool-mint vault controlled by the wallet holds the subsidy.
+    const subsidyVault = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      pg.wallet.publicKey,
+      new web3.Keypair()
+    );
+    await pg.program.methods
+      .setSubsidy(subsidyVault, new BN(100))
+      .accounts(adminAccounts)
+      .rpc();
+    await pg.program.methods
+      .fundSubsidy(new BN(150))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        funder: pg.wallet.publicKey,
+        funderTokenAccount: pg.wallet.publicKey,
+        subsidyVault,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const { fee: fullFee } = await pg.program.methods
+      .simulateFlashLoan(loanAmount, new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        borrowerReputation: null,
+      })
+      .view();
+    assert(fullFee.gtn(100));
+
+    // Borrows and repays, returning the fee the borrower owed and the fee the pool booked.
+    const subsidizedLoan = async () => {
+      const flashLoanStateKp = new web3.Keypair();
+      await pg.program.methods
+        .flashLoan(loanAmount, new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          subsidyVault,
+          subsidyVaultAuthority: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+      const { fee: owed } = await pg.program.account.flashLoanState.fetch(
+        flashLoanStateKp.publicKey
+      );
+      const before = await pg.program.account.pool.fetch(poolPda);
+      await pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          repayer: borrower.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrowerReputation: borrowerReputationPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower])
+        .rpc();
+      const after = await pg.program.account.pool.fetch(poolPda);
+      return { owed, booked: after.accumulatedFees.sub(before.accumulatedFees) };
+    };
+
+    // The first loan gets the full per-loan subsidy, the second what's left, the third none.
+    for (const covered of [100, 50, 0]) {
+      const { owed, booked } = await subsidizedLoan();
+      assert(owed.eq(fullFee.subn(covered)), owed.toString());
+      assert(booked.eq(fullFee));
+    }
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(state.subsidyBalance.isZero());
+    const vault = await pg.connection.getTokenAccountBalance(subsidyVault);
+    assert.equal(vault.value.amount, "0");
+
+    await pg.program.methods
+      .setSubsidy(subsidyVault, new BN(0))
+      .accounts(adminAccounts)
+      .rpc();
+  });
+
+  it("Reward Debt Never Exceeds Accrued Rewards", async () => {
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const stake = (amount: number) =>
+      pg.program.methods
+        .stake(new BN(amount))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: pg.wallet.publicKey,
+          userStake: userStakePda,
+          userTokenAccount: pg.wallet.publicKey,
+          stakeVault: stakeVault.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+    const unstake = (amount: number) =>
+      pg.program.methods
+        .unstake(new BN(amount))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: pg.wallet.publicKey,
+          userStake: userStakePda,
+          stakeVault: stakeVault.publicKey,
+          stakeVaultAuthority: pg.wallet.publicKey,
+          userTokenAccount: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    const compound = () =>
+      pg.program.methods
+        .compoundRewards()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: pg.wallet.publicKey,
+          userStake: userStakePda,
+          rewardVault: rewardVault.publicKey,
+          rewardVaultAuthority: pg.wallet.publicKey,
+          stakeVault: stakeVault.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    const donate = (amount: number) =>
+      pg.program.methods
+        .donateRewards(new BN(amount))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          donor: pg.wallet.publicKey,
+          donorTokenAccount: pg.wallet.publicKey,
+          rewardVault: rewardVault.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    const steps: (() => Promise<unknown>)[] = [
+      () => stake(200),
+      () => donate(500),
+      () => compound(),
+      () => unstake(100),
+      () => donate(333),
+      () => stake(50),
+      () => compound(),
+      () => unstake(25),
+    ];
+    for (const step of steps) {
+      await step();
+      const { accRewardPerShare } = await pg.program.account.globalState.fetch(
+        globalStateKp.publicKey
+      );
+      const userStake = await pg.program.account.userStake.fetch(userStakePda);
+      // Every settlement leaves the debt at or below what the stake has accrued.
+      const accrued = stakedBalance(userStake).mul(accRewardPerShare).div(REWARD_PRECISION);
+      assert(userStake.rewardDebt.lte(accrued), userStake.rewardDebt.toString());
+      assert(!pendingRewards(userStake, accRewardPerShare).isNeg());
+    }
+  });
+
+  it("Permissionless Crank Distributes Staker Fees Once Per Interval", async () => {
+    const interval = 4;
+    const crankReward = new BN(5);
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .setDistributionParams(new BN(2000), new BN(interval), crankReward)
+      .accounts(adminAccounts)
+      .rpc();
+    const cranker = new web3.Keypair();
+    await pg.connection.confirmTransaction(
+      await pg.connection.requestAirdrop(cranker.publicKey, web3.LAMPORTS_PER_SOL)
+    );
+    const crankerTokens = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      cranker.publicKey,
+      new web3.Keypair()
+    );
+
+    // A repaid loan sets the stakers' share of its fee aside in the reward vault.
+    const borrowAndRepay = async () => {
+      const flashLoanStateKp = new web3.Keypair();
+      await pg.program.methods
+        .flashLoan(new BN(10_000), new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+      await pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          repayer: borrower.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrowerReputation: borrowerReputationPda,
+          rewardVault: rewardVault.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower])
+        .rpc();
+    };
+    const crank = () =>
+      pg.program.methods
+        .crankDistribution()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          cranker: cranker.publicKey,
+          rewardVault: rewardVault.publicKey,
+          rewardVaultAuthority: pg.wallet.publicKey,
+          crankerTokenAccount: crankerTokens,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers([cranker])
+        .rpc();
+
+    await borrowAndRepay();
+    await crank();
+    // Straight after a run, the next crank is too early even with fees waiting.
+    await borrowAndRepay();
+    const pending = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(pending.pendingStakerFees.gt(crankReward));
+    await expectError(crank(), "DistributionTooEarly");
+
+    await sleep((interval + 1) * 1000);
+    const crankerBefore = new BN(
+      (await pg.connection.getTokenAccountBalance(crankerTokens)).value.amount
+    );
+    const crankTx = await crank();
+    const after = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const [distributed] = await fetchEvents(crankTx, "rewardsDistributed");
+
+    // The cranker is paid, at most 1% of the distribution, and the rest of the set-aside
+    // fees go to stakers.
+    const expectedReward = BN.min(crankReward, pending.pendingStakerFees.divn(100));
+    assert(distributed.cranker.equals(cranker.publicKey));
+    assert(distributed.crankReward.eq(expectedReward));
+    assert(distributed.amount.eq(pending.pendingStakerFees.sub(expectedReward)));
+    const crankerAfter = new BN(
+      (await pg.connection.getTokenAccountBalance(crankerTokens)).value.amount
+    );
+    assert(crankerAfter.sub(crankerBefore).eq(expectedReward));
+    assert(after.pendingStakerFees.isZero());
+    assert(after.accRewardPerShare.gt(pending.accRewardPerShare));
+
+    await pg.program.methods
+      .setDistributionParams(new BN(0), new BN(0), new BN(0))
+      .accounts(adminAccounts)
+      .rpc();
+  });
+
+  it("Round Fees Up Charges One Extra Unit Only When There Is A Remainder", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const quote = (amount: BN) =>
+      pg.program.methods
+        .simulateFlashLoan(amount, new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          borrowerReputation: null,
+        })
+        .view();
+    const setRoundUp = (roundUp: boolean) =>
+      pg.program.methods.setRoundFeesUp(roundUp).accounts(adminAccounts).rpc();
+
+    // 10,001 at any fee rate below 100% leaves a remainder after dividing by 10,000.
+    const remainderAmount = new BN(10_001);
+    const evenAmount = new BN(10_000);
+
+    await setRoundUp(false);
+    const down = await quote(remainderAmount);
+    const floor = remainderAmount.mul(down.feeRate).divn(10000);
+    assert(down.fee.eq(floor), down.fee.toString());
+
+    await setRoundUp(true);
+    const up = await quote(remainderAmount);
+    assert(up.feeRate.eq(down.feeRate));
+    assert(up.fee.eq(floor.addn(1)), up.fee.toString());
+    // With no remainder there is nothing to round, so both modes agree.
+    const even = await quote(evenAmount);
+    assert(even.fee.eq(evenAmount.mul(even.feeRate).divn(10000)));
+
+    await setRoundUp(false);
+  });
+
+  it("Wind-Down Lets Everyone Exit And Then Closes The Protocol", async () => {
+    // A fresh state with its own pool and reward vault, since finalizing sweeps both.
+    const stateKp = new web3.Keypair();
+    const provider = new web3.Keypair();
+    await pg.connection.confirmTransaction(
+      await pg.connection.requestAirdrop(provider.publicKey, web3.LAMPORTS_PER_SOL)
+    );
+    const mint = await splToken.createMint(
+      pg.connection,
+      pg.wallet.keypair,
+      pg.wallet.publicKey,
+      null,
+      0
+    );
+    const newAccount = (owner: web3.PublicKey) =>
+      splToken.createAccount(pg.connection, pg.wallet.keypair, mint, owner, new web3.Keypair());
+    const pool = await newAccount(pg.wallet.publicKey);
+    const stateRewardVault = await newAccount(pg.wallet.publicKey);
+    const providerTokens = await newAccount(provider.publicKey);
+    const sweepDestination = await newAccount(pg.wallet.publicKey);
+    await splToken.mintTo(
+      pg.connection,
+      pg.wallet.keypair,
+      mint,
+      providerTokens,
+      pg.wallet.keypair,
+      10_000
+    );
+    await splToken.mintTo(
+      pg.connection,
+      pg.wallet.keypair,
+      mint,
+      stateRewardVault,
+      pg.wallet.keypair,
+      50
+    );
+    await pg.program.methods
+      .initialize(new BN(500))
+      .accounts({
+        globalState: stateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        treasury: pg.wallet.publicKey,
+        rewardVault: stateRewardVault,
+        feeVault: feeVault.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([stateKp])
+      .rpc();
+    const [positionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("provider_position"), stateKp.publicKey.toBuffer(), provider.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const deposit = (amount: BN) =>
+      pg.program.methods
+        .depositLiquidity(amount)
+        .accounts({
+          globalState: stateKp.publicKey,
+          provider: provider.publicKey,
+          providerPosition: positionPda,
+          providerTokenAccount: providerTokens,
+          poolAccount: pool,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([provider])
+        .rpc();
+    const finalize = () =>
+      pg.program.methods
+        .finalizeWindDown()
+        .accounts({
+          globalState: stateKp.publicKey,
+          admin: pg.wallet.publicKey,
+          poolAccount: pool,
+          poolAuthority: pg.wallet.publicKey,
+          rewardVault: stateRewardVault,
+          rewardVaultAuthority: pg.wallet.publicKey,
+          destination: sweepDestination,
+          rewardDestination: sweepDestination,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    await deposit(new BN(9_000));
+    await expectError(finalize(), "NotWindingDown");
+    await pg.program.methods
+      .initiateWindDown()
+      .accounts({ globalState: stateKp.publicKey, admin: pg.wallet.publicKey })
+      .rpc();
+
+    // New deposits are refused, and the protocol can't close while the LP is still in.
+    await expectError(deposit(new BN(1_000)), "WindingDown");
+    await expectError(finalize(), "WindDownIncomplete");
+
+    // 8,000 shares are worth 8,000 tokens; the provider takes all of it back.
+    await pg.program.methods
+      .withdrawLiquidity(new BN(8_000))
+      .accounts({
+        globalState: stateKp.publicKey,
+        poolAccount: pool,
+        provider: provider.publicKey,
+        authority: provider.publicKey,
+        providerPosition: positionPda,
+        allowance: null,
+        providerTokenAccount: providerTokens,
+        destination: null,
+        poolAuthority: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .signers([provider])
+      .rpc();
+    const position = await pg.program.account.providerPosition.fetch(positionPda);
+    assert(position.shares.isZero());
+    const providerBalance = await pg.connection.getTokenAccountBalance(providerTokens);
+    assert.equal(providerBalance.value.amount, "9000");
+
+    // Finalizing sweeps the locked minimum and the reward vault, then closes the state.
+    const finalizeTx = await finalize();
+    const [finalized] = await fetchEvents(finalizeTx, "windDownFinalized");
+    assert(finalized.poolResidual.eqn(1000));
+    assert(finalized.rewardResidual.eqn(50));
+    const swept = await pg.connection.getTokenAccountBalance(sweepDestination);
+    assert.equal(swept.value.amount, "1050");
+    assert((await pg.connection.getAccountInfo(stateKp.publicKey)) === null);
+  });
+
+  it("Repayment Checks The Source Account Before Transferring", async () => {
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const flashLoanStateKp = new web3.Keypair();
+    await pg.program.methods
+      .flashLoan(new BN(1_000), new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const repay = (source: web3.PublicKey) =>
+      pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          repayer: borrower.publicKey,
+          borrowerTokenAccount: source,
+          borrowerReputation: borrowerReputationPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower])
+        .rpc();
+
+    // An account in another mint is rejected before any transfer is attempted.
+    const otherMint = await splToken.createMint(
+      pg.connection,
+      pg.wallet.keypair,
+      pg.wallet.publicKey,
+      null,
+      0
+    );
+    const wrongMintAccount = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      otherMint,
+      borrower.publicKey,
+      new web3.Keypair()
+    );
+    await splToken.mintTo(
+      pg.connection,
+      pg.wallet.keypair,
+      otherMint,
+      wrongMintAccount,
+      pg.wallet.keypair,
+      1_000_000
+    );
+    await expectError(repay(wrongMintAccount), "MintMismatch");
+
+    // So is a pool-mint account too small to cover principal plus fee.
+    const emptyAccount = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      borrower.publicKey,
+      new web3.Keypair()
+    );
+    await expectError(repay(emptyAccount), "RepaymentInsufficient");
+
+    // The loan is still open and repays normally from the funded account.
+    await repay(pg.wallet.publicKey);
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(!state.isFlashLoanActive);
+  });
+
+  it("Repaid Fees Split Between LPs, Stakers And The Treasury", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const balanceOf = async (account: web3.PublicKey) =>
+      new BN((await pg.connection.getTokenAccountBalance(account)).value.amount);
+    const treasuryTokenAccount = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      pg.wallet.publicKey,
+      new web3.Keypair()
+    );
+    await expectError(
+      pg.program.methods.setFeeSplit(new BN(6000), new BN(5000)).accounts(adminAccounts).rpc(),
+      "InvalidBps"
+    );
+    await pg.program.methods.setFeeSplit(new BN(6000), new BN(3000)).accounts(adminAccounts).rpc();
+
+    const flashLoanStateKp = new web3.Keypair();
+    await pg.program.methods
+      .flashLoan(new BN(10_000), new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const loan = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+    const before = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const rewardVaultBefore = await balanceOf(rewardVault.publicKey);
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        treasuryTokenAccount,
+        rewardVault: rewardVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+    const after = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+
+    // 60% to LPs, 30% to stakers, and the remaining 10% (plus rounding) to the treasury.
+    const lpFee = loan.fee.muln(6000).divn(10000);
+    const stakerFee = loan.fee.muln(3000).divn(10000);
+    const treasuryFee = loan.fee.sub(lpFee).sub(stakerFee);
+    assert(after.accumulatedFees.sub(before.accumulatedFees).eq(treasuryFee));
+    assert((await balanceOf(treasuryTokenAccount)).eq(treasuryFee));
+    assert((await balanceOf(rewardVault.publicKey)).sub(rewardVaultBefore).eq(stakerFee));
+    // Stakers are credited straight away rather than waiting for the crank.
+    const credited = stakerFee.mul(REWARD_PRECISION).div(before.totalRewardWeight);
+    assert(after.accRewardPerShare.sub(before.accRewardPerShare).gte(credited));
+    assert(after.pendingStakerFees.eq(before.pendingStakerFees));
+    // The LP share is what the fee lock picked up.
+    assert(after.lockedFees.gte(lpFee));
+
+    await pg.program.methods.setFeeSplit(new BN(0), new BN(0)).accounts(adminAccounts).rpc();
+  });
+
+  it("Quoted Repayment Amount Is Exactly What Repaying Takes", async () => {
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const flashLoanStateKp = new web3.Keypair();
+    const quote = () =>
+      pg.program.methods
+        .getRepaymentAmount()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          borrowerStake: null,
+        })
+        .view();
+    await pg.program.methods
+      .flashLoan(new BN(2_000), new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const quoted: BN = await quote();
+    const loan = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+    assert(quoted.eq(loan.amount.add(loan.fee)), quoted.toString());
+
+    // A repayment account holding one unit less than quoted is refused...
+    const repaymentAccount = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      borrower.publicKey,
+      new web3.Keypair()
+    );
+    const fund = (amount: BN) =>
+      splToken.transfer(
+        pg.connection,
+        pg.wallet.keypair,
+        pg.wallet.publicKey,
+        repaymentAccount,
+        pg.wallet.keypair,
+        BigInt(amount.toString())
+      );
+    const repay = () =>
+      pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          repayer: borrower.publicKey,
+          borrowerTokenAccount: repaymentAccount,
+          borrowerReputation: borrowerReputationPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower])
+        .rpc();
+    await fund(quoted.subn(1));
+    await expectError(repay(), "RepaymentInsufficient");
+
+    // ...and exactly the quoted amount repays the loan, leaving nothing behind.
+    await fund(new BN(1));
+    await repay();
+    const left = await pg.connection.getTokenAccountBalance(repaymentAccount);
+    assert.equal(left.value.amount, "0");
+
+    // With the loan closed there is nothing left to quote.
+    await expectError(quote(), "AccountNotInitialized");
+  });
+
+  it("Program Whitelist Entries Admit Accounts The Program Owns", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    // Admission is checked through the simulator, since a PDA can only sign by CPI.
+    const admits = async (who: web3.PublicKey) => {
+      const result = await pg.program.methods
+        .simulateFlashLoan(new BN(100), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: who,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          borrowerReputation: null,
+        })
+        .view();
+      return result.accepted;
+    };
+    // Any account owned by the token program stands in for a composing protocol's PDA.
+    const programOwned = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      pg.wallet.publicKey,
+      new web3.Keypair()
+    );
+    const wallet = new web3.Keypair();
+    await pg.connection.confirmTransaction(
+      await pg.connection.requestAirdrop(wallet.publicKey, web3.LAMPORTS_PER_SOL)
+    );
+
+    await pg.program.methods
+      .addProgramToWhitelist(splToken.TOKEN_PROGRAM_ID)
+      .accounts(adminAccounts)
+      .rpc();
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const index = state.flashLoanWhitelist.findIndex((entry) =>
+      entry.equals(splToken.TOKEN_PROGRAM_ID)
+    );
+    assert(index >= 0);
+    assert.equal(state.whitelistEntryTypes[index], 1);
+
+    assert(await admits(programOwned));
+    assert(!(await admits(wallet.publicKey)));
+
+    await pg.program.methods
+      .removeFromWhitelist(splToken.TOKEN_PROGRAM_ID)
+      .accounts(adminAccounts)
+      .rpc();
+    const cleared = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert.equal(cleared.whitelistEntryTypes.length, cleared.flashLoanWhitelist.length);
+  });
+
+  it("Discounts Never Push A Fee Below The Floor", async () => {
+    const loanAmount = new BN(10_000);
+    const floorBps = 20;
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const [reputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    // Discount as hard as the config allows: a zero-rate tier for every amount and a
+    // rebate capped at the whole fee.
+    await pg.program.methods
+      .setFeeTiers([{ threshold: new BN(0), feeBps: new BN(0) }])
+      .accounts(adminAccounts)
+      .rpc();
+    await pg.program.methods.setRebateCapBps(new BN(10_000)).accounts(adminAccounts).rpc();
+    await expectError(
+      pg.program.methods.setMinEffectiveFeeBps(new BN(10_001)).accounts(adminAccounts).rpc(),
+      "InvalidBps"
+    );
+    await pg.program.methods
+      .setMinEffectiveFeeBps(new BN(floorBps))
+      .accounts(adminAccounts)
+      .rpc();
+    const floorFee = loanAmount.muln(floorBps).divn(10_000);
+
+    const quoted = await pg.program.methods
+      .simulateFlashLoan(loanAmount, new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: pg.wallet.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        borrowerReputation: null,
+      })
+      .view();
+    assert(quoted.feeRate.isZero());
+    assert(quoted.fee.eq(floorFee), quoted.fee.toString());
+
+    const flashLoanStateKp = new web3.Keypair();
+    await pg.program.methods
+      .flashLoan(loanAmount, new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([flashLoanStateKp])
+      .rpc();
+    const loan = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+    assert(loan.fee.eq(floorFee));
+
+    // The staking rebate would otherwise take the fee to zero; the floor holds it up.
+    const before = await pg.program.account.pool.fetch(poolPda);
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: pg.wallet.publicKey,
+        repayer: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: reputationPda,
+        borrowerStake: userStakePda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    const after = await pg.program.account.pool.fetch(poolPda);
+    assert(after.accumulatedFees.sub(before.accumulatedFees).eq(floorFee));
+
+    await pg.program.methods.setMinEffectiveFeeBps(new BN(0)).accounts(adminAccounts).rpc();
+    await pg.program.methods.setRebateCapBps(new BN(0)).accounts(adminAccounts).rpc();
+    await pg.program.methods.setFeeTiers([]).accounts(adminAccounts).rpc();
+  });
+
+  it("Stake Vault Migration Moves Stakes Without Unstaking Anyone", async () => {
+    // A fresh state and staker, so the shared stake vault stays in place for other tests.
+    const stateKp = new web3.Keypair();
+    const staker = new web3.Keypair();
+    const newVaultAuthority = new web3.Keypair();
+    await pg.connection.confirmTransaction(
+      await pg.connection.requestAirdrop(staker.publicKey, web3.LAMPORTS_PER_SOL)
+    );
+    await pg.program.methods
+      .initialize(new BN(500))
+      .accounts({
+        globalState: stateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        treasury: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        feeVault: feeVault.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([stateKp])
+      .rpc();
+    const mint = await splToken.createMint(
+      pg.connection,
+      pg.wallet.keypair,
+      pg.wallet.publicKey,
+      null,
+      0
+    );
+    const newAccount = (owner: web3.PublicKey) =>
+      splToken.createAccount(pg.connection, pg.wallet.keypair, mint, owner, new web3.Keypair());
+    const oldVault = await newAccount(pg.wallet.publicKey);
+    const newVault = await newAccount(newVaultAuthority.publicKey);
+    const stakerTokens = await newAccount(staker.publicKey);
+    await splToken.mintTo(
+      pg.connection,
+      pg.wallet.keypair,
+      mint,
+      stakerTokens,
+      pg.wallet.keypair,
+      1_000
+    );
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), staker.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .stake(new BN(1_000))
+      .accounts({
+        globalState: stateKp.publicKey,
+        user: staker.publicKey,
+        userStake: userStakePda,
+        userTokenAccount: stakerTokens,
+        stakeVault: oldVault,
+        stakeVaultAuthority: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([staker])
+      .rpc();
+    const before = await pg.program.account.globalState.fetch(stateKp.publicKey);
+
+    const migrateTx = await pg.program.methods
+      .migrateStakeVault()
+      .accounts({
+        globalState: stateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        stakeVault: oldVault,
+        stakeVaultAuthority: pg.wallet.publicKey,
+        newVault,
+        newVaultAuthority: newVaultAuthority.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .signers([newVaultAuthority])
+      .rpc();
+    const [migrated] = await fetchEvents(migrateTx, "stakeVaultMigrated");
+    assert(migrated.amount.eqn(1_000));
+    const after = await pg.program.account.globalState.fetch(stateKp.publicKey);
+    assert(after.stakeVault.equals(newVault));
+    assert(after.totalStaked.eq(before.totalStaked));
+    const balance = async (account: web3.PublicKey) =>
+      (await pg.connection.getTokenAccountBalance(account)).value.amount;
+    assert.equal(await balance(oldVault), "0");
+    assert.equal(await balance(newVault), "1000");
+
+    // Unstaking now draws from the new vault; the old one is no longer accepted.
+    const unstake = (vault: web3.PublicKey, authority: web3.Keypair | null) =>
+      pg.program.methods
+        .unstake(new BN(400))
+        .accounts({
+          globalState: stateKp.publicKey,
+          user: staker.publicKey,
+          userStake: userStakePda,
+          stakeVault: vault,
+          stakeVaultAuthority: authority ? authority.publicKey : pg.wallet.publicKey,
+          userTokenAccount: stakerTokens,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers(authority ? [staker, authority] : [staker])
+        .rpc();
+    await expectError(unstake(oldVault, null), "InvalidStakeVault");
+    await unstake(newVault, newVaultAuthority);
+    assert.equal(await balance(newVault), "600");
+    assert.equal(await balance(stakerTokens), "400");
+  });
+
+  it("Loan Proceeds Can Go To A Separate Recipient", async () => {
+    const loanAmount = new BN(1_000);
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    // A strategy vault the borrower doesn't own.
+    const strategy = new web3.Keypair();
+    const recipient = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      strategy.publicKey,
+      new web3.Keypair()
+    );
+    const otherMint = await splToken.createMint(
+      pg.connection,
+      pg.wallet.keypair,
+      pg.wallet.publicKey,
+      null,
+      0
+    );
+    const wrongMintRecipient = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      otherMint,
+      strategy.publicKey,
+      new web3.Keypair()
+    );
+    const borrow = (to: web3.PublicKey, flashLoanStateKp: web3.Keypair) =>
+      pg.program.methods
+        .flashLoan(loanAmount, new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          recipient: to,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+
+    await expectError(borrow(wrongMintRecipient, new web3.Keypair()), "MintMismatch");
+
+    const flashLoanStateKp = new web3.Keypair();
+    const reputationBefore = await pg.program.account.borrowerReputation.fetch(
+      borrowerReputationPda
+    );
+    await borrow(recipient, flashLoanStateKp);
+    const received = await pg.connection.getTokenAccountBalance(recipient);
+    assert.equal(received.value.amount, loanAmount.toString());
+    const loan = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+    assert(loan.borrower.equals(borrower.publicKey));
+
+    // The borrower repays from its own account and is credited for it.
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+    const reputationAfter = await pg.program.account.borrowerReputation.fetch(
+      borrowerReputationPda
+    );
+    assert(reputationAfter.reputation.gt(reputationBefore.reputation));
+    // The recipient keeps the proceeds; nothing was drawn back from it.
+    const kept = await pg.connection.getTokenAccountBalance(recipient);
+    assert.equal(kept.value.amount, loanAmount.toString());
+  });
+
+  it("Transferring A Locked Stake Carries The Lock Over", async () => {
+    const index = 6;
+    const newOwner = new web3.Keypair();
+    await pg.connection.confirmTransaction(
+      await pg.connection.requestAirdrop(newOwner.publicKey, web3.LAMPORTS_PER_SOL)
+    );
+    const positionPda = (owner: web3.PublicKey) =>
+      web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("stake"), owner.toBuffer(), Buffer.from([index])],
+        pg.program.programId
+      )[0];
+    await pg.program.methods
+      .openPosition(index, new BN(1000), new BN(30 * 24 * 60 * 60))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        user: pg.wallet.publicKey,
+        stakePosition: positionPda(pg.wallet.publicKey),
+        userTokenAccount: pg.wallet.publicKey,
+        stakeVault: stakeVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    const original = await pg.program.account.stakePosition.fetch(positionPda(pg.wallet.publicKey));
+    const before = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+
+    const transferTx = await pg.program.methods
+      .transferStake(newOwner.publicKey)
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        owner: pg.wallet.publicKey,
+        stakePosition: positionPda(pg.wallet.publicKey),
+        newPosition: positionPda(newOwner.publicKey),
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    // The sender's position is gone and the receiver holds it, lock and boost intact.
+    assert((await pg.connection.getAccountInfo(positionPda(pg.wallet.publicKey))) === null);
+    const moved = await pg.program.account.stakePosition.fetch(positionPda(newOwner.publicKey));
+    assert(moved.owner.equals(newOwner.publicKey));
+    assert(moved.amount.eq(original.amount));
+    assert(moved.boostBps.eq(original.boostBps));
+    assert(moved.lockUntil.eq(original.lockUntil));
+    const [transferred] = await fetchEvents(transferTx, "stakeTransferred");
+    assert(transferred.lockUntil.eq(original.lockUntil));
+    // Nothing was unstaked along the way.
+    const after = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(after.totalStaked.eq(before.totalStaked));
+    assert(after.totalRewardWeight.eq(before.totalRewardWeight));
+
+    // The new owner is bound by the same lock.
+    await expectError(
+      pg.program.methods
+        .closePosition()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: newOwner.publicKey,
+          stakePosition: positionPda(newOwner.publicKey),
+          stakeVault: stakeVault.publicKey,
+          stakeVaultAuthority: pg.wallet.publicKey,
+          userTokenAccount: pg.wallet.publicKey,
+          rewardVault: rewardVault.publicKey,
+          rewardVaultAuthority: pg.wallet.publicKey,
+          userRewardAccount: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers([newOwner])
+        .rpc(),
+      "StakeLocked"
+    );
+
+    // Merging into a position the new owner already holds needs their signature.
+    await pg.program.methods
+      .openPosition(index, new BN(1000), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        user: pg.wallet.publicKey,
+        stakePosition: positionPda(pg.wallet.publicKey),
+        userTokenAccount: pg.wallet.publicKey,
+        stakeVault: stakeVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    const merge = (newOwnerSigner: web3.PublicKey | null) =>
+      pg.program.methods.transferStake(newOwner.publicKey).accounts({
+        globalState: globalStateKp.publicKey,
+        owner: pg.wallet.publicKey,
+        stakePosition: positionPda(pg.wallet.publicKey),
+        newPosition: positionPda(newOwner.publicKey),
+        newOwnerSigner,
+        systemProgram: web3.SystemProgram.programId,
+      });
+    await expectError(merge(null).rpc(), "ReceiverMustSign");
+    await merge(newOwner.publicKey).signers([newOwner]).rpc();
+    const merged = await pg.program.account.stakePosition.fetch(positionPda(newOwner.publicKey));
+    assert(merged.amount.eq(original.amount.addn(1000)));
+    assert(merged.lockUntil.eq(original.lockUntil));
+  });
+
+  it("Transferring A User Stake Keeps Its Early-Unstake Window", async () => {
+    const newOwner = new web3.Keypair();
+    await pg.connection.confirmTransaction(
+      await pg.connection.requestAirdrop(newOwner.publicKey, web3.LAMPORTS_PER_SOL)
+    );
+    const userStakePda = (owner: web3.PublicKey) =>
+      web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("user_stake"), owner.toBuffer()],
+        pg.program.programId
+      )[0];
+    const transfer = (from: web3.Keypair, to: web3.PublicKey) =>
+      pg.program.methods
+        .transferUserStake(to)
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          owner: from.publicKey,
+          userStake: userStakePda(from.publicKey),
+          newUserStake: userStakePda(to),
+          newOwnerSigner: null,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([from])
+        .rpc();
+    const original = await pg.program.account.userStake.fetch(userStakePda(pg.wallet.publicKey));
+    const before = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+
+    await transfer(pg.wallet.keypair, newOwner.publicKey);
+    assert((await pg.connection.getAccountInfo(userStakePda(pg.wallet.publicKey))) === null);
+    const moved = await pg.program.account.userStake.fetch(userStakePda(newOwner.publicKey));
+    assert(moved.owner.equals(newOwner.publicKey));
+    assert(moved.amount.eq(original.amount));
+    assert(moved.compoundedAmount.eq(original.compoundedAmount));
+    assert(moved.firstStakeTimestamp.eq(original.firstStakeTimestamp));
+    assert(moved.unclaimedRewards.gte(original.unclaimedRewards));
+    // Nothing was unstaked along the way.
+    const after = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(after.totalStaked.eq(before.totalStaked));
+    assert(after.totalRewardWeight.eq(before.totalRewardWeight));
+
+    // Hand it back so the wallet's stake is where later tests expect it.
+    await transfer(newOwner, pg.wallet.publicKey);
+    const restored = await pg.program.account.userStake.fetch(userStakePda(pg.wallet.publicKey));
+    assert(restored.amount.eq(original.amount));
+    assert(restored.firstStakeTimestamp.eq(original.firstStakeTimestamp));
+  });
+
+  it("Burn Share Of Fee-Mint Fees Reduces Supply", async () => {
+    const PRICE_PRECISION = new BN(1_000_000_000);
+    const burnShareBps = 2500;
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const feeMint = (await splToken.getAccount(pg.connection, feeVault.publicKey)).mint;
+    const supply = async () => new BN((await splToken.getMint(pg.connection, feeMint)).supply.toString());
+    const feeVaultBalance = async () =>
+      new BN((await pg.connection.getTokenAccountBalance(feeVault.publicKey)).value.amount);
+    await pg.program.methods.setFeeMint(feeMint, PRICE_PRECISION).accounts(adminAccounts).rpc();
+    await expectError(
+      pg.program.methods.setBurnShareBps(new BN(10_001)).accounts(adminAccounts).rpc(),
+      "InvalidBps"
+    );
+    await pg.program.methods.setBurnShareBps(new BN(burnShareBps)).accounts(adminAccounts).rpc();
+
+    const flashLoanStateKp = new web3.Keypair();
+    await pg.program.methods
+      .flashLoan(new BN(10_000), new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const loan = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+    const repay = (feeMintAccount: web3.PublicKey | null) =>
+      pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          repayer: borrower.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrowerReputation: borrowerReputationPda,
+          feeVault: feeVault.publicKey,
+          borrowerFeeAccount: pg.wallet.publicKey,
+          feeMintAccount,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower])
+        .rpc();
+
+    // Without the mint there is nothing to burn against, so the repayment is refused.
+    await expectError(repay(null), "FeeBurnUnavailable");
+
+    const supplyBefore = await supply();
+    const vaultBefore = await feeVaultBalance();
+    await repay(feeMint);
+    const burned = loan.fee.muln(burnShareBps).divn(10_000);
+    assert(supplyBefore.sub(await supply()).eq(burned));
+    assert((await feeVaultBalance()).sub(vaultBefore).eq(loan.fee.sub(burned)));
+
+    await pg.program.methods.setBurnShareBps(new BN(0)).accounts(adminAccounts).rpc();
+    await pg.program.methods.setFeeMint(null, new BN(0)).accounts(adminAccounts).rpc();
+  });
+
+  it("Claim And Compound Cooldowns Are Enforced Separately", async () => {
+    const COOLDOWN = 3;
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const donate = () =>
+      pg.program.methods
+        .donateRewards(new BN(100))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          donor: pg.wallet.publicKey,
+          donorTokenAccount: pg.wallet.publicKey,
+          rewardVault: rewardVault.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    const claim = () =>
+      pg.program.methods
+        .claimRewards()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: pg.wallet.publicKey,
+          userStake: userStakePda,
+          rewardVault: rewardVault.publicKey,
+          rewardVaultAuthority: pg.wallet.publicKey,
+          userRewardAccount: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    const compound = () =>
+      pg.program.methods
+        .compoundRewards()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: pg.wallet.publicKey,
+          userStake: userStakePda,
+          rewardVault: rewardVault.publicKey,
+          rewardVaultAuthority: pg.wallet.publicKey,
+          stakeVault: stakeVault.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    await expectError(
+      pg.program.methods.setClaimCooldowns(new BN(-1), new BN(0)).accounts(adminAccounts).rpc(),
+      "InvalidDuration"
+    );
+    await pg.program.methods
+      .setClaimCooldowns(new BN(COOLDOWN), new BN(COOLDOWN))
+      .accounts(adminAccounts)
+      .rpc();
+
+    // A claim starts the claim cooldown but not the compound one.
+    await donate();
+    await claim();
+    const claimed = await pg.program.account.userStake.fetch(userStakePda);
+    await donate();
+    await expectError(claim(), "ClaimTooSoon");
+    await compound();
+    const compounded = await pg.program.account.userStake.fetch(userStakePda);
+    assert(compounded.lastClaimTimestamp.eq(claimed.lastClaimTimestamp));
+
+    // Just inside either cooldown is still too soon; once it has elapsed both go through.
+    await donate();
+    await expectError(compound(), "ClaimTooSoon");
+    await sleep((COOLDOWN + 1) * 1000);
+    await claim();
+    await donate();
+    await compound();
+    const after = await pg.program.account.userStake.fetch(userStakePda);
+    assert(after.lastClaimTimestamp.gte(claimed.lastClaimTimestamp.addn(COOLDOWN)));
+    assert(after.lastCompoundTimestamp.gte(compounded.lastCompoundTimestamp.addn(COOLDOWN)));
+
+    // Rewards paid out of stake positions wait out the same cooldown.
+    const index = 9;
+    const [positionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("stake"), pg.wallet.publicKey.toBuffer(), Buffer.from([index])],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .openPosition(index, new BN(1000), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        user: pg.wallet.publicKey,
+        stakePosition: positionPda,
+        userTokenAccount: pg.wallet.publicKey,
+        stakeVault: stakeVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    await donate();
+    const rewardAccounts = {
+      globalState: globalStateKp.publicKey,
+      user: pg.wallet.publicKey,
+      userStake: userStakePda,
+      rewardVault: rewardVault.publicKey,
+      rewardVaultAuthority: pg.wallet.publicKey,
+      userRewardAccount: pg.wallet.publicKey,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+    };
+    const claimAll = () =>
+      pg.program.methods
+        .claimAllRewards()
+        .accounts(rewardAccounts)
+        .remainingAccounts([{ pubkey: positionPda, isSigner: false, isWritable: true }])
+        .rpc();
+    const closePosition = () =>
+      pg.program.methods
+        .closePosition()
+        .accounts({
+          ...rewardAccounts,
+          stakePosition: positionPda,
+          stakeVault: stakeVault.publicKey,
+          stakeVaultAuthority: pg.wallet.publicKey,
+          userTokenAccount: pg.wallet.publicKey,
+        })
+        .rpc();
+    await expectError(claimAll(), "ClaimTooSoon");
+    await expectError(closePosition(), "ClaimTooSoon");
+    await sleep((COOLDOWN + 1) * 1000);
+    await claimAll();
+    const claimedAll = await pg.program.account.userStake.fetch(userStakePda);
+    assert(claimedAll.lastClaimTimestamp.gt(after.lastClaimTimestamp));
+    await donate();
+    await expectError(closePosition(), "ClaimTooSoon");
+    await sleep((COOLDOWN + 1) * 1000);
+    await closePosition();
+    assert.isNull(await pg.connection.getAccountInfo(positionPda));
+
+    await pg.program.methods.setClaimCooldowns(new BN(0), new BN(0)).accounts(adminAccounts).rpc();
+  });
+
+  it("Withdrawals Must Leave The Liquidity Reserve In Place", async () => {
+    const RESERVE_BPS = 9000;
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [providerPositionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("provider_position"), globalStateKp.publicKey.toBuffer(), liquidityProvider.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const withdraw = (amount: BN) =>
+      pg.program.methods
+        .withdrawLiquidity(amount)
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          poolAccount: poolAccount.publicKey,
+          provider: liquidityProvider.publicKey,
+          authority: liquidityProvider.publicKey,
+          providerPosition: providerPositionPda,
+          allowance: null,
+          providerTokenAccount: pg.wallet.publicKey,
+          destination: null,
+          poolAuthority: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers([liquidityProvider])
+        .rpc();
+
+    await expectError(
+      pg.program.methods.setReserveBps(new BN(10_001)).accounts(adminAccounts).rpc(),
+      "InvalidBps"
+    );
+    await pg.program.methods.setReserveBps(new BN(RESERVE_BPS)).accounts(adminAccounts).rpc();
+
+    // Mirrors `within_reserve`: liquidity missing from the pool account counts as lent out.
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const poolBalance = new BN((await pg.connection.getTokenAccountBalance(poolAccount.publicKey)).value.amount);
+    const outstanding = BN.max(state.totalLiquidity.sub(poolBalance), new BN(0));
+    const reserve = state.totalLiquidity.muln(RESERVE_BPS).divn(10_000);
+    const headroom = state.totalLiquidity.sub(outstanding).sub(reserve);
+    assert(headroom.gten(10));
+
+    await expectError(withdraw(headroom.addn(1)), "ReserveBreached");
+    await withdraw(new BN(10));
+
+    await pg.program.methods.setReserveBps(new BN(0)).accounts(adminAccounts).rpc();
+  });
+
+  it("Canonical Vaults Reject Any Other Vault", async () => {
+    const stateKp = new web3.Keypair();
+    const provider = new web3.Keypair();
+    await pg.connection.confirmTransaction(
+      await pg.connection.requestAirdrop(provider.publicKey, web3.LAMPORTS_PER_SOL)
+    );
+    await pg.program.methods
+      .initialize(new BN(500))
+      .accounts({
+        globalState: stateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        treasury: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        feeVault: feeVault.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([stateKp])
+      .rpc();
+    const mint = await splToken.createMint(pg.connection, pg.wallet.keypair, pg.wallet.publicKey, null, 0);
+    const pda = (...seeds: Buffer[]) => web3.PublicKey.findProgramAddressSync(seeds, pg.program.programId)[0];
+    const vaultAuthority = pda(Buffer.from("vault_authority"), stateKp.publicKey.toBuffer());
+    const poolVault = pda(Buffer.from("pool_vault"), stateKp.publicKey.toBuffer(), mint.toBuffer());
+    const canonicalFeeVault = pda(Buffer.from("fee_vault"), stateKp.publicKey.toBuffer(), mint.toBuffer());
+    const canonicalStakeVault = pda(Buffer.from("stake_vault"), stateKp.publicKey.toBuffer(), mint.toBuffer());
+    const vaultAccounts = {
+      globalState: stateKp.publicKey,
+      admin: pg.wallet.publicKey,
+      poolMint: mint,
+      feeMint: mint,
+      stakeMint: mint,
+      poolVault,
+      feeVault: canonicalFeeVault,
+      stakeVault: canonicalStakeVault,
+      vaultAuthority,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+      rent: web3.SYSVAR_RENT_PUBKEY,
+    };
+    await pg.program.methods.initializeVaults().accounts(vaultAccounts).rpc();
+    const state = await pg.program.account.globalState.fetch(stateKp.publicKey);
+    assert(state.canonicalVaults);
+    assert(state.poolVault.equals(poolVault));
+    assert(state.feeVault.equals(canonicalFeeVault));
+    assert(state.stakeVault.equals(canonicalStakeVault));
+    assert((await splToken.getAccount(pg.connection, poolVault)).owner.equals(vaultAuthority));
+
+    // A wallet-owned account of the same mint is no longer good enough for any vault.
+    const otherVault = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      mint,
+      pg.wallet.publicKey,
+      new web3.Keypair()
+    );
+    const [poolPdaForMint] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("pool"), mint.toBuffer()],
+      pg.program.programId
+    );
+    const createPool = (poolAccount: web3.PublicKey) =>
+      pg.program.methods
+        .createPool()
+        .accounts({
+          globalState: stateKp.publicKey,
+          admin: pg.wallet.publicKey,
+          pool: poolPdaForMint,
+          poolAccount,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+    await expectError(createPool(otherVault), "InvalidPoolVault");
+    await expectError(
+      pg.program.methods
+        .withdrawFees(new BN(1))
+        .accounts({
+          globalState: stateKp.publicKey,
+          treasurer: pg.wallet.publicKey,
+          feeVault: otherVault,
+          feeVaultAuthority: pg.wallet.publicKey,
+          destination: otherVault,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc(),
+      "ConstraintAddress"
+    );
+    await expectError(
+      pg.program.methods
+        .migrateStakeVault()
+        .accounts({
+          globalState: stateKp.publicKey,
+          admin: pg.wallet.publicKey,
+          stakeVault: canonicalStakeVault,
+          stakeVaultAuthority: vaultAuthority,
+          newVault: otherVault,
+          newVaultAuthority: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc(),
+      "InvalidStakeVault"
+    );
+    await createPool(poolVault);
+
+    // Liquidity in the canonical pool vault comes back out on the program's signature alone.
+    const [positionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("provider_position"), stateKp.publicKey.toBuffer(), provider.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const providerTokens = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      mint,
+      provider.publicKey,
+      new web3.Keypair()
+    );
+    await splToken.mintTo(pg.connection, pg.wallet.keypair, mint, providerTokens, pg.wallet.keypair, 5_000);
+    const deposit = (poolAccount: web3.PublicKey) =>
+      pg.program.methods
+        .depositLiquidity(new BN(5_000))
+        .accounts({
+          globalState: stateKp.publicKey,
+          provider: provider.publicKey,
+          providerPosition: positionPda,
+          providerTokenAccount: providerTokens,
+          poolAccount,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([provider])
+        .rpc();
+    await expectError(deposit(otherVault), "InvalidPoolVault");
+    await deposit(poolVault);
+    await pg.program.methods
+      .withdrawLiquidity(new BN(1_000))
+      .accounts({
+        globalState: stateKp.publicKey,
+        poolAccount: poolVault,
+        provider: provider.publicKey,
+        authority: provider.publicKey,
+        providerPosition: positionPda,
+        allowance: null,
+        providerTokenAccount: providerTokens,
+        destination: null,
+        poolAuthority: vaultAuthority,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .signers([provider])
+      .rpc();
+    const balance = await pg.connection.getTokenAccountBalance(providerTokens);
+    assert.equal(balance.value.amount, "1000");
+
+    // A subsidy vault owned by the vault authority pays out on the program's signature too.
+    const subsidyVault = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      mint,
+      vaultAuthority,
+      new web3.Keypair()
+    );
+    await pg.program.methods
+      .setSubsidy(subsidyVault, new BN(20))
+      .accounts({ globalState: stateKp.publicKey, admin: pg.wallet.publicKey })
+      .rpc();
+    await pg.program.methods
+      .fundSubsidy(new BN(20))
+      .accounts({
+        globalState: stateKp.publicKey,
+        funder: provider.publicKey,
+        funderTokenAccount: providerTokens,
+        subsidyVault,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .signers([provider])
+      .rpc();
+    const flashLoanStateKp = new web3.Keypair();
+    await pg.program.methods
+      .flashLoan(new BN(1_000), new BN(0), new BN(0))
+      .accounts({
+        globalState: stateKp.publicKey,
+        pool: poolPdaForMint,
+        poolAccount: poolVault,
+        poolAuthority: vaultAuthority,
+        borrowerTokenAccount: providerTokens,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: providerTokens,
+        collateralEscrow: collateralEscrowPda,
+        subsidyVault,
+        subsidyVaultAuthority: vaultAuthority,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    // 5% of 1,000 is 50, so the whole 20 is drawn and paid into the pool vault.
+    assert.equal((await pg.connection.getTokenAccountBalance(subsidyVault)).value.amount, "0");
+    assert.equal((await pg.connection.getTokenAccountBalance(poolVault)).value.amount, "3020");
+  });
+
+  it("Crank Pays Out Of A Canonical Reward Vault On The Program's Signature", async () => {
+    const stateKp = new web3.Keypair();
+    const user = new web3.Keypair();
+    const cranker = new web3.Keypair();
+    for (const who of [user, cranker]) {
+      await pg.connection.confirmTransaction(
+        await pg.connection.requestAirdrop(who.publicKey, web3.LAMPORTS_PER_SOL)
+      );
+    }
+    const mint = await splToken.createMint(pg.connection, pg.wallet.keypair, pg.wallet.publicKey, null, 0);
+    const pda = (...seeds: Buffer[]) => web3.PublicKey.findProgramAddressSync(seeds, pg.program.programId)[0];
+    const vaultAuthority = pda(Buffer.from("vault_authority"), stateKp.publicKey.toBuffer());
+    const poolVault = pda(Buffer.from("pool_vault"), stateKp.publicKey.toBuffer(), mint.toBuffer());
+    const canonicalStakeVault = pda(Buffer.from("stake_vault"), stateKp.publicKey.toBuffer(), mint.toBuffer());
+    const [poolPdaForMint] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("pool"), mint.toBuffer()],
+      pg.program.programId
+    );
+    // The reward vault belongs to the vault authority, so no wallet can move its funds.
+    const canonicalRewardVault = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      mint,
+      vaultAuthority,
+      new web3.Keypair()
+    );
+    const userTokens = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      mint,
+      user.publicKey,
+      new web3.Keypair()
+    );
+    const crankerTokens = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      mint,
+      cranker.publicKey,
+      new web3.Keypair()
+    );
+    await splToken.mintTo(pg.connection, pg.wallet.keypair, mint, userTokens, pg.wallet.keypair, 30_000);
+
+    await pg.program.methods
+      .initialize(new BN(500))
+      .accounts({
+        globalState: stateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        treasury: pg.wallet.publicKey,
+        rewardVault: canonicalRewardVault,
+        feeVault: feeVault.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([stateKp])
+      .rpc();
+    await pg.program.methods
+      .initializeVaults()
+      .accounts({
+        globalState: stateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        poolMint: mint,
+        feeMint: mint,
+        stakeMint: mint,
+        poolVault,
+        feeVault: pda(Buffer.from("fee_vault"), stateKp.publicKey.toBuffer(), mint.toBuffer()),
+        stakeVault: canonicalStakeVault,
+        vaultAuthority,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+        rent: web3.SYSVAR_RENT_PUBKEY,
+      })
+      .rpc();
+    await pg.program.methods
+      .createPool()
+      .accounts({
+        globalState: stateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        pool: poolPdaForMint,
+        poolAccount: poolVault,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    await pg.program.methods
+      .setDistributionParams(new BN(10_000), new BN(0), new BN(1))
+      .accounts({ globalState: stateKp.publicKey, admin: pg.wallet.publicKey })
+      .rpc();
+    await pg.program.methods
+      .depositLiquidity(new BN(20_000))
+      .accounts({
+        globalState: stateKp.publicKey,
+        provider: user.publicKey,
+        providerPosition: pda(Buffer.from("provider_position"), stateKp.publicKey.toBuffer(), user.publicKey.toBuffer()),
+        providerTokenAccount: userTokens,
+        poolAccount: poolVault,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([user])
+      .rpc();
+    await pg.program.methods
+      .stake(new BN(1_000))
+      .accounts({
+        globalState: stateKp.publicKey,
+        user: user.publicKey,
+        userTokenAccount: userTokens,
+        stakeVault: canonicalStakeVault,
+        stakeVaultAuthority: vaultAuthority,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([user])
+      .rpc();
+
+    // A repaid loan sets the stakers' share of its fee aside in the reward vault.
+    const flashLoanStateKp = new web3.Keypair();
+    await pg.program.methods
+      .flashLoan(new BN(10_000), new BN(0), new BN(0))
+      .accounts({
+        globalState: stateKp.publicKey,
+        pool: poolPdaForMint,
+        poolAccount: poolVault,
+        poolAuthority: vaultAuthority,
+        borrowerTokenAccount: userTokens,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: userTokens,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: stateKp.publicKey,
+        pool: poolPdaForMint,
+        poolAccount: poolVault,
+        poolAuthority: vaultAuthority,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: user.publicKey,
+        borrowerTokenAccount: userTokens,
+        borrowerReputation: pda(Buffer.from("reputation"), borrower.publicKey.toBuffer()),
+        rewardVault: canonicalRewardVault,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([user])
+      .rpc();
+    const pending = await pg.program.account.globalState.fetch(stateKp.publicKey);
+    assert(pending.pendingStakerFees.gten(100));
+
+    // The cranker is the only signer and fee payer; the program signs for the reward vault.
+    const tx = await pg.program.methods
+      .crankDistribution()
+      .accounts({
+        globalState: stateKp.publicKey,
+        cranker: cranker.publicKey,
+        rewardVault: canonicalRewardVault,
+        rewardVaultAuthority: vaultAuthority,
+        crankerTokenAccount: crankerTokens,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .transaction();
+    tx.feePayer = cranker.publicKey;
+    const crankTx = await web3.sendAndConfirmTransaction(pg.connection, tx, [cranker]);
+    const [distributed] = await fetchEvents(crankTx, "rewardsDistributed");
+    assert(distributed.crankReward.eqn(1));
+    const crankerBalance = await pg.connection.getTokenAccountBalance(crankerTokens);
+    assert.equal(crankerBalance.value.amount, "1");
+    const after = await pg.program.account.globalState.fetch(stateKp.publicKey);
+    assert(after.pendingStakerFees.isZero());
+  });
+
+  it("Reputation Caps Start Low And Grow With Reputation", async () => {
+    const BASE_CAP = 100;
+    const CAP_PER_REP = 1_000;
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const { reputation } = await pg.program.account.borrowerReputation.fetch(borrowerReputationPda);
+    assert(reputation.gtn(0));
+    const simulate = (amount: BN, who: web3.PublicKey, borrowerReputation: web3.PublicKey | null) =>
+      pg.program.methods
+        .simulateFlashLoan(amount, new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: who,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          borrowerReputation,
+        })
+        .view();
+    const capExceeded = pg.program.idl.errors.find((err) => err.name === "ReputationCapExceeded");
+    const borrow = (amount: BN, borrowerReputation: web3.PublicKey | null, flashLoanStateKp: web3.Keypair) =>
+      pg.program.methods
+        .flashLoan(amount, new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          borrowerReputation,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+
+    await pg.program.methods
+      .setReputationCaps(new BN(BASE_CAP), new BN(CAP_PER_REP), new BN(0))
+      .accounts(adminAccounts)
+      .rpc();
+
+    // A borrower with no reputation is held to the base cap.
+    const fresh = new web3.Keypair().publicKey;
+    assert((await simulate(new BN(BASE_CAP), fresh, null)).accepted);
+    const overBase = await simulate(new BN(BASE_CAP + 1), fresh, null);
+    assert(!overBase.accepted);
+    assert(overBase.errorCode === capExceeded.code);
+    // Leaving the reputation account out forfeits the higher cap.
+    await expectError(borrow(new BN(BASE_CAP + 1), null, new web3.Keypair()), "ReputationCapExceeded");
+
+    // An established borrower may borrow past the base cap, up to its own cap.
+    const repCap = reputation.muln(CAP_PER_REP).addn(BASE_CAP);
+    assert((await simulate(repCap, borrower.publicKey, borrowerReputationPda)).accepted);
+    assert(!(await simulate(repCap.addn(1), borrower.publicKey, borrowerReputationPda)).accepted);
+    const flashLoanStateKp = new web3.Keypair();
+    await borrow(new BN(BASE_CAP + 1), borrowerReputationPda, flashLoanStateKp);
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+
+    // The ceiling binds however much reputation a borrower has.
+    await pg.program.methods
+      .setReputationCaps(new BN(BASE_CAP), new BN(CAP_PER_REP), new BN(BASE_CAP + 1))
+      .accounts(adminAccounts)
+      .rpc();
+    assert((await simulate(new BN(BASE_CAP + 1), borrower.publicKey, borrowerReputationPda)).accepted);
+    assert(!(await simulate(new BN(BASE_CAP + 2), borrower.publicKey, borrowerReputationPda)).accepted);
+
+    await pg.program.methods
+      .setReputationCaps(new BN(0), new BN(0), new BN(0))
+      .accounts(adminAccounts)
+      .rpc();
+  });
+
+  it("Initialize With Config Applies Everything Or Nothing", async () => {
+    const config = {
+      feeRate: new BN(30),
+      roundFeesUp: true,
+      routePolFeesToTreasury: false,
+      perTxLoanCap: new BN(1_000_000),
+      maxCollateralBps: new BN(5_000),
+      rebateCapBps: new BN(2_000),
+      minEffectiveFeeBps: new BN(5),
+      reserveBps: new BN(1_000),
+      lpShareBps: new BN(7_000),
+      stakerShareBps: new BN(2_000),
+      stakerFeeBps: new BN(0),
+      burnShareBps: new BN(0),
+      minStake: new BN(10),
+      dustThreshold: new BN(5),
+      repBaseCap: new BN(0),
+      repCapPerPoint: new BN(0),
+      repCapCeiling: new BN(0),
+      epochEmissionCap: new BN(0),
+      epochLength: new BN(86_400),
+      feeUnlockPeriod: new BN(3_600),
+      distributionInterval: new BN(60),
+      claimCooldown: new BN(0),
+      compoundCooldown: new BN(0),
+    };
+    const init = (stateKp: web3.Keypair, cfg: typeof config) =>
+      pg.program.methods
+        .initializeWithConfig(cfg)
+        .accounts({
+          globalState: stateKp.publicKey,
+          admin: pg.wallet.publicKey,
+          treasury: pg.wallet.publicKey,
+          rewardVault: rewardVault.publicKey,
+          feeVault: feeVault.publicKey,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([stateKp])
+        .rpc();
+
+    // Shares that sum past 100% reject the whole call; no state account is left behind.
+    const rejectedKp = new web3.Keypair();
+    await expectError(init(rejectedKp, { ...config, stakerShareBps: new BN(3_001) }), "InvalidBps");
+    assert((await pg.connection.getAccountInfo(rejectedKp.publicKey)) === null);
+    const noEpochKp = new web3.Keypair();
+    await expectError(init(noEpochKp, { ...config, epochLength: new BN(0) }), "InvalidDuration");
+    assert((await pg.connection.getAccountInfo(noEpochKp.publicKey)) === null);
+
+    const stateKp = new web3.Keypair();
+    await init(stateKp, config);
+    const state = await pg.program.account.globalState.fetch(stateKp.publicKey);
+    assert(state.admin.equals(pg.wallet.publicKey));
+    assert(state.feeRate.eq(config.feeRate));
+    assert(state.roundFeesUp);
+    assert(state.perTxLoanCap.eq(config.perTxLoanCap));
+    assert(state.reserveBps.eq(config.reserveBps));
+    assert(state.lpShareBps.eq(config.lpShareBps));
+    assert(state.stakerShareBps.eq(config.stakerShareBps));
+    assert(state.feeUnlockPeriod.eq(config.feeUnlockPeriod));
+    assert(state.emissionEpochLength.eq(config.epochLength));
+    assert(state.reputationPerLoan.eqn(1));
+  });
+
+  it("Loan History Keeps The Most Recent Loans", async () => {
+    const LOAN_HISTORY_LEN = 16;
+    const [loanHistoryPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("loan_history")],
+      pg.program.programId
+    );
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .createLoanHistory()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        loanHistory: loanHistoryPda,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    // Borrow two more times than the buffer holds, each with a distinct amount.
+    const loans = LOAN_HISTORY_LEN + 2;
+    for (let i = 1; i <= loans; i++) {
+      const flashLoanStateKp = new web3.Keypair();
+      await pg.program.methods
+        .flashLoan(new BN(i), new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          loanHistory: loanHistoryPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+      await pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          repayer: borrower.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrowerReputation: borrowerReputationPda,
+          loanHistory: loanHistoryPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower])
+        .rpc();
+    }
+
+    const history = await pg.program.account.loanHistory.fetch(loanHistoryPda);
+    assert(history.totalLoans.eqn(loans));
+    assert(history.records.length === LOAN_HISTORY_LEN);
+
+    // Paging newest first returns the last LOAN_HISTORY_LEN loans; the first two are gone.
+    const page = (offset: number, limit: number) =>
+      pg.program.methods
+        .getLoanHistory(new BN(offset), limit)
+        .accounts({ loanHistory: loanHistoryPda })
+        .view();
+    const first = await page(0, 10);
+    const second = await page(10, 10);
+    assert(first.length === 10);
+    assert(second.length === LOAN_HISTORY_LEN - 10);
+    const amounts = [...first, ...second].map((record) => record.amount.toNumber());
+    const expected = Array.from({ length: LOAN_HISTORY_LEN }, (_, i) => loans - i);
+    assert.deepEqual(amounts, expected);
+    for (const record of [...first, ...second]) {
+      assert(record.borrower.equals(borrower.publicKey));
+      assert(record.status === 1);
+    }
+  });
+
+  it("Unstake All Returns The Full Balance And Zeroes The Position", async () => {
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const stakeAccounts = {
+      globalState: globalStateKp.publicKey,
+      user: pg.wallet.publicKey,
+      userStake: userStakePda,
+      userTokenAccount: pg.wallet.publicKey,
+      stakeVault: stakeVault.publicKey,
+      stakeVaultAuthority: pg.wallet.publicKey,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+    };
+    const unstakeAccounts = {
+      globalState: globalStateKp.publicKey,
+      user: pg.wallet.publicKey,
+      userStake: userStakePda,
+      stakeVault: stakeVault.publicKey,
+      stakeVaultAuthority: pg.wallet.publicKey,
+      userTokenAccount: pg.wallet.publicKey,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+    };
+    const balanceOf = async (account: web3.PublicKey) =>
+      new BN((await pg.connection.getTokenAccountBalance(account)).value.amount);
+    await pg.program.methods.stake(new BN(500)).accounts(stakeAccounts).rpc();
+    const position = await pg.program.account.userStake.fetch(userStakePda);
+    const staked = stakedBalance(position);
+    const stateBefore = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const vaultBefore = await balanceOf(stakeVault.publicKey);
+
+    await pg.program.methods.unstakeAll(false).accounts(unstakeAccounts).rpc();
+
+    // The whole balance left the vault and the global totals.
+    const vaultAfter = await balanceOf(stakeVault.publicKey);
+    const stateAfter = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(vaultBefore.sub(vaultAfter).eq(staked));
+    assert(stateBefore.totalStaked.sub(stateAfter.totalStaked).eq(staked));
+    // Unless swept as dust, the position stays open with nothing staked.
+    const info = await pg.connection.getAccountInfo(userStakePda);
+    if (info !== null) {
+      const cleared = await pg.program.account.userStake.fetch(userStakePda);
+      assert(cleared.amount.isZero());
+      assert(cleared.compoundedAmount.isZero());
+      assert(cleared.rewardDebt.isZero());
+      assert(cleared.firstStakeTimestamp.isZero());
+      // Nothing is left to unstake.
+      await expectError(
+        pg.program.methods.unstakeAll(false).accounts(unstakeAccounts).rpc(),
+        "InsufficientStake"
+      );
+    }
+  });
+
+  it("Compounding LP Fees Grows The Provider's Share", async () => {
+    const loanAmount = new BN(10_000);
+    const flashLoanStateKp = new web3.Keypair();
+    const [providerPositionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("provider_position"), globalStateKp.publicKey.toBuffer(), liquidityProvider.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const [reservationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reservation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    // A prepaid fee leaves the LPs' share in the fee vault.
+    await pg.program.methods
+      .reserveLoan(loanAmount, new BN(Math.floor(Date.now() / 1000) + 600))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        borrower: borrower.publicKey,
+        reservation: reservationPda,
+        pool: poolPda,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        feeVault: feeVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+    await pg.program.methods
+      .flashLoan(loanAmount, new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        reservation: reservationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+
+    const stateBefore = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const positionBefore = await pg.program.account.providerPosition.fetch(providerPositionPda);
+    assert(stateBefore.lpVaultFees.gtn(0));
+
+    await pg.program.methods
+      .compoundLpFees()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        provider: liquidityProvider.publicKey,
+        providerPosition: providerPositionPda,
+        feeVault: feeVault.publicKey,
+        feeVaultAuthority: pg.wallet.publicKey,
+        poolAccount: poolAccount.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .signers([liquidityProvider])
+      .rpc();
+
+    const stateAfter = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const positionAfter = await pg.program.account.providerPosition.fetch(providerPositionPda);
+    const compounded = stateBefore.lpVaultFees.sub(stateAfter.lpVaultFees);
+    assert(compounded.gtn(0));
+    assert(stateAfter.totalLiquidity.sub(stateBefore.totalLiquidity).eq(compounded));
+    assert(positionAfter.shareSeconds.isZero());
+    // More shares out of a larger total: a bigger slice of every future fee.
+    assert(positionAfter.shares.gt(positionBefore.shares));
+    const shareBefore = positionBefore.shares.muln(1_000_000).div(stateBefore.totalShares);
+    const shareAfter = positionAfter.shares.muln(1_000_000).div(stateAfter.totalShares);
+    assert(shareAfter.gt(shareBefore));
+  });
+
+  it("Repayment Landing Mid-Distribution Neither Loses Nor Double-Counts Fees", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .setDistributionParams(new BN(2000), new BN(0), new BN(0))
+      .accounts(adminAccounts)
+      .rpc();
+    const cranker = new web3.Keypair();
+    await pg.connection.confirmTransaction(
+      await pg.connection.requestAirdrop(cranker.publicKey, web3.LAMPORTS_PER_SOL)
+    );
+    const crankerTokens = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      cranker.publicKey,
+      new web3.Keypair()
+    );
+    const borrow = async () => {
+      const flashLoanStateKp = new web3.Keypair();
+      await pg.program.methods
+        .flashLoan(new BN(10_000), new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+      return flashLoanStateKp.publicKey;
+    };
+    const repay = (flashLoanState: web3.PublicKey) =>
+      pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState,
+          borrower: borrower.publicKey,
+          repayer: borrower.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrowerReputation: borrowerReputationPda,
+          rewardVault: rewardVault.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower])
+        .rpc();
+    const crank = () =>
+      pg.program.methods
+        .crankDistribution()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          cranker: cranker.publicKey,
+          rewardVault: rewardVault.publicKey,
+          rewardVaultAuthority: pg.wallet.publicKey,
+          crankerTokenAccount: crankerTokens,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers([cranker])
+        .rpc();
+
+    // Fees are waiting when the crank starts, and another repayment races it.
+    await repay(await borrow());
+    const before = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(before.pendingStakerFees.gtn(0));
+    const racingLoan = await borrow();
+    await Promise.all([crank(), repay(racingLoan)]);
+
+    // Every fee set aside was either distributed or is still pending, exactly once.
+    const after = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const accrued = after.stakerFeesAccrued.sub(before.stakerFeesAccrued);
+    const distributed = after.stakerFeesDistributed.sub(before.stakerFeesDistributed);
+    assert(accrued.gtn(0));
+    assert(distributed.gte(before.pendingStakerFees));
+    assert(before.pendingStakerFees.add(accrued).eq(distributed.add(after.pendingStakerFees)));
+
+    // Whatever the race left pending goes out on the next run.
+    if (after.pendingStakerFees.gtn(0)) {
+      await crank();
+      const drained = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+      assert(drained.pendingStakerFees.isZero());
+      assert(drained.stakerFeesDistributed.sub(after.stakerFeesDistributed).eq(after.pendingStakerFees));
+    }
+
+    await pg.program.methods
+      .setDistributionParams(new BN(0), new BN(0), new BN(0))
+      .accounts(adminAccounts)
+      .rpc();
+  });
+
+  it("Fixed Absolute Fee Charges Every Loan The Same", async () => {
+    const FIXED_FEE = new BN(50);
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await expectError(
+      pg.program.methods
+        .setFeeModel({ fixedAbsolute: { 0: new BN(0) } })
+        .accounts(adminAccounts)
+        .rpc(),
+      "InvalidFixedFee"
+    );
+    await pg.program.methods
+      .setFeeModel({ fixedAbsolute: { 0: FIXED_FEE } })
+      .accounts(adminAccounts)
+      .rpc();
+
+    const borrowAndRepay = async (amount: BN) => {
+      const flashLoanStateKp = new web3.Keypair();
+      await pg.program.methods
+        .flashLoan(amount, new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+      const loan = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+      await pg.program.methods
+        .repayFlashLoan()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          repayer: borrower.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrowerReputation: borrowerReputationPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower])
+        .rpc();
+      return loan;
+    };
+
+    // A loan fifty times larger pays exactly the same flat fee.
+    const small = await borrowAndRepay(new BN(100));
+    const large = await borrowAndRepay(new BN(5_000));
+    assert(small.fee.eq(FIXED_FEE));
+    assert(large.fee.eq(FIXED_FEE));
+    assert(small.fixedFee.eq(FIXED_FEE));
+    assert(small.feeRate.isZero());
+
+    await pg.program.methods
+      .setFeeModel({ proportional: {} })
+      .accounts(adminAccounts)
+      .rpc();
+  });
+
+  it("Whitelist Rejection Leaves The Reentrancy Guard Clear", async () => {
+    const outsider = new web3.Keypair();
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const flashLoanAccounts = (who: web3.PublicKey, flashLoanState: web3.PublicKey) => ({
+      globalState: globalStateKp.publicKey,
+      pool: poolPda,
+      poolAccount: poolAccount.publicKey,
+      poolAuthority: pg.wallet.publicKey,
+      borrowerTokenAccount: pg.wallet.publicKey,
+      borrower: who,
+      flashLoanState,
+      borrowerCollateralAccount: pg.wallet.publicKey,
+      collateralEscrow: collateralEscrowPda,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      systemProgram: web3.SystemProgram.programId,
+    });
+
+    // Only the regular borrower is listed.
+    await pg.program.methods
+      .addToWhitelist(borrower.publicKey)
+      .accounts(adminAccounts)
+      .rpc();
+    const rejectedState = new web3.Keypair();
+    await expectError(
+      pg.program.methods
+        .flashLoan(new BN(100), new BN(0), new BN(0))
+        .accounts(flashLoanAccounts(outsider.publicKey, rejectedState.publicKey))
+        .signers([outsider, rejectedState])
+        .rpc(),
+      "NotWhitelisted"
+    );
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(!state.isFlashLoanActive);
+
+    // The next borrower is not blocked by a stale guard.
+    const flashLoanStateKp = new web3.Keypair();
+    await pg.program.methods
+      .flashLoan(new BN(100), new BN(0), new BN(0))
+      .accounts(flashLoanAccounts(borrower.publicKey, flashLoanStateKp.publicKey))
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+    const after = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(!after.isFlashLoanActive);
+
+    await pg.program.methods
+      .removeFromWhitelist(borrower.publicKey)
+      .accounts(adminAccounts)
+      .rpc();
+  });
+
+  it("Admin Actions Execute Only Once The Threshold Approves", async () => {
+    const secondAdmin = new web3.Keypair();
+    const thirdAdmin = new web3.Keypair();
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const propose = async (action: any) => {
+      const adminActionKp = new web3.Keypair();
+      await pg.program.methods
+        .proposeAdminAction(action)
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          proposer: pg.wallet.publicKey,
+          adminAction: adminActionKp.publicKey,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([adminActionKp])
+        .rpc();
+      return adminActionKp.publicKey;
+    };
+    const approve = (adminAction: web3.PublicKey, admin: web3.Keypair) =>
+      pg.program.methods
+        .approveAdminAction()
+        .accounts({ globalState: globalStateKp.publicKey, admin: admin.publicKey, adminAction })
+        .signers([admin])
+        .rpc();
+    const execute = (adminAction: web3.PublicKey, pool: web3.PublicKey | null = null) =>
+      pg.program.methods
+        .executeAdminAction()
+        .accounts({ globalState: globalStateKp.publicKey, adminAction, pool })
+        .rpc();
+
+    const { feeRate } = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    await pg.program.methods
+      .setAdmins([pg.wallet.publicKey, secondAdmin.publicKey, thirdAdmin.publicKey], 2)
+      .accounts(adminAccounts)
+      .rpc();
+
+    // The single admin can no longer act alone, on any setting.
+    await expectError(
+      pg.program.methods.updateFeeRate(feeRate.addn(1)).accounts(adminAccounts).rpc(),
+      "MultisigRequired"
+    );
+    await expectError(
+      pg.program.methods
+        .setDistributionParams(new BN(2000), new BN(0), new BN("18446744073709551615"))
+        .accounts(adminAccounts)
+        .rpc(),
+      "MultisigRequired"
+    );
+    await expectError(
+      pg.program.methods.addToWhitelist(secondAdmin.publicKey).accounts(adminAccounts).rpc(),
+      "MultisigRequired"
+    );
+
+    // One approval (the proposer's) is not enough.
+    const raise = await propose({ updateFeeRate: { 0: feeRate.addn(1) } });
+    await expectError(execute(raise), "ThresholdNotMet");
+    let state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(state.feeRate.eq(feeRate));
+
+    // A second distinct admin meets the threshold.
+    await approve(raise, secondAdmin);
+    await expectError(approve(raise, secondAdmin), "AlreadyApproved");
+    await execute(raise);
+    state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(state.feeRate.eq(feeRate.addn(1)));
+    await expectError(execute(raise), "ActionAlreadyExecuted");
+    await expectError(propose({ updateFeeRate: { 0: new BN(10_001) } }), "InvalidBps");
+
+    // A pool's fee rate also needs co-signing, and the pool itself to execute.
+    const poolAccounts = { ...adminAccounts, pool: poolPda };
+    const { feeRate: poolFeeRate } = await pg.program.account.pool.fetch(poolPda);
+    await expectError(
+      pg.program.methods.updatePoolFeeRate(poolFeeRate.addn(1)).accounts(poolAccounts).rpc(),
+      "MultisigRequired"
+    );
+    await expectError(
+      propose({ updatePoolFeeRate: { pool: poolPda, feeRate: new BN(10_001) } }),
+      "InvalidBps"
+    );
+    const poolRaise = await propose({ updatePoolFeeRate: { pool: poolPda, feeRate: poolFeeRate.addn(1) } });
+    await approve(poolRaise, secondAdmin);
+    await expectError(execute(poolRaise), "ActionNeedsAccounts");
+    await execute(poolRaise, poolPda);
+    const pool = await pg.program.account.pool.fetch(poolPda);
+    assert(pool.feeRate.eq(poolFeeRate.addn(1)));
+
+    // Fee settings, hooks and signers go through the same flow.
+    await expectError(
+      pg.program.methods.setFeeSplit(state.lpShareBps, state.stakerShareBps).accounts(adminAccounts).rpc(),
+      "MultisigRequired"
+    );
+    const split = await propose({
+      setFeeSplit: { lpShareBps: state.lpShareBps, stakerShareBps: state.stakerShareBps },
+    });
+    await approve(split, thirdAdmin);
+    await execute(split);
+
+    // Instructions that move tokens instead consume an approved action for the same call.
+    const destination = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      pg.wallet.publicKey,
+      new web3.Keypair()
+    );
+    const withdrawPol = (adminAction: web3.PublicKey | null) =>
+      pg.program.methods
+        .withdrawProtocolLiquidity(new BN(1))
+        .accounts({
+          ...adminAccounts,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          destination,
+          adminAction,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+    await expectError(withdrawPol(null), "MultisigRequired");
+    const otherAmount = await propose({ withdrawProtocolLiquidity: { amount: new BN(2), destination } });
+    await approve(otherAmount, secondAdmin);
+    await expectError(execute(otherAmount), "ActionNeedsAccounts");
+    await expectError(withdrawPol(otherAmount), "ActionMismatch");
+
+    // Hand control back to the single admin, again through the co-signing flow.
+    const restore = await propose({ setAdmins: { admins: [], threshold: 0 } });
+    await approve(restore, thirdAdmin);
+    await execute(restore);
+    await pg.program.methods.updateFeeRate(feeRate).accounts(adminAccounts).rpc();
+    await pg.program.methods.updatePoolFeeRate(poolFeeRate).accounts(poolAccounts).rpc();
+    await expectError(
+      pg.program.methods.updateFeeRate(new BN(10_001)).accounts(adminAccounts).rpc(),
+      "InvalidBps"
+    );
+  });
+
+  it("A Utilization Spike Moves The EMA Less Than The Spot", async () => {
+    const SMOOTHING = 2_000;
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods.setUtilSmoothing(new BN(SMOOTHING)).accounts(adminAccounts).rpc();
+
+    const before = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const poolBalance = new BN(
+      (await pg.connection.getTokenAccountBalance(poolAccount.publicKey)).value.amount
+    );
+    // Borrow most of the pool for a single loan.
+    const amount = poolBalance.muln(9).divn(10);
+    const spot = BN.min(amount.muln(10_000).div(before.totalLiquidity), new BN(10_000));
+    const flashLoanStateKp = new web3.Keypair();
+    await pg.program.methods
+      .flashLoan(amount, new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const during = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+
+    // The average moves only SMOOTHING / 10_000 of the way towards the spike.
+    const expected = before.utilEma.muln(10_000 - SMOOTHING).add(spot.muln(SMOOTHING)).divn(10_000);
+    assert(during.utilEma.eq(expected));
+    assert(during.utilEma.sub(before.utilEma).lt(spot.sub(before.utilEma)));
+
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+    // Once repaid, the average decays back towards zero.
+    const after = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(after.utilEma.lt(during.utilEma));
+
+    await pg.program.methods.setUtilSmoothing(new BN(0)).accounts(adminAccounts).rpc();
+  });
+
+  it("Reconcile Liquidity Corrects A Desynced Total", async () => {
+    // A fresh state books no liquidity although the vault it points at holds some.
+    const stateKp = new web3.Keypair();
+    await pg.program.methods
+      .initialize(new BN(30))
+      .accounts({
+        globalState: stateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        treasury: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        feeVault: feeVault.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([stateKp])
+      .rpc();
+    const reconcile = () =>
+      pg.program.methods
+        .reconcileLiquidity()
+        .accounts({
+          globalState: stateKp.publicKey,
+          admin: pg.wallet.publicKey,
+          poolAccount: poolAccount.publicKey,
+        })
+        .rpc();
+    const vaultBalance = new BN(
+      (await pg.connection.getTokenAccountBalance(poolAccount.publicKey)).value.amount
+    );
+    assert(vaultBalance.gtn(0));
+
+    // Only while paused.
+    await expectError(reconcile(), "NotPaused");
+    await pg.program.methods
+      .setPaused(true)
+      .accounts({ globalState: stateKp.publicKey, pauser: pg.wallet.publicKey })
+      .rpc();
+
+    const tx = await reconcile();
+    const state = await pg.program.account.globalState.fetch(stateKp.publicKey);
+    assert(state.totalLiquidity.eq(vaultBalance.sub(state.accumulatedFees)));
+    const [reconciled] = await fetchEvents(tx, "liquidityReconciled");
+    assert(reconciled.oldTotal.isZero());
+    assert(reconciled.newTotal.eq(state.totalLiquidity));
+    assert(reconciled.delta.eq(state.totalLiquidity));
+
+    // Another admin cannot run it.
+    const stranger = new web3.Keypair();
+    await expectError(
+      pg.program.methods
+        .reconcileLiquidity()
+        .accounts({
+          globalState: stateKp.publicKey,
+          admin: stranger.publicKey,
+          poolAccount: poolAccount.publicKey,
+        })
+        .signers([stranger])
+        .rpc(),
+      "Unauthorized"
+    );
+  });
+
+  it("Max Fee Reverts A Loan Priced Above The Borrower's Cap", async () => {
+    const amount = new BN(10_000);
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const borrow = (maxFee: BN, flashLoanStateKp: web3.Keypair) =>
+      pg.program.methods
+        .flashLoan(amount, new BN(0), maxFee)
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+
+    // The borrower signs expecting the pool's current rate.
+    const { feeRate } = await pg.program.account.pool.fetch(poolPda);
+    const poolAccounts = { ...adminAccounts, pool: poolPda };
+    const expectedFee = amount.mul(feeRate).divn(10_000);
+    const flashLoanStateKp = new web3.Keypair();
+    await borrow(expectedFee, flashLoanStateKp);
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+
+    // The rate is raised before the next loan lands, which now reverts.
+    await pg.program.methods.updatePoolFeeRate(feeRate.muln(2)).accounts(poolAccounts).rpc();
+    await expectError(borrow(expectedFee, new web3.Keypair()), "FeeExceedsMax");
+
+    await pg.program.methods.updatePoolFeeRate(feeRate).accounts(poolAccounts).rpc();
+  });
+
+  it("Set Whitelist Replaces The List Atomically", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const keys = (n: number) => Array.from({ length: n }, () => new web3.Keypair().publicKey);
+    const whitelist = async () =>
+      (await pg.program.account.globalState.fetch(globalStateKp.publicKey)).flashLoanWhitelist;
+
+    const initial = keys(3);
+    await pg.program.methods.setWhitelist(initial).accounts(adminAccounts).rpc();
+    assert.deepEqual((await whitelist()).map(String), initial.map(String));
+
+    // Duplicates collapse, and the old entries are gone.
+    const replacement = keys(7);
+    await pg.program.methods
+      .setWhitelist([...replacement, replacement[0], replacement[3]])
+      .accounts(adminAccounts)
+      .rpc();
+    assert.deepEqual((await whitelist()).map(String), replacement.map(String));
+
+    // Bulk additions keep what is listed; an overflowing batch changes nothing.
+    const extra = keys(2);
+    await pg.program.methods.addManyToWhitelist([replacement[1], ...extra]).accounts(adminAccounts).rpc();
+    assert((await whitelist()).length === 9);
+    await expectError(
+      pg.program.methods.addManyToWhitelist(keys(2)).accounts(adminAccounts).rpc(),
+      "WhitelistFull"
+    );
+    assert((await whitelist()).length === 9);
+    await expectError(
+      pg.program.methods.setWhitelist(keys(11)).accounts(adminAccounts).rpc(),
+      "WhitelistFull"
+    );
+    assert((await whitelist()).length === 9);
+
+    // Restore an open pool for subsequent tests.
+    await pg.program.methods.setWhitelist([]).accounts(adminAccounts).rpc();
+  });
+
+  it("A Third-Party Liquidator Earns The Incentive On An Expired Loan", async () => {
+    const INCENTIVE_BPS = 500;
+    const COLLATERAL = new BN(1000);
+    const flashLoanStateKp = new web3.Keypair();
+    const liquidator = new web3.Keypair();
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const liquidatorTokenAccount = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      poolMint.publicKey,
+      liquidator.publicKey,
+      new web3.Keypair()
+    );
+    await expectError(
+      pg.program.methods.setLiquidationIncentive(new BN(2_001)).accounts(adminAccounts).rpc(),
+      "InvalidBps"
+    );
+    await pg.program.methods.setLiquidationIncentive(new BN(INCENTIVE_BPS)).accounts(adminAccounts).rpc();
+
+    await pg.program.methods
+      .flashLoan(new BN(1000), COLLATERAL, new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const liquidate = () =>
+      pg.program.methods
+        .liquidateCollateral()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          liquidator: liquidator.publicKey,
+          liquidatorTokenAccount,
+          collateralEscrow: collateralEscrowPda,
+          escrowAuthority: escrowAuthorityPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers([liquidator])
+        .rpc();
+
+    // A loan still inside its window cannot be liquidated.
+    await expectError(liquidate(), "LoanNotExpired");
+
+    await sleep(31_000);
+    const poolBefore = new BN(
+      (await pg.connection.getTokenAccountBalance(poolAccount.publicKey)).value.amount
+    );
+    const { totalLiquidity: liquidityBefore } = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const tx = await liquidate();
+
+    const incentive = COLLATERAL.muln(INCENTIVE_BPS).divn(10_000);
+    const remainder = COLLATERAL.sub(incentive);
+    const [event] = await fetchEvents(tx, "collateralLiquidated");
+    assert(event.liquidator.equals(liquidator.publicKey));
+    assert(event.incentive.eq(incentive));
+    assert(event.remainder.eq(remainder));
+    const liquidatorBalance = await pg.connection.getTokenAccountBalance(liquidatorTokenAccount);
+    assert(new BN(liquidatorBalance.value.amount).eq(incentive));
+    // The rest of the collateral goes back to the pool, and the principal it still falls
+    // short of, the liquidator's incentive, is written off the pool's liquidity.
+    const poolAfter = new BN(
+      (await pg.connection.getTokenAccountBalance(poolAccount.publicKey)).value.amount
+    );
+    assert(poolAfter.sub(poolBefore).eq(remainder));
+    assert(event.writtenOff.eq(incentive));
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(liquidityBefore.sub(state.totalLiquidity).eq(incentive));
+    assert(!state.isFlashLoanActive);
+    assert((await pg.connection.getAccountInfo(flashLoanStateKp.publicKey)) === null);
+
+    // Restore no incentive for subsequent tests.
+    await pg.program.methods.setLiquidationIncentive(new BN(0)).accounts(adminAccounts).rpc();
+  });
+
+  it("Event Level Off Emits No Events", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [providerPositionPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("provider_position"), globalStateKp.publicKey.toBuffer(), liquidityProvider.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const deposit = () =>
+      pg.program.methods
+        .depositLiquidity(new BN(100))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          provider: liquidityProvider.publicKey,
+          providerPosition: providerPositionPda,
+          providerTokenAccount: pg.wallet.publicKey,
+          poolAccount: poolAccount.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([liquidityProvider])
+        .rpc();
+
+    // Minimal keeps the actor and amount but drops the balances.
+    await pg.program.methods.setEventLevel({ minimal: {} }).accounts(adminAccounts).rpc();
+    const [minimal] = await fetchEvents(await deposit(), "liquidityDeposited");
+    assert(minimal.provider.equals(liquidityProvider.publicKey));
+    assert(minimal.amount.eqn(100));
+    assert(minimal.preBalance.isZero() && minimal.postBalance.isZero());
+
+    await pg.program.methods.setEventLevel({ off: {} }).accounts(adminAccounts).rpc();
+    assert.equal((await fetchEvents(await deposit(), "liquidityDeposited")).length, 0);
+
+    // Restore full events for subsequent tests.
+    await pg.program.methods.setEventLevel({ full: {} }).accounts(adminAccounts).rpc();
+    const [full] = await fetchEvents(await deposit(), "liquidityDeposited");
+    assert(full.postBalance.sub(full.preBalance).eqn(100));
+  });
+
+  it("Repaying A Time-Priced Loan At Half The Window Costs About Half The Fee", async () => {
+    const MAX_FEE_BPS = 300;
+    const amount = new BN(10_000);
+    const maxFee = amount.muln(MAX_FEE_BPS).divn(10_000);
+    const flashLoanStateKp = new web3.Keypair();
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await expectError(
+      pg.program.methods
+        .setFeeModel({ timeBased: { maxFeeBps: new BN(10_001) } })
+        .accounts(adminAccounts)
+        .rpc(),
+      "InvalidBps"
+    );
+    await pg.program.methods
+      .setFeeModel({ timeBased: { maxFeeBps: new BN(MAX_FEE_BPS) } })
+      .accounts(adminAccounts)
+      .rpc();
+
+    const poolBalance = async () =>
+      new BN((await pg.connection.getTokenAccountBalance(poolAccount.publicKey)).value.amount);
+    const before = await poolBalance();
+    await pg.program.methods
+      .flashLoan(amount, new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const loan = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+    assert(loan.timePriced);
+    assert(loan.fee.eq(maxFee));
+
+    await sleep(14_000);
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+
+    // Roughly half the window was used, so roughly half the fee is charged.
+    const charged = (await poolBalance()).sub(before);
+    assert(charged.gte(maxFee.muln(4).divn(10)), charged.toString());
+    assert(charged.lte(maxFee.muln(6).divn(10)), charged.toString());
+
+    await pg.program.methods
+      .setFeeModel({ proportional: {} })
+      .accounts(adminAccounts)
+      .rpc();
+  });
+
+  it("A Reallocated Global State Reads As Sane Defaults", async () => {
+    // Growing the account to the current layout leaves every setting usable.
+    await pg.program.methods
+      .migrateGlobalState()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    for (const bps of [
+      state.rebateCapBps,
+      state.stakerFeeBps,
+      state.minEffectiveFeeBps,
+      state.burnShareBps,
+      state.reserveBps,
+      state.utilSmoothingBps,
+      state.lpShareBps.add(state.stakerShareBps),
+    ]) {
+      assert(bps.lten(10_000), bps.toString());
+    }
+    assert(state.liquidationIncentiveBps.lten(2_000));
+    assert(state.feeMint === null || !state.feeMintPrice.isZero());
+    assert(state.epochEmissionCap.isZero() || state.emissionEpochLength.gtn(0));
+    assert.deepEqual(state.eventLevel, { full: {} });
+    assert.deepEqual(state.feeModel, { proportional: {} });
+    assert.equal(state.adminThreshold, 0);
+  });
+
+  it("Collateral Outside The Accepted Mint Is Rejected", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const otherMint = await splToken.createMint(
+      pg.connection,
+      pg.wallet.keypair,
+      pg.wallet.publicKey,
+      null,
+      0
+    );
+    const [otherEscrowPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("collateral_escrow"), otherMint.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .createCollateralEscrow()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        collateralMint: otherMint,
+        collateralEscrow: otherEscrowPda,
+        escrowAuthority: escrowAuthorityPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+    const otherCollateralAccount = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      otherMint,
+      borrower.publicKey,
+      new web3.Keypair()
+    );
+    await splToken.mintTo(
+      pg.connection,
+      pg.wallet.keypair,
+      otherMint,
+      otherCollateralAccount,
+      pg.wallet.keypair,
+      1_000
+    );
+    const flashLoan = (borrowerCollateralAccount: web3.PublicKey, collateralEscrow: web3.PublicKey) => {
+      const flashLoanStateKp = new web3.Keypair();
+      return pg.program.methods
+        .flashLoan(new BN(100), new BN(10), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount,
+          collateralEscrow,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc();
+    };
+
+    // With no mint configured, collateral must be in the pool's own mint.
+    await expectError(flashLoan(otherCollateralAccount, otherEscrowPda), "CollateralMintMismatch");
+
+    // Once another mint is accepted, pool-mint collateral is refused instead.
+    await pg.program.methods.setAcceptedCollateralMint(otherMint).accounts(adminAccounts).rpc();
+    await expectError(flashLoan(pg.wallet.publicKey, collateralEscrowPda), "CollateralMintMismatch");
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(state.acceptedCollateralMint.equals(otherMint));
+
+    // Restore the pool's mint for subsequent tests.
+    await pg.program.methods
+      .setAcceptedCollateralMint(web3.PublicKey.default)
+      .accounts(adminAccounts)
+      .rpc();
+  });
+
+  it("Adding Collateral Raises A Loan's Health", async () => {
+    const flashLoanStateKp = new web3.Keypair();
+    const liquidator = new web3.Keypair();
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .flashLoan(new BN(1000), new BN(100), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    // Health: escrowed collateral per unit owed, in bps.
+    const health = (loan: any) => loan.collateral.sub(loan.collateralReleased).muln(10_000).div(loan.amount.add(loan.fee));
+    const before = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+
+    await expectError(
+      pg.program.methods
+        .addCollateral(new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers([borrower])
+        .rpc(),
+      "ZeroAmount"
+    );
+    await pg.program.methods
+      .addCollateral(new BN(400))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .signers([borrower])
+      .rpc();
+    const after = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+    assert(after.collateral.eqn(500));
+    assert(health(after).gt(health(before)));
+
+    // The topped-up loan is still open and cannot be liquidated.
+    await expectError(
+      pg.program.methods
+        .liquidateCollateral()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrower: borrower.publicKey,
+          liquidator: liquidator.publicKey,
+          liquidatorTokenAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          escrowAuthority: escrowAuthorityPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .signers([liquidator])
+        .rpc(),
+      "LoanNotExpired"
+    );
+
+    // Repaying returns all of it.
+    const escrowBefore = await splToken.getAccount(pg.connection, collateralEscrowPda);
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        collateralEscrow: collateralEscrowPda,
+        escrowAuthority: escrowAuthorityPda,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+    const escrowAfter = await splToken.getAccount(pg.connection, collateralEscrowPda);
+    assert.equal(escrowBefore.amount - escrowAfter.amount, BigInt(500));
+  });
+
+  it("Health Report Flags An Insolvent Pool", async () => {
+    const health = () =>
+      pg.program.methods
+        .getHealth()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          poolAccount: poolAccount.publicKey,
+          stakeVault: stakeVault.publicKey,
+        })
+        .view();
+
+    const healthy = await health();
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(healthy.totalLiquidity.eq(state.totalLiquidity));
+    assert(healthy.totalStaked.eq(state.totalStaked));
+    assert(healthy.outstandingLoans.isZero());
+    assert(healthy.utilizationBps.isZero());
+    assert(healthy.poolSolvent && healthy.stakeSolvent && healthy.isSolvent);
+
+    // Pull tokens out of the pool behind the program's back until it cannot cover LPs.
+    const shortfall = healthy.poolBalance.sub(healthy.totalLiquidity).addn(1);
+    await splToken.transfer(
+      pg.connection,
+      pg.wallet.keypair,
+      poolAccount.publicKey,
+      pg.wallet.publicKey,
+      pg.wallet.keypair,
+      BigInt(shortfall.toString())
+    );
+    const insolvent = await health();
+    assert(insolvent.poolBalance.eq(healthy.poolBalance.sub(shortfall)));
+    assert(!insolvent.poolSolvent);
+    assert(insolvent.stakeSolvent);
+    assert(!insolvent.isSolvent);
+    // Reading the report changes nothing.
+    const after = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(after.totalLiquidity.eq(state.totalLiquidity));
+
+    // Put the tokens back for subsequent tests.
+    await splToken.transfer(
+      pg.connection,
+      pg.wallet.keypair,
+      pg.wallet.publicKey,
+      poolAccount.publicKey,
+      pg.wallet.keypair,
+      BigInt(shortfall.toString())
+    );
+    assert((await health()).isSolvent);
+  });
+
+  it("Staking Pause Blocks New Stake But Not Unstaking", async () => {
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const pauserAccounts = {
+      globalState: globalStateKp.publicKey,
+      pauser: pg.wallet.publicKey,
+    };
+    const stake = () =>
+      pg.program.methods
+        .stake(new BN(100))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: pg.wallet.publicKey,
+          userStake: userStakePda,
+          userTokenAccount: pg.wallet.publicKey,
+          stakeVault: stakeVault.publicKey,
+          stakeVaultAuthority: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+    await stake();
+    const before = await pg.program.account.userStake.fetch(userStakePda);
+
+    await pg.program.methods.setStakingPaused(true).accounts(pauserAccounts).rpc();
+    await expectError(stake(), "StakingPaused");
+    await expectError(
+      pg.program.methods
+        .compoundRewards()
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: pg.wallet.publicKey,
+          userStake: userStakePda,
+          rewardVault: rewardVault.publicKey,
+          rewardVaultAuthority: pg.wallet.publicKey,
+          stakeVault: stakeVault.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc(),
+      "StakingPaused"
+    );
+    // Exits stay open.
+    await pg.program.methods
+      .unstake(new BN(100))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        user: pg.wallet.publicKey,
+        userStake: userStakePda,
+        stakeVault: stakeVault.publicKey,
+        stakeVaultAuthority: pg.wallet.publicKey,
+        userTokenAccount: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const after = await pg.program.account.userStake.fetch(userStakePda);
+    assert(after.amount.eq(before.amount.subn(100)));
+
+    // Restore staking for subsequent tests.
+    await pg.program.methods.setStakingPaused(false).accounts(pauserAccounts).rpc();
+  });
+
+  it("A Whitelist Entry Lapses After Its Expiry", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const simulate = () =>
+      pg.program.methods
+        .simulateFlashLoan(new BN(100), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          borrowerReputation: null,
+        })
+        .view();
+    const now = await pg.connection.getBlockTime(await pg.connection.getSlot());
+    const expiry = new BN(now + 5);
+    await pg.program.methods
+      .addToWhitelistWithExpiry(borrower.publicKey, expiry)
+      .accounts(adminAccounts)
+      .rpc();
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const index = state.flashLoanWhitelist.findIndex((key) => key.equals(borrower.publicKey));
+    assert(state.whitelistExpiries[index].eq(expiry));
+    assert((await simulate()).accepted);
+
+    await sleep(7_000);
+    assert(!(await simulate()).accepted);
+    const flashLoanStateKp = new web3.Keypair();
+    await expectError(
+      pg.program.methods
+        .flashLoan(new BN(100), new BN(0), new BN(0))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          pool: poolPda,
+          poolAccount: poolAccount.publicKey,
+          poolAuthority: pg.wallet.publicKey,
+          borrowerTokenAccount: pg.wallet.publicKey,
+          borrower: borrower.publicKey,
+          flashLoanState: flashLoanStateKp.publicKey,
+          borrowerCollateralAccount: pg.wallet.publicKey,
+          collateralEscrow: collateralEscrowPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([borrower, flashLoanStateKp])
+        .rpc(),
+      "NotWhitelisted"
+    );
+
+    // Re-adding without an expiry makes the entry permanent again.
+    await pg.program.methods.addToWhitelist(borrower.publicKey).accounts(adminAccounts).rpc();
+    assert((await simulate()).accepted);
+
+    // Restore an open pool for subsequent tests.
+    await pg.program.methods.setWhitelist([]).accounts(adminAccounts).rpc();
+  });
+
+  it("Fee Quote Breaks Down Each Active Modifier", async () => {
+    const amount = new BN(100_000);
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const quote = (elapsed: BN | null, voucher: boolean, subsidized: boolean) =>
+      pg.program.methods
+        .quoteFee(amount, elapsed, voucher, subsidized)
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          borrower: borrower.publicKey,
+          borrowerStake: null,
+          pool: null,
+        })
+        .view();
+
+    // No modifiers: the borrower owes the base fee.
+    const plain = await quote(null, false, false);
+    assert(plain.base.gtn(0));
+    assert(plain.discount.isZero() && plain.subsidy.isZero());
+    assert(!plain.floorApplied);
+    assert(plain.net.eq(plain.base));
+
+    // A voucher waives the whole base fee.
+    const vouched = await quote(null, true, false);
+    assert(vouched.discount.eq(vouched.base));
+    assert(vouched.net.isZero());
+
+    // With a floor set, the waived fee is raised back up to it.
+    await pg.program.methods.setMinEffectiveFeeBps(new BN(5)).accounts(adminAccounts).rpc();
+    const floored = await quote(null, true, false);
+    assert(floored.floorApplied);
+    assert(floored.net.eq(amount.muln(5).divn(10_000)));
+
+    // Time-based pricing pro-rates the fee for an early repayment, down to the floor.
+    await pg.program.methods
+      .setFeeModel({ timeBased: { maxFeeBps: new BN(100) } })
+      .accounts(adminAccounts)
+      .rpc();
+    const full = await quote(null, false, false);
+    const half = await quote(new BN(15), false, false);
+    assert(half.base.eq(full.net));
+    assert(half.net.add(half.discount).eq(half.base));
+    assert(half.net.gte(half.base.muln(4).divn(10)) && half.net.lte(half.base.muln(6).divn(10)));
+    const instant = await quote(new BN(0), false, false);
+    assert(instant.net.gte(amount.muln(5).divn(10_000)));
+
+    await pg.program.methods.setFeeModel({ proportional: {} }).accounts(adminAccounts).rpc();
+    await pg.program.methods.setMinEffectiveFeeBps(new BN(0)).accounts(adminAccounts).rpc();
+  });
+
+  it("Withdraw All Liquidity Redeems And Closes Positions Across Pools", async () => {
+    // A second pool is a fresh state with its own mint and vault.
+    const stateKp = new web3.Keypair();
+    const mint = await splToken.createMint(
+      pg.connection,
+      pg.wallet.keypair,
+      pg.wallet.publicKey,
+      null,
+      0
+    );
+    const newAccount = (accountMint: web3.PublicKey) =>
+      splToken.createAccount(pg.connection, pg.wallet.keypair, accountMint, pg.wallet.publicKey, new web3.Keypair());
+    const pool = await newAccount(mint);
+    const stateRewardVault = await newAccount(mint);
+    const walletTokens = await newAccount(mint);
+    await splToken.mintTo(pg.connection, pg.wallet.keypair, mint, walletTokens, pg.wallet.keypair, 8_000);
+    await pg.program.methods
+      .initialize(new BN(500))
+      .accounts({
+        globalState: stateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        treasury: pg.wallet.publicKey,
+        rewardVault: stateRewardVault,
+        feeVault: feeVault.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([stateKp])
+      .rpc();
+
+    const positionIn = (state: web3.PublicKey) =>
+      web3.PublicKey.findProgramAddressSync(
+        [Buffer.from("provider_position"), state.toBuffer(), pg.wallet.publicKey.toBuffer()],
+        pg.program.programId
+      )[0];
+    const firstPosition = positionIn(globalStateKp.publicKey);
+    const secondPosition = positionIn(stateKp.publicKey);
+    const deposit = (state: web3.PublicKey, position: web3.PublicKey, from: web3.PublicKey, to: web3.PublicKey, amount: BN) =>
+      pg.program.methods
+        .depositLiquidity(amount)
+        .accounts({
+          globalState: state,
+          provider: pg.wallet.publicKey,
+          providerPosition: position,
+          providerTokenAccount: from,
+          poolAccount: to,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .rpc();
+    await deposit(globalStateKp.publicKey, firstPosition, pg.wallet.publicKey, poolAccount.publicKey, new BN(5_000));
+    await deposit(stateKp.publicKey, secondPosition, walletTokens, pool, new BN(8_000));
+
+    // A partial withdrawal takes only the principal behind the burned shares out of both the
+    // position and `total_liquidity`, whatever fees are paid on top.
+    const partialBefore = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const positionBefore = await pg.program.account.providerPosition.fetch(firstPosition);
+    await pg.program.methods
+      .withdrawLiquidity(new BN(1_000))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        poolAccount: poolAccount.publicKey,
+        provider: pg.wallet.publicKey,
+        authority: pg.wallet.publicKey,
+        providerPosition: firstPosition,
+        allowance: null,
+        providerTokenAccount: pg.wallet.publicKey,
+        destination: null,
+        poolAuthority: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const firstBefore = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const { shares: firstShares, amount: firstPrincipal } = await pg.program.account.providerPosition.fetch(firstPosition);
+    const principalWithdrawn = positionBefore.amount.sub(firstPrincipal);
+    assert(principalWithdrawn.gtn(0) && principalWithdrawn.lten(1_000));
+    assert(partialBefore.totalLiquidity.sub(firstBefore.totalLiquidity).eq(principalWithdrawn));
+    const firstRecipient = await newAccount(poolMint.publicKey);
+    const secondRecipient = await newAccount(mint);
+
+    const group = (state: web3.PublicKey, position: web3.PublicKey, vault: web3.PublicKey, recipient: web3.PublicKey) => [
+      { pubkey: state, isSigner: false, isWritable: true },
+      { pubkey: position, isSigner: false, isWritable: true },
+      { pubkey: vault, isSigner: false, isWritable: true },
+      { pubkey: pg.wallet.publicKey, isSigner: false, isWritable: false },
+      { pubkey: recipient, isSigner: false, isWritable: true },
+    ];
+    const withdrawAll = (remaining: web3.AccountMeta[]) =>
+      pg.program.methods
+        .withdrawAllLiquidity()
+        .accounts({ provider: pg.wallet.publicKey, tokenProgram: splToken.TOKEN_PROGRAM_ID })
+        .remainingAccounts(remaining)
+        .rpc();
+
+    // A pool passed with a missing account is refused outright.
+    await expectError(
+      withdrawAll(group(stateKp.publicKey, secondPosition, pool, secondRecipient).slice(0, 4)),
+      "PoolAccountsMismatch"
+    );
+    // A position must sit in the state it is passed with.
+    await expectError(
+      withdrawAll(group(stateKp.publicKey, firstPosition, pool, secondRecipient)),
+      "Unauthorized"
+    );
+
+    const txHash = await withdrawAll([
+      ...group(globalStateKp.publicKey, firstPosition, poolAccount.publicKey, firstRecipient),
+      ...group(stateKp.publicKey, secondPosition, pool, secondRecipient),
+    ]);
+    await pg.connection.confirmTransaction(txHash, "confirmed");
+
+    // Each pool pays out its whole position: at least the principal, less rounding, and in
+    // the fresh pool exactly the deposit less the locked minimum.
+    const withdrawn = await fetchEvents(txHash, "liquidityWithdrawn");
+    assert.equal(withdrawn.length, 2);
+    const firstPaid = await pg.connection.getTokenAccountBalance(firstRecipient);
+    assert(new BN(firstPaid.value.amount).gte(firstPrincipal.subn(1)), firstPaid.value.amount);
+    const secondPaid = await pg.connection.getTokenAccountBalance(secondRecipient);
+    assert.equal(secondPaid.value.amount, "7000");
+
+    // Only the principal leaves `total_liquidity`, and the closed positions take their
+    // shares with them.
+    const firstAfter = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(firstBefore.totalShares.sub(firstAfter.totalShares).eq(firstShares));
+    assert(firstBefore.totalLiquidity.sub(firstAfter.totalLiquidity).eq(firstPrincipal));
+    const secondAfter = await pg.program.account.globalState.fetch(stateKp.publicKey);
+    assert(secondAfter.totalLiquidity.isZero());
+    assert(secondAfter.totalShares.eqn(1_000));
+    assert.isNull(await pg.connection.getAccountInfo(firstPosition));
+    assert.isNull(await pg.connection.getAccountInfo(secondPosition));
+  });
+
+  it("Unpausing Waits Out The Unpause Delay", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const pauserAccounts = {
+      globalState: globalStateKp.publicKey,
+      pauser: pg.wallet.publicKey,
+    };
+    await expectError(
+      pg.program.methods.setUnpauseDelay(new BN(-1)).accounts(adminAccounts).rpc(),
+      "InvalidDuration"
+    );
+    await pg.program.methods.setUnpauseDelay(new BN(3)).accounts(adminAccounts).rpc();
+
+    // Pausing is immediate; unpausing right away is rejected.
+    await pg.program.methods.setPaused(true).accounts(pauserAccounts).rpc();
+    const paused = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(paused.paused);
+    assert(paused.pausedAt.gtn(0));
+    await expectError(
+      pg.program.methods.setPaused(false).accounts(pauserAccounts).rpc(),
+      "UnpauseTooSoon"
+    );
+    // Nor can the admin cut the delay short while the pause is on.
+    await expectError(
+      pg.program.methods.setUnpauseDelay(new BN(0)).accounts(adminAccounts).rpc(),
+      "ProgramPaused"
+    );
+    await expectError(
+      pg.program.methods.setPaused(false).accounts(pauserAccounts).rpc(),
+      "UnpauseTooSoon"
+    );
+
+    // Once the delay has passed, unpausing succeeds.
+    await sleep(4_000);
+    await pg.program.methods.setPaused(false).accounts(pauserAccounts).rpc();
+    assert(!(await pg.program.account.globalState.fetch(globalStateKp.publicKey)).paused);
+
+    await pg.program.methods.setUnpauseDelay(new BN(0)).accounts(adminAccounts).rpc();
+  });
+
+  it("Each Pool Charges Its Own Fee Rate", async () => {
+    const amount = new BN(10_000);
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const otherMint = new web3.Keypair();
+    const otherPoolAccount = new web3.Keypair();
+    const [otherPoolPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("pool"), otherMint.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .createPool()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        admin: pg.wallet.publicKey,
+        pool: otherPoolPda,
+        poolAccount: otherPoolAccount.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    // A new pool starts on the global default rate.
+    const state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert((await pg.program.account.pool.fetch(otherPoolPda)).feeRate.eq(state.feeRate));
+
+    const { feeRate } = await pg.program.account.pool.fetch(poolPda);
+    await expectError(
+      pg.program.methods
+        .updatePoolFeeRate(new BN(10_001))
+        .accounts({ ...adminAccounts, pool: otherPoolPda })
+        .rpc(),
+      "InvalidBps"
+    );
+    await pg.program.methods
+      .updatePoolFeeRate(feeRate.muln(3))
+      .accounts({ ...adminAccounts, pool: otherPoolPda })
+      .rpc();
+
+    // Equal loans from the two pools are quoted at their own rates.
+    const quote = (pool: web3.PublicKey) =>
+      pg.program.methods
+        .quoteFee(amount, null, false, false)
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          borrower: borrower.publicKey,
+          borrowerStake: null,
+          pool,
+        })
+        .view();
+    const cheap = await quote(poolPda);
+    const dear = await quote(otherPoolPda);
+    assert(cheap.net.eq(amount.mul(feeRate).divn(10_000)));
+    assert(dear.net.eq(amount.mul(feeRate.muln(3)).divn(10_000)));
+
+    // A loan from the original pool is charged that pool's rate.
+    const flashLoanStateKp = new web3.Keypair();
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .flashLoan(amount, new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const loan = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+    assert(loan.feeRate.eq(feeRate));
+    assert(loan.fee.eq(cheap.net));
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+  });
+
+  it("Vested Rewards Release Linearly After The Cliff", async () => {
+    const CLIFF = 4;
+    const DURATION = 10;
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const [vestingPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("vesting"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const [vestingVaultPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("vesting_vault"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const rewardMint = (await splToken.getAccount(pg.connection, rewardVault.publicKey)).mint;
+    await pg.program.methods
+      .donateRewards(new BN(1_000))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        donor: pg.wallet.publicKey,
+        donorTokenAccount: pg.wallet.publicKey,
+        rewardVault: rewardVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const claimToVesting = (cliff: number, duration: number) =>
+      pg.program.methods
+        .claimToVesting(new BN(cliff), new BN(duration))
+        .accounts({
+          globalState: globalStateKp.publicKey,
+          user: pg.wallet.publicKey,
+          userStake: userStakePda,
+          rewardVault: rewardVault.publicKey,
+          rewardVaultAuthority: pg.wallet.publicKey,
+          rewardMint,
+          vesting: vestingPda,
+          vestingVault: vestingVaultPda,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+          rent: web3.SYSVAR_RENT_PUBKEY,
+        })
+        .rpc();
+    const claimVested = () =>
+      pg.program.methods
+        .claimVested()
+        .accounts({
+          user: pg.wallet.publicKey,
+          vesting: vestingPda,
+          vestingVault: vestingVaultPda,
+          userRewardAccount: pg.wallet.publicKey,
+          tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+
+    // The cliff cannot outlast the schedule.
+    await expectError(claimToVesting(DURATION + 1, DURATION), "InvalidDuration");
+    await claimToVesting(CLIFF, DURATION);
+    const vesting = await pg.program.account.vestingAccount.fetch(vestingPda);
+    assert(vesting.total.gtn(0));
+    const escrow = await splToken.getAccount(pg.connection, vestingVaultPda);
+    assert.equal(escrow.amount, BigInt(vesting.total.toString()));
+
+    // Nothing is released before the cliff, and a second schedule cannot start yet.
+    await expectError(claimVested(), "NothingVested");
+    await expectError(claimToVesting(CLIFF, DURATION), "VestingActive");
+
+    // Past the cliff, the linear share of the total is released.
+    await sleep((CLIFF + 1) * 1_000);
+    await claimVested();
+    const released = (await pg.program.account.vestingAccount.fetch(vestingPda)).released;
+    assert(released.gte(vesting.total.muln(CLIFF).divn(DURATION)), released.toString());
+    assert(released.lt(vesting.total), released.toString());
+
+    // Once the schedule has run out, the rest is released.
+    await sleep(DURATION * 1_000);
+    await claimVested();
+    const done = await pg.program.account.vestingAccount.fetch(vestingPda);
+    assert(done.released.eq(vesting.total));
+    assert.equal((await splToken.getAccount(pg.connection, vestingVaultPda)).amount, BigInt(0));
+  });
+
+  it("Tiny Early Unstakes Each Pay The Penalty Floor", async () => {
+    const CHUNK = new BN(10);
+    const MIN_PENALTY = new BN(3);
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [userStakePda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("user_stake"), pg.wallet.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    const stakeMint = (await splToken.getAccount(pg.connection, stakeVault.publicKey)).mint;
+    const treasuryTokenAccount = await splToken.createAccount(
+      pg.connection,
+      pg.wallet.keypair,
+      stakeMint,
+      pg.wallet.publicKey,
+      new web3.Keypair()
+    );
+    const unstakeAccounts = {
+      globalState: globalStateKp.publicKey,
+      user: pg.wallet.publicKey,
+      userStake: userStakePda,
+      stakeVault: stakeVault.publicKey,
+      stakeVaultAuthority: pg.wallet.publicKey,
+      userTokenAccount: pg.wallet.publicKey,
+      treasuryTokenAccount,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+    };
+    await expectError(
+      pg.program.methods
+        .setUnstakePenalty(new BN(0), new BN(10_001), MIN_PENALTY)
+        .accounts(adminAccounts)
+        .rpc(),
+      "InvalidBps"
+    );
+    // Every unstake from the current position counts as early; 1% of a 10-token chunk
+    // would round to nothing, so the floor applies.
+    await pg.program.methods
+      .setUnstakePenalty(new BN(1_000_000_000), new BN(100), MIN_PENALTY)
+      .accounts(adminAccounts)
+      .rpc();
+
+    // The penalty has to go somewhere.
+    await expectError(
+      pg.program.methods
+        .unstake(CHUNK)
+        .accounts({ ...unstakeAccounts, treasuryTokenAccount: null })
+        .rpc(),
+      "InvalidTreasury"
+    );
+
+    for (let i = 1; i <= 3; i++) {
+      const tx = await pg.program.methods.unstake(CHUNK).accounts(unstakeAccounts).rpc();
+      await pg.connection.confirmTransaction(tx, "confirmed");
+      const [unstaked] = await fetchEvents(tx, "unstaked");
+      assert(unstaked.penalty.eq(MIN_PENALTY));
+      const treasury = await splToken.getAccount(pg.connection, treasuryTokenAccount);
+      assert.equal(treasury.amount, BigInt(MIN_PENALTY.muln(i).toString()));
+    }
+
+    // An emergency exit is no way around the penalty: 1% of the balance, rounded up.
+    const position = await pg.program.account.userStake.fetch(userStakePda);
+    const balance = stakedBalance(position);
+    const penalty = BN.min(BN.max(balance.addn(99).divn(100), MIN_PENALTY), balance);
+    const treasuryBefore = (await splToken.getAccount(pg.connection, treasuryTokenAccount)).amount;
+    await expectError(
+      pg.program.methods
+        .emergencyUnstake()
+        .accounts({ ...unstakeAccounts, treasuryTokenAccount: null })
+        .rpc(),
+      "InvalidTreasury"
+    );
+    await pg.program.methods.emergencyUnstake().accounts(unstakeAccounts).rpc();
+    const treasuryAfter = (await splToken.getAccount(pg.connection, treasuryTokenAccount)).amount;
+    assert.equal(treasuryAfter - treasuryBefore, BigInt(penalty.toString()));
+
+    await pg.program.methods
+      .setUnstakePenalty(new BN(0), new BN(0), new BN(0))
+      .accounts(adminAccounts)
+      .rpc();
+    // Put the stake back for the tests that follow.
+    await pg.program.methods
+      .stake(balance)
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        user: pg.wallet.publicKey,
+        userTokenAccount: pg.wallet.publicKey,
+        stakeVault: stakeVault.publicKey,
+        stakeVaultAuthority: pg.wallet.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .rpc();
+  });
+
+  it("Admin Instructions Emit An Audit Event With Before And After Values", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const pauserAccounts = {
+      globalState: globalStateKp.publicKey,
+      pauser: pg.wallet.publicKey,
+    };
+    // Tuple variants decode with positional keys.
+    const number = (value: any): BN => value.number["0"];
+    const numbers = (value: any): string[] => value.numbers["0"].map((n: BN) => n.toString());
+    const keys = (value: any): string[] => value.keys["0"].map((k: web3.PublicKey) => k.toBase58());
+    const audited = async (tx: string, action: string) => {
+      const events = await fetchEvents(tx, "adminAudit");
+      assert.equal(events.length, 1);
+      const [event] = events;
+      assert(action in event.action, JSON.stringify(event.action));
+      assert(event.actor.equals(pg.wallet.publicKey));
+      assert(event.timestamp.gtn(0));
+      return event;
+    };
+
+    let state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const oldFeeRate = state.feeRate;
+    let event = await audited(
+      await pg.program.methods.updateFeeRate(oldFeeRate.addn(7)).accounts(adminAccounts).rpc(),
+      "updateFeeRate"
+    );
+    assert(number(event.oldValue).eq(oldFeeRate));
+    assert(number(event.newValue).eq(oldFeeRate.addn(7)));
+    await pg.program.methods.updateFeeRate(oldFeeRate).accounts(adminAccounts).rpc();
+
+    const oldSplit = [state.lpShareBps.toString(), state.stakerShareBps.toString()];
+    event = await audited(
+      await pg.program.methods.setFeeSplit(new BN(6000), new BN(3000)).accounts(adminAccounts).rpc(),
+      "setFeeSplit"
+    );
+    assert.deepEqual(numbers(event.oldValue), oldSplit);
+    assert.deepEqual(numbers(event.newValue), ["6000", "3000"]);
+    await pg.program.methods
+      .setFeeSplit(state.lpShareBps, state.stakerShareBps)
+      .accounts(adminAccounts)
+      .rpc();
+
+    const newTreasurer = web3.Keypair.generate().publicKey;
+    event = await audited(
+      await pg.program.methods.setTreasurer(newTreasurer).accounts(adminAccounts).rpc(),
+      "setTreasurer"
+    );
+    assert(event.oldValue.key["0"].equals(state.treasurer));
+    assert(event.newValue.key["0"].equals(newTreasurer));
+    await pg.program.methods.setTreasurer(state.treasurer).accounts(adminAccounts).rpc();
+
+    const borrower = web3.Keypair.generate().publicKey;
+    const oldWhitelist = state.flashLoanWhitelist.map((k: web3.PublicKey) => k.toBase58());
+    event = await audited(
+      await pg.program.methods.addToWhitelist(borrower).accounts(adminAccounts).rpc(),
+      "addToWhitelist"
+    );
+    assert.deepEqual(keys(event.oldValue), oldWhitelist);
+    assert.deepEqual(keys(event.newValue), [...oldWhitelist, borrower.toBase58()]);
+    event = await audited(
+      await pg.program.methods.removeFromWhitelist(borrower).accounts(adminAccounts).rpc(),
+      "removeFromWhitelist"
+    );
+    assert.deepEqual(keys(event.newValue), oldWhitelist);
+
+    // Turning events off does not silence the audit trail.
+    event = await audited(
+      await pg.program.methods.setEventLevel({ off: {} }).accounts(adminAccounts).rpc(),
+      "setEventLevel"
+    );
+    assert("full" in event.oldValue.eventLevel["0"]);
+    assert("off" in event.newValue.eventLevel["0"]);
+    event = await audited(
+      await pg.program.methods.setPaused(true).accounts(pauserAccounts).rpc(),
+      "setPaused"
+    );
+    assert.equal(event.oldValue.flag["0"], false);
+    assert.equal(event.newValue.flag["0"], true);
+    event = await audited(
+      await pg.program.methods.setPaused(false).accounts(pauserAccounts).rpc(),
+      "setPaused"
+    );
+    assert.equal(event.newValue.flag["0"], false);
+    await pg.program.methods.setEventLevel({ full: {} }).accounts(adminAccounts).rpc();
+
+    state = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    assert(state.feeRate.eq(oldFeeRate));
+    assert("full" in state.eventLevel);
+  });
+
+  it("Rollover Settles And Prices Like Repaying And Borrowing Again", async () => {
+    const adminAccounts = {
+      globalState: globalStateKp.publicKey,
+      admin: pg.wallet.publicKey,
+    };
+    const [borrowerReputationPda] = web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("reputation"), borrower.publicKey.toBuffer()],
+      pg.program.programId
+    );
+    await pg.program.methods
+      .setDistributionParams(new BN(2000), new BN(0), new BN(0))
+      .accounts(adminAccounts)
+      .rpc();
+    const flashLoanStateKp = new web3.Keypair();
+    await pg.program.methods
+      .flashLoan(new BN(10_000), new BN(0), new BN(0))
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrower: borrower.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrowerCollateralAccount: pg.wallet.publicKey,
+        collateralEscrow: collateralEscrowPda,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower, flashLoanStateKp])
+      .rpc();
+    const rolloverAccounts = {
+      globalState: globalStateKp.publicKey,
+      pool: poolPda,
+      poolAccount: poolAccount.publicKey,
+      poolAuthority: pg.wallet.publicKey,
+      borrower: borrower.publicKey,
+      borrowerTokenAccount: pg.wallet.publicKey,
+      flashLoanState: flashLoanStateKp.publicKey,
+      borrowerCollateralAccount: pg.wallet.publicKey,
+      collateralEscrow: collateralEscrowPda,
+      borrowerReputation: borrowerReputationPda,
+      rewardVault: rewardVault.publicKey,
+      tokenProgram: splToken.TOKEN_PROGRAM_ID,
+    };
+    const rollover = (maxFee: BN, accounts = rolloverAccounts) =>
+      pg.program.methods
+        .rolloverFlashLoan(new BN(10_000), new BN(0), maxFee)
+        .accounts(accounts)
+        .signers([borrower])
+        .rpc();
+
+    // The stakers' share of the old fee has to reach the reward vault, as on repayment.
+    await expectError(rollover(new BN(0), { ...rolloverAccounts, rewardVault: null }), "RewardVaultRequired");
+    // The new loan honours the borrower's fee ceiling, as `flash_loan` does.
+    await expectError(rollover(new BN(1)), "FeeExceedsMax");
+
+    const oldLoan = await pg.program.account.flashLoanState.fetch(flashLoanStateKp.publicKey);
+    const before = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    await rollover(new BN(0));
+    const after = await pg.program.account.globalState.fetch(globalStateKp.publicKey);
+    const stakerFee = oldLoan.fee.muln(2000).divn(10_000);
+    assert(after.pendingStakerFees.sub(before.pendingStakerFees).eq(stakerFee));
+    assert(after.accumulatedFees.sub(before.accumulatedFees).eq(oldLoan.fee));
+    assert(after.isFlashLoanActive);
+
+    await pg.program.methods
+      .repayFlashLoan()
+      .accounts({
+        globalState: globalStateKp.publicKey,
+        pool: poolPda,
+        poolAccount: poolAccount.publicKey,
+        poolAuthority: pg.wallet.publicKey,
+        flashLoanState: flashLoanStateKp.publicKey,
+        borrower: borrower.publicKey,
+        repayer: borrower.publicKey,
+        borrowerTokenAccount: pg.wallet.publicKey,
+        borrowerReputation: borrowerReputationPda,
+        rewardVault: rewardVault.publicKey,
+        tokenProgram: splToken.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      })
+      .signers([borrower])
+      .rpc();
+    await pg.program.methods
+      .setDistributionParams(new BN(0), new BN(0), new BN(0))
+      .accounts(adminAccounts)
+      .rpc();
+  });
+});
+
+const REWARD_PRECISION = new BN("1000000000000");
+
+// Mirrors `staked_balance` in the program.
+function stakedBalance(userStake: any): BN {
+  return userStake.amount.add(userStake.compoundedAmount);
+}
+
+// Mirrors `pending_rewards` in the program.
+function pendingRewards(userStake: any, accRewardPerShare: BN): BN {
+  const accrued = stakedBalance(userStake).mul(accRewardPerShare).div(REWARD_PRECISION);
+  return userStake.unclaimedRewards.add(BN.max(accrued.sub(userStake.rewardDebt), new BN(0)));
+}
+
+function sleep(ms: number): Promise<void> {
+  return new Promise((resolve) => setTimeout(resolve, ms));
+}
+
+// Registers `vault` as an extra vault of `pool`, returning the registration.
+async function registerPoolVault(
+  globalState: web3.PublicKey,
+  pool: web3.PublicKey,
+  vault: web3.PublicKey
+): Promise<web3.PublicKey> {
+  const [registration] = web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("extra_pool_vault"), pool.toBuffer(), vault.toBuffer()],
+    pg.program.programId
+  );
+  await pg.program.methods
+    .addPoolVault()
+    .accounts({
+      globalState,
+      admin: pg.wallet.publicKey,
+      pool,
+      vault,
+      registration,
+      systemProgram: web3.SystemProgram.programId,
+    })
+    .rpc();
+  return registration;
+}
+
+// Asserts that `promise` rejects with the named program error.
+async function expectError(promise: Promise<unknown>, errorName: string) {
+  try {
+    await promise;
+  } catch (err) {
+    assert(err.toString().includes(errorName), err.toString());
+    return;
+  }
+  assert(false, `expected ${errorName}`);
+}
+
+// Decodes the program events named `eventName` emitted by a confirmed transaction.
+async function fetchEvents(txHash: string, eventName: string): Promise<any[]> {
+  await pg.connection.confirmTransaction(txHash);
+  const tx = await pg.connection.getTransaction(txHash, {
+    commitment: "confirmed",
+    maxSupportedTransactionVersion: 0,
+  });
+  const parser = new anchor.EventParser(
+    pg.program.programId,
+    new anchor.BorshCoder(pg.program.idl)
+  );
+  return [...parser.parseLogs(tx.meta.logMessages)]
+    .filter((event) => event.name === eventName)
+    .map((event) => event.data);
+}
+
+// Small deterministic PRNG so randomized sequences are reproducible.
+function mulberry32(seed: number): () => number {
+  return () => {
+    seed = (seed + 0x6d2b79f5) | 0;
+    let t = Math.imul(seed ^ (seed >>> 15), 1 | seed);
+    t = (t + Math.imul(t ^ (t >>> 7), 61 | t)) ^ t;
+    return ((t ^ (t >>> 14)) >>> 0) / 4294967296;
+  };
+}